@@ -0,0 +1,11 @@
+extern crate pkg_config;
+
+fn main() {
+    // libcryptsetup has no function that returns its version, so the one that is built against is recorded instead
+    // (cryptsetup-rs already links the library, this only reads its version)
+    match pkg_config::Config::new().cargo_metadata(false).probe("libcryptsetup") {
+        Ok(library) => println!("cargo:rustc-env=PEROXS_LIBCRYPTSETUP_VERSION={}", library.version),
+        Err(e) => println!("cargo:warning=Could not determine the version of libcryptsetup: {}", e),
+    }
+    println!("cargo:rerun-if-env-changed=PKG_CONFIG_PATH");
+}
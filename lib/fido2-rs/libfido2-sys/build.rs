@@ -0,0 +1,5 @@
+extern crate pkg_config;
+
+fn main() {
+    pkg_config::Config::new().find("libfido2").unwrap();
+}
@@ -0,0 +1,75 @@
+#![allow(non_camel_case_types)]
+
+extern crate libc;
+
+use libc::{c_char, c_int, c_uchar, size_t};
+
+// fido/err.h
+pub const FIDO_OK: c_int = 0x00;
+pub const FIDO_ERR_NO_CREDENTIALS: c_int = 0x2e;
+pub const FIDO_ERR_PIN_REQUIRED: c_int = 0x36;
+pub const FIDO_ERR_INVALID_ARGUMENT: c_int = -7;
+pub const FIDO_ERR_INTERNAL: c_int = -9;
+
+// fido/param.h
+pub const COSE_ES256: c_int = -7;
+pub const FIDO_EXT_HMAC_SECRET: c_int = 0x01;
+
+// fido_opt_t
+pub const FIDO_OPT_OMIT: c_int = 0;
+pub const FIDO_OPT_FALSE: c_int = 1;
+pub const FIDO_OPT_TRUE: c_int = 2;
+
+pub enum fido_dev_t {}
+pub enum fido_dev_info_t {}
+pub enum fido_assert_t {}
+pub enum fido_cred_t {}
+
+extern "C" {
+    // fido.h
+    pub fn fido_init(flags: c_int);
+    pub fn fido_strerr(n: c_int) -> *const c_char;
+
+    pub fn fido_dev_info_new(n: size_t) -> *mut fido_dev_info_t;
+    pub fn fido_dev_info_free(devlist_p: *mut *mut fido_dev_info_t, n: size_t);
+    pub fn fido_dev_info_manifest(devlist: *mut fido_dev_info_t, ilen: size_t, olen: *mut size_t) -> c_int;
+    pub fn fido_dev_info_ptr(devlist: *const fido_dev_info_t, i: size_t) -> *const fido_dev_info_t;
+    pub fn fido_dev_info_path(di: *const fido_dev_info_t) -> *const c_char;
+
+    pub fn fido_dev_new() -> *mut fido_dev_t;
+    pub fn fido_dev_free(dev_p: *mut *mut fido_dev_t);
+    pub fn fido_dev_open(dev: *mut fido_dev_t, path: *const c_char) -> c_int;
+    pub fn fido_dev_close(dev: *mut fido_dev_t) -> c_int;
+    pub fn fido_dev_make_cred(dev: *mut fido_dev_t, cred: *mut fido_cred_t, pin: *const c_char) -> c_int;
+    pub fn fido_dev_get_assert(dev: *mut fido_dev_t, assert: *mut fido_assert_t, pin: *const c_char) -> c_int;
+
+    pub fn fido_cred_new() -> *mut fido_cred_t;
+    pub fn fido_cred_free(cred_p: *mut *mut fido_cred_t);
+    pub fn fido_cred_set_type(cred: *mut fido_cred_t, cose_alg: c_int) -> c_int;
+    pub fn fido_cred_set_clientdata_hash(cred: *mut fido_cred_t, hash: *const c_uchar, len: size_t) -> c_int;
+    pub fn fido_cred_set_rp(cred: *mut fido_cred_t, id: *const c_char, name: *const c_char) -> c_int;
+    pub fn fido_cred_set_user(
+        cred: *mut fido_cred_t,
+        user_id: *const c_uchar,
+        user_id_len: size_t,
+        name: *const c_char,
+        display_name: *const c_char,
+        icon: *const c_char,
+    ) -> c_int;
+    pub fn fido_cred_set_extensions(cred: *mut fido_cred_t, flags: c_int) -> c_int;
+    pub fn fido_cred_id_ptr(cred: *const fido_cred_t) -> *const c_uchar;
+    pub fn fido_cred_id_len(cred: *const fido_cred_t) -> size_t;
+
+    pub fn fido_assert_new() -> *mut fido_assert_t;
+    pub fn fido_assert_free(assert_p: *mut *mut fido_assert_t);
+    pub fn fido_assert_set_rp(assert: *mut fido_assert_t, id: *const c_char) -> c_int;
+    pub fn fido_assert_set_clientdata_hash(assert: *mut fido_assert_t, hash: *const c_uchar, len: size_t) -> c_int;
+    pub fn fido_assert_allow_cred(assert: *mut fido_assert_t, ptr: *const c_uchar, len: size_t) -> c_int;
+    pub fn fido_assert_set_extensions(assert: *mut fido_assert_t, flags: c_int) -> c_int;
+    pub fn fido_assert_set_hmac_salt(assert: *mut fido_assert_t, ptr: *const c_uchar, len: size_t) -> c_int;
+    // fido_opt_t is a C enum, which has the representation of an int
+    pub fn fido_assert_set_up(assert: *mut fido_assert_t, up: c_int) -> c_int;
+    pub fn fido_assert_count(assert: *const fido_assert_t) -> size_t;
+    pub fn fido_assert_hmac_secret_ptr(assert: *const fido_assert_t, idx: size_t) -> *const c_uchar;
+    pub fn fido_assert_hmac_secret_len(assert: *const fido_assert_t, idx: size_t) -> size_t;
+}
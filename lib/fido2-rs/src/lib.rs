@@ -0,0 +1,253 @@
+extern crate libc;
+extern crate libfido2_sys;
+
+use std::error;
+use std::ffi::{CStr, CString};
+use std::fmt::{Display, Formatter};
+use std::ptr;
+use std::result;
+use std::slice;
+use std::sync::Once;
+
+use libc::c_char;
+use libfido2_sys as ffi;
+
+static FFI_INIT: Once = Once::new();
+
+/// Maximum number of devices that are considered when looking for a FIDO2 device
+const MAX_DEVICES: usize = 64;
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Length of the client data hash passed to the device
+pub const CLIENT_DATA_HASH_LENGTH: usize = 32;
+/// Length of the salt passed to the hmac-secret extension
+pub const HMAC_SECRET_SALT_LENGTH: usize = 32;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Error {
+    NoDevice,
+    NoHmacSecret,
+    PinRequired,
+    InvalidArgument,
+    FidoError(i32),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NoDevice => write!(f, "No FIDO2 device found"),
+            Error::NoHmacSecret => write!(f, "The FIDO2 device did not return a hmac-secret"),
+            Error::PinRequired => write!(f, "The FIDO2 device requires a PIN"),
+            Error::InvalidArgument => write!(f, "Invalid argument passed to libfido2"),
+            Error::FidoError(code) => {
+                let msg = unsafe { CStr::from_ptr(ffi::fido_strerr(*code)) };
+                write!(f, "FIDO2 error code {} ({})", code, msg.to_string_lossy())
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        None
+    }
+}
+
+impl Error {
+    fn from(res: i32) -> Result<()> {
+        match res {
+            ffi::FIDO_OK => Ok(()),
+            ffi::FIDO_ERR_PIN_REQUIRED => Err(Error::PinRequired),
+            ffi::FIDO_ERR_INVALID_ARGUMENT => Err(Error::InvalidArgument),
+            other => Err(Error::FidoError(other)),
+        }
+    }
+}
+
+struct NullPtr;
+
+impl NullPtr {
+    fn wrap_err<T>(t: *mut T) -> Result<*mut T> {
+        if t.is_null() {
+            Err(Error::FidoError(ffi::FIDO_ERR_INTERNAL))
+        } else {
+            Ok(t)
+        }
+    }
+}
+
+fn fido_init() {
+    FFI_INIT.call_once(|| unsafe { ffi::fido_init(0) });
+}
+
+fn c_string(bytes: &[u8]) -> Result<CString> {
+    CString::new(bytes).map_err(|_| Error::InvalidArgument)
+}
+
+fn pin_ptr(pin: &Option<CString>) -> *const c_char {
+    pin.as_ref().map_or(ptr::null(), |p| p.as_ptr())
+}
+
+/// Find the path of the first FIDO2 device attached to the system
+fn first_device_path() -> Result<CString> {
+    let mut devlist = NullPtr::wrap_err(unsafe { ffi::fido_dev_info_new(MAX_DEVICES) })?;
+    let mut found = 0;
+    let res = Error::from(unsafe { ffi::fido_dev_info_manifest(devlist, MAX_DEVICES, &mut found) }).and_then(|_| {
+        if found == 0 {
+            Err(Error::NoDevice)
+        } else {
+            let path = unsafe { CStr::from_ptr(ffi::fido_dev_info_path(ffi::fido_dev_info_ptr(devlist, 0))) };
+            Ok(path.to_owned())
+        }
+    });
+    unsafe { ffi::fido_dev_info_free(&mut devlist, MAX_DEVICES) };
+    res
+}
+
+struct Credential {
+    cred: *mut ffi::fido_cred_t,
+}
+
+impl Drop for Credential {
+    fn drop(&mut self) {
+        unsafe { ffi::fido_cred_free(&mut self.cred) }
+    }
+}
+
+struct Assertion {
+    assert: *mut ffi::fido_assert_t,
+}
+
+impl Drop for Assertion {
+    fn drop(&mut self) {
+        unsafe { ffi::fido_assert_free(&mut self.assert) }
+    }
+}
+
+pub struct Fido2Device {
+    dev: *mut ffi::fido_dev_t,
+}
+
+impl Drop for Fido2Device {
+    fn drop(&mut self) {
+        // closing a device that failed to open is harmless (and returns an error we can ignore)
+        unsafe {
+            ffi::fido_dev_close(self.dev);
+            ffi::fido_dev_free(&mut self.dev);
+        }
+    }
+}
+
+impl Fido2Device {
+    /// Open the first FIDO2 device attached to the system
+    pub fn open_first() -> Result<Fido2Device> {
+        fido_init();
+        let path = first_device_path()?;
+        let device = NullPtr::wrap_err(unsafe { ffi::fido_dev_new() }).map(|dev| Fido2Device { dev })?;
+        Error::from(unsafe { ffi::fido_dev_open(device.dev, path.as_ptr()) })?;
+        Ok(device)
+    }
+}
+
+pub trait HmacSecret {
+    /// Create a new (non-resident) credential with the hmac-secret extension enabled, returning its id
+    fn make_credential(
+        &mut self,
+        rp_id: &str,
+        user_id: &[u8],
+        client_data_hash: &[u8; CLIENT_DATA_HASH_LENGTH],
+        pin: Option<&[u8]>,
+    ) -> Result<Vec<u8>>;
+
+    /// Get the hmac-secret of an existing credential for the given salt
+    fn hmac_secret(
+        &mut self,
+        rp_id: &str,
+        credential_id: &[u8],
+        salt: &[u8; HMAC_SECRET_SALT_LENGTH],
+        client_data_hash: &[u8; CLIENT_DATA_HASH_LENGTH],
+        pin: Option<&[u8]>,
+    ) -> Result<Vec<u8>>;
+}
+
+impl HmacSecret for Fido2Device {
+    fn make_credential(
+        &mut self,
+        rp_id: &str,
+        user_id: &[u8],
+        client_data_hash: &[u8; CLIENT_DATA_HASH_LENGTH],
+        pin: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        let rp_id = c_string(rp_id.as_bytes())?;
+        let pin = pin.map(c_string).transpose()?;
+        let cred = NullPtr::wrap_err(unsafe { ffi::fido_cred_new() }).map(|cred| Credential { cred })?;
+
+        unsafe {
+            Error::from(ffi::fido_cred_set_type(cred.cred, ffi::COSE_ES256))?;
+            Error::from(ffi::fido_cred_set_clientdata_hash(
+                cred.cred,
+                client_data_hash.as_ptr(),
+                client_data_hash.len(),
+            ))?;
+            Error::from(ffi::fido_cred_set_rp(cred.cred, rp_id.as_ptr(), ptr::null()))?;
+            Error::from(ffi::fido_cred_set_user(
+                cred.cred,
+                user_id.as_ptr(),
+                user_id.len(),
+                rp_id.as_ptr(),
+                ptr::null(),
+                ptr::null(),
+            ))?;
+            Error::from(ffi::fido_cred_set_extensions(cred.cred, ffi::FIDO_EXT_HMAC_SECRET))?;
+            Error::from(ffi::fido_dev_make_cred(self.dev, cred.cred, pin_ptr(&pin)))?;
+
+            let id = slice::from_raw_parts(ffi::fido_cred_id_ptr(cred.cred), ffi::fido_cred_id_len(cred.cred));
+            Ok(id.to_vec())
+        }
+    }
+
+    fn hmac_secret(
+        &mut self,
+        rp_id: &str,
+        credential_id: &[u8],
+        salt: &[u8; HMAC_SECRET_SALT_LENGTH],
+        client_data_hash: &[u8; CLIENT_DATA_HASH_LENGTH],
+        pin: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        let rp_id = c_string(rp_id.as_bytes())?;
+        let pin = pin.map(c_string).transpose()?;
+        let assert = NullPtr::wrap_err(unsafe { ffi::fido_assert_new() }).map(|assert| Assertion { assert })?;
+
+        unsafe {
+            Error::from(ffi::fido_assert_set_rp(assert.assert, rp_id.as_ptr()))?;
+            Error::from(ffi::fido_assert_set_clientdata_hash(
+                assert.assert,
+                client_data_hash.as_ptr(),
+                client_data_hash.len(),
+            ))?;
+            Error::from(ffi::fido_assert_allow_cred(
+                assert.assert,
+                credential_id.as_ptr(),
+                credential_id.len(),
+            ))?;
+            Error::from(ffi::fido_assert_set_extensions(
+                assert.assert,
+                ffi::FIDO_EXT_HMAC_SECRET,
+            ))?;
+            Error::from(ffi::fido_assert_set_hmac_salt(assert.assert, salt.as_ptr(), salt.len()))?;
+            Error::from(ffi::fido_assert_set_up(assert.assert, ffi::FIDO_OPT_TRUE))?;
+            Error::from(ffi::fido_dev_get_assert(self.dev, assert.assert, pin_ptr(&pin)))?;
+
+            if ffi::fido_assert_count(assert.assert) < 1 {
+                return Err(Error::NoHmacSecret);
+            }
+            let secret_ptr = ffi::fido_assert_hmac_secret_ptr(assert.assert, 0);
+            let secret_len = ffi::fido_assert_hmac_secret_len(assert.assert, 0);
+            if secret_ptr.is_null() || secret_len == 0 {
+                return Err(Error::NoHmacSecret);
+            }
+            Ok(slice::from_raw_parts(secret_ptr, secret_len).to_vec())
+        }
+    }
+}
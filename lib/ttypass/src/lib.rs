@@ -1,35 +1,53 @@
-#[deny(warnings)]
-#[warn(unused_must_use)]
+#![deny(warnings)]
+#![warn(unused_must_use)]
 extern crate libc;
 extern crate termios;
 
 use std::io;
-use std::io::{Error, ErrorKind, Write};
-use std::mem;
+use std::io::{BufRead, Error, ErrorKind, Read, Write};
+use std::mem::{self, MaybeUninit};
 use std::os::unix::io::RawFd;
 use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
 use termios::*;
 
 pub type Result<T> = io::Result<T>;
 
-const INITIAL_PASSWORD_LENGTH: usize = 255;
+/// Maximum length (in bytes) of a password read by `read_password` (the same as cryptsetup's interactive maximum)
+pub const DEFAULT_MAX_PASSWORD_LENGTH: usize = 512;
 const STDIN_FD: RawFd = libc::STDIN_FILENO;
+/// Signals that would otherwise end the process with echo still turned off
+const RESTORE_ON_SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+// terminal attributes for the signal handler to restore (it cannot reach the guard)
+static mut SAVED_TERMIOS: MaybeUninit<libc::termios> = MaybeUninit::uninit();
+static TERMIOS_SAVED: AtomicBool = AtomicBool::new(false);
+
+/// Check whether stdin is attached to a TTY
+pub fn stdin_is_tty() -> bool {
+    unsafe { libc::isatty(STDIN_FD) == 1 }
+}
 
 /// Prompt for a password from a TTY, returning either the vector of bytes or an error (not a tty, timeout, etc.)
 pub fn read_password(prompt: &str, timeout_opt: Option<Duration>) -> Result<Vec<u8>> {
-    let is_tty = unsafe { libc::isatty(STDIN_FD) } == 1;
-    if !is_tty {
+    read_password_bytes(prompt, timeout_opt, DEFAULT_MAX_PASSWORD_LENGTH)
+}
+
+/// Prompt for a password from a TTY like `read_password`, accepting up to `max_length` bytes of any value (the password
+/// does not have to be valid UTF-8)
+pub fn read_password_bytes(prompt: &str, timeout_opt: Option<Duration>, max_length: usize) -> Result<Vec<u8>> {
+    if !stdin_is_tty() {
         return Err(Error::new(ErrorKind::BrokenPipe, "stdin is not a tty"));
     }
 
-    // save original and modified attrs
-    let term_orig = Termios::from_fd(STDIN_FD)?;
+    // save original (restored when the guard is dropped or the prompt is interrupted) and modified attrs
+    let _guard = TermiosGuard::new()?;
     let mut term_prompt = Termios::from_fd(STDIN_FD)?;
 
     // write prompt
-    io::stdout().write(prompt.as_bytes())?;
+    io::stdout().write_all(prompt.as_bytes())?;
     io::stdout().flush()?;
 
     // turn off echo, but allow newline
@@ -39,23 +57,93 @@ pub fn read_password(prompt: &str, timeout_opt: Option<Duration>) -> Result<Vec<
     tcsetattr(STDIN_FD, TCSANOW, &term_prompt)?;
 
     // read password
-    let password_res = match timeout_opt {
-        Some(timeout) => read_with_timeout(timeout),
-        _ => read_stdin(),
-    };
+    match timeout_opt {
+        Some(timeout) => read_with_timeout(timeout, max_length),
+        _ => read_stdin(max_length),
+    }
+}
+
+/// Restores the original terminal attributes of stdin when dropped, and if the process is killed by a signal while it
+/// is alive (e.g. Ctrl-C during a prompt)
+struct TermiosGuard {
+    original: Termios,
+    /// Signals whose handler was installed by the guard, with the action to put back
+    previous_actions: Vec<(libc::c_int, libc::sigaction)>,
+}
+
+impl TermiosGuard {
+    fn new() -> Result<TermiosGuard> {
+        let original = Termios::from_fd(STDIN_FD)?;
+
+        unsafe {
+            if libc::tcgetattr(STDIN_FD, ptr::addr_of_mut!(SAVED_TERMIOS).cast()) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        TERMIOS_SAVED.store(true, Ordering::SeqCst);
+
+        let mut previous_actions = Vec::with_capacity(RESTORE_ON_SIGNALS.len());
+        for &signal in RESTORE_ON_SIGNALS.iter() {
+            unsafe {
+                let mut previous: libc::sigaction = mem::zeroed();
+                if libc::sigaction(signal, ptr::null(), &mut previous) != 0 {
+                    continue;
+                }
+                // signals that are ignored or handled by the application are left alone
+                if previous.sa_sigaction != libc::SIG_DFL {
+                    continue;
+                }
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = restore_and_reraise as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                libc::sigemptyset(&mut action.sa_mask);
+                if libc::sigaction(signal, &action, ptr::null_mut()) == 0 {
+                    previous_actions.push((signal, previous));
+                }
+            }
+        }
+
+        Ok(TermiosGuard {
+            original,
+            previous_actions,
+        })
+    }
+}
+
+impl Drop for TermiosGuard {
+    fn drop(&mut self) {
+        // always try to reset the terminal
+        let _ = tcsetattr(STDIN_FD, TCSANOW, &self.original);
+        TERMIOS_SAVED.store(false, Ordering::SeqCst);
+        for (signal, previous) in self.previous_actions.drain(..) {
+            unsafe {
+                libc::sigaction(signal, &previous, ptr::null_mut());
+            }
+        }
+    }
+}
 
-    // always try to reset the terminal
-    tcsetattr(STDIN_FD, TCSANOW, &term_orig)?;
+/// Signal handler that resets the terminal and then lets the signal kill the process as it would have done
+extern "C" fn restore_and_reraise(signal: libc::c_int) {
+    unsafe {
+        if TERMIOS_SAVED.load(Ordering::SeqCst) {
+            libc::tcsetattr(STDIN_FD, libc::TCSANOW, ptr::addr_of!(SAVED_TERMIOS).cast());
+        }
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
 
-    password_res
+/// Convert a duration to a `timeval` for `select`, clamping durations that do not fit to the longest possible one
+fn timeval_from(duration: Duration) -> libc::timeval {
+    libc::timeval {
+        tv_sec: duration.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_usec: duration.subsec_micros() as libc::suseconds_t,
+    }
 }
 
-fn read_with_timeout(timeout: Duration) -> Result<Vec<u8>> {
+fn read_with_timeout(timeout: Duration, max_length: usize) -> Result<Vec<u8>> {
     // time interval
-    let mut timeval = libc::timeval {
-        tv_sec: timeout.as_secs() as libc::time_t,
-        tv_usec: timeout.subsec_nanos() as libc::suseconds_t,
-    };
+    let mut timeval = timeval_from(timeout);
 
     // create fd_set
     let num_fds = STDIN_FD + 1;
@@ -96,26 +184,67 @@ fn read_with_timeout(timeout: Duration) -> Result<Vec<u8>> {
         return Err(Error::new(ErrorKind::TimedOut, "timed out while reading passphrase"));
     }
 
-    read_stdin()
+    read_stdin(max_length)
 }
 
-fn read_stdin() -> Result<Vec<u8>> {
-    let mut pass = String::with_capacity(INITIAL_PASSWORD_LENGTH);
-    io::stdin()
-        .read_line(&mut pass)
-        .map(|_| pass.into_bytes())
-        .and_then(|mut buf| {
-            if buf.len() > 0 {
-                if let Some(b'\n') = buf.pop() {
-                    Ok(buf)
-                } else {
-                    Err(Error::new(
-                        ErrorKind::UnexpectedEof,
-                        "passphrase should contain a newline at end",
-                    ))
-                }
-            } else {
-                Err(Error::new(ErrorKind::UnexpectedEof, "passphrase cannot be empty"))
+fn read_stdin(max_length: usize) -> Result<Vec<u8>> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    // room for the newline is reserved up front, so that no copy of the password is left behind by a reallocation
+    let mut buf = Vec::with_capacity(max_length + 1);
+    (&mut input).take(max_length as u64 + 1).read_until(b'\n', &mut buf)?;
+
+    match buf.pop() {
+        Some(b'\n') => Ok(buf),
+        Some(_) if buf.len() >= max_length => {
+            // the rest of the line is not the next input
+            discard_line(&mut input)?;
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("passphrase cannot be longer than {} bytes", max_length),
+            ))
+        }
+        Some(_) => Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "passphrase should contain a newline at end",
+        )),
+        None => Err(Error::new(ErrorKind::UnexpectedEof, "passphrase cannot be empty")),
+    }
+}
+
+fn discard_line<R: BufRead>(input: &mut R) -> Result<()> {
+    loop {
+        let (found, used) = {
+            let available = input.fill_buf()?;
+            if available.is_empty() {
+                return Ok(());
             }
-        })
+            match available.iter().position(|&b| b == b'\n') {
+                Some(pos) => (true, pos + 1),
+                None => (false, available.len()),
+            }
+        };
+        input.consume(used);
+        if found {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeval_from_uses_microseconds() {
+        let timeval = timeval_from(Duration::from_millis(1500));
+        assert_eq!(timeval.tv_sec, 1);
+        assert_eq!(timeval.tv_usec, 500_000);
+    }
+
+    #[test]
+    fn test_timeval_from_clamps_huge_durations() {
+        let timeval = timeval_from(Duration::from_secs(u64::MAX));
+        assert_eq!(timeval.tv_sec, libc::time_t::MAX);
+    }
 }
@@ -265,7 +265,7 @@ pub enum MODE {
 
 pub const DEFAULT_CHAL_TIMEOUT: i32 = 15;
 
-pub const SCAN_MAP: &'static [u8] = b"cbdefghijklnrtuvCBDEFGHIJKLNRTUV0123456789!\t\r";
+pub const SCAN_MAP: &[u8] = b"cbdefghijklnrtuvCBDEFGHIJKLNRTUV0123456789!\t\r";
 pub const SHIFT_FLAG: i32 = 0x80;
 
 #[repr(C)]
@@ -288,7 +288,7 @@ pub enum TOUCHLEVEL_BIT {
     CONFIG_STATUS_MASK = 0x1f,
 }
 
-pub const MODHEX_MAP: &'static [u8] = b"cbdefghijklnrtuv";
+pub const MODHEX_MAP: &[u8] = b"cbdefghijklnrtuv";
 
 pub const YUBICO_VID: i32 = 0x1050;
 
@@ -388,6 +388,7 @@ extern "C" {
     pub fn yk_release() -> c_int;
 
     pub fn yk_open_first_key() -> *mut yk_key_st;
+    pub fn yk_open_key(index: c_int) -> *mut yk_key_st;
     pub fn yk_close_key(k: *mut yk_key_st) -> c_int;
 
     pub fn yk_get_status(k: *mut yk_key_st, status: *mut yk_status_st) -> c_int;
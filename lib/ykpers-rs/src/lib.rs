@@ -3,15 +3,26 @@ extern crate libykpers_sys;
 
 use std::error;
 use std::fmt::{Display, Formatter};
+use std::ptr;
+use std::rc::Rc;
 use std::result;
-use std::sync::atomic::{AtomicIsize, Ordering};
-use std::sync::Once;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use libc::{c_int, c_uint, c_void};
 use libykpers_sys as ffi;
+pub use libykpers_sys::YK_ERR;
 
-static FFI_INIT: Once = Once::new();
-static FFI_INIT_RESULT: AtomicIsize = AtomicIsize::new(-1);
+/// State of libykpers, which keeps a single (global) libusb context for all the keys
+static FFI_STATE: Mutex<FfiState> = Mutex::new(FfiState { initialised: false });
 static MIN_VERSION_CHAL_RESP: Version = (2, 2, 0);
+/// Maximum number of keys that are considered when enumerating the attached keys
+const MAX_KEYS: i32 = 16;
+/// Length of the response of an OTP challenge-response (as libykpers expects it)
+const OTP_RESPONSE_LENGTH: usize = 16;
+/// How often the key is polled for a response while it waits for a touch
+const TOUCH_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
 pub type Result<T> = result::Result<T, Error>;
 pub type Version = (i32, i32, i32);
@@ -23,6 +34,7 @@ pub const SHA1_RESPONSE_LENGTH: usize = ffi::SHA1_DIGEST_SIZE;
 pub enum ValidationError {
     InvalidSlot,
     MinimumVersionNotMet { expected: Version, got: Version },
+    SerialNotFound { expected: u32 },
 }
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -42,6 +54,9 @@ impl Display for Error {
                 "Validation error - expected minimum version {}.{}.{}, got {}.{}.{} instead",
                 expected.0, expected.1, expected.2, got.0, got.1, got.2
             ),
+            Error::Validation(ValidationError::SerialNotFound { expected }) => {
+                write!(f, "Validation error - no Yubikey with serial {} found", expected)
+            }
             Error::UsbError => write!(f, "USB error - unknown"),
             Error::YkError(ref cause) => write!(f, "Yubikey error code {:?}", cause),
             Error::Unknown(ref num) => write!(f, "Unknown error code {}", num),
@@ -98,20 +113,150 @@ impl NullPtr {
     }
 }
 
-fn yk_init() -> Result<()> {
-    FFI_INIT.call_once(|| {
-        let res = unsafe { ffi::yk_init() };
-        FFI_INIT_RESULT.store(res as isize, Ordering::Release);
-    });
-    let res = FFI_INIT_RESULT.load(Ordering::Acquire);
-    Error::from_zero_err(res as i32)
+struct FfiState {
+    initialised: bool,
+}
+
+impl FfiState {
+    fn init(&mut self) -> Result<()> {
+        if !self.initialised {
+            Error::from_zero_err(unsafe { ffi::yk_init() })?;
+            self.initialised = true;
+        }
+        Ok(())
+    }
+
+    /// Release libykpers (and its libusb context) if it is initialised
+    fn release(&mut self) {
+        if self.initialised {
+            // TODO - check return code?
+            unsafe { ffi::yk_release() };
+            self.initialised = false;
+        }
+    }
+}
+
+/// Exclusive access to libykpers for the current thread
+///
+/// libykpers keeps the libusb state of all the keys in one global, so the keys must not be opened, enumerated or used
+/// by several threads at once. Other threads block in `YubikeyManager::lock` until the manager is dropped, which also
+/// releases libykpers. The devices opened by the manager borrow it, so they are all closed before that.
+///
+/// The functions of `Yubikey` lock a manager themselves (which their devices keep until they are dropped), so they must
+/// not be called by a thread that holds one (which would deadlock): use the methods of the manager instead.
+pub struct YubikeyManager {
+    state: MutexGuard<'static, FfiState>,
+}
+
+/// An attached key, as found by `YubikeyManager::enumerate`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct YubikeyInfo {
+    /// Index of the key for libykpers (in the order of the USB enumeration)
+    pub index: i32,
+    /// Serial of the key (not all keys expose it)
+    pub serial: Option<u32>,
+    pub version: Version,
+    /// Slots (1 and/or 2) that have a configuration
+    pub configured_slots: Vec<u8>,
+}
+
+impl YubikeyManager {
+    /// Wait for exclusive access to libykpers, initialising it if needed
+    pub fn lock() -> Result<YubikeyManager> {
+        // the state is only a flag that is updated after the FFI call, so it is still consistent after a panic
+        let mut state = FFI_STATE.lock().unwrap_or_else(PoisonError::into_inner);
+        state.init()?;
+        Ok(YubikeyManager { state })
+    }
+
+    /// Open the first attached key
+    pub fn open_first(&self) -> Result<YubikeyDevice<'_>> {
+        open_first(ManagerRef::Borrowed(self))
+    }
+
+    /// Open the attached key with the given serial (keys that do not expose their serial can never match)
+    pub fn open_by_serial(&self, serial: u32) -> Result<YubikeyDevice<'_>> {
+        open_by_serial(ManagerRef::Borrowed(self), serial)
+    }
+
+    /// Open all the attached keys (up to `MAX_KEYS`)
+    pub fn open_all(&self) -> Result<Vec<YubikeyDevice<'_>>> {
+        open_all(ManagerRef::Borrowed(self))
+    }
+
+    /// Read the serial, firmware version and configured slots of all the attached keys (which are closed again)
+    pub fn enumerate(&self) -> Result<Vec<YubikeyInfo>> {
+        self.open_all()?
+            .iter()
+            .zip(0..)
+            .map(|(device, index)| {
+                let status = device.get_status()?;
+                Ok(YubikeyInfo {
+                    index,
+                    serial: device.get_serial().ok(),
+                    version: status.get_version_triple(),
+                    configured_slots: [1, 2]
+                        .into_iter()
+                        .filter(|&slot| status.is_slot_configured(slot))
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl Drop for YubikeyManager {
+    fn drop(&mut self) {
+        // the devices borrow the manager, so none of them is open anymore
+        self.state.release();
+    }
+}
+
+/// The manager a device was opened by, which keeps libykpers locked while the device is open
+// the manager is never used through it, only held
+#[allow(dead_code)]
+#[derive(Clone)]
+enum ManagerRef<'m> {
+    /// Opened by the methods of a manager held by the caller
+    Borrowed(&'m YubikeyManager),
+    /// Opened by the functions of `Yubikey`, which lock a manager of their own (shared by the devices they open)
+    Owned(Rc<YubikeyManager>),
 }
 
-pub struct YubikeyDevice {
+fn open_first(manager: ManagerRef<'_>) -> Result<YubikeyDevice<'_>> {
+    NullPtr::wrap_err(unsafe { ffi::yk_open_first_key() }).map(|key| YubikeyDevice { key, _manager: manager })
+}
+
+fn open_by_serial(manager: ManagerRef<'_>, serial: u32) -> Result<YubikeyDevice<'_>> {
+    open_all(manager)?
+        .into_iter()
+        .find(|device| device.get_serial() == Ok(serial))
+        .ok_or(Error::Validation(ValidationError::SerialNotFound { expected: serial }))
+}
+
+fn open_all(manager: ManagerRef<'_>) -> Result<Vec<YubikeyDevice<'_>>> {
+    let mut devices = vec![];
+    for index in 0..MAX_KEYS {
+        let key = unsafe { ffi::yk_open_key(index) };
+        if key.is_null() {
+            break;
+        }
+        devices.push(YubikeyDevice {
+            key,
+            _manager: manager.clone(),
+        });
+    }
+    Ok(devices)
+}
+
+/// An open key, which can only be used while libykpers is locked by the manager it was opened by
+pub struct YubikeyDevice<'m> {
     key: *mut ffi::yk_key_st,
+    // dropped after the key is closed (see `Drop`), so that the lock is released last
+    _manager: ManagerRef<'m>,
 }
 
-impl Drop for YubikeyDevice {
+impl<'m> Drop for YubikeyDevice<'m> {
     fn drop(&mut self) {
         // TODO - check return code?
         unsafe { ffi::yk_close_key(self.key) };
@@ -132,6 +277,16 @@ pub trait YubikeyStatus {
     fn get_version_triple(&self) -> Version;
     fn get_programming_seq(&self) -> i32;
     fn get_touch_level(&self) -> i32;
+
+    /// Check whether a slot (1 or 2) is configured (according to the touch level bits)
+    fn is_slot_configured(&self, slot: u8) -> bool {
+        let valid_bit = match slot {
+            1 => ffi::TOUCHLEVEL_BIT::CONFIG1_VALID,
+            2 => ffi::TOUCHLEVEL_BIT::CONFIG2_VALID,
+            _ => return false,
+        };
+        self.get_touch_level() & valid_bit as i32 != 0
+    }
 }
 
 pub trait Yubikey {
@@ -146,26 +301,39 @@ pub trait Yubikey {
     fn get_serial(&self) -> Result<u32>
     where
         Self::Status: YubikeyStatus;
+    fn open_by_serial(serial: u32) -> Result<Self>
+    where
+        Self: Sized;
+    fn open_all() -> Result<Vec<Self>>
+    where
+        Self: Sized;
 }
 
-impl Yubikey for YubikeyDevice {
+impl<'m> Yubikey for YubikeyDevice<'m> {
     type Status = YubikeyDeviceStatus;
 
-    fn new() -> Result<YubikeyDevice> {
-        yk_init()
-            .and_then(|_| NullPtr::wrap_err(unsafe { ffi::yk_open_first_key() }).map(|key| YubikeyDevice { key: key }))
+    fn new() -> Result<YubikeyDevice<'m>> {
+        open_first(ManagerRef::Owned(Rc::new(YubikeyManager::lock()?)))
     }
 
     fn get_status(&self) -> Result<Self::Status> {
         NullPtr::wrap_err(unsafe { ffi::ykds_alloc() })
             .and_then(|status| Error::from_zero_err(unsafe { ffi::yk_get_status(self.key, status) }).map(|_| status))
-            .map(|status| YubikeyDeviceStatus { status: status })
+            .map(|status| YubikeyDeviceStatus { status })
     }
     fn get_serial(&self) -> Result<u32> {
         let mut serial = 0;
         Error::from_zero_err(unsafe { ffi::yk_get_serial(self.key, 1, 0, &mut serial) })?;
         Ok(serial)
     }
+
+    fn open_by_serial(serial: u32) -> Result<YubikeyDevice<'m>> {
+        open_by_serial(ManagerRef::Owned(Rc::new(YubikeyManager::lock()?)), serial)
+    }
+
+    fn open_all() -> Result<Vec<YubikeyDevice<'m>>> {
+        open_all(ManagerRef::Owned(Rc::new(YubikeyManager::lock()?)))
+    }
 }
 
 impl YubikeyStatus for YubikeyDeviceStatus {
@@ -188,18 +356,154 @@ impl YubikeyStatus for YubikeyDeviceStatus {
 pub struct ChallengeResponseParams {
     pub is_hmac: bool,
     pub slot: u8,
+    /// Wait for the key (e.g. for a button press) instead of failing with `YkError(EWOULDBLOCK)`
+    pub may_block: bool,
+    /// How long a blocking challenge-response polls the key while it waits for a touch before failing with
+    /// `YkError(ETIMEOUT)` (until the key gives up on its own if not set, ignored unless `may_block` is set)
+    pub timeout: Option<Duration>,
+}
+
+impl ChallengeResponseParams {
+    /// Parameters for a blocking challenge-response (which waits for the key until it gives up on its own)
+    pub fn new(is_hmac: bool, slot: u8) -> ChallengeResponseParams {
+        ChallengeResponseParams {
+            is_hmac,
+            slot,
+            may_block: true,
+            timeout: None,
+        }
+    }
+
+    fn yk_cmd(&self) -> Result<ffi::SLOT> {
+        match (self.slot, self.is_hmac) {
+            (1, true) => Ok(ffi::SLOT::CHAL_HMAC1),
+            (1, false) => Ok(ffi::SLOT::CHAL_OTP1),
+            (2, true) => Ok(ffi::SLOT::CHAL_HMAC2),
+            (2, false) => Ok(ffi::SLOT::CHAL_OTP2),
+            _ => Err(Error::Validation(ValidationError::InvalidSlot)),
+        }
+    }
+
+    fn expected_length(&self) -> usize {
+        if self.is_hmac {
+            SHA1_RESPONSE_LENGTH
+        } else {
+            OTP_RESPONSE_LENGTH
+        }
+    }
+}
+
+/// Response of an HMAC-SHA1 challenge-response: only the digest, which is cleared when dropped
+pub struct HmacResponse([u8; SHA1_RESPONSE_LENGTH]);
+
+impl HmacResponse {
+    pub fn as_bytes(&self) -> &[u8; SHA1_RESPONSE_LENGTH] {
+        &self.0
+    }
+}
+
+impl Drop for HmacResponse {
+    fn drop(&mut self) {
+        clear(&mut self.0);
+    }
+}
+
+/// Overwrite a buffer with zeroes (with volatile writes, so that they are not optimised away)
+fn clear(buffer: &mut [u8]) {
+    for byte in buffer.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
 }
 
 pub trait ChallengeResponse {
+    /// Raw challenge-response (e.g. for OTP mode): the response is written to the whole block, of which only the start
+    /// is meaningful
     fn challenge_response(
         &mut self,
         params: ChallengeResponseParams,
         challenge: &[u8],
         response: &mut [u8; SHA1_BLOCK_LENGTH],
     ) -> Result<()>;
+
+    /// Wait for the response to the challenge that was sent last, after a non-blocking challenge-response failed with
+    /// `YkError(EWOULDBLOCK)` because the key waits for a touch (`params.may_block` is ignored)
+    fn read_pending_response(
+        &mut self,
+        params: ChallengeResponseParams,
+        response: &mut [u8; SHA1_BLOCK_LENGTH],
+    ) -> Result<()>;
+
+    /// HMAC-SHA1 challenge-response (`params.is_hmac` is ignored), returning only the digest
+    fn hmac_challenge_response(&mut self, params: ChallengeResponseParams, challenge: &[u8]) -> Result<HmacResponse> {
+        let params = ChallengeResponseParams {
+            is_hmac: true,
+            ..params
+        };
+        hmac_response(|block| self.challenge_response(params, challenge, block))
+    }
+
+    /// Wait for the response to a pending HMAC-SHA1 challenge (see `read_pending_response`), returning only the digest
+    fn hmac_read_pending_response(&mut self, params: ChallengeResponseParams) -> Result<HmacResponse> {
+        let params = ChallengeResponseParams {
+            is_hmac: true,
+            ..params
+        };
+        hmac_response(|block| self.read_pending_response(params, block))
+    }
+}
+
+/// Keep only the digest of an HMAC-SHA1 response that is read into a whole block
+fn hmac_response<F: FnOnce(&mut [u8; SHA1_BLOCK_LENGTH]) -> Result<()>>(read: F) -> Result<HmacResponse> {
+    let mut block = [0u8; SHA1_BLOCK_LENGTH];
+    let res = read(&mut block);
+
+    let mut response = HmacResponse([0u8; SHA1_RESPONSE_LENGTH]);
+    response.0.copy_from_slice(&block[..SHA1_RESPONSE_LENGTH]);
+    // the block is cleared even if the challenge-response failed
+    clear(&mut block);
+    res.map(|_| response)
 }
 
-impl ChallengeResponse for YubikeyDevice {
+impl<'m> YubikeyDevice<'m> {
+    /// Poll the key for the response to the challenge that was sent last
+    ///
+    /// libykpers fails with `YK_EWOULDBLOCK` at once while the key waits for a touch (as `YK_FLAG_MAYBLOCK` is not
+    /// passed), so a blocking read keeps polling until the key is touched, the timeout runs out or the key gives up
+    /// on its own (libykpers then fails with `YK_ETIMEOUT`).
+    fn read_response(
+        &mut self,
+        params: &ChallengeResponseParams,
+        response: &mut [u8; SHA1_BLOCK_LENGTH],
+    ) -> Result<()> {
+        let yk_cmd = params.yk_cmd()?;
+        let started = Instant::now();
+        loop {
+            let mut bytes_read = 0;
+            let res = Error::from_zero_err(unsafe {
+                ffi::yk_read_response_from_key(
+                    self.key,
+                    yk_cmd as u8,
+                    0,
+                    response.as_mut_ptr() as *mut c_void,
+                    response.len() as c_uint,
+                    params.expected_length() as c_uint,
+                    &mut bytes_read,
+                )
+            });
+            match res {
+                Err(Error::YkError(ffi::YK_ERR::EWOULDBLOCK)) if params.may_block => {
+                    if params.timeout.map_or(false, |timeout| started.elapsed() >= timeout) {
+                        return Err(Error::YkError(ffi::YK_ERR::ETIMEOUT));
+                    }
+                    thread::sleep(TOUCH_POLL_INTERVAL);
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<'m> ChallengeResponse for YubikeyDevice<'m> {
     fn challenge_response(
         &mut self,
         params: ChallengeResponseParams,
@@ -216,36 +520,64 @@ impl ChallengeResponse for YubikeyDevice {
             }));
         }
 
-        let may_block = 1;
-        let yk_cmd = match params.slot {
-            1 => {
-                if params.is_hmac {
-                    ffi::SLOT::CHAL_HMAC1
-                } else {
-                    ffi::SLOT::CHAL_OTP1
-                }
-            }
-            2 => {
-                if params.is_hmac {
-                    ffi::SLOT::CHAL_HMAC2
-                } else {
-                    ffi::SLOT::CHAL_OTP2
-                }
-            }
-            _ => return Err(Error::Validation(ValidationError::InvalidSlot)),
-        };
-        let res = unsafe {
-            ffi::yk_challenge_response(
+        // the challenge is sent once, and the key is then only polled (sending it again would restart the wait)
+        let yk_cmd = params.yk_cmd()?;
+        Error::from_zero_err(unsafe {
+            ffi::yk_write_to_key(
                 self.key,
                 yk_cmd as u8,
-                may_block,
-                challenge.len() as u32,
-                challenge.as_ptr(),
-                response.len() as u32,
-                response.as_mut_ptr(),
+                challenge.as_ptr() as *const c_void,
+                challenge.len() as c_int,
             )
+        })?;
+        self.read_response(&params, response)
+    }
+
+    fn read_pending_response(
+        &mut self,
+        params: ChallengeResponseParams,
+        response: &mut [u8; SHA1_BLOCK_LENGTH],
+    ) -> Result<()> {
+        let params = ChallengeResponseParams {
+            may_block: true,
+            ..params
+        };
+        self.read_response(&params, response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::MaybeUninit;
+
+    use expectest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_hmac_response_is_cleared_when_dropped() {
+        let mut response = MaybeUninit::new(HmacResponse([0xaa; SHA1_RESPONSE_LENGTH]));
+        // the memory of the response stays valid after it is dropped in place, so the digest can be read back
+        let digest = unsafe {
+            ptr::drop_in_place(response.as_mut_ptr());
+            ptr::read(ptr::addr_of!((*response.as_ptr()).0))
         };
+        expect!(digest).to(be_equal_to([0u8; SHA1_RESPONSE_LENGTH]));
+    }
+
+    #[test]
+    fn test_hmac_response_keeps_only_the_digest() {
+        let response = hmac_response(|block| {
+            block.fill(0xaa);
+            Ok(())
+        });
+        expect!(response.map(|r| *r.as_bytes())).to(be_ok().value([0xaa; SHA1_RESPONSE_LENGTH]));
+    }
 
-        Error::from_zero_err(res as i32)
+    #[test]
+    fn test_clear_zeroes_the_buffer() {
+        let mut buffer = [0xaau8; SHA1_BLOCK_LENGTH];
+        clear(&mut buffer);
+        expect!(buffer.iter().all(|&byte| byte == 0)).to(be_true());
     }
 }
@@ -0,0 +1,97 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_derive::Deserialize;
+use snafu::prelude::*;
+
+use crate::operation::{ConfigSnafu, IoSnafu, Result};
+
+/// Defaults for the global options, read from `$XDG_CONFIG_HOME/peroxs/config.toml` (or `~/.config/peroxs/config.toml`)
+///
+/// Every setting is optional, and the command line flags take precedence over all of them.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Database to use (relative paths are relative to the directory of the configuration file)
+    pub database: Option<PathBuf>,
+    /// Number of seconds to wait for a passphrase to be entered (0 waits forever)
+    pub input_timeout: Option<u64>,
+    /// Whether to prompt for passphrases with pinentry (instead of on the terminal)
+    pub pinentry: Option<bool>,
+}
+
+impl Config {
+    /// Load the configuration file (an empty configuration if there is none)
+    pub fn load() -> Result<Config> {
+        match config_path() {
+            Some(path) => Config::load_from(&path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    fn load_from(path: &Path) -> Result<Config> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(e).context(IoSnafu),
+        };
+        let mut config: Config = toml::from_str(&contents).context(ConfigSnafu { path })?;
+
+        if let (Some(database), Some(dir)) = (config.database.as_mut(), path.parent()) {
+            *database = dir.join(&*database);
+        }
+        Ok(config)
+    }
+}
+
+/// Location of the configuration file according to the XDG base directory specification
+fn config_path() -> Option<PathBuf> {
+    let absolute = |var: &str| env::var_os(var).map(PathBuf::from).filter(|p| p.is_absolute());
+    absolute("XDG_CONFIG_HOME")
+        .or_else(|| absolute("HOME").map(|home| home.join(".config")))
+        .map(|dir| dir.join("peroxs").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use expectest::prelude::*;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_load_config_resolves_database_relative_to_config_dir() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            "database = \"peroxs-db.json\"\ninput_timeout = 60\npinentry = false\n",
+        )
+        .unwrap();
+
+        expect!(Config::load_from(&path)).to(be_ok().value(Config {
+            database: Some(dir.path().join("peroxs-db.json")),
+            input_timeout: Some(60),
+            pinentry: Some(false),
+        }));
+    }
+
+    #[test]
+    fn test_load_config_missing_file_is_empty() {
+        let dir = tempdir().unwrap();
+        expect!(Config::load_from(&dir.path().join("config.toml"))).to(be_ok().value(Config::default()));
+    }
+
+    #[test]
+    fn test_load_config_rejects_unknown_settings() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "databse = \"peroxs-db.json\"\n").unwrap();
+
+        expect!(Config::load_from(&path)).to(be_err());
+    }
+}
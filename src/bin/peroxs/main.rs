@@ -1,26 +1,45 @@
 #![deny(warnings)]
 #![deny(bare_trait_objects)]
-#[warn(unused_must_use)]
+#![warn(unused_must_use)]
 #[macro_use]
 extern crate log;
 #[macro_use]
 extern crate prettytable;
 
 use std::convert::Infallible;
-use std::path::PathBuf;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::FromStr;
+use std::time::Duration;
 
-use clap::{value_parser, Args, Parser, Subcommand, ValueHint};
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{value_parser, ArgAction, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum, ValueHint};
+use clap_complete::Shell;
 use log::Level;
-use snafu::ErrorCompat;
+use serde_derive::{Deserialize, Serialize};
+use snafu::{ensure, ErrorCompat, OptionExt};
 
-use operation::{PathOrUuid, Result};
-use peroxide_cryptsetup::context::{DiskEnrolmentParams, EntryParams, FormatContainerParams, MainContext};
-use peroxide_cryptsetup::db::{DbEntryType, DbType, YubikeyEntryType};
+use config::Config;
+use manifest::Manifest;
+use name_template::NameTemplate;
+use operation::{PathOrUuid, Result, ValidationSnafu};
+use peroxide_cryptsetup::context::{
+    DiskEnrolmentParams, EntryParams, FormatContainerParams, MainContext, RecoveryOutput,
+};
+use peroxide_cryptsetup::db::{ActivationOptions, DbEntryType, DbType, PeroxideDb, YubikeyEntryType};
+use peroxide_cryptsetup::device::{ActivationFlags, KeyslotPriority};
+use peroxide_cryptsetup::input::{passphrase_from_env, FdPrompt, PassphraseInput, SecStr};
+use peroxide_cryptsetup::messages::{self, Verbosity};
 
+mod config;
+mod manifest;
+mod name_template;
 mod operation;
 
+/// Database used when neither `--database` nor the configuration file name one
+const DEFAULT_DATABASE: &str = "peroxs-db.json";
+
 #[derive(Parser, Debug)]
 #[command(author, about, version, max_term_width = 120, disable_colored_help = false)]
 struct Opts {
@@ -32,28 +51,300 @@ struct Opts {
 
 #[derive(Args, Debug)]
 struct GlobalOpts {
-    #[arg(short, long, visible_aliases = &["db"], long_help = "The database to use", default_value = "peroxs-db.json", value_hint = ValueHint::FilePath, global=true)]
-    database: PathBuf,
+    #[arg(short, long, visible_aliases = &["db"], long_help = "The database to use, - reads it from standard input (only for the commands that do not change it) [default: the database in the configuration file, or peroxs-db.json]", value_hint = ValueHint::FilePath, global=true)]
+    database: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        long_help = "Number of seconds to wait for a passphrase to be entered, 0 waits forever [default: 30]",
+        global = true
+    )]
+    input_timeout: Option<u64>,
+    #[arg(
+        long,
+        value_name = "BOOL",
+        long_help = "Prompt for passphrases with pinentry instead of on the terminal (if compiled in) [default: true]",
+        global = true
+    )]
+    pinentry: Option<bool>,
+    #[arg(
+        long,
+        long_help = "Read passphrases from standard input (one line per prompt) instead of prompting for them",
+        global = true
+    )]
+    passphrase_stdin: bool,
+    #[arg(
+        long,
+        value_name = "FD",
+        long_help = "Read the key (a passphrase or the contents of a keyfile) from this inherited file descriptor until EOF, instead of prompting or reading the keyfile",
+        global = true,
+        conflicts_with = "passphrase_stdin"
+    )]
+    key_fd: Option<RawFd>,
+    #[arg(
+        long,
+        value_name = "VAR",
+        long_help = "Read passphrases from this environment variable (which is then removed from the environment) instead of prompting for them - meant for CI systems, as the variable may be readable by other processes",
+        global = true,
+        conflicts_with_all = ["passphrase_stdin", "key_fd"]
+    )]
+    passphrase_env: Option<String>,
+    /// Passphrase read from the `--passphrase-env` variable (once, as the variable is removed afterwards)
+    #[arg(skip)]
+    env_passphrase: Option<SecStr>,
+    #[arg(
+        long,
+        long_help = "Only print what enrolling or opening disks would do, without changing the disks or the database",
+        global = true
+    )]
+    dry_run: bool,
+    #[arg(
+        long,
+        long_help = "Do not save the database after enrolling, registering or rotating keys (the disks are still changed, so the database will not know about their new keyslots - use with care)",
+        global = true
+    )]
+    no_db_save: bool,
+    #[arg(
+        long,
+        long_help = "Do not lock the database (by default it is locked with `<database>.lock`, so that concurrent peroxs commands do not overwrite each other's changes)",
+        global = true
+    )]
+    no_lock: bool,
+    #[arg(
+        long,
+        long_help = "Number of attempts at entering a passphrase interactively when opening disks",
+        default_value = "3",
+        value_parser = value_parser!(u32).range(1..),
+        global = true
+    )]
+    tries: u32,
+    #[cfg(feature = "systemd")]
+    #[arg(
+        long,
+        long_help = "Ask for passphrases through systemd-ask-password (so that password agents like plymouth are used)",
+        global = true,
+        conflicts_with_all = ["passphrase_stdin", "key_fd", "passphrase_env"]
+    )]
+    ask_password_agent: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ErrorFormat::Human,
+        long_help = "Format of the error printed on failure (json prints an object with the error and its kind to stderr)",
+        global = true
+    )]
+    error_format: ErrorFormat,
+    #[arg(
+        long,
+        long_help = "Print the result of the command as JSON (for use by other tools) instead of the human readable form",
+        global = true
+    )]
+    json: bool,
+    #[arg(
+        short,
+        long,
+        long_help = "Do not print status messages (e.g. asking to touch a token), only prompts, results and errors",
+        global = true,
+        conflicts_with = "verbose"
+    )]
+    quiet: bool,
+    #[arg(
+        short,
+        long,
+        long_help = "Also print details of what is being done (e.g. the disks that were activated)",
+        global = true
+    )]
+    verbose: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ErrorFormat {
+    Human,
+    Json,
+}
+
+/// Error printed with `--error-format json`
+#[derive(Serialize, Debug)]
+struct ErrorOutput {
+    error: String,
+    kind: &'static str,
 }
 
 #[derive(Subcommand, Debug)]
 enum TopSubcommand {
+    #[command(
+        about = "Check the database, its keyfiles and the disks for security issues (failing if a high-severity one is found)"
+    )]
+    Audit(AuditCommand),
+    #[command(about = "Benchmark argon2id on this machine to suggest the PBKDF parameters of new keyslots")]
+    Benchmark(BenchmarkCommand),
+    #[command(
+        about = "Open the enrolled disks flagged to be opened at boot that are present (e.g. from a systemd service)"
+    )]
+    BootOpen(BootOpenCommand),
+    #[command(about = "Close (deactivate) enrolled LUKS disk(s)")]
+    Close(CloseCommand),
+    #[command(about = "Print the completion script of peroxs for a shell", hide = true)]
+    Completions(CompletionsCommand),
+    #[command(about = "Manage the database itself")]
+    Db(DbCommand),
     #[command(about = "Enroll a new or existing LUKS disk(s) in the database (adding a new keyslot)")]
     Enroll(EnrollCommand),
+    #[command(about = "Export the database entries for use by other tools")]
+    Export(ExportCommand),
+    #[command(about = "List the database entries of disks that are not present (and remove them with --prune)")]
+    Gc(GcCommand),
+    #[command(about = "Generate a keyfile of random bytes (readable only by its owner) to enroll disks with")]
+    Genkey(GenkeyCommand),
+    #[command(about = "Back up or restore the header of an enrolled LUKS disk")]
+    Header(HeaderCommand),
+    #[command(about = "Import the entries of another database into the database (existing entries are kept)")]
+    Import(ImportCommand),
     #[command(about = "Initialize a new peroxide-db database")]
     Init(InitCommand),
+    #[command(about = "Manage the label and subsystem in the header of LUKS2 disks")]
+    Label(LabelCommand),
     #[command(about = "List disks enrolled in a database")]
     List(ListCommand),
+    #[command(about = "Convert an enrolled LUKS1 disk to LUKS2 in place (adding a token for its key)")]
+    Migrate(MigrateCommand),
     #[command(about = "Open enrolled LUKS disk(s)")]
     Open(OpenCommand),
+    #[command(about = "Check whether enrolled disks are attached (exits with 2 if any is not, printing nothing)")]
+    Present(PresentCommand),
     #[command(about = "Register an existing entry in the database (without adding a new keyslot)")]
     Register(RegisterCommand),
+    #[command(about = "Remove the enrolled key of a LUKS disk (the keyslot and the database entry)")]
+    Remove(RemoveCommand),
+    #[command(about = "Change the name of a device in the database")]
+    Rename(RenameCommand),
+    #[command(about = "Resume a suspended LUKS disk (prompting for its key again)")]
+    Resume(ResumeCommand),
+    #[command(
+        about = "Replace the key of an enrolled LUKS disk (the previous keyslot is removed)",
+        disable_help_flag = true
+    )]
+    Rotate(RotateCommand),
+    #[command(about = "Set the activation flags an enrolled disk is always opened with (e.g. discards for an SSD)")]
+    SetActivationOptions(SetActivationOptionsCommand),
+    #[command(about = "Set whether an enrolled disk is opened by `boot-open`")]
+    SetAutoOpen(SetAutoOpenCommand),
+    #[command(about = "Show the parameters of active LUKS mappings")]
+    Status(StatusCommand),
+    #[command(about = "Suspend an open LUKS disk (IO is frozen and the key is wiped from the kernel until resumed)")]
+    Suspend(SuspendCommand),
+    #[command(about = "Check which keyslot of a LUKS disk a key unlocks (without opening the disk)")]
+    TestKey(TestKeyCommand),
+    #[command(about = "Verify that the database entries are consistent with the disks that are present")]
+    Verify(VerifyCommand),
+    #[command(about = "Destroy a keyslot of a LUKS disk without any of its keys (e.g. to revoke a lost key)")]
+    WipeKeyslot(WipeKeyslotCommand),
+    #[cfg(feature = "yubikey")]
+    #[command(about = "Inspect the Yubikeys attached to the system")]
+    Yubikey(YubikeyCommand),
+}
+
+#[derive(Args, Debug)]
+struct AuditCommand {}
+
+#[derive(Args, Debug)]
+struct BenchmarkCommand {
+    #[arg(
+        short = 'i',
+        long,
+        long_help = "Number of milliseconds one key derivation should take",
+        default_value = "1000"
+    )]
+    iteration_ms: u32,
+    #[arg(
+        long,
+        long_help = "Maximum memory (in KiB) argon2 may use (the suggested memory cost is at most this)",
+        default_value = "1048576"
+    )]
+    max_memory_kb: u32,
+    #[arg(long, long_help = "Number of parallel threads for argon2", default_value = "4")]
+    argon2_parallel_threads: u32,
+    #[arg(
+        short = 's',
+        long,
+        long_help = "Number of key bits of the LUKS container the keyslots are for",
+        default_value = "512"
+    )]
+    key_bits: usize,
+}
+
+#[derive(Args, Debug)]
+struct BootOpenCommand {
+    #[arg(long, long_help = "Allow TRIM/discard requests to be passed through to the devices")]
+    allow_discards: bool,
+    #[arg(long, long_help = "Activate the devices read-only")]
+    read_only: bool,
+}
+
+#[derive(Args, Debug)]
+struct CloseCommand {
+    #[arg(
+        short,
+        long,
+        long_help = "Override name specified in database (if any), as it was given when activating the device"
+    )]
+    name: Option<String>,
+    #[arg(
+        long,
+        conflicts_with = "name",
+        long_help = "Template for the device mapper names, as it was given when activating the device(s)"
+    )]
+    name_template: Option<String>,
+    #[arg(long_help ="The path(s) to the device, the LUKS UUID(s) or the name(s) of the device", value_hint = ValueHint::FilePath)]
+    device_or_uuid: Vec<DiskReference>,
+}
+
+#[derive(Args, Debug)]
+struct CompletionsCommand {
+    #[arg(value_enum, long_help = "The shell to print the completion script for")]
+    shell: CompletionShell,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+#[derive(Parser, Debug)]
+struct DbCommand {
+    #[command(subcommand)]
+    subcmd: DbSubcommand,
+}
+
+#[derive(Parser, Debug)]
+enum DbSubcommand {
+    #[command(about = "Save a copy of the database as a backup database (for use with `enroll --backup-db`)")]
+    Backup(DbBackup),
+}
+
+#[derive(Args, Debug)]
+struct DbBackup {
+    #[arg(long_help = "Path to save the backup database to", value_hint = ValueHint::FilePath)]
+    dest: PathBuf,
+    #[arg(long, long_help = "Overwrite the file at the destination if it exists")]
+    force: bool,
 }
 
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, arg_required_else_help = true)]
 struct EnrollCommand {
+    #[arg(long, value_name = "FILE", long_help = "Enroll the disks listed in a JSON manifest (each with its own key type and parameters) instead", value_hint = ValueHint::FilePath)]
+    manifest: Option<PathBuf>,
+    #[arg(
+        long,
+        requires = "manifest",
+        long_help = "Do not ask for confirmation before force formatting the disks of the manifest (required when standard input is not a terminal)"
+    )]
+    yes: bool,
     #[command(subcommand)]
-    subcmd: EnrollSubcommand,
+    subcmd: Option<EnrollSubcommand>,
 }
 
 #[derive(Parser, Debug)]
@@ -65,6 +356,56 @@ enum EnrollSubcommand {
     #[cfg(feature = "yubikey")]
     #[command(about = "Enroll using a Yubikey token", disable_help_flag = true)]
     Yubikey(EnrollYubikey),
+    #[cfg(feature = "fido2")]
+    #[command(about = "Enroll using a FIDO2 token", disable_help_flag = true)]
+    Fido2(EnrollFido2),
+}
+
+/// Named argon2 parameters for new LUKS2 keyslots, so that they don't have to be picked one by one
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Argon2Preset {
+    /// Low memory, e.g. for small machines that have to unlock quickly
+    Fast,
+    Balanced,
+    /// High memory and more iterations, for disks that are opened rarely
+    Paranoid,
+}
+
+/// The argon2 iterations, memory (in KiB) and parallel threads used when neither a preset nor the flags are given
+const DEFAULT_ARGON2_PARAMS: Argon2Params = Argon2Params {
+    iterations: 1000000,
+    memory_kb: 512000,
+    parallel_threads: 4,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Argon2Params {
+    iterations: u32,
+    memory_kb: u32,
+    parallel_threads: u32,
+}
+
+impl Argon2Preset {
+    fn params(self) -> Argon2Params {
+        match self {
+            Argon2Preset::Fast => Argon2Params {
+                iterations: 4,
+                memory_kb: 65536,
+                parallel_threads: 2,
+            },
+            Argon2Preset::Balanced => Argon2Params {
+                iterations: 4,
+                memory_kb: 524288,
+                parallel_threads: 4,
+            },
+            Argon2Preset::Paranoid => Argon2Params {
+                iterations: 10,
+                memory_kb: 2097152,
+                parallel_threads: 4,
+            },
+        }
+    }
 }
 
 #[derive(Args, Debug)]
@@ -78,6 +419,11 @@ struct LuksFormatParams {
         requires = "format"
     )]
     force_format: bool,
+    #[arg(
+        long,
+        long_help = "Do not ask for confirmation before force formatting (required when standard input is not a terminal)"
+    )]
+    yes: bool,
     #[arg(short='1', long, long_help ="Use LUKS version 1", groups=&["luks-version"])]
     luks1: bool,
     #[arg(short='2', long, long_help ="Use LUKS version 2 (default)", groups=&["luks-version"])]
@@ -112,25 +458,60 @@ struct LuksFormatParams {
     hash: String,
     #[arg(
         long,
-        long_help = "Number of iterations for argon2",
-        default_value = "1000000",
+        value_enum,
+        long_help = "Preset of the argon2 iterations, memory and threads: fast (4, 65536 KiB, 2), balanced (4, 524288 KiB, 4) or paranoid (10, 2097152 KiB, 4), each overridden by the individual flags",
         conflicts_with = "luks1"
     )]
-    argon2_iterations: u32,
+    argon2_preset: Option<Argon2Preset>,
     #[arg(
         long,
-        long_help = "Number of parallel threads for argon2",
-        default_value = "4",
+        long_help = "Number of iterations for argon2 [default: 1000000]",
         conflicts_with = "luks1"
     )]
-    argon2_parallel_threads: u32,
+    argon2_iterations: Option<u32>,
     #[arg(
         long,
-        long_help = "Memory to use for argon2",
-        default_value = "512000",
+        long_help = "Number of parallel threads for argon2 [default: 4]",
         conflicts_with = "luks1"
     )]
-    argon2_memory_kb: u32,
+    argon2_parallel_threads: Option<u32>,
+    #[arg(
+        long,
+        long_help = "Memory to use for argon2 [default: 512000]",
+        conflicts_with = "luks1"
+    )]
+    argon2_memory_kb: Option<u32>,
+    #[arg(
+        long,
+        long_help = "Sector size of the encrypted data in bytes (one of 512, 1024, 2048 or 4096)",
+        conflicts_with = "luks1",
+        requires = "format"
+    )]
+    sector_size: Option<u32>,
+    #[arg(
+        long,
+        value_name = "SECTORS",
+        long_help = "Align the start of the data area to a multiple of this many 512-byte sectors",
+        conflicts_with = "luks1",
+        requires = "format"
+    )]
+    data_alignment: Option<u32>,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        long_help = "Size of the LUKS2 JSON metadata area, a power of two from 16384 to 4194304 bytes (e.g. for large tokens)",
+        conflicts_with = "luks1",
+        requires = "format"
+    )]
+    luks2_metadata_size: Option<u64>,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        long_help = "Size of the LUKS2 keyslots area, a multiple of 4096 bytes up to 134217728 (e.g. for many keyslots)",
+        conflicts_with = "luks1",
+        requires = "format"
+    )]
+    luks2_keyslots_size: Option<u64>,
     #[arg(
         long,
         visible_alias = "save-label",
@@ -139,6 +520,20 @@ struct LuksFormatParams {
         requires = "format"
     )]
     save_label_in_header: bool,
+    #[arg(
+        long,
+        long_help = "Label to save in the LUKS header (independently of the name in the database)",
+        conflicts_with_all = ["luks1", "save_label_in_header"],
+        requires = "format"
+    )]
+    label: Option<String>,
+    #[arg(
+        long,
+        long_help = "Subsystem to save in the LUKS header",
+        conflicts_with = "luks1",
+        requires = "format"
+    )]
+    subsystem: Option<String>,
 }
 
 #[derive(Args, Debug)]
@@ -149,8 +544,55 @@ struct EnrollCommon {
     format_params: LuksFormatParams,
     #[arg(short, long, long_help = "The name of the device in the database")]
     name: Option<String>,
+    #[arg(
+        long,
+        value_name = "NAME",
+        long_help = "Enroll the disk that is open under the mapping NAME (instead of giving its path or UUID)",
+        conflicts_with_all = ["device_or_uuid", "format", "force_format"]
+    )]
+    use_active: Option<String>,
     #[arg(long, long_help ="Path to another database that can be used to unlock the device", value_hint = ValueHint::FilePath, conflicts_with = "format")]
     backup_db: Option<PathBuf>,
+    #[arg(long, long_help ="File with the raw volume key of the device, used instead of an existing key (e.g. when migrating from other tooling)", value_hint = ValueHint::FilePath, conflicts_with_all = ["format", "backup_db"])]
+    volume_key_file: Option<PathBuf>,
+    #[arg(
+        long,
+        long_help = "LUKS keyslot to add the key to (0-7 for LUKS1, 0-31 for LUKS2) [default: first free keyslot]"
+    )]
+    keyslot: Option<u8>,
+    #[arg(
+        long,
+        long_help = "LUKS2 priority of the new keyslot: ignore (only used when asked for explicitly), normal or prefer"
+    )]
+    priority: Option<KeyslotPriority>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "-",
+        long_help = "Also add a generated recovery passphrase to another keyslot, printed once or written to a new file at PATH (only readable by its owner)",
+        value_hint = ValueHint::FilePath
+    )]
+    with_recovery: Option<PathBuf>,
+    #[arg(
+        long,
+        long_help = "Only format the device(s) with the key and print their UUID, without adding them to the database (their key can be registered later)",
+        requires = "format",
+        conflicts_with = "with_recovery"
+    )]
+    format_only: bool,
+}
+
+#[derive(Args, Debug)]
+struct KeyfileRegion {
+    #[arg(long, long_help = "Offset (in bytes) of the key in the key file")]
+    keyfile_offset: Option<u64>,
+    #[arg(
+        long,
+        long_help = "Size (in bytes) of the key in the key file [default: rest of the file]"
+    )]
+    keyfile_size: Option<usize>,
 }
 
 #[derive(Args, Debug)]
@@ -158,6 +600,8 @@ struct EnrollKeyfile {
     #[arg(long_help ="An existing key file with randomness inside", value_hint = ValueHint::FilePath)]
     keyfile: PathBuf,
     #[command(flatten)]
+    region: KeyfileRegion,
+    #[command(flatten)]
     common: EnrollCommon,
 }
 
@@ -180,97 +624,589 @@ struct EnrollYubikey {
     common: EnrollCommon,
 }
 
+#[cfg(feature = "fido2")]
 #[derive(Args, Debug)]
-struct InitCommand {
-    #[arg(long_help = "Database type to enroll")]
-    db_type: DbType, // todo: show possible values
+struct EnrollFido2 {
+    #[command(flatten)]
+    common: EnrollCommon,
 }
 
 #[derive(Args, Debug)]
-struct ListCommand {
+struct ExportCommand {
+    #[command(subcommand)]
+    subcmd: ExportSubcommand,
+}
+
+#[derive(Parser, Debug)]
+enum ExportSubcommand {
+    #[command(about = "Print /etc/crypttab lines for the disks in the database")]
+    Crypttab(ExportCrypttab),
+}
+
+#[derive(Args, Debug)]
+struct ExportCrypttab {
     #[arg(
         long,
-        long_help = "List all devices in database, regardless of whether they can be found to be attached to the system currently"
+        long_help = "Append the `discard` option to all entries (allow TRIM requests on the devices)"
     )]
-    all: bool,
+    discard: bool,
 }
 
 #[derive(Args, Debug)]
-struct OpenCommand {
+struct GcCommand {
     #[arg(
-        short,
         long,
-        long_help = "Override name specified in database (if any) when activating the device"
+        long_help = "Remove the listed entries from the database (an entry of a disk that is present is never removed)"
     )]
-    name: Option<String>,
-    #[arg(long_help ="The path(s) to the device or the LUKS UUID(s) of the device", value_hint = ValueHint::FilePath)]
-    device_or_uuid: Vec<DiskReference>,
+    prune: bool,
+    #[arg(
+        long,
+        requires = "prune",
+        long_help = "Do not ask for confirmation before removing the entries (required when standard input is not a terminal)"
+    )]
+    yes: bool,
 }
 
 #[derive(Args, Debug)]
-struct RegisterCommand {
-    #[command(subcommand)]
-    subcmd: RegisterSubcommand,
+struct GenkeyCommand {
+    #[arg(long_help = "Path of the new keyfile (an existing file is never overwritten)", value_hint = ValueHint::FilePath)]
+    path: PathBuf,
+    #[arg(long, long_help = "Number of random bytes in the keyfile", default_value = "4096")]
+    bytes: usize,
 }
 
-#[derive(Parser, Debug)]
-enum RegisterSubcommand {
-    #[command(about = "Register an existing keyfile")]
-    Keyfile(RegisterKeyfile),
-    #[command(about = "Register an existing passphrase")]
-    Passphrase(RegisterPassphrase),
+#[derive(Args, Debug)]
+struct ImportCommand {
+    #[arg(long_help = "The database to import the entries of", value_hint = ValueHint::FilePath)]
+    other_db: PathBuf,
+    #[arg(long, long_help = "Import the entries even if the databases are of different types")]
+    force: bool,
 }
 
 #[derive(Args, Debug)]
-struct RegisterCommon {
-    #[arg(long_help ="The path(s) to the device or the LUKS UUID(s) of the device", value_hint = ValueHint::FilePath)]
-    device_or_uuid: Vec<PathOrUuid>,
-    #[arg(short, long, long_help = "The name of the device in the database")]
-    name: Option<String>,
+struct InitCommand {
+    #[arg(
+        long_help = "Database type to enroll",
+        value_parser = PossibleValuesParser::new(["operation", "backup"]).map(|s| DbType::from_str(&s).unwrap())
+    )]
+    db_type: DbType,
 }
 
-#[derive(Args, Debug)]
-struct RegisterKeyfile {
-    #[arg(long_help ="Path to an existing keyfile", value_hint = ValueHint::FilePath)]
-    keyfile: PathBuf,
-    #[command(flatten)]
-    common: RegisterCommon,
+#[derive(Parser, Debug)]
+struct HeaderCommand {
+    #[command(subcommand)]
+    subcmd: HeaderSubcommand,
+}
+
+#[derive(Parser, Debug)]
+enum HeaderSubcommand {
+    #[command(about = "Save a copy of the LUKS header (including the keyslots) of a disk to a new file")]
+    Backup(HeaderBackup),
+    #[command(about = "Overwrite the LUKS header of a disk with a backup of it (the UUIDs must match)")]
+    Restore(HeaderRestore),
 }
 
 #[derive(Args, Debug)]
-struct RegisterPassphrase {
-    #[command(flatten)]
-    common: RegisterCommon,
+struct HeaderBackup {
+    #[arg(long_help ="The path to the device, the LUKS UUID or the name of the device", value_hint = ValueHint::FilePath)]
+    device_or_uuid: DiskReference,
+    #[arg(long_help = "The file to write the header to (it must not exist)", value_hint = ValueHint::FilePath)]
+    out: PathBuf,
 }
 
-#[derive(Debug, Clone)]
-pub struct DiskReference(String);
+#[derive(Args, Debug)]
+struct HeaderRestore {
+    #[arg(long_help ="The path to the device, the LUKS UUID or the name of the device", value_hint = ValueHint::FilePath)]
+    device_or_uuid: DiskReference,
+    #[arg(long_help = "The header backup of the device", value_hint = ValueHint::FilePath)]
+    from: PathBuf,
+}
 
-impl FromStr for DiskReference {
-    type Err = Infallible;
+#[derive(Parser, Debug)]
+struct LabelCommand {
+    #[command(subcommand)]
+    subcmd: LabelSubcommand,
+}
 
-    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        Ok(DiskReference(s.to_string()))
-    }
+#[derive(Parser, Debug)]
+enum LabelSubcommand {
+    #[command(about = "Change the label and/or subsystem in the header of an existing LUKS2 disk")]
+    Set(LabelSet),
 }
 
-fn cipher_mode(params: &LuksFormatParams) -> (String, String) {
-    // split cipher string by - e.g. 'aes-xts-plain' becomes 'aes' and 'xts-plain'
-    let res = params.cipher.splitn(2, '-').collect::<Vec<_>>();
-    if res.len() != 2 {
-        panic!("Expect cipher to be splittable by name-mode e.g. aes-xts-plain")
-    } else {
-        (res[0].to_string(), res[1].to_string())
-    }
+#[derive(Args, Debug)]
+struct LabelSet {
+    #[arg(long_help ="The path to the device, the LUKS UUID or the name of the device", value_hint = ValueHint::FilePath)]
+    device_or_uuid: DiskReference,
+    #[arg(long, long_help = "The new label of the device")]
+    label: Option<String>,
+    #[arg(long, long_help = "The new subsystem of the device")]
+    subsystem: Option<String>,
 }
 
-fn format_params(params: &LuksFormatParams) -> FormatContainerParams {
-    let (cipher, cipher_mode) = cipher_mode(params);
-    let hash = params.hash.clone();
-    let key_bits = params.key_bits.clone();
-    let iteration_ms = params.iteration_ms.clone();
+#[derive(Args, Debug)]
+struct ListCommand {
+    #[arg(
+        long,
+        long_help = "List all devices in database, regardless of whether they can be found to be attached to the system currently"
+    )]
+    all: bool,
+    #[arg(
+        long,
+        long_help = "Show the full UUIDs of the disks (instead of the shortest prefixes that tell them apart)"
+    )]
+    full_uuid: bool,
+}
 
-    if params.luks1 {
+#[derive(Args, Debug)]
+struct MigrateCommand {
+    #[arg(long_help ="The path to the device, the LUKS UUID or the name of the device", value_hint = ValueHint::FilePath)]
+    device_or_uuid: DiskReference,
+    #[arg(
+        long,
+        long_help = "Do not ask for confirmation before converting the header (required when standard input is not a terminal)"
+    )]
+    yes: bool,
+}
+
+#[derive(Args, Debug)]
+struct OpenCommand {
+    #[arg(
+        short,
+        long,
+        long_help = "Override name specified in database (if any) when activating the device"
+    )]
+    name: Option<String>,
+    #[arg(
+        long,
+        conflicts_with = "name",
+        long_help = "Template for the device mapper names, evaluated per device (placeholders: {name}, {uuid}, {short_uuid}, {index}, {serial})"
+    )]
+    name_template: Option<String>,
+    #[arg(
+        long,
+        long_help = "Allow TRIM/discard requests to be passed through to the device(s)"
+    )]
+    allow_discards: bool,
+    #[arg(
+        long,
+        long_help = "Activate the device(s) read-only (e.g. for recovery, the mapping can then be mounted with `-o ro`)"
+    )]
+    read_only: bool,
+    #[arg(
+        long,
+        long_help = "Keep the volume key in the kernel keyring instead of the device mapper table (LUKS2 only), so that later operations like a resize do not ask for the key again"
+    )]
+    keyring: bool,
+    #[arg(long, long_help = "Bypass the dm-crypt workqueue for reads (e.g. for fast SSDs)")]
+    no_read_workqueue: bool,
+    #[arg(long, long_help = "Bypass the dm-crypt workqueue for writes")]
+    no_write_workqueue: bool,
+    #[arg(
+        long,
+        long_help = "Do not add the activation flags stored in the database entries (see `set-activation-options`), only use the ones given"
+    )]
+    ignore_entry_options: bool,
+    #[arg(
+        long,
+        value_name = "SECS",
+        long_help = "Wait up to this many seconds for the device(s) to appear (e.g. for hotplugged disks)"
+    )]
+    wait: Option<u64>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        long_help = "Open the device(s) with the contents of this file as the key, whatever the type of their database entries",
+        value_hint = ValueHint::FilePath
+    )]
+    key_file: Option<PathBuf>,
+    #[arg(
+        long,
+        conflicts_with_all = ["name", "name_template", "allow_discards", "read_only", "keyring", "no_read_workqueue", "no_write_workqueue", "ignore_entry_options"],
+        long_help = "Only check that the key of each device unlocks one of its keyslots, without activating any of them"
+    )]
+    test_only: bool,
+    #[arg(long_help ="The path(s) to the device or the LUKS UUID(s) of the device", value_hint = ValueHint::FilePath)]
+    device_or_uuid: Vec<DiskReference>,
+}
+
+#[derive(Args, Debug)]
+struct PresentCommand {
+    #[arg(
+        long,
+        value_name = "SECS",
+        long_help = "Wait up to this many seconds for the device(s) to appear"
+    )]
+    wait: Option<u64>,
+    #[arg(required = true, long_help ="The path(s) to the device, the LUKS UUID(s) or the name(s) of the device", value_hint = ValueHint::FilePath)]
+    device_or_uuid: Vec<DiskReference>,
+}
+
+#[derive(Args, Debug)]
+struct RegisterCommand {
+    #[command(subcommand)]
+    subcmd: RegisterSubcommand,
+}
+
+#[derive(Parser, Debug)]
+enum RegisterSubcommand {
+    #[command(about = "Register an existing keyfile")]
+    Keyfile(RegisterKeyfile),
+    #[command(about = "Register an existing passphrase")]
+    Passphrase(RegisterPassphrase),
+}
+
+#[derive(Args, Debug)]
+struct RegisterCommon {
+    #[arg(long_help ="The path(s) to the device or the LUKS UUID(s) of the device", value_hint = ValueHint::FilePath)]
+    device_or_uuid: Vec<PathOrUuid>,
+    #[arg(short, long, long_help = "The name of the device in the database")]
+    name: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct RegisterKeyfile {
+    #[arg(long_help ="Path to an existing keyfile (a relative path is relative to the directory of the database)", value_hint = ValueHint::FilePath)]
+    keyfile: PathBuf,
+    #[command(flatten)]
+    region: KeyfileRegion,
+    #[command(flatten)]
+    common: RegisterCommon,
+}
+
+#[derive(Args, Debug)]
+struct RegisterPassphrase {
+    #[command(flatten)]
+    common: RegisterCommon,
+}
+
+#[derive(Args, Debug)]
+struct RemoveCommand {
+    #[arg(
+        long,
+        value_name = "KEYSLOT",
+        long_help = "Destroy this keyslot instead of the one of the enrolled key, after prompting for the key of another keyslot (like `cryptsetup luksKillSlot`)"
+    )]
+    slot: Option<u8>,
+    #[arg(long_help ="The path to the device, the LUKS UUID or the name of the device", value_hint = ValueHint::FilePath)]
+    device_or_uuid: DiskReference,
+}
+
+#[derive(Args, Debug)]
+struct RenameCommand {
+    #[arg(long_help ="The path to the device, the LUKS UUID or the current name of the device", value_hint = ValueHint::FilePath)]
+    device_or_uuid: DiskReference,
+    #[arg(long_help = "The new name of the device in the database")]
+    new_name: String,
+}
+
+#[derive(Args, Debug)]
+struct ResumeCommand {
+    #[arg(
+        short,
+        long,
+        long_help = "Override name specified in database (if any), as it was given when activating the device"
+    )]
+    name: Option<String>,
+    #[arg(long_help ="The path to the device, the LUKS UUID or the name of the device", value_hint = ValueHint::FilePath)]
+    device_or_uuid: DiskReference,
+}
+
+#[derive(Args, Debug)]
+struct RotateCommand {
+    #[arg(long_help ="The path to the device, the LUKS UUID or the name of the device", value_hint = ValueHint::FilePath)]
+    device_or_uuid: DiskReference,
+    #[arg(
+        long,
+        value_name = "PATH",
+        long_help = "The keyfile with the new key (required for a disk enrolled with a keyfile, whose entry then refers to it)",
+        value_hint = ValueHint::FilePath
+    )]
+    new_keyfile: Option<PathBuf>,
+    #[command(flatten)]
+    format_params: LuksFormatParams,
+}
+
+#[derive(Args, Debug)]
+struct SetActivationOptionsCommand {
+    #[arg(long_help ="The path to the device, the LUKS UUID or the name of the device", value_hint = ValueHint::FilePath)]
+    device_or_uuid: DiskReference,
+    #[arg(long, long_help = "Always pass TRIM/discard requests through to the device")]
+    allow_discards: bool,
+    #[arg(long, long_help = "Always activate the device read-only")]
+    read_only: bool,
+    #[arg(long, long_help = "Always bypass the dm-crypt workqueue for reads")]
+    no_read_workqueue: bool,
+    #[arg(long, long_help = "Always bypass the dm-crypt workqueue for writes")]
+    no_write_workqueue: bool,
+}
+
+#[derive(Args, Debug)]
+struct SetAutoOpenCommand {
+    #[arg(long_help ="The path to the device, the LUKS UUID or the name of the device", value_hint = ValueHint::FilePath)]
+    device_or_uuid: DiskReference,
+    #[arg(long_help = "Whether the device is opened by `boot-open` (true or false)", action = ArgAction::Set, value_parser = value_parser!(bool))]
+    auto_open: bool,
+}
+
+#[derive(Args, Debug)]
+struct StatusCommand {
+    #[arg(long_help = "The name of the active mapping to show (all active mappings are shown otherwise)")]
+    name: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct SuspendCommand {
+    #[arg(long_help = "The name of the active mapping to suspend")]
+    name: String,
+}
+
+#[derive(Args, Debug)]
+struct TestKeyCommand {
+    #[arg(long_help ="The path to the device, the LUKS UUID or the name of the device", value_hint = ValueHint::FilePath)]
+    device_or_uuid: DiskReference,
+}
+
+#[derive(Args, Debug)]
+struct VerifyCommand {}
+
+#[derive(Args, Debug)]
+struct WipeKeyslotCommand {
+    #[arg(long_help ="The path to the device, the LUKS UUID or the name of the device", value_hint = ValueHint::FilePath)]
+    device_or_uuid: DiskReference,
+    #[arg(long_help = "The keyslot to destroy (0-7 for LUKS1, 0-31 for LUKS2)")]
+    keyslot: u8,
+    #[arg(
+        long,
+        long_help = "Do not ask for confirmation before destroying the keyslot (required when standard input is not a terminal)"
+    )]
+    yes: bool,
+}
+
+#[cfg(feature = "yubikey")]
+#[derive(Parser, Debug)]
+struct YubikeyCommand {
+    #[command(subcommand)]
+    subcmd: YubikeySubcommand,
+}
+
+#[cfg(feature = "yubikey")]
+#[derive(Parser, Debug)]
+enum YubikeySubcommand {
+    #[command(about = "List the attached Yubikeys with their serial, firmware version and configured slots")]
+    List(YubikeyList),
+}
+
+#[cfg(feature = "yubikey")]
+#[derive(Args, Debug)]
+struct YubikeyList {}
+
+#[derive(Debug, Clone)]
+pub struct DiskReference(String);
+
+impl FromStr for DiskReference {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(DiskReference(s.to_string()))
+    }
+}
+
+/// Sector sizes supported by LUKS2 for the encrypted data
+const VALID_SECTOR_SIZES: [u32; 4] = [512, 1024, 2048, 4096];
+/// Sizes of the LUKS2 JSON metadata area that libcryptsetup accepts
+const VALID_LUKS2_METADATA_SIZES: [u64; 9] = [16384, 32768, 65536, 131072, 262144, 524288, 1048576, 2097152, 4194304];
+/// The LUKS2 keyslots area is made of 4 KiB blocks
+const LUKS2_KEYSLOTS_SIZE_ALIGNMENT: u64 = 4096;
+/// Largest LUKS2 keyslots area that libcryptsetup accepts
+const MAX_LUKS2_KEYSLOTS_SIZE: u64 = 128 * 1024 * 1024;
+
+/// Ciphers that the kernel crypto API (and so libcryptsetup) provides for disk encryption
+const KNOWN_CIPHERS: [&str; 13] = [
+    "aes",
+    "anubis",
+    "blowfish",
+    "camellia",
+    "cast5",
+    "cast6",
+    "chacha20",
+    "des3_ede",
+    "serpent",
+    "sm4",
+    "twofish",
+    "xchacha12",
+    "xchacha20",
+];
+/// Stream ciphers of the above, which are followed directly by the IV generator (e.g. `chacha20-plain64`)
+const KNOWN_STREAM_CIPHERS: [&str; 1] = ["chacha20"];
+/// Block cipher modes that can be used with the ciphers above
+const KNOWN_CHAIN_MODES: [&str; 7] = ["adiantum", "cbc", "ctr", "ecb", "hctr2", "lrw", "xts"];
+/// The only mode that combines several ciphers (`xchacha12,aes-adiantum-plain64` or `xchacha20,aes-adiantum-plain64`)
+const CIPHER_CHAIN_MODE: &str = "adiantum";
+/// Prefix of a cipher given in the format of the kernel crypto API, which libcryptsetup passes on unchecked (e.g.
+/// `capi:xts(aes)-plain64`)
+const KERNEL_CRYPTO_API_PREFIX: &str = "capi:";
+/// IV generators of dm-crypt (`essiv` also needs a hash, e.g. `cbc-essiv:sha256`)
+const KNOWN_IV_MODES: [&str; 10] = [
+    "benbi",
+    "eboiv",
+    "elephant",
+    "essiv",
+    "lmk",
+    "null",
+    "plain",
+    "plain64",
+    "plain64be",
+    "tcw",
+];
+/// Hash functions that libcryptsetup can use for the header and keyslots
+const KNOWN_HASHES: [&str; 12] = [
+    "blake2b-512",
+    "blake2s-256",
+    "ripemd160",
+    "sha1",
+    "sha224",
+    "sha256",
+    "sha3-256",
+    "sha3-512",
+    "sha384",
+    "sha512",
+    "sm3",
+    "whirlpool",
+];
+
+/// Split a cipher string by the first - e.g. 'aes-xts-plain' becomes 'aes' and 'xts-plain', checking that both parts
+/// are known to libcryptsetup (so that a typo fails before any disk is formatted)
+fn cipher_mode(cipher_spec: &str) -> Result<(String, String)> {
+    let invalid = |reason: String| {
+        ValidationSnafu {
+            message: format!("Invalid cipher '{}': {}", cipher_spec, reason),
+        }
+        .fail()
+    };
+
+    let (cipher, mode) = match cipher_spec.split_once('-') {
+        Some((cipher, mode)) if !cipher.is_empty() && !mode.is_empty() => (cipher, mode),
+        _ => return invalid("expected name-mode e.g. aes-xts-plain".to_string()),
+    };
+
+    // the mode of a kernel crypto API cipher or a stream cipher is only the IV generator
+    let (chain_mode, iv_mode) = if cipher.len() > KERNEL_CRYPTO_API_PREFIX.len()
+        && cipher.starts_with(KERNEL_CRYPTO_API_PREFIX)
+        || KNOWN_STREAM_CIPHERS.contains(&cipher)
+    {
+        (None, Some(mode))
+    } else {
+        if let Some(unknown) = cipher.split(',').find(|cipher| !KNOWN_CIPHERS.contains(cipher)) {
+            return invalid(format!(
+                "unknown cipher '{}', must be one of {:?}",
+                unknown, KNOWN_CIPHERS
+            ));
+        }
+        match mode.split_once('-') {
+            Some((chain_mode, iv_mode)) => (Some(chain_mode), Some(iv_mode)),
+            None => (Some(mode), None),
+        }
+    };
+    if let Some(chain_mode) = chain_mode {
+        if !KNOWN_CHAIN_MODES.contains(&chain_mode) {
+            return invalid(format!(
+                "unknown mode '{}', must be one of {:?}",
+                chain_mode, KNOWN_CHAIN_MODES
+            ));
+        }
+        if cipher.contains(',') != (chain_mode == CIPHER_CHAIN_MODE) {
+            return invalid(format!(
+                "only mode '{}' combines ciphers e.g. xchacha12,aes-{}-plain64",
+                CIPHER_CHAIN_MODE, CIPHER_CHAIN_MODE
+            ));
+        }
+    }
+    match iv_mode.map(|iv_mode| iv_mode.split_once(':').unwrap_or((iv_mode, ""))) {
+        // only ECB works without an IV
+        None if chain_mode == Some("ecb") => {}
+        None => return invalid(format!("mode '{}' needs an IV generator e.g. {}-plain64", mode, mode)),
+        Some((iv, _)) if !KNOWN_IV_MODES.contains(&iv) => {
+            return invalid(format!(
+                "unknown IV generator '{}', must be one of {:?}",
+                iv, KNOWN_IV_MODES
+            ))
+        }
+        Some(("essiv", iv_hash)) if !KNOWN_HASHES.contains(&iv_hash) => {
+            return invalid(format!(
+                "unknown ESSIV hash '{}', must be one of {:?}",
+                iv_hash, KNOWN_HASHES
+            ))
+        }
+        _ => {}
+    }
+
+    Ok((cipher.to_string(), mode.to_string()))
+}
+
+/// Check that a hash function is known to libcryptsetup
+fn validate_hash(hash: &str) -> Result<()> {
+    ensure!(
+        KNOWN_HASHES.contains(&hash),
+        ValidationSnafu {
+            message: format!("Invalid hash '{}', must be one of {:?}", hash, KNOWN_HASHES),
+        }
+    );
+    Ok(())
+}
+
+/// Check that the sizes of the LUKS2 metadata and keyslots areas are ones that libcryptsetup accepts
+fn validate_luks2_area_sizes(metadata_size: Option<u64>, keyslots_size: Option<u64>) -> Result<()> {
+    if let Some(metadata_size) = metadata_size {
+        ensure!(
+            VALID_LUKS2_METADATA_SIZES.contains(&metadata_size),
+            ValidationSnafu {
+                message: format!(
+                    "Invalid LUKS2 metadata size {}, must be one of {:?}",
+                    metadata_size, VALID_LUKS2_METADATA_SIZES
+                ),
+            }
+        );
+    }
+    if let Some(keyslots_size) = keyslots_size {
+        ensure!(
+            keyslots_size > 0
+                && keyslots_size % LUKS2_KEYSLOTS_SIZE_ALIGNMENT == 0
+                && keyslots_size <= MAX_LUKS2_KEYSLOTS_SIZE,
+            ValidationSnafu {
+                message: format!(
+                    "Invalid LUKS2 keyslots size {}, must be a multiple of {} up to {}",
+                    keyslots_size, LUKS2_KEYSLOTS_SIZE_ALIGNMENT, MAX_LUKS2_KEYSLOTS_SIZE
+                ),
+            }
+        );
+    }
+    Ok(())
+}
+
+fn format_params(params: &LuksFormatParams) -> Result<FormatContainerParams> {
+    if let Some(sector_size) = params.sector_size {
+        if !VALID_SECTOR_SIZES.contains(&sector_size) {
+            return Err(ValidationSnafu {
+                message: format!(
+                    "Invalid sector size {}, must be one of {:?}",
+                    sector_size, VALID_SECTOR_SIZES
+                ),
+            }
+            .build());
+        }
+    }
+
+    validate_luks2_area_sizes(params.luks2_metadata_size, params.luks2_keyslots_size)?;
+
+    let (cipher, cipher_mode) = cipher_mode(&params.cipher)?;
+    validate_hash(&params.hash)?;
+    let hash = params.hash.clone();
+    let key_bits = params.key_bits;
+    let iteration_ms = params.iteration_ms;
+    let argon2 = argon2_params(params);
+
+    Ok(if params.luks1 {
         FormatContainerParams::Luks1 {
             iteration_ms,
             cipher,
@@ -286,24 +1222,153 @@ fn format_params(params: &LuksFormatParams) -> FormatContainerParams {
             mk_bits: key_bits,
             hash,
             time_ms: iteration_ms,
-            iterations: params.argon2_iterations,
-            max_memory_kb: params.argon2_memory_kb,
-            parallel_threads: params.argon2_parallel_threads,
-            sector_size: None,
-            data_alignment: None,
+            iterations: argon2.iterations,
+            max_memory_kb: argon2.memory_kb,
+            parallel_threads: argon2.parallel_threads,
+            sector_size: params.sector_size,
+            data_alignment: params.data_alignment,
+            metadata_size: params.luks2_metadata_size,
+            keyslots_size: params.luks2_keyslots_size,
             save_label_in_header: params.save_label_in_header,
             uuid: None,
-            label: None,
+            label: params.label.clone(),
+            subsystem: params.subsystem.clone(),
             token_id: None,
         }
+    })
+}
+
+/// The argon2 parameters of new keyslots: the individual flags take precedence over the preset
+fn argon2_params(params: &LuksFormatParams) -> Argon2Params {
+    let base = params.argon2_preset.map_or(DEFAULT_ARGON2_PARAMS, Argon2Preset::params);
+    Argon2Params {
+        iterations: params.argon2_iterations.unwrap_or(base.iterations),
+        memory_kb: params.argon2_memory_kb.unwrap_or(base.memory_kb),
+        parallel_threads: params.argon2_parallel_threads.unwrap_or(base.parallel_threads),
     }
 }
 
-fn enroll(cmd: EnrollCommand) -> Result<operation::enroll::Params<MainContext>> {
+fn benchmark(cmd: BenchmarkCommand) -> Result<operation::benchmark::Params> {
+    Ok(operation::benchmark::Params {
+        iteration_ms: cmd.iteration_ms,
+        max_memory_kb: cmd.max_memory_kb,
+        parallel_threads: cmd.argon2_parallel_threads,
+        key_bits: cmd.key_bits,
+    })
+}
+
+fn boot_open(cmd: BootOpenCommand) -> Result<operation::boot_open::Params> {
+    Ok(operation::boot_open::Params {
+        flags: ActivationFlags {
+            allow_discards: cmd.allow_discards,
+            read_only: cmd.read_only,
+            ..ActivationFlags::default()
+        },
+    })
+}
+
+fn close(cmd: CloseCommand) -> Result<operation::close::Params> {
+    Ok(operation::close::Params {
+        disk_references: cmd.device_or_uuid,
+        name: cmd.name,
+        name_template: cmd.name_template.as_deref().map(NameTemplate::from_str).transpose()?,
+    })
+}
+
+/// The completion script for the shell, generated from the definition of the command line
+fn completions(shell: CompletionShell) -> operation::CommandOutput {
+    let shell = match shell {
+        CompletionShell::Bash => Shell::Bash,
+        CompletionShell::Zsh => Shell::Zsh,
+        CompletionShell::Fish => Shell::Fish,
+    };
+    let mut script = vec![];
+    clap_complete::generate(shell, &mut Opts::command(), "peroxs", &mut script);
+    operation::CommandOutput::Completions {
+        script: String::from_utf8_lossy(&script).into_owned(),
+    }
+}
+
+fn verbosity(global: &GlobalOpts) -> Verbosity {
+    if global.quiet {
+        Verbosity::Quiet
+    } else if global.verbose {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    }
+}
+
+/// Fill in the global options that were not given on the command line from the configuration file
+fn merge_config(global: &mut GlobalOpts, config: Config) {
+    global.database = global.database.take().or(config.database);
+    global.input_timeout = global.input_timeout.or(config.input_timeout);
+    global.pinentry = global.pinentry.or(config.pinentry);
+}
+
+fn context(db_path: PathBuf, global: &GlobalOpts) -> MainContext {
+    let mut ctx = MainContext::new(db_path);
+    ctx.dry_run = global.dry_run;
+    ctx.no_db_save = global.no_db_save;
+    ctx.no_lock = global.no_lock;
+    ctx.activation_tries = global.tries;
+    match global.input_timeout {
+        Some(0) => ctx.key_input_config.password_input_timeout = None,
+        Some(secs) => ctx.key_input_config.password_input_timeout = Some(Duration::from_secs(secs)),
+        None => {}
+    }
+    if let Some(pinentry) = global.pinentry {
+        ctx.key_input_config.use_pinentry = pinentry;
+    }
+    if global.passphrase_stdin {
+        ctx.key_input_config.passphrase_input = PassphraseInput::Stdin;
+    }
+    if let Some(fd) = global.key_fd {
+        ctx.key_input_config.passphrase_input = PassphraseInput::Fd(FdPrompt::new(fd));
+    }
+    if let Some(passphrase) = &global.env_passphrase {
+        ctx.key_input_config.passphrase_input = PassphraseInput::Env(passphrase.clone());
+    }
+    #[cfg(feature = "systemd")]
+    if global.ask_password_agent {
+        ctx.key_input_config.passphrase_input = PassphraseInput::AskPassword;
+    }
+    ctx
+}
+
+/// Read the passphrase from the variable given with `--passphrase-env` (before any context is created, as every context
+/// uses it but the variable can only be read once)
+fn read_passphrase_env(global: &mut GlobalOpts) -> Result<()> {
+    if let Some(var) = &global.passphrase_env {
+        let passphrase = passphrase_from_env(var).map_err(|source| operation::OperationError::InputError { source })?;
+        global.env_passphrase = Some(passphrase);
+    }
+    Ok(())
+}
+
+/// Refuse to read both the database and a passphrase from standard input
+fn check_stdin_database(db_path: &Path, global: &GlobalOpts) -> Result<()> {
+    ensure!(
+        !(PeroxideDb::is_stdio_path(db_path) && global.passphrase_stdin),
+        ValidationSnafu {
+            message: "The database and the passphrase cannot both be read from standard input".to_string(),
+        }
+    );
+    Ok(())
+}
+
+fn enroll(cmd: EnrollCommand, global: &GlobalOpts) -> Result<operation::enroll::Params<MainContext>> {
     // let backup_ctx = cmd.flag_backup_db.as_ref().map(PathBuf::from).map(MainContext::new);
-    let (common, entry) = match cmd.subcmd {
+    let subcmd = cmd.subcmd.context(ValidationSnafu {
+        message: "Either a key type or a manifest must be given".to_string(),
+    })?;
+    let (common, entry) = match subcmd {
         EnrollSubcommand::Keyfile(keyfile) => {
-            let params = EntryParams::Keyfile(keyfile.keyfile);
+            let params = EntryParams::Keyfile(
+                keyfile.keyfile,
+                keyfile.region.keyfile_offset,
+                keyfile.region.keyfile_size,
+            );
             (keyfile.common, params)
         }
         EnrollSubcommand::Passphrase(passphrase) => {
@@ -321,9 +1386,19 @@ fn enroll(cmd: EnrollCommand) -> Result<operation::enroll::Params<MainContext>>
 
             (yubikey.common, params)
         }
+        #[cfg(feature = "fido2")]
+        EnrollSubcommand::Fido2(fido2) => (fido2.common, EntryParams::Fido2),
     };
 
-    let format_params = format_params(&common.format_params);
+    // every disk has its own volume key
+    if common.volume_key_file.is_some() && common.device_or_uuid.len() > 1 {
+        return Err(ValidationSnafu {
+            message: "Cannot enroll more than one device with a volume key".to_string(),
+        }
+        .build());
+    }
+
+    let format_params = format_params(&common.format_params)?;
 
     let params = DiskEnrolmentParams {
         name: common.name,
@@ -332,20 +1407,95 @@ fn enroll(cmd: EnrollCommand) -> Result<operation::enroll::Params<MainContext>>
         force_format: common.format_params.force_format,
         format_params,
         iteration_ms: common.format_params.iteration_ms,
+        keyslot: common.keyslot,
+        priority: common.priority,
+        volume_key_file: common.volume_key_file,
+        recovery: common.with_recovery.map(|path| {
+            if path == Path::new("-") {
+                RecoveryOutput::Print
+            } else {
+                RecoveryOutput::File(path)
+            }
+        }),
     };
 
-    let backup_context = common.backup_db.map(MainContext::new);
+    let backup_context = common.backup_db.map(|db_path| context(db_path, global));
 
     Ok(operation::enroll::Params {
         device_paths_or_uuids: common.device_or_uuid,
+        active_mapping: common.use_active,
         backup_context,
+        confirmed: common.format_params.yes,
+        format_only: common.format_only,
         params,
     })
 }
 
+fn enroll_manifest(path: &Path, confirmed: bool) -> Result<operation::enroll::ManifestParams> {
+    Ok(operation::enroll::ManifestParams {
+        disks: Manifest::load(path)?.enrolments()?,
+        confirmed,
+    })
+}
+
+fn export_crypttab(cmd: ExportCrypttab) -> Result<operation::export::CrypttabParams> {
+    Ok(operation::export::CrypttabParams { discard: cmd.discard })
+}
+
+fn db_backup(cmd: DbBackup) -> Result<operation::db::BackupParams> {
+    Ok(operation::db::BackupParams {
+        dest: cmd.dest,
+        force: cmd.force,
+    })
+}
+
+fn gc(cmd: GcCommand) -> Result<operation::gc::Params> {
+    Ok(operation::gc::Params {
+        prune: cmd.prune,
+        confirmed: cmd.yes,
+    })
+}
+
+fn genkey(cmd: GenkeyCommand) -> Result<operation::genkey::Params> {
+    Ok(operation::genkey::Params {
+        path: cmd.path,
+        bytes: cmd.bytes,
+    })
+}
+
+fn header_backup(cmd: HeaderBackup) -> Result<operation::header::BackupParams> {
+    Ok(operation::header::BackupParams {
+        disk_reference: cmd.device_or_uuid,
+        out: cmd.out,
+    })
+}
+
+fn header_restore(cmd: HeaderRestore) -> Result<operation::header::RestoreParams> {
+    Ok(operation::header::RestoreParams {
+        disk_reference: cmd.device_or_uuid,
+        from: cmd.from,
+    })
+}
+
+fn import(cmd: ImportCommand) -> Result<operation::import::Params> {
+    Ok(operation::import::Params {
+        other_db: cmd.other_db,
+        force: cmd.force,
+    })
+}
+
+fn label_set(cmd: LabelSet) -> Result<operation::label::SetParams> {
+    Ok(operation::label::SetParams {
+        disk_reference: cmd.device_or_uuid,
+        label: cmd.label,
+        subsystem: cmd.subsystem,
+    })
+}
+
 fn list(cmd: ListCommand) -> Result<operation::list::Params> {
     Ok(operation::list::Params {
         only_available: !cmd.all,
+        full_uuid: cmd.full_uuid,
     })
 }
 
@@ -353,27 +1503,169 @@ fn newdb(cmd: InitCommand) -> Result<operation::newdb::Params> {
     Ok(operation::newdb::Params(cmd.db_type))
 }
 
+fn migrate(cmd: MigrateCommand) -> Result<operation::migrate::Params> {
+    Ok(operation::migrate::Params {
+        disk_reference: cmd.device_or_uuid,
+        confirmed: cmd.yes,
+    })
+}
+
 fn open(cmd: OpenCommand) -> Result<operation::open::Params> {
     Ok(operation::open::Params {
         disk_references: cmd.device_or_uuid,
         name: cmd.name,
+        name_template: cmd.name_template.as_deref().map(NameTemplate::from_str).transpose()?,
+        flags: ActivationFlags {
+            allow_discards: cmd.allow_discards,
+            read_only: cmd.read_only,
+            keyring: cmd.keyring,
+            no_read_workqueue: cmd.no_read_workqueue,
+            no_write_workqueue: cmd.no_write_workqueue,
+            ignore_entry_options: cmd.ignore_entry_options,
+        },
+        wait: cmd.wait.map(Duration::from_secs),
+        key_file: cmd.key_file,
+        test_only: cmd.test_only,
+    })
+}
+
+fn present(cmd: PresentCommand) -> Result<operation::present::Params> {
+    Ok(operation::present::Params {
+        disk_references: cmd.device_or_uuid,
+        wait: cmd.wait.map(Duration::from_secs),
     })
 }
 
-fn register(cmd: RegisterCommand) -> Result<operation::register::Params> {
-    let (common, entry_type, keyfile_opt) = match cmd.subcmd {
-        RegisterSubcommand::Keyfile(keyfile) => (keyfile.common, DbEntryType::Keyfile, Some(keyfile.keyfile)),
-        RegisterSubcommand::Passphrase(passphrase) => (passphrase.common, DbEntryType::Passphrase, None),
+fn register(cmd: RegisterCommand, global: &GlobalOpts) -> Result<operation::register::Params> {
+    let (common, entry_type, keyfile_opt, region_opt) = match cmd.subcmd {
+        RegisterSubcommand::Keyfile(keyfile) => (
+            keyfile.common,
+            DbEntryType::Keyfile,
+            Some(keyfile.keyfile),
+            Some(keyfile.region),
+        ),
+        RegisterSubcommand::Passphrase(passphrase) => (passphrase.common, DbEntryType::Passphrase, None, None),
     };
 
     Ok(operation::register::Params {
         device_paths_or_uuids: common.device_or_uuid,
         entry_type,
         keyfile: keyfile_opt,
+        key_offset: region_opt.as_ref().and_then(|r| r.keyfile_offset),
+        key_size: region_opt.as_ref().and_then(|r| r.keyfile_size),
+        save_db: !global.no_db_save,
         name: common.name,
     })
 }
 
+fn remove(cmd: RemoveCommand) -> Result<operation::remove::Params> {
+    Ok(operation::remove::Params {
+        disk_reference: cmd.device_or_uuid,
+        keyslot: cmd.slot,
+    })
+}
+
+fn rename(cmd: RenameCommand) -> Result<operation::rename::Params> {
+    Ok(operation::rename::Params {
+        disk_reference: cmd.device_or_uuid,
+        new_name: cmd.new_name,
+    })
+}
+
+fn resume(cmd: ResumeCommand) -> Result<operation::resume::Params> {
+    Ok(operation::resume::Params {
+        disk_reference: cmd.device_or_uuid,
+        name: cmd.name,
+    })
+}
+
+fn rotate(cmd: RotateCommand) -> Result<operation::rotate::Params> {
+    if cmd.format_params.format {
+        return Err(ValidationSnafu {
+            message: "Cannot format a device when rotating its key".to_string(),
+        }
+        .build());
+    }
+
+    Ok(operation::rotate::Params {
+        disk_reference: cmd.device_or_uuid,
+        iteration_ms: cmd.format_params.iteration_ms,
+        format_params: format_params(&cmd.format_params)?,
+        new_key_file: cmd.new_keyfile,
+    })
+}
+
+fn set_activation_options(cmd: SetActivationOptionsCommand) -> Result<operation::set_activation_options::Params> {
+    Ok(operation::set_activation_options::Params {
+        disk_reference: cmd.device_or_uuid,
+        options: ActivationOptions {
+            allow_discards: cmd.allow_discards,
+            read_only: cmd.read_only,
+            no_read_workqueue: cmd.no_read_workqueue,
+            no_write_workqueue: cmd.no_write_workqueue,
+        },
+    })
+}
+
+fn set_auto_open(cmd: SetAutoOpenCommand) -> Result<operation::set_auto_open::Params> {
+    Ok(operation::set_auto_open::Params {
+        disk_reference: cmd.device_or_uuid,
+        auto_open: cmd.auto_open,
+    })
+}
+
+fn status(cmd: StatusCommand) -> Result<operation::status::Params> {
+    Ok(operation::status::Params { name: cmd.name })
+}
+
+fn suspend(cmd: SuspendCommand) -> Result<operation::suspend::Params> {
+    Ok(operation::suspend::Params { name: cmd.name })
+}
+
+fn test_key(cmd: TestKeyCommand) -> Result<operation::test_key::Params> {
+    Ok(operation::test_key::Params {
+        disk_reference: cmd.device_or_uuid,
+    })
+}
+
+fn wipe_keyslot(cmd: WipeKeyslotCommand) -> Result<operation::wipe_keyslot::Params> {
+    Ok(operation::wipe_keyslot::Params {
+        disk_reference: cmd.device_or_uuid,
+        keyslot: cmd.keyslot,
+        confirmed: cmd.yes,
+    })
+}
+
+/// Version information shown by `--version` (the versions of the libraries used and the features compiled in)
+fn version_info() -> String {
+    let cryptsetup_version = MainContext::cryptsetup_version().unwrap_or_else(|| "unknown".to_string());
+    let available = |enabled: bool| if enabled { "available" } else { "not compiled in" };
+    let features = [
+        ("yubikey", cfg!(feature = "yubikey")),
+        ("yubikey_hybrid", cfg!(feature = "yubikey_hybrid")),
+        ("pinentry", cfg!(feature = "pinentry")),
+        ("fido2", cfg!(feature = "fido2")),
+        ("systemd", cfg!(feature = "systemd")),
+    ]
+    .iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(feature, _)| *feature)
+    .collect::<Vec<_>>();
+
+    format!(
+        "{}\nlibcryptsetup: {}\nykpers: {}\nlibsodium: {}\nfeatures: {}",
+        env!("CARGO_PKG_VERSION"),
+        cryptsetup_version,
+        available(cfg!(feature = "yubikey")),
+        available(cfg!(feature = "yubikey_hybrid")),
+        if features.is_empty() {
+            "none".to_string()
+        } else {
+            features.join(", ")
+        }
+    )
+}
+
 fn run_peroxs() -> i32 {
     env_logger::init();
     if log_enabled!(Level::Debug) {
@@ -381,29 +1673,303 @@ fn run_peroxs() -> i32 {
         MainContext::trace_on();
     }
 
-    let opts: Opts = Opts::parse();
-    let ctx = MainContext::new(opts.global.database);
+    // the long version is only known at runtime and is needed for as long as the command, so it is leaked
+    let long_version: &'static str = Box::leak(version_info().into_boxed_str());
+    let mut opts =
+        Opts::from_arg_matches(&Opts::command().long_version(long_version).get_matches()).unwrap_or_else(|e| e.exit());
+    let error_format = opts.global.error_format;
+    let json = opts.global.json;
+    messages::set_verbosity(verbosity(&opts.global));
+
+    match Config::load() {
+        Ok(config) => merge_config(&mut opts.global, config),
+        Err(e) => return report_error(e, error_format),
+    }
+    if let Err(e) = read_passphrase_env(&mut opts.global) {
+        return report_error(e, error_format);
+    }
+    let db_path = opts
+        .global
+        .database
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_DATABASE));
+    if let Err(e) = check_stdin_database(&db_path, &opts.global) {
+        return report_error(e, error_format);
+    }
+    let ctx = context(db_path, &opts.global);
 
     let res = match opts.subcmd {
-        TopSubcommand::Enroll(cmd) => enroll(cmd).and_then(|p| operation::enroll::enroll(&ctx, p)),
+        TopSubcommand::Audit(_) => operation::audit::audit(&ctx),
+        TopSubcommand::Benchmark(cmd) => benchmark(cmd).and_then(operation::benchmark::benchmark),
+        TopSubcommand::BootOpen(cmd) => boot_open(cmd).and_then(|p| operation::boot_open::boot_open(&ctx, p)),
+        TopSubcommand::Close(cmd) => close(cmd).and_then(|p| operation::close::close(&ctx, p)),
+        TopSubcommand::Completions(cmd) => Ok(completions(cmd.shell)),
+        TopSubcommand::Db(cmd) => match cmd.subcmd {
+            DbSubcommand::Backup(cmd) => db_backup(cmd).and_then(|p| operation::db::backup(&ctx, p)),
+        },
+        TopSubcommand::Enroll(EnrollCommand {
+            manifest: Some(path),
+            yes,
+            ..
+        }) => enroll_manifest(&path, yes).and_then(|p| operation::enroll::enroll_manifest(&ctx, p)),
+        TopSubcommand::Enroll(cmd) => enroll(cmd, &opts.global).and_then(|p| operation::enroll::enroll(&ctx, p)),
+        TopSubcommand::Export(cmd) => match cmd.subcmd {
+            ExportSubcommand::Crypttab(cmd) => {
+                export_crypttab(cmd).and_then(|p| operation::export::export_crypttab(&ctx, p))
+            }
+        },
+        TopSubcommand::Gc(cmd) => gc(cmd).and_then(|p| operation::gc::gc(&ctx, p)),
+        TopSubcommand::Genkey(cmd) => genkey(cmd).and_then(operation::genkey::genkey),
+        TopSubcommand::Header(cmd) => match cmd.subcmd {
+            HeaderSubcommand::Backup(cmd) => header_backup(cmd).and_then(|p| operation::header::backup(&ctx, p)),
+            HeaderSubcommand::Restore(cmd) => header_restore(cmd).and_then(|p| operation::header::restore(&ctx, p)),
+        },
+        TopSubcommand::Import(cmd) => import(cmd).and_then(|p| operation::import::import(&ctx, p)),
         TopSubcommand::Init(cmd) => newdb(cmd).and_then(|p| operation::newdb::newdb(&ctx, p)),
+        TopSubcommand::Label(cmd) => match cmd.subcmd {
+            LabelSubcommand::Set(cmd) => label_set(cmd).and_then(|p| operation::label::set(&ctx, p)),
+        },
         TopSubcommand::List(cmd) => list(cmd).and_then(|p| operation::list::list(&ctx, p)),
+        TopSubcommand::Migrate(cmd) => migrate(cmd).and_then(|p| operation::migrate::migrate(&ctx, p)),
         TopSubcommand::Open(cmd) => open(cmd).and_then(|p| operation::open::open(&ctx, p)),
-        TopSubcommand::Register(cmd) => register(cmd).and_then(|p| operation::register::register(&ctx, p)),
-    };
+        TopSubcommand::Present(cmd) => present(cmd).and_then(|p| operation::present::present(&ctx, p)),
+        TopSubcommand::Register(cmd) => {
+            register(cmd, &opts.global).and_then(|p| operation::register::register(&ctx, p))
+        }
+        TopSubcommand::Remove(cmd) => remove(cmd).and_then(|p| operation::remove::remove(&ctx, p)),
+        TopSubcommand::Rename(cmd) => rename(cmd).and_then(|p| operation::rename::rename(&ctx, p)),
+        TopSubcommand::Resume(cmd) => resume(cmd).and_then(|p| operation::resume::resume(&ctx, p)),
+        TopSubcommand::Rotate(cmd) => rotate(cmd).and_then(|p| operation::rotate::rotate(&ctx, p)),
+        TopSubcommand::SetActivationOptions(cmd) => {
+            set_activation_options(cmd).and_then(|p| operation::set_activation_options::set_activation_options(&ctx, p))
+        }
+        TopSubcommand::SetAutoOpen(cmd) => {
+            set_auto_open(cmd).and_then(|p| operation::set_auto_open::set_auto_open(&ctx, p))
+        }
+        TopSubcommand::Status(cmd) => status(cmd).and_then(operation::status::status),
+        TopSubcommand::Suspend(cmd) => suspend(cmd).and_then(|p| operation::suspend::suspend(&ctx, p)),
+        TopSubcommand::TestKey(cmd) => test_key(cmd).and_then(|p| operation::test_key::test_key(&ctx, p)),
+        TopSubcommand::Verify(_) => operation::verify::verify(&ctx),
+        TopSubcommand::WipeKeyslot(cmd) => {
+            wipe_keyslot(cmd).and_then(|p| operation::wipe_keyslot::wipe_keyslot(&ctx, p))
+        }
+        #[cfg(feature = "yubikey")]
+        TopSubcommand::Yubikey(cmd) => match cmd.subcmd {
+            YubikeySubcommand::List(_) => operation::yubikey::list_yubikeys(),
+        },
+    }
+    .and_then(|output| output.print(json).map(|_| output.exit_code()));
 
     match res {
-        Ok(_) => 0,
-        Err(e) => {
-            println!("ERROR: {}", e);
-            if let Some(bt) = ErrorCompat::backtrace(&e) {
-                eprintln!("{}", bt)
-            }
-            1
+        Ok(code) => code,
+        Err(e) => report_error(e, error_format),
+    }
+}
+
+/// Print the error in the requested format (returns the exit code)
+fn report_error(e: operation::OperationError, error_format: ErrorFormat) -> i32 {
+    if error_format == ErrorFormat::Json {
+        let output = ErrorOutput {
+            error: e.to_string(),
+            kind: e.kind(),
+        };
+        match serde_json::to_string(&output) {
+            Ok(json) => eprintln!("{}", json),
+            Err(_) => eprintln!("ERROR: {}", e),
+        }
+    } else {
+        eprintln!("ERROR: {}", e);
+        if let Some(bt) = ErrorCompat::backtrace(&e) {
+            eprintln!("{}", bt)
         }
     }
+    1
 }
 
 fn main() {
     exit(run_peroxs());
 }
+
+#[cfg(test)]
+mod tests {
+    use expectest::prelude::*;
+
+    use super::*;
+
+    #[derive(Parser, Debug)]
+    #[command(disable_help_flag = true)]
+    struct FormatArgs {
+        #[command(flatten)]
+        params: LuksFormatParams,
+    }
+
+    fn argon2_params_of(args: &[&str]) -> Argon2Params {
+        let args = FormatArgs::try_parse_from(std::iter::once("peroxs").chain(args.iter().copied())).unwrap();
+        argon2_params(&args.params)
+    }
+
+    #[test]
+    fn test_luks2_area_sizes() {
+        expect!(validate_luks2_area_sizes(None, None)).to(be_ok());
+        expect!(validate_luks2_area_sizes(Some(65536), Some(16 * 1024 * 1024))).to(be_ok());
+        expect!(validate_luks2_area_sizes(Some(4096), None)).to(be_err());
+        expect!(validate_luks2_area_sizes(Some(8 * 1024 * 1024), None)).to(be_err());
+        expect!(validate_luks2_area_sizes(None, Some(4097))).to(be_err());
+        expect!(validate_luks2_area_sizes(None, Some(0))).to(be_err());
+        expect!(validate_luks2_area_sizes(None, Some(256 * 1024 * 1024))).to(be_err());
+    }
+
+    #[test]
+    fn test_argon2_presets_and_explicit_flags() {
+        expect!(argon2_params_of(&[])).to(be_equal_to(DEFAULT_ARGON2_PARAMS));
+        expect!(argon2_params_of(&["--argon2-preset", "fast"])).to(be_equal_to(Argon2Params {
+            iterations: 4,
+            memory_kb: 65536,
+            parallel_threads: 2,
+        }));
+        expect!(argon2_params_of(&["--argon2-preset", "balanced"])).to(be_equal_to(Argon2Params {
+            iterations: 4,
+            memory_kb: 524288,
+            parallel_threads: 4,
+        }));
+        expect!(argon2_params_of(&["--argon2-preset", "paranoid"])).to(be_equal_to(Argon2Params {
+            iterations: 10,
+            memory_kb: 2097152,
+            parallel_threads: 4,
+        }));
+        expect!(argon2_params_of(&[
+            "--argon2-preset",
+            "paranoid",
+            "--argon2-memory-kb",
+            "1048576",
+            "--argon2-parallel-threads",
+            "8"
+        ]))
+        .to(be_equal_to(Argon2Params {
+            iterations: 10,
+            memory_kb: 1048576,
+            parallel_threads: 8,
+        }));
+    }
+
+    #[test]
+    fn test_cipher_mode_splits_known_ciphers() {
+        expect!(cipher_mode("aes-xts-plain")).to(be_ok().value(("aes".to_string(), "xts-plain".to_string())));
+        expect!(cipher_mode("serpent-cbc-essiv:sha256"))
+            .to(be_ok().value(("serpent".to_string(), "cbc-essiv:sha256".to_string())));
+        expect!(cipher_mode("aes-ecb")).to(be_ok().value(("aes".to_string(), "ecb".to_string())));
+        expect!(cipher_mode("aes-cbc-essiv:sha256"))
+            .to(be_ok().value(("aes".to_string(), "cbc-essiv:sha256".to_string())));
+        expect!(cipher_mode("xchacha12,aes-adiantum-plain64"))
+            .to(be_ok().value(("xchacha12,aes".to_string(), "adiantum-plain64".to_string())));
+        expect!(cipher_mode("xchacha20,aes-adiantum-plain64"))
+            .to(be_ok().value(("xchacha20,aes".to_string(), "adiantum-plain64".to_string())));
+        expect!(cipher_mode("chacha20-plain64")).to(be_ok().value(("chacha20".to_string(), "plain64".to_string())));
+        expect!(cipher_mode("capi:xts(aes)-plain64"))
+            .to(be_ok().value(("capi:xts(aes)".to_string(), "plain64".to_string())));
+    }
+
+    #[test]
+    fn test_cipher_mode_rejects_invalid_ciphers() {
+        expect!(cipher_mode("aes")).to(be_err());
+        expect!(cipher_mode("aes-")).to(be_err());
+        expect!(cipher_mode("-xts-plain64")).to(be_err());
+        expect!(cipher_mode("aez-xts-plain64")).to(be_err());
+        expect!(cipher_mode("aes-xtz-plain64")).to(be_err());
+        expect!(cipher_mode("aes-xts")).to(be_err());
+        expect!(cipher_mode("aes-xts-plain63")).to(be_err());
+        expect!(cipher_mode("aes-cbc-essiv:sha255")).to(be_err());
+        expect!(cipher_mode("aes-cbc-essiv")).to(be_err());
+        expect!(cipher_mode("xchacha12,aes-xts-plain64")).to(be_err());
+        expect!(cipher_mode("aes-adiantum-plain64")).to(be_err());
+        expect!(cipher_mode("xchacha12,aez-adiantum-plain64")).to(be_err());
+        expect!(cipher_mode("capi:xts(aes)-plain63")).to(be_err());
+        expect!(cipher_mode("capi:-plain64")).to(be_err());
+    }
+
+    #[test]
+    fn test_validate_hash() {
+        expect!(validate_hash("sha256")).to(be_ok());
+        expect!(validate_hash("sha257")).to(be_err());
+    }
+
+    #[test]
+    fn test_version_info_must_contain_crate_version() {
+        let info = version_info();
+        expect!(info.starts_with(env!("CARGO_PKG_VERSION"))).to(be_true());
+        expect!(info.contains("libcryptsetup: ")).to(be_true());
+    }
+
+    fn global_opts(args: &[&str]) -> GlobalOpts {
+        let mut argv = vec!["peroxs"];
+        argv.extend_from_slice(args);
+        argv.push("list");
+        Opts::try_parse_from(argv).unwrap().global
+    }
+
+    fn config() -> Config {
+        Config {
+            database: Some(PathBuf::from("/etc/peroxs/db.json")),
+            input_timeout: Some(60),
+            pinentry: Some(false),
+        }
+    }
+
+    #[test]
+    fn test_merge_config_command_line_overrides_config() {
+        let mut global = global_opts(&["--database", "cli.json", "--input-timeout", "10", "--pinentry", "true"]);
+        merge_config(&mut global, config());
+        expect!(global.database).to(be_some().value(PathBuf::from("cli.json")));
+        expect!(global.input_timeout).to(be_some().value(10));
+        expect!(global.pinentry).to(be_some().value(true));
+    }
+
+    #[test]
+    fn test_merge_config_config_overrides_defaults() {
+        let mut global = global_opts(&[]);
+        merge_config(&mut global, config());
+        let ctx = context(global.database.clone().unwrap(), &global);
+        expect!(ctx.db_path).to(be_equal_to(PathBuf::from("/etc/peroxs/db.json")));
+        expect!(ctx.key_input_config.password_input_timeout).to(be_some().value(Duration::from_secs(60)));
+        expect!(ctx.key_input_config.use_pinentry).to(be_false());
+    }
+
+    #[test]
+    fn test_merge_config_defaults_without_config() {
+        let mut global = global_opts(&[]);
+        merge_config(&mut global, Config::default());
+        let ctx = context(PathBuf::from(DEFAULT_DATABASE), &global);
+        expect!(global.database).to(be_none());
+        expect!(ctx.key_input_config.password_input_timeout).to(be_some().value(Duration::from_secs(30)));
+        expect!(ctx.key_input_config.use_pinentry).to(be_true());
+    }
+
+    #[test]
+    fn test_completions_include_subcommands_and_db_types() {
+        let script = match completions(CompletionShell::Bash) {
+            operation::CommandOutput::Completions { script } => script,
+            other => panic!("Unexpected output {:?}", other),
+        };
+        expect!(script.contains("boot-open")).to(be_true());
+        expect!(script.contains("operation")).to(be_true());
+    }
+
+    #[test]
+    fn test_format_only_requires_format() {
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["peroxs", "enroll", "passphrase", "/dev/sdz"];
+            argv.extend_from_slice(args);
+            Opts::try_parse_from(argv)
+        };
+        expect!(parse(&["--format-only"])).to(be_err());
+        expect!(parse(&["--format-only", "--format", "--with-recovery"])).to(be_err());
+
+        let opts = parse(&["--format-only", "--format"]).unwrap();
+        let params = match opts.subcmd {
+            TopSubcommand::Enroll(cmd) => enroll(cmd, &opts.global).unwrap(),
+            other => panic!("Unexpected command {:?}", other),
+        };
+        expect!(params.format_only).to(be_true());
+        expect!(params.params.format).to(be_true());
+    }
+}
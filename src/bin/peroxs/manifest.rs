@@ -0,0 +1,302 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde_derive::Deserialize;
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{DiskEnrolmentParams, EntryParams};
+#[cfg(feature = "yubikey")]
+use peroxide_cryptsetup::db::{YubikeyEntryType, YubikeySlot};
+use peroxide_cryptsetup::device::{Keyslot, KeyslotPriority};
+
+use crate::operation::{DeviceSnafu, IoSnafu, ManifestSnafu, PathOrUuid, Result, ValidationSnafu};
+use crate::{format_params, Argon2Preset, LuksFormatParams};
+
+/// Disks to enroll in one go (for `peroxs enroll --manifest`), read from a JSON file
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub disks: Vec<ManifestDisk>,
+}
+
+/// A disk of the manifest and how it is enrolled
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestDisk {
+    /// Path to the device or its LUKS UUID (or a `UUID=`, `LABEL=` or `PARTUUID=` selector)
+    pub device: String,
+    /// Name of the device in the database
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Kind of key that is enrolled
+    pub entry: ManifestEntry,
+    /// LUKS keyslot to add the key to (the first free one if not set)
+    #[serde(default)]
+    pub keyslot: Option<Keyslot>,
+    /// LUKS2 priority of the new keyslot (`ignore`, `normal` or `prefer`)
+    #[serde(default)]
+    pub priority: Option<String>,
+    /// How the disk is formatted (it is not formatted if not set)
+    #[serde(default)]
+    pub format: Option<ManifestFormat>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ManifestEntry {
+    Keyfile {
+        path: PathBuf,
+        /// Offset (in bytes) of the key in the key file
+        #[serde(default)]
+        offset: Option<u64>,
+        /// Size (in bytes) of the key in the key file (the rest of the file if not set)
+        #[serde(default)]
+        size: Option<usize>,
+    },
+    Passphrase,
+    #[cfg(feature = "yubikey")]
+    Yubikey {
+        slot: YubikeySlot,
+        #[serde(default)]
+        hybrid: bool,
+    },
+    #[cfg(feature = "fido2")]
+    Fido2,
+}
+
+/// Parameters of a new LUKS container, with the same defaults as the flags of `peroxs enroll`
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ManifestFormat {
+    /// Whether an existing LUKS container is formatted again (destroying its data)
+    pub force: bool,
+    pub luks1: bool,
+    pub cipher: String,
+    pub hash: String,
+    pub key_bits: usize,
+    pub iteration_ms: u32,
+    pub argon2_preset: Option<Argon2Preset>,
+    pub argon2_iterations: Option<u32>,
+    pub argon2_parallel_threads: Option<u32>,
+    pub argon2_memory_kb: Option<u32>,
+    pub sector_size: Option<u32>,
+    pub data_alignment: Option<u32>,
+    pub luks2_metadata_size: Option<u64>,
+    pub luks2_keyslots_size: Option<u64>,
+    pub label: Option<String>,
+    pub subsystem: Option<String>,
+}
+
+impl Default for ManifestFormat {
+    fn default() -> Self {
+        ManifestFormat {
+            force: false,
+            luks1: false,
+            cipher: "aes-xts-plain".to_string(),
+            hash: "sha256".to_string(),
+            key_bits: 512,
+            iteration_ms: 1000,
+            argon2_preset: None,
+            argon2_iterations: None,
+            argon2_parallel_threads: None,
+            argon2_memory_kb: None,
+            sector_size: None,
+            data_alignment: None,
+            luks2_metadata_size: None,
+            luks2_keyslots_size: None,
+            label: None,
+            subsystem: None,
+        }
+    }
+}
+
+impl Manifest {
+    /// Read a manifest from a JSON file
+    pub fn load(path: &Path) -> Result<Manifest> {
+        let contents = fs::read_to_string(path).context(IoSnafu)?;
+        serde_json::from_str(&contents).context(ManifestSnafu { path })
+    }
+
+    /// Validate the manifest and convert it to the enrolment parameters of every disk (in the order of the manifest)
+    pub fn enrolments(self) -> Result<Vec<(PathOrUuid, DiskEnrolmentParams)>> {
+        ensure!(
+            !self.disks.is_empty(),
+            ValidationSnafu {
+                message: "The manifest does not list any disk".to_string(),
+            }
+        );
+
+        let mut enrolments: Vec<(PathOrUuid, DiskEnrolmentParams)> = Vec::with_capacity(self.disks.len());
+        for disk in self.disks {
+            let device = PathOrUuid::from_str(&disk.device)?;
+            ensure!(
+                !enrolments.iter().any(|(other, _)| *other == device),
+                ValidationSnafu {
+                    message: format!("The device '{}' is listed more than once in the manifest", disk.device),
+                }
+            );
+            let params = disk.enrolment_params()?;
+            enrolments.push((device, params));
+        }
+        Ok(enrolments)
+    }
+}
+
+impl ManifestDisk {
+    fn enrolment_params(self) -> Result<DiskEnrolmentParams> {
+        let entry = match self.entry {
+            ManifestEntry::Keyfile { path, offset, size } => EntryParams::Keyfile(path, offset, size),
+            ManifestEntry::Passphrase => EntryParams::Passphrase,
+            #[cfg(feature = "yubikey")]
+            ManifestEntry::Yubikey { slot, hybrid } => {
+                ensure!(
+                    (1..=2).contains(&slot),
+                    ValidationSnafu {
+                        message: format!("Invalid Yubikey slot {} for '{}', must be 1 or 2", slot, self.device),
+                    }
+                );
+                let entry_type = if hybrid {
+                    YubikeyEntryType::HybridChallengeResponse
+                } else {
+                    YubikeyEntryType::ChallengeResponse
+                };
+                EntryParams::Yubikey(slot, entry_type)
+            }
+            #[cfg(feature = "fido2")]
+            ManifestEntry::Fido2 => EntryParams::Fido2,
+        };
+        let priority = self
+            .priority
+            .as_deref()
+            .map(KeyslotPriority::from_str)
+            .transpose()
+            .context(DeviceSnafu)?;
+
+        let format = self.format.is_some();
+        let luks_params = self.format.unwrap_or_default().into_luks_format_params(format);
+
+        Ok(DiskEnrolmentParams {
+            name: self.name,
+            entry,
+            format,
+            force_format: luks_params.force_format,
+            format_params: format_params(&luks_params)?,
+            iteration_ms: luks_params.iteration_ms,
+            keyslot: self.keyslot,
+            priority,
+            volume_key_file: None,
+            recovery: None,
+        })
+    }
+}
+
+impl ManifestFormat {
+    /// The flags of `peroxs enroll` that format a disk like this (so that both are validated the same way)
+    fn into_luks_format_params(self, format: bool) -> LuksFormatParams {
+        LuksFormatParams {
+            format,
+            force_format: self.force,
+            yes: false,
+            luks1: self.luks1,
+            _luks2: !self.luks1,
+            iteration_ms: self.iteration_ms,
+            key_bits: self.key_bits,
+            cipher: self.cipher,
+            hash: self.hash,
+            argon2_preset: self.argon2_preset,
+            argon2_iterations: self.argon2_iterations,
+            argon2_parallel_threads: self.argon2_parallel_threads,
+            argon2_memory_kb: self.argon2_memory_kb,
+            sector_size: self.sector_size,
+            data_alignment: self.data_alignment,
+            luks2_metadata_size: self.luks2_metadata_size,
+            luks2_keyslots_size: self.luks2_keyslots_size,
+            save_label_in_header: false,
+            label: self.label,
+            subsystem: self.subsystem,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use expectest::prelude::*;
+
+    use peroxide_cryptsetup::device::FormatContainerParams;
+
+    use super::*;
+
+    fn enrolments(json: &str) -> Result<Vec<(PathOrUuid, DiskEnrolmentParams)>> {
+        serde_json::from_str::<Manifest>(json).unwrap().enrolments()
+    }
+
+    #[test]
+    fn test_manifest_enrolments() {
+        let json = r#"{
+            "disks": [
+                {"device": "/dev/sdb", "name": "data1", "entry": {"type": "passphrase"}, "keyslot": 2},
+                {
+                    "device": "/dev/sdc",
+                    "entry": {"type": "keyfile", "path": "/etc/keys/data2", "size": 64},
+                    "priority": "prefer",
+                    "format": {"cipher": "serpent-xts-plain64", "label": "data2"}
+                }
+            ]
+        }"#;
+        let enrolments = enrolments(json).unwrap();
+        expect!(enrolments.len()).to(be_equal_to(2));
+
+        let (device, params) = &enrolments[0];
+        expect!(device).to(be_equal_to(&PathOrUuid::Path(PathBuf::from("/dev/sdb"))));
+        expect!(params.name.as_deref()).to(be_some().value("data1"));
+        expect!(params.format).to(be_false());
+        expect!(params.keyslot).to(be_some().value(2));
+
+        let (_, params) = &enrolments[1];
+        expect!(params.format).to(be_true());
+        expect!(params.force_format).to(be_false());
+        expect!(params.priority).to(be_some().value(KeyslotPriority::Prefer));
+        expect!(matches!(params.entry, EntryParams::Keyfile(_, None, Some(64)))).to(be_true());
+        match &params.format_params {
+            FormatContainerParams::Luks2 {
+                cipher,
+                cipher_mode,
+                label,
+                ..
+            } => {
+                expect!(cipher.as_str()).to(be_equal_to("serpent"));
+                expect!(cipher_mode.as_str()).to(be_equal_to("xts-plain64"));
+                expect!(label.as_deref()).to(be_some().value("data2"));
+            }
+            other => panic!("Expected LUKS2 format parameters, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_manifest_devices_must_be_unique() {
+        let json = r#"{"disks": [
+            {"device": "/dev/sdb", "entry": {"type": "passphrase"}},
+            {"device": "/dev/sdb", "entry": {"type": "passphrase"}}
+        ]}"#;
+        expect!(enrolments(json)).to(be_err());
+    }
+
+    #[test]
+    fn test_manifest_ciphers_must_have_a_mode() {
+        let json =
+            r#"{"disks": [{"device": "/dev/sdb", "entry": {"type": "passphrase"}, "format": {"cipher": "aes"}}]}"#;
+        expect!(enrolments(json)).to(be_err());
+    }
+
+    #[test]
+    fn test_manifest_must_list_disks() {
+        expect!(enrolments(r#"{"disks": []}"#)).to(be_err());
+    }
+
+    #[test]
+    fn test_manifest_rejects_unknown_fields() {
+        let json = r#"{"disks": [{"device": "/dev/sdb", "entry": {"type": "passphrase"}, "slot": 1}]}"#;
+        expect!(serde_json::from_str::<Manifest>(json)).to(be_err());
+    }
+}
@@ -0,0 +1,260 @@
+use std::str::FromStr;
+
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{mapping_name, NameOverride};
+use peroxide_cryptsetup::db::DbEntry;
+use peroxide_cryptsetup::device::Disks;
+
+use crate::operation::{OperationError, Result, ValidationSnafu};
+
+/// Maximum length of a device mapper name (`DM_NAME_LEN` minus the terminating NUL)
+const MAX_DM_NAME_LENGTH: usize = 127;
+/// Number of characters of the UUID used for `{short_uuid}`
+const SHORT_UUID_LENGTH: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    /// Name of the database entry (or `uuid_<uuid>` if it has none)
+    Name,
+    /// LUKS UUID of the device
+    Uuid,
+    /// First 8 characters of the LUKS UUID of the device
+    ShortUuid,
+    /// Position of the device on the command line (starting from 0)
+    Index,
+    /// Serial number of the drive of the device (the short UUID if it has none)
+    Serial,
+}
+
+/// Template for device mapper names, e.g. `crypt-{short_uuid}` or `{name}_{index}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    template: String,
+    parts: Vec<TemplatePart>,
+}
+
+impl FromStr for NameTemplate {
+    type Err = OperationError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find(['{', '}']) {
+            ensure!(
+                rest[start..].starts_with('{'),
+                ValidationSnafu {
+                    message: format!("Unexpected `}}` in name template '{}'", s),
+                }
+            );
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .context(ValidationSnafu {
+                    message: format!("Unclosed `{{` in name template '{}'", s),
+                })?;
+            if start > 0 {
+                parts.push(TemplatePart::Literal(rest[..start].to_string()));
+            }
+            parts.push(match &rest[start + 1..end] {
+                "name" => TemplatePart::Name,
+                "uuid" => TemplatePart::Uuid,
+                "short_uuid" => TemplatePart::ShortUuid,
+                "index" => TemplatePart::Index,
+                "serial" => TemplatePart::Serial,
+                other => {
+                    return ValidationSnafu {
+                        message: format!(
+                            "Unknown placeholder `{{{}}}` in name template '{}' (expected one of {{name}}, {{uuid}}, {{short_uuid}}, {{index}}, {{serial}})",
+                            other, s
+                        ),
+                    }
+                    .fail()
+                }
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_string()));
+        }
+        ensure!(
+            !parts.is_empty(),
+            ValidationSnafu {
+                message: "Name template cannot be empty".to_string(),
+            }
+        );
+
+        Ok(NameTemplate {
+            template: s.to_string(),
+            parts,
+        })
+    }
+}
+
+impl NameTemplate {
+    /// Expand the template for the device of `entry` at position `index`, validating the resulting name
+    pub fn expand(&self, entry: &DbEntry, index: usize) -> Result<String> {
+        // the drive is only looked up if its serial is used
+        let serial = if self.parts.contains(&TemplatePart::Serial) {
+            Disks::disk_uuid_path(entry.volume_id().uuid())
+                .ok()
+                .and_then(|path| Disks::block_serial(&path))
+        } else {
+            None
+        };
+        self.expand_with_serial(entry, index, serial.as_deref())
+    }
+
+    fn expand_with_serial(&self, entry: &DbEntry, index: usize, serial: Option<&str>) -> Result<String> {
+        let uuid = entry.volume_id().uuid().to_string();
+        let short_uuid = uuid.chars().take(SHORT_UUID_LENGTH).collect::<String>();
+        let name = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Literal(literal) => literal.clone(),
+                TemplatePart::Name => mapping_name(entry, None),
+                TemplatePart::Uuid => uuid.clone(),
+                TemplatePart::ShortUuid => short_uuid.clone(),
+                TemplatePart::Index => index.to_string(),
+                TemplatePart::Serial => serial.map(sanitize_serial).unwrap_or_else(|| short_uuid.clone()),
+            })
+            .collect::<String>();
+        validate_dm_name(&name).map_err(|reason| {
+            ValidationSnafu {
+                message: format!(
+                    "Name template '{}' expands to an invalid device mapper name `{}`: {}",
+                    self.template, name, reason
+                ),
+            }
+            .build()
+        })?;
+        Ok(name)
+    }
+}
+
+/// Replace the characters of a drive serial that do not belong in a device mapper name (e.g. spaces or `/`) with `_`
+fn sanitize_serial(serial: &str) -> String {
+    serial
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "#+-.:=@_".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn validate_dm_name(name: &str) -> std::result::Result<(), String> {
+    if name.is_empty() {
+        Err("the name is empty".to_string())
+    } else if name == "." || name == ".." {
+        Err("the name cannot be `.` or `..`".to_string())
+    } else if name.contains('/') {
+        Err("the name cannot contain `/`".to_string())
+    } else if name.len() > MAX_DM_NAME_LENGTH {
+        Err(format!("the name is longer than {} characters", MAX_DM_NAME_LENGTH))
+    } else {
+        Ok(())
+    }
+}
+
+/// Resolve the names to use for the devices of `entries` from either a name (prefix) or a name template
+pub fn name_override(
+    name: Option<String>,
+    template: Option<&NameTemplate>,
+    entries: &[&DbEntry],
+) -> Result<Option<NameOverride>> {
+    match template {
+        Some(template) => {
+            let names = entries
+                .iter()
+                .enumerate()
+                .map(|(idx, entry)| template.expand(entry, idx))
+                .collect::<Result<Vec<_>>>()?;
+            if let Some(duplicate) = names
+                .iter()
+                .enumerate()
+                .find(|(idx, name)| names[..*idx].contains(name))
+            {
+                return ValidationSnafu {
+                    message: format!(
+                        "Name template '{}' expands to `{}` for more than one device",
+                        template.template, duplicate.1
+                    ),
+                }
+                .fail();
+            }
+            Ok(Some(NameOverride::PerDisk(names)))
+        }
+        None => Ok(name.map(NameOverride::Prefix)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use expectest::prelude::*;
+    use peroxide_cryptsetup::db::VolumeId;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn entry(name: Option<&str>, uuid: u128) -> DbEntry {
+        DbEntry::PassphraseEntry {
+            volume_id: VolumeId::of(name.map(|n| n.to_string()), Uuid::from_u128(uuid)),
+        }
+    }
+
+    #[test]
+    fn test_expand_placeholders() {
+        let template = NameTemplate::from_str("crypt-{name}-{short_uuid}_{index}").unwrap();
+        expect!(template
+            .expand(&entry(Some("disk"), 0x1234_5678_0000_0000_0000_0000_0000_0000), 2)
+            .ok())
+        .to(be_some().value("crypt-disk-12345678_2".to_string()));
+        expect!(template.expand(&entry(None, 1), 0).ok())
+            .to(be_some().value("crypt-uuid_00000000-0000-0000-0000-000000000001-00000000_0".to_string()));
+    }
+
+    #[test]
+    fn test_expand_serial() {
+        let template = NameTemplate::from_str("crypt-{serial}").unwrap();
+        let entry = entry(Some("disk"), 0x1234_5678_0000_0000_0000_0000_0000_0000);
+        expect!(template.expand_with_serial(&entry, 0, Some("WD-WX12 3456/7")).ok())
+            .to(be_some().value("crypt-WD-WX12_3456_7".to_string()));
+        // a drive without a serial is named after its uuid instead
+        expect!(template.expand_with_serial(&entry, 0, None).ok()).to(be_some().value("crypt-12345678".to_string()));
+    }
+
+    #[test]
+    fn test_unknown_placeholder_must_be_rejected() {
+        expect!(NameTemplate::from_str("crypt-{label}")).to(be_err());
+        expect!(NameTemplate::from_str("crypt-{name")).to(be_err());
+        expect!(NameTemplate::from_str("crypt-name}")).to(be_err());
+        expect!(NameTemplate::from_str("")).to(be_err());
+    }
+
+    #[test]
+    fn test_invalid_dm_name_must_be_rejected() {
+        let entry = entry(Some("disk"), 1);
+        expect!(NameTemplate::from_str("crypt/{name}").unwrap().expand(&entry, 0)).to(be_err());
+        expect!(NameTemplate::from_str("..").unwrap().expand(&entry, 0)).to(be_err());
+        expect!(NameTemplate::from_str(&format!("{}{{uuid}}", "x".repeat(100)))
+            .unwrap()
+            .expand(&entry, 0))
+        .to(be_err());
+    }
+
+    #[test]
+    fn test_name_override_must_reject_duplicate_names() {
+        let first = entry(Some("first"), 1);
+        let second = entry(Some("second"), 2);
+        let template = NameTemplate::from_str("crypt-{name}").unwrap();
+        expect!(name_override(None, Some(&template), &[&first, &second])).to(be_ok());
+        let template = NameTemplate::from_str("crypt").unwrap();
+        expect!(name_override(None, Some(&template), &[&first, &second])).to(be_err());
+    }
+}
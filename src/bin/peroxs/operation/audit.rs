@@ -0,0 +1,322 @@
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use serde_derive::Serialize;
+use snafu::prelude::*;
+use uuid::Uuid;
+
+use peroxide_cryptsetup::context::{Context, DatabaseOps, PeroxideDbOps};
+use peroxide_cryptsetup::db::{DbEntry, PeroxideDb};
+use peroxide_cryptsetup::device::{Disks, LuksVolumeOps};
+use peroxide_cryptsetup::input::{entry_key_file, resolve_key_file, SecStr};
+
+use crate::operation::{db_dir, CommandOutput, ContextSnafu, DiskRecord, Result};
+
+/// Keys shorter than this are reported even if their bytes look random
+const MIN_KEY_BYTES: usize = 32;
+/// Keys with less (estimated) entropy than this are reported
+const MIN_KEY_ENTROPY_BITS: f64 = 128.0;
+
+/// How bad an issue found by the audit is (high-severity issues make the audit fail)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+}
+
+impl Severity {
+    fn label(&self) -> &'static str {
+        match self {
+            Severity::Low => "LOW",
+            Severity::Medium => "MEDIUM",
+            Severity::High => "HIGH",
+        }
+    }
+}
+
+/// Kind of issue found by the audit (the report is grouped by it)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditCategory {
+    /// The same key is in the keyfiles of several disks
+    SharedKeyfile,
+    /// The key in a keyfile is short or has little entropy
+    WeakKeyfile,
+    /// A keyfile can be accessed by other users
+    KeyfilePermissions,
+    /// A LUKS disk that is present has no entry in the database
+    UnenrolledDisk,
+}
+
+impl AuditCategory {
+    fn heading(&self) -> &'static str {
+        match self {
+            AuditCategory::SharedKeyfile => "Keyfiles shared between disks",
+            AuditCategory::WeakKeyfile => "Weak keyfiles",
+            AuditCategory::KeyfilePermissions => "Keyfiles accessible by other users",
+            AuditCategory::UnenrolledDisk => "LUKS disks that are not enrolled",
+        }
+    }
+}
+
+/// An issue found by the audit
+#[derive(Debug, Serialize, PartialEq)]
+pub struct AuditFinding {
+    pub category: AuditCategory,
+    pub severity: Severity,
+    pub message: String,
+    /// Disks of the database affected by the issue
+    pub disks: Vec<DiskRecord>,
+}
+
+/// The key of a keyfile entry (of a composite entry too), read the way opening its disk would
+struct EntryKeyfile {
+    path: PathBuf,
+    key: SecStr,
+    disk: DiskRecord,
+}
+
+/// Check the database entries, their keyfiles and the disks that are present for common security issues
+///
+/// Keyfiles that cannot be read are skipped (`peroxs verify` and opening the disk report those).
+pub fn audit<C: Context>(ctx: &C) -> Result<CommandOutput> {
+    let db = ctx.open_db().context(ContextSnafu)?;
+    let working_dir = db_dir(ctx)?;
+
+    let keyfiles = entry_keyfiles(&db, &working_dir);
+    let mut findings = shared_keyfile_findings(&keyfiles);
+    findings.extend(keyfiles.iter().filter_map(|keyfile| {
+        key_strength(keyfile.key.unsecure()).map(|(severity, problem)| AuditFinding {
+            category: AuditCategory::WeakKeyfile,
+            severity,
+            message: format!("The key in {} {}", keyfile.path.display(), problem),
+            disks: vec![keyfile.disk.clone()],
+        })
+    }));
+    findings.extend(permission_findings(&keyfiles));
+    findings.extend(unenrolled_disk_findings(&db));
+
+    let high = findings.iter().filter(|f| f.severity == Severity::High).count();
+    Ok(CommandOutput::Audit { findings, high })
+}
+
+fn entry_keyfiles(db: &PeroxideDb, working_dir: &Path) -> Vec<EntryKeyfile> {
+    let mut keyfiles = vec![];
+    for entry in db.entries.iter() {
+        let parts = match entry {
+            DbEntry::CompositeEntry { parts, .. } => parts.iter().collect(),
+            _ => vec![entry],
+        };
+        for part in parts {
+            if let DbEntry::KeyfileEntry {
+                key_file,
+                key_offset,
+                key_size,
+                ..
+            } = part
+            {
+                let read = resolve_key_file(key_file, working_dir)
+                    .and_then(|path| entry_key_file(&path, *key_offset, *key_size, working_dir).map(|key| (path, key)));
+                match read {
+                    Ok((path, key)) => keyfiles.push(EntryKeyfile {
+                        path,
+                        key,
+                        disk: DiskRecord::of(entry),
+                    }),
+                    Err(e) => debug!("Skipping the keyfile {} in the audit: {}", key_file.display(), e),
+                }
+            }
+        }
+    }
+    keyfiles
+}
+
+/// A finding for every key that is used by more than one disk (a leaked keyfile unlocks all of them)
+fn shared_keyfile_findings(keyfiles: &[EntryKeyfile]) -> Vec<AuditFinding> {
+    let mut groups: Vec<(&EntryKeyfile, Vec<&EntryKeyfile>)> = vec![];
+    for keyfile in keyfiles {
+        match groups.iter_mut().find(|(first, _)| first.key == keyfile.key) {
+            Some((_, group)) => group.push(keyfile),
+            None => groups.push((keyfile, vec![keyfile])),
+        }
+    }
+
+    groups
+        .into_iter()
+        .filter(|(_, group)| group.iter().map(|k| k.disk.uuid).collect::<HashSet<Uuid>>().len() > 1)
+        .map(|(_, group)| {
+            let mut paths = group.iter().map(|k| k.path.display().to_string()).collect::<Vec<_>>();
+            paths.dedup();
+            AuditFinding {
+                category: AuditCategory::SharedKeyfile,
+                severity: Severity::Medium,
+                message: format!("The same key unlocks {} disks ({})", group.len(), paths.join(", ")),
+                disks: group.iter().map(|k| k.disk.clone()).collect(),
+            }
+        })
+        .collect()
+}
+
+/// How weak a key is, if it is too weak to be left as is
+fn key_strength(key: &[u8]) -> Option<(Severity, String)> {
+    if key.iter().all(|&b| Some(&b) == key.first()) {
+        return Some((
+            Severity::High,
+            format!("has no entropy ({} identical bytes)", key.len()),
+        ));
+    }
+    if key.len() < MIN_KEY_BYTES {
+        return Some((Severity::Medium, format!("is only {} bytes long", key.len())));
+    }
+    match entropy_bits(key) {
+        bits if bits < MIN_KEY_ENTROPY_BITS => {
+            Some((Severity::Medium, format!("has only about {:.0} bits of entropy", bits)))
+        }
+        _ => None,
+    }
+}
+
+/// Estimate of the entropy of a key from the frequency of its bytes (which is an upper bound for text or patterns)
+fn entropy_bits(key: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    key.iter().for_each(|&b| counts[b as usize] += 1);
+    let len = key.len() as f64;
+    let per_byte: f64 = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+    per_byte * len
+}
+
+fn permission_findings(keyfiles: &[EntryKeyfile]) -> Vec<AuditFinding> {
+    let mut checked = HashSet::new();
+    keyfiles
+        .iter()
+        .filter(|keyfile| checked.insert(keyfile.path.clone()))
+        .filter_map(|keyfile| {
+            let mode = fs::metadata(&keyfile.path).ok()?.permissions().mode();
+            let (severity, access) = mode_access(mode)?;
+            Some(AuditFinding {
+                category: AuditCategory::KeyfilePermissions,
+                severity,
+                message: format!("{} is {} (mode {:o})", keyfile.path.display(), access, mode & 0o777),
+                disks: keyfiles
+                    .iter()
+                    .filter(|k| k.path == keyfile.path)
+                    .map(|k| k.disk.clone())
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// Who else than the owner can access a keyfile with the mode (if anyone)
+fn mode_access(mode: u32) -> Option<(Severity, &'static str)> {
+    if mode & 0o007 != 0 {
+        Some((Severity::High, "accessible by all users"))
+    } else if mode & 0o070 != 0 {
+        Some((Severity::Low, "accessible by its group"))
+    } else {
+        None
+    }
+}
+
+/// A finding for every LUKS disk that is present but has no entry (so its keys are not managed by the database)
+fn unenrolled_disk_findings(db: &PeroxideDb) -> Vec<AuditFinding> {
+    let uuids = Disks::all_disk_uuids().unwrap_or_else(|e| {
+        debug!("Could not list the disks for the audit: {}", e);
+        vec![]
+    });
+    uuids
+        .into_iter()
+        .filter(|uuid| db.find_entry(uuid).is_none())
+        .filter_map(|uuid| {
+            let path = Disks::disk_uuid_path(&uuid).ok().filter(|path| path.is_luks())?;
+            Some(AuditFinding {
+                category: AuditCategory::UnenrolledDisk,
+                severity: Severity::Low,
+                message: format!("{} ({}) is not enrolled", path.display(), uuid),
+                disks: vec![],
+            })
+        })
+        .collect()
+}
+
+/// Print the findings grouped by category
+pub fn print_report(findings: &[AuditFinding]) {
+    if findings.is_empty() {
+        println!("No issues found");
+        return;
+    }
+
+    let mut categories = vec![];
+    for finding in findings {
+        if !categories.contains(&finding.category) {
+            categories.push(finding.category);
+        }
+    }
+    for category in categories {
+        println!("{}:", category.heading());
+        for finding in findings.iter().filter(|f| f.category == category) {
+            println!("  [{}] {}", finding.severity.label(), finding.message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use expectest::prelude::*;
+
+    use super::*;
+
+    fn keyfile(path: &str, key: &[u8], uuid: u128) -> EntryKeyfile {
+        EntryKeyfile {
+            path: PathBuf::from(path),
+            key: SecStr::new(key.to_vec()),
+            disk: DiskRecord {
+                name: None,
+                uuid: Uuid::from_u128(uuid),
+            },
+        }
+    }
+
+    #[test]
+    fn test_shared_keyfile_findings() {
+        let keyfiles = vec![
+            keyfile("/keys/a.key", b"secret", 1),
+            keyfile("/keys/b.key", b"secret", 2),
+            keyfile("/keys/c.key", b"other", 3),
+            // the same disk with two entries is not sharing its key
+            keyfile("/keys/c.key", b"other", 3),
+        ];
+        let findings = shared_keyfile_findings(&keyfiles);
+
+        expect!(findings.len()).to(be_equal_to(1));
+        expect!(&findings[0].message).to(be_equal_to("The same key unlocks 2 disks (/keys/a.key, /keys/b.key)"));
+    }
+
+    #[test]
+    fn test_key_strength() {
+        expect!(key_strength(&[0u8; 4096]).map(|(severity, _)| severity)).to(be_some().value(Severity::High));
+        expect!(key_strength(b"hunter2").map(|(severity, _)| severity)).to(be_some().value(Severity::Medium));
+        expect!(key_strength(&[b'a', b'b'].repeat(32)).map(|(severity, _)| severity))
+            .to(be_some().value(Severity::Medium));
+        let random = (0..=255u8).collect::<Vec<_>>();
+        expect!(key_strength(&random)).to(be_none());
+    }
+
+    #[test]
+    fn test_mode_access() {
+        expect!(mode_access(0o100644)).to(be_some().value((Severity::High, "accessible by all users")));
+        expect!(mode_access(0o100640)).to(be_some().value((Severity::Low, "accessible by its group")));
+        expect!(mode_access(0o100600)).to(be_none());
+    }
+}
@@ -0,0 +1,119 @@
+use prettytable::{format, Table};
+use serde_derive::Serialize;
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::device::{benchmark_pbkdf, KeyslotPbkdf, PbkdfBenchmarkParams};
+
+use crate::operation::{CommandOutput, DeviceSnafu, Result, ValidationSnafu};
+
+/// The PBKDF that is benchmarked (the default of LUKS2)
+const BENCHMARK_ALGORITHM: &str = "argon2id";
+
+#[derive(Debug)]
+pub struct Params {
+    /// Time one key derivation should take
+    pub iteration_ms: u32,
+    /// Upper bound of the suggested memory cost
+    pub max_memory_kb: u32,
+    pub parallel_threads: u32,
+    pub key_bits: usize,
+}
+
+/// Suggested argon2 parameters, named after the `peroxs enroll` flags they are passed with
+#[derive(Debug, Serialize, PartialEq)]
+pub struct BenchmarkRecord {
+    pub algorithm: String,
+    pub iteration_ms: u32,
+    pub argon2_iterations: u32,
+    pub argon2_memory_kb: u32,
+    pub argon2_parallel_threads: u32,
+}
+
+impl From<KeyslotPbkdf> for BenchmarkRecord {
+    fn from(pbkdf: KeyslotPbkdf) -> Self {
+        BenchmarkRecord {
+            algorithm: pbkdf.algorithm,
+            iteration_ms: pbkdf.time_ms,
+            argon2_iterations: pbkdf.iterations,
+            argon2_memory_kb: pbkdf.max_memory_kb,
+            argon2_parallel_threads: pbkdf.parallel_threads,
+        }
+    }
+}
+
+/// Benchmark argon2id on this machine to suggest the parameters of new keyslots (no disk or database is needed)
+pub fn benchmark(params: Params) -> Result<CommandOutput> {
+    ensure!(
+        params.iteration_ms > 0 && params.max_memory_kb > 0 && params.parallel_threads > 0,
+        ValidationSnafu {
+            message: "The iteration time, memory and number of threads must be greater than 0".to_string(),
+        }
+    );
+
+    let pbkdf = benchmark_pbkdf(&PbkdfBenchmarkParams {
+        algorithm: BENCHMARK_ALGORITHM.to_string(),
+        time_ms: params.iteration_ms,
+        max_memory_kb: params.max_memory_kb,
+        parallel_threads: params.parallel_threads,
+        key_bits: params.key_bits,
+    })
+    .context(DeviceSnafu)?;
+
+    Ok(CommandOutput::Benchmark { pbkdf: pbkdf.into() })
+}
+
+pub fn print_table(record: &BenchmarkRecord) {
+    let mut table = Table::new();
+    table.add_row(row![b->"Algorithm", b->"Time", b->"Iterations", b->"Memory", b->"Threads"]);
+    table.add_row(row![
+        record.algorithm,
+        format!("{} ms", record.iteration_ms),
+        record.argon2_iterations,
+        format!("{} KiB", record.argon2_memory_kb),
+        record.argon2_parallel_threads
+    ]);
+    table.set_format(*format::consts::FORMAT_CLEAN);
+    table.printstd();
+
+    println!("\nSuggested flags for `peroxs enroll`: {}", enroll_flags(record));
+}
+
+/// The flags of `peroxs enroll` that format a disk with the suggested parameters
+fn enroll_flags(record: &BenchmarkRecord) -> String {
+    format!(
+        "--iteration-ms {} --argon2-iterations {} --argon2-memory-kb {} --argon2-parallel-threads {}",
+        record.iteration_ms, record.argon2_iterations, record.argon2_memory_kb, record.argon2_parallel_threads
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use expectest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_enroll_flags_for_benchmark() {
+        let record = BenchmarkRecord::from(KeyslotPbkdf {
+            algorithm: "argon2id".to_string(),
+            time_ms: 2000,
+            iterations: 5,
+            max_memory_kb: 1048576,
+            parallel_threads: 4,
+        });
+        expect!(enroll_flags(&record)).to(be_equal_to(
+            "--iteration-ms 2000 --argon2-iterations 5 --argon2-memory-kb 1048576 --argon2-parallel-threads 4",
+        ));
+    }
+
+    #[test]
+    fn test_benchmark_rejects_zero_time() {
+        let params = Params {
+            iteration_ms: 0,
+            max_memory_kb: 1048576,
+            parallel_threads: 4,
+            key_bits: 512,
+        };
+        expect!(benchmark(params)).to(be_err());
+    }
+}
@@ -0,0 +1,120 @@
+use serde_derive::Serialize;
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{Context, DeviceOps, PeroxideDbOps};
+use peroxide_cryptsetup::db::DbEntry;
+use peroxide_cryptsetup::device::ActivationFlags;
+
+use crate::operation::{CommandOutput, ContextSnafu, DiskRecord, Result};
+
+#[derive(Debug)]
+pub struct Params {
+    /// Flags to activate the devices with
+    pub flags: ActivationFlags,
+}
+
+/// What happened to a disk flagged to be opened at boot
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BootOpenStatus {
+    Opened,
+    AlreadyActive,
+    NotPresent,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BootOpenRecord {
+    #[serde(flatten)]
+    pub disk: DiskRecord,
+    pub status: BootOpenStatus,
+    /// Name of the mapping (if the disk was opened)
+    pub mapping: Option<String>,
+    /// Why the disk could not be opened
+    pub error: Option<String>,
+}
+
+/// Open the disks of all the entries flagged to be opened at boot, skipping the ones that are missing or already open
+pub fn boot_open<C: Context + DeviceOps>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    let db = ctx.open_db().context(ContextSnafu)?;
+
+    let mut records = Vec::new();
+    let mut to_open = Vec::new();
+    for entry in db.entries.iter().filter(|e| e.volume_id().is_auto_open()) {
+        if !ctx.is_present(entry) {
+            records.push(record(entry, BootOpenStatus::NotPresent, None, None));
+        } else if ctx.is_active(entry, None) {
+            records.push(record(entry, BootOpenStatus::AlreadyActive, None, None));
+        } else {
+            to_open.push(entry);
+        }
+    }
+
+    for (entry, res) in to_open.iter().zip(ctx.open_entries(&to_open, params.flags)) {
+        records.push(match res {
+            Ok(mapping) => record(entry, BootOpenStatus::Opened, Some(mapping), None),
+            Err(e) => record(entry, BootOpenStatus::Failed, None, Some(e.to_string())),
+        });
+    }
+    let failed = records.iter().filter(|r| r.status == BootOpenStatus::Failed).count();
+
+    Ok(CommandOutput::BootOpen { disks: records, failed })
+}
+
+fn record(entry: &DbEntry, status: BootOpenStatus, mapping: Option<String>, error: Option<String>) -> BootOpenRecord {
+    BootOpenRecord {
+        disk: DiskRecord::of(entry),
+        status,
+        mapping,
+        error,
+    }
+}
+
+/// Print a line for every disk flagged to be opened at boot
+pub fn print_summary(records: &[BootOpenRecord]) {
+    for record in records {
+        println!("{}", summary_line(record));
+    }
+}
+
+fn summary_line(record: &BootOpenRecord) -> String {
+    let disk = record.disk.name.clone().unwrap_or_else(|| record.disk.uuid.to_string());
+    match record.status {
+        BootOpenStatus::Opened => format!("Opened {} as {}", disk, record.mapping.as_deref().unwrap_or_default()),
+        BootOpenStatus::AlreadyActive => format!("{} is already open", disk),
+        BootOpenStatus::NotPresent => format!("{} is not present", disk),
+        BootOpenStatus::Failed => format!(
+            "Could not open {}: {}",
+            disk,
+            record.error.as_deref().unwrap_or_default()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use expectest::prelude::*;
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn test_summary_line() {
+        let mut record = BootOpenRecord {
+            disk: DiskRecord {
+                name: Some("data1".to_string()),
+                uuid: Uuid::nil(),
+            },
+            status: BootOpenStatus::Opened,
+            mapping: Some("data1".to_string()),
+            error: None,
+        };
+        expect!(summary_line(&record)).to(be_equal_to("Opened data1 as data1"));
+        record.status = BootOpenStatus::NotPresent;
+        record.mapping = None;
+        expect!(summary_line(&record)).to(be_equal_to("data1 is not present"));
+        record.status = BootOpenStatus::Failed;
+        record.error = Some("Key input error".to_string());
+        expect!(summary_line(&record)).to(be_equal_to("Could not open data1: Key input error"));
+    }
+}
@@ -0,0 +1,61 @@
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{mapping_name, Context, DeviceOps, PeroxideDbOps};
+
+use crate::name_template::{name_override, NameTemplate};
+use crate::operation::{find_entry_for, CommandOutput, ContextSnafu, Disks, Result, ValidationSnafu};
+use crate::DiskReference;
+
+#[derive(Debug)]
+pub struct Params {
+    /// List of device paths, UUIDs or names corresponding to the devices we want to close
+    pub disk_references: Vec<DiskReference>,
+    /// Name override (the same one that was used when opening the devices)
+    pub name: Option<String>,
+    /// Name template (the same one that was used when opening the devices)
+    pub name_template: Option<NameTemplate>,
+}
+
+pub fn close<C: Context + DeviceOps>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    let db = ctx.open_db().context(ContextSnafu)?;
+
+    if params.disk_references.is_empty() {
+        return Err(ValidationSnafu {
+            message: "Cannot close 0 devices".to_string(),
+        }
+        .build());
+    }
+
+    let entries = params
+        .disk_references
+        .iter()
+        .map(|disk_ref| find_entry_for(&db, disk_ref))
+        .collect::<Result<Vec<_>>>()?;
+
+    // resolve the names in the same way as `open` does, so that the override round-trips
+    let name_override = name_override(params.name, params.name_template.as_ref(), &entries)?;
+    let names = entries
+        .iter()
+        .enumerate()
+        .map(|(idx, entry)| {
+            mapping_name(
+                entry,
+                name_override.as_ref().and_then(|n| n.for_disk(idx, entries.len())),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // validate: all devices must be active before any of them is closed
+    if let Some(inactive) = names.iter().find(|name| !Disks::is_device_active(name)) {
+        return Err(ValidationSnafu {
+            message: format!("Device `{}` is not active", inactive),
+        }
+        .build());
+    }
+
+    for name in names.iter() {
+        ctx.deactivate(name).context(ContextSnafu)?;
+    }
+
+    Ok(CommandOutput::Close { mappings: names })
+}
@@ -0,0 +1,92 @@
+use std::path::{Path, PathBuf};
+
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{Context, PeroxideDbOps};
+use peroxide_cryptsetup::db::{DbType, PeroxideDb};
+
+use crate::operation::import::with_absolute_key_file;
+use crate::operation::{db_dir, CommandOutput, ContextSnafu, DatabaseSnafu, Result, ValidationSnafu};
+
+#[derive(Debug)]
+pub struct BackupParams {
+    /// Path to save the backup database to
+    pub dest: PathBuf,
+    /// Flag to overwrite an existing file at the destination
+    pub force: bool,
+}
+
+/// Save a copy of the database as a backup database (that can be passed to `enroll --backup-db`)
+pub fn backup<C: Context>(ctx: &C, params: BackupParams) -> Result<CommandOutput> {
+    if params.dest.exists() && !params.force {
+        return Err(ValidationSnafu {
+            message: format!(
+                "File already exists at {} (use --force to overwrite it)",
+                params.dest.display()
+            ),
+        }
+        .build());
+    }
+
+    let db = ctx.open_db().context(ContextSnafu)?;
+    // relative keyfile paths are relative to the directory of the database, which the backup may not be saved in
+    let db_dir = db_dir(ctx)?;
+
+    let backup = as_backup(db, &db_dir);
+    backup.save_to(&params.dest).context(DatabaseSnafu)?;
+
+    Ok(CommandOutput::Backup {
+        path: params.dest,
+        entries: backup.entries.len(),
+    })
+}
+
+/// Re-type a database as a backup database (with the keyfile paths made absolute)
+fn as_backup(db: PeroxideDb, db_dir: &Path) -> PeroxideDb {
+    PeroxideDb {
+        entries: db
+            .entries
+            .into_iter()
+            .map(|entry| with_absolute_key_file(entry, db_dir))
+            .collect(),
+        db_type: DbType::Backup,
+        ..db
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use expectest::prelude::*;
+    use peroxide_cryptsetup::db::{DbEntry, VolumeId};
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn test_as_backup() {
+        let mut db = PeroxideDb::new(DbType::Operation);
+        db.entries.push(DbEntry::KeyfileEntry {
+            key_file: PathBuf::from("secret.key"),
+            key_offset: None,
+            key_size: None,
+            volume_id: VolumeId::of(Some("keyfile-disk".to_string()), Uuid::from_u128(1)),
+        });
+        db.entries.push(DbEntry::PassphraseEntry {
+            volume_id: VolumeId::of(None, Uuid::from_u128(2)),
+        });
+
+        let backup = as_backup(db.clone(), Path::new("/secure/keys"));
+
+        expect!(backup.db_type).to(be_equal_to(DbType::Backup));
+        expect!(backup.version).to(be_equal_to(db.version));
+        expect!(backup.entries).to(be_equal_to(vec![
+            DbEntry::KeyfileEntry {
+                key_file: PathBuf::from("/secure/keys/secret.key"),
+                key_offset: None,
+                key_size: None,
+                volume_id: VolumeId::of(Some("keyfile-disk".to_string()), Uuid::from_u128(1)),
+            },
+            db.entries[1].clone(),
+        ]));
+    }
+}
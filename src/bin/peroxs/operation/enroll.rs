@@ -1,40 +1,130 @@
 use std::convert::TryFrom;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
 
+use log::Level;
+use serde_derive::Serialize;
 use snafu::prelude::*;
+use uuid::Uuid;
 use vec1::Vec1;
 
 use peroxide_cryptsetup::context::{Context, DeviceOps, DiskEnrolmentParams, PeroxideDbOps};
+use peroxide_cryptsetup::db::DbEntry;
+use peroxide_cryptsetup::device::{
+    luks_uuid_via_blkid, Disks, Error as DeviceError, Keyslot, KeyslotPbkdf, LuksVersion, LuksVolumeOps, OpenedDevices,
+};
+use peroxide_cryptsetup::disk_log;
 use peroxide_cryptsetup::input::BackupPrompt;
+use peroxide_cryptsetup::messages::{self, Verbosity};
 
-use crate::operation::{ContextSnafu, PathOrUuid, Result, ValidationSnafu};
+use crate::operation::{
+    confirm, CommandOutput, ContextSnafu, DeviceSnafu, DiskRecord, PathOrUuid, Result, ValidationSnafu,
+};
 
 #[derive(Debug)]
 pub struct Params<BCtx: Context + DeviceOps> {
     /// List of device paths or UUIDs corresponding to the devices we want to enroll
     pub device_paths_or_uuids: Vec<PathOrUuid>,
+    /// Name of an active mapping whose disk is enrolled (instead of the devices)
+    pub active_mapping: Option<String>,
     /// Backup context (if using a backup database)
     pub backup_context: Option<BCtx>,
+    /// Flag to skip the confirmation before force formatting existing LUKS containers
+    pub confirmed: bool,
+    /// Flag to only format the devices, without adding entries to the database
+    pub format_only: bool,
     /// Disk enrollment parameters
     pub params: DiskEnrolmentParams,
 }
 
-pub fn enroll<Ctx: Context + DeviceOps, BCtx: Context + DeviceOps>(ctx: &Ctx, params: Params<BCtx>) -> Result<()> {
-    let mut db = ctx.open_db().context(ContextSnafu)?;
+#[derive(Debug)]
+pub struct ManifestParams {
+    /// Devices to enroll (in the order of the manifest), each with its own enrollment parameters
+    pub disks: Vec<(PathOrUuid, DiskEnrolmentParams)>,
+    /// Flag to skip the confirmation before force formatting existing LUKS containers
+    pub confirmed: bool,
+}
 
-    let paths = params
-        .device_paths_or_uuids
-        .iter()
-        .map(|p| p.to_path())
-        .collect::<Result<Vec<_>>>()?;
-    let paths_v1 = if let Some(vec) = Vec1::try_from(paths).ok() {
+/// A disk that was enrolled, with the key derivation parameters of its new keyslot (if they could be read back)
+#[derive(Debug, Serialize)]
+pub struct EnrollRecord {
+    #[serde(flatten)]
+    pub disk: DiskRecord,
+    pub keyslot: Option<Keyslot>,
+    pub pbkdf: Option<KeyslotPbkdf>,
+    /// The header of the enrolled disk (if it could be read back)
+    pub device: Option<EnrolledDevice>,
+    pub token_id: Option<i32>,
+}
+
+/// A disk that was formatted without being enrolled, with the UUID that `peroxs register` can find it by
+#[derive(Debug, Serialize)]
+pub struct FormatRecord {
+    pub path: PathBuf,
+    pub uuid: Uuid,
+}
+
+/// The path and volume parameters of an enrolled disk, as read from its header after the enrollment
+#[derive(Debug, Serialize)]
+pub struct EnrolledDevice {
+    pub path: PathBuf,
+    pub luks_version: LuksVersion,
+    /// Cipher in the name-mode form (e.g. `aes-xts-plain64`)
+    pub cipher: String,
+    pub key_bits: usize,
+}
+
+pub fn enroll<Ctx: Context + DeviceOps, BCtx: Context + DeviceOps>(
+    ctx: &Ctx,
+    params: Params<BCtx>,
+) -> Result<CommandOutput> {
+    ensure!(
+        !params.format_only || params.params.format,
+        ValidationSnafu {
+            message: "Only a disk that is being formatted can skip the enrollment".to_string(),
+        }
+    );
+
+    let paths = match params.active_mapping {
+        Some(ref name) => vec![active_mapping_path(name, params.params.format)?],
+        None => params
+            .device_paths_or_uuids
+            .iter()
+            .map(|p| p.to_path())
+            .collect::<Result<Vec<_>>>()?,
+    };
+    let paths_v1 = if let Ok(vec) = Vec1::try_from(paths) {
         vec
     } else {
         return Err(ValidationSnafu {
-            message: format!("At least one path/uuid must be supplied"),
+            message: "At least one path/uuid must be supplied".to_string(),
         }
         .build());
     };
 
+    if params.params.force_format && !params.confirmed {
+        let stdin = io::stdin();
+        let is_tty = ttypass::stdin_is_tty();
+        for path in paths_v1.iter() {
+            // only containers that are already formatted lose their data
+            if let Ok(uuid) = luks_uuid_via_blkid(path) {
+                confirm_force_format(path, &uuid, is_tty, &mut stdin.lock())?;
+            }
+        }
+    }
+
+    // the disks are formatted like when they are enrolled, but no database is opened (or needs to exist)
+    if params.format_only {
+        let disks = ctx.format_disks(paths_v1, params.params).context(ContextSnafu)?;
+        return Ok(CommandOutput::Format {
+            disks: disks
+                .into_iter()
+                .map(|(path, uuid)| FormatRecord { path, uuid })
+                .collect(),
+        });
+    }
+
+    let mut db = ctx.open_db_for_update().context(ContextSnafu)?;
     let backup_db = if let Some(bctx) = params.backup_context {
         let bdb = bctx.open_db().context(ContextSnafu)?;
         Some(BackupPrompt { db: bdb, ctx: bctx })
@@ -42,8 +132,329 @@ pub fn enroll<Ctx: Context + DeviceOps, BCtx: Context + DeviceOps>(ctx: &Ctx, pa
         None
     };
 
-    ctx.enroll_disks(&mut db, paths_v1, params.params, backup_db)
+    let entries = ctx
+        .enroll_disks(&mut db, paths_v1, params.params, backup_db)
         .context(ContextSnafu)?;
 
-    Ok(())
+    Ok(CommandOutput::Enroll {
+        disks: entries.iter().map(enroll_record).collect(),
+    })
+}
+
+/// The disk under an active mapping, which gets a new keyslot like any other disk (the key of one of its existing
+/// keyslots is still needed, as cryptsetup-rs cannot add a keyslot with the volume key of the mapping)
+fn active_mapping_path(name: &str, format: bool) -> Result<PathBuf> {
+    ensure!(
+        !format,
+        ValidationSnafu {
+            message: format!("Cannot format the disk that is open as {}", name),
+        }
+    );
+    let mapping = Disks::active_device_info(name)
+        .context(DeviceSnafu)?
+        .context(ValidationSnafu {
+            message: format!("No LUKS disk is open as {}", name),
+        })?;
+    debug!(
+        "Enrolling {} ({}), which is open as {}",
+        mapping.underlying.display(),
+        mapping.underlying_uuid,
+        name
+    );
+    Ok(mapping.underlying)
+}
+
+/// The result of enrolling a disk of a manifest (a failure does not stop the other disks from being enrolled)
+#[derive(Debug, Serialize)]
+pub struct ManifestRecord {
+    pub device: PathBuf,
+    /// The enrolled disk (none if its enrollment failed)
+    pub enrolled: Option<EnrollRecord>,
+    /// Why the enrollment failed
+    pub error: Option<String>,
+}
+
+pub fn enroll_manifest<Ctx: Context + DeviceOps>(ctx: &Ctx, params: ManifestParams) -> Result<CommandOutput> {
+    let mut db = ctx.open_db_for_update().context(ContextSnafu)?;
+
+    // every device is found (and every force format confirmed) before any disk is changed
+    let disks = params
+        .disks
+        .into_iter()
+        .map(|(device, disk_params)| device.to_path().map(|path| (path, disk_params)))
+        .collect::<Result<Vec<_>>>()?;
+
+    if !params.confirmed {
+        let stdin = io::stdin();
+        let is_tty = ttypass::stdin_is_tty();
+        for (path, _) in disks.iter().filter(|(_, disk_params)| disk_params.force_format) {
+            if let Ok(uuid) = luks_uuid_via_blkid(path) {
+                confirm_force_format(path, &uuid, is_tty, &mut stdin.lock())?;
+            }
+        }
+    }
+
+    let records = disks
+        .into_iter()
+        .map(|(path, disk_params)| {
+            let no_backup: Option<BackupPrompt<Ctx>> = None;
+            match ctx.enroll_disks_unsaved(&mut db, Vec1::new(path.clone()), disk_params, no_backup) {
+                Ok(entries) => ManifestRecord {
+                    device: path,
+                    enrolled: Some(enroll_record(entries.first())),
+                    error: None,
+                },
+                Err(e) => ManifestRecord {
+                    device: path,
+                    enrolled: None,
+                    error: Some(e.to_string()),
+                },
+            }
+        })
+        .collect::<Vec<_>>();
+    // the entries of all the disks are saved at once, while the database is still locked for the whole manifest
+    ctx.save_enrolled_db(&db).context(ContextSnafu)?;
+    let failed = records.iter().filter(|r| r.error.is_some()).count();
+
+    Ok(CommandOutput::EnrollManifest { disks: records, failed })
+}
+
+fn enroll_record(entry: &DbEntry) -> EnrollRecord {
+    let keyslot = entry.volume_id().keyslot();
+    // the parameters are only informational, so failing to read them does not fail the enrollment
+    let log_error = |what: &str, e: DeviceError| {
+        disk_log!(
+            Level::Debug,
+            "enroll",
+            entry.uuid(),
+            "Could not read the {}: {}",
+            what,
+            e
+        )
+    };
+    let path = Disks::disk_uuid_path(entry.uuid())
+        .map_err(|e| log_error("path of the disk", e))
+        .ok();
+
+    // the disk is only opened once for all the parameters
+    let devices = OpenedDevices::new();
+    let device = path.as_ref().map(|path| devices.device(path));
+    let pbkdf = keyslot.zip(device.as_ref()).and_then(|(keyslot, device)| {
+        device
+            .luks_keyslot_pbkdf(keyslot)
+            .map_err(|e| log_error("PBKDF parameters", e))
+            .ok()
+    });
+    let header = device.as_ref().and_then(|device| {
+        device
+            .luks_version()
+            .and_then(|version| device.luks_volume_info().map(|info| (version, info)))
+            .map_err(|e| log_error("volume parameters", e))
+            .ok()
+    });
+
+    EnrollRecord {
+        disk: DiskRecord::of(entry),
+        keyslot,
+        pbkdf,
+        device: path.zip(header).map(|(path, (luks_version, info))| EnrolledDevice {
+            path,
+            luks_version,
+            cipher: info.cipher,
+            key_bits: info.key_bits,
+        }),
+        token_id: entry.volume_id().luks2_token_id(),
+    }
+}
+
+/// Print a summary of every enrolled disk (with the parameters that cryptsetup applied, if known), which is cut down to
+/// a line per disk when quiet
+pub fn print_summary(records: &[EnrollRecord]) {
+    for record in records {
+        if messages::verbosity() >= Verbosity::Normal {
+            println!("{}", format_enroll_summary(record));
+        } else {
+            println!("{}", summary_line(record));
+        }
+    }
+}
+
+/// Print the UUID of every formatted disk, which is needed to register its key later
+pub fn print_format_summary(records: &[FormatRecord]) {
+    for record in records {
+        println!("Formatted {} with UUID {}", record.path.display(), record.uuid);
+    }
+    messages::status("The disks are not in the database yet, add their keys with `peroxs register`");
+}
+
+/// Print a line for every disk of a manifest, whether it was enrolled or not
+pub fn print_manifest_summary(records: &[ManifestRecord]) {
+    for record in records {
+        println!("{}", manifest_summary_line(record));
+    }
+}
+
+fn manifest_summary_line(record: &ManifestRecord) -> String {
+    match (&record.enrolled, &record.error) {
+        (Some(enrolled), _) => format!("{}: {}", record.device.display(), summary_line(enrolled)),
+        (None, Some(error)) => format!("{}: Could not enroll: {}", record.device.display(), error),
+        (None, None) => format!("{}: Not enrolled", record.device.display()),
+    }
+}
+
+fn summary_line(record: &EnrollRecord) -> String {
+    let disk = record.disk.name.clone().unwrap_or_else(|| record.disk.uuid.to_string());
+    match (record.keyslot, &record.pbkdf) {
+        (Some(keyslot), Some(pbkdf)) => format!(
+            "Enrolled {} in keyslot {} ({}: {} ms, {} iterations, {} memory, {} threads)",
+            disk,
+            keyslot,
+            pbkdf.algorithm,
+            pbkdf.time_ms,
+            pbkdf.iterations,
+            human_size_kb(pbkdf.max_memory_kb),
+            pbkdf.parallel_threads
+        ),
+        (Some(keyslot), None) => format!("Enrolled {} in keyslot {}", disk, keyslot),
+        (None, _) => format!("Enrolled {}", disk),
+    }
+}
+
+/// The summary line of an enrolled disk, followed by its device, UUID, cipher and LUKS2 token (whichever are known)
+fn format_enroll_summary(record: &EnrollRecord) -> String {
+    let mut summary = summary_line(record);
+    if let Some(device) = &record.device {
+        let version = match device.luks_version {
+            LuksVersion::Luks1 => "LUKS1",
+            LuksVersion::Luks2 => "LUKS2",
+        };
+        summary.push_str(&format!("\n  Device: {} ({})", device.path.display(), version));
+    }
+    summary.push_str(&format!("\n  UUID:   {}", record.disk.uuid));
+    if let Some(device) = &record.device {
+        summary.push_str(&format!("\n  Cipher: {}, {}-bit key", device.cipher, device.key_bits));
+    }
+    if let Some(token_id) = record.token_id {
+        summary.push_str(&format!("\n  Token:  {}", token_id));
+    }
+    summary
+}
+
+/// A size in KiB in the largest unit that it is a whole number of (e.g. `1048576` is `1 GiB`)
+fn human_size_kb(kb: u32) -> String {
+    if kb > 0 && kb % (1024 * 1024) == 0 {
+        format!("{} GiB", kb / (1024 * 1024))
+    } else if kb > 0 && kb % 1024 == 0 {
+        format!("{} MiB", kb / 1024)
+    } else {
+        format!("{} KiB", kb)
+    }
+}
+
+/// Ask for the UUID of the container (or 'yes') before it is force formatted
+fn confirm_force_format<R: BufRead>(path: &Path, uuid: &Uuid, is_tty: bool, input: &mut R) -> Result<()> {
+    confirm(
+        &format!(
+            "Force formatting {} will destroy all of its data. Type its UUID ({}) or 'yes' to continue:",
+            path.display(),
+            uuid
+        ),
+        &format!("force format the LUKS container at {}", path.display()),
+        &["yes", &uuid.to_string()],
+        is_tty,
+        input,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use expectest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_active_disk_must_not_be_formatted() {
+        expect!(active_mapping_path("peroxs-test-mapping", true)).to(be_err());
+    }
+
+    #[test]
+    fn test_summary_line_must_show_pbkdf_parameters() {
+        let mut record = EnrollRecord {
+            disk: DiskRecord {
+                name: Some("awesome".to_string()),
+                uuid: Uuid::nil(),
+            },
+            keyslot: Some(1),
+            pbkdf: Some(KeyslotPbkdf {
+                algorithm: "argon2id".to_string(),
+                time_ms: 2000,
+                iterations: 4,
+                max_memory_kb: 1048576,
+                parallel_threads: 4,
+            }),
+            device: None,
+            token_id: None,
+        };
+        expect!(summary_line(&record)).to(be_equal_to(
+            "Enrolled awesome in keyslot 1 (argon2id: 2000 ms, 4 iterations, 1 GiB memory, 4 threads)",
+        ));
+        record.pbkdf = None;
+        expect!(summary_line(&record)).to(be_equal_to("Enrolled awesome in keyslot 1"));
+    }
+
+    #[test]
+    fn test_format_enroll_summary_must_show_device_and_token() {
+        let mut record = EnrollRecord {
+            disk: DiskRecord {
+                name: Some("awesome".to_string()),
+                uuid: Uuid::nil(),
+            },
+            keyslot: Some(0),
+            pbkdf: None,
+            device: Some(EnrolledDevice {
+                path: PathBuf::from("/dev/sdb"),
+                luks_version: LuksVersion::Luks2,
+                cipher: "aes-xts-plain64".to_string(),
+                key_bits: 512,
+            }),
+            token_id: Some(1),
+        };
+        expect!(format_enroll_summary(&record)).to(be_equal_to(
+            "Enrolled awesome in keyslot 0\n  Device: /dev/sdb (LUKS2)\n  UUID:   00000000-0000-0000-0000-000000000000\n  Cipher: aes-xts-plain64, 512-bit key\n  Token:  1",
+        ));
+        record.device = None;
+        record.token_id = None;
+        expect!(format_enroll_summary(&record)).to(be_equal_to(
+            "Enrolled awesome in keyslot 0\n  UUID:   00000000-0000-0000-0000-000000000000",
+        ));
+    }
+
+    #[test]
+    fn test_human_size_kb() {
+        expect!(human_size_kb(1048576)).to(be_equal_to("1 GiB"));
+        expect!(human_size_kb(524288)).to(be_equal_to("512 MiB"));
+        expect!(human_size_kb(1000)).to(be_equal_to("1000 KiB"));
+    }
+
+    #[test]
+    fn test_manifest_summary_line_must_show_failures() {
+        let mut record = ManifestRecord {
+            device: PathBuf::from("/dev/sdb"),
+            enrolled: Some(EnrollRecord {
+                disk: DiskRecord {
+                    name: Some("data1".to_string()),
+                    uuid: Uuid::nil(),
+                },
+                keyslot: None,
+                pbkdf: None,
+                device: None,
+                token_id: None,
+            }),
+            error: None,
+        };
+        expect!(manifest_summary_line(&record)).to(be_equal_to("/dev/sdb: Enrolled data1"));
+        record.enrolled = None;
+        record.error = Some("Device already formatted".to_string());
+        expect!(manifest_summary_line(&record)).to(be_equal_to("/dev/sdb: Could not enroll: Device already formatted"));
+    }
 }
@@ -0,0 +1,171 @@
+use std::path::Path;
+
+use peroxide_cryptsetup::context::{mapping_name, Context, PeroxideDbOps};
+use peroxide_cryptsetup::db::{DbEntry, PeroxideDb};
+use snafu::prelude::*;
+
+use crate::operation::{CommandOutput, ContextSnafu, IoSnafu, Result};
+
+#[derive(Debug)]
+pub struct CrypttabParams {
+    /// Flag to append the `discard` option to all entries
+    pub discard: bool,
+}
+
+pub fn export_crypttab<C: Context>(ctx: &C, params: CrypttabParams) -> Result<CommandOutput> {
+    let db = ctx.open_db().context(ContextSnafu)?;
+
+    // relative keyfile paths are relative to the directory of the database
+    let db_path = ctx.db_location().canonicalize().context(IoSnafu)?;
+    let working_dir = db_path.parent().unwrap_or(Path::new("/"));
+
+    Ok(CommandOutput::Crypttab {
+        crypttab: crypttab(&db, working_dir, &params),
+    })
+}
+
+/// Generate the crypttab lines (`<name> UUID=<uuid> <keyfile-or-none> <options>`) for all entries in the database
+pub fn crypttab(db: &PeroxideDb, working_dir: &Path, params: &CrypttabParams) -> String {
+    // sort entries by name, then by uuid
+    let mut entries = db.entries.clone();
+    entries.sort_by_key(|entry| entry.volume_id().clone());
+
+    entries
+        .iter()
+        // a recovery entry is for a disk that already has a line
+        .filter(|entry| !entry.volume_id().is_recovery())
+        .map(|entry| crypttab_line(entry, working_dir, params))
+        .collect()
+}
+
+fn crypttab_line(entry: &DbEntry, working_dir: &Path, params: &CrypttabParams) -> String {
+    let (key_file, mut options) = match entry {
+        DbEntry::KeyfileEntry {
+            key_file,
+            key_offset,
+            key_size,
+            ..
+        } => {
+            let mut options = vec!["luks".to_string()];
+            options.extend(key_offset.map(|offset| format!("keyfile-offset={}", offset)));
+            options.extend(key_size.map(|size| format!("keyfile-size={}", size)));
+            (working_dir.join(key_file).display().to_string(), options)
+        }
+        // systemd-cryptsetup prompts for the passphrase at boot
+        DbEntry::PassphraseEntry { .. } => ("none".to_string(), vec!["luks".to_string()]),
+        // keys derived with hardware tokens (or combined from several inputs) are not understood by systemd-cryptsetup,
+        // `peroxs open` is needed instead
+        DbEntry::YubikeyEntry { .. } | DbEntry::Fido2Entry { .. } | DbEntry::CompositeEntry { .. } => {
+            ("none".to_string(), vec!["luks".to_string(), "noauto".to_string()])
+        }
+    };
+    // note: the sector size of a LUKS2 device is read from its header, so it does not need to be an option here
+    let entry_options = entry.volume_id().activation_options().unwrap_or_default();
+    if params.discard || entry_options.allow_discards {
+        options.push("discard".to_string());
+    }
+    for (set, option) in [
+        (entry_options.read_only, "read-only"),
+        (entry_options.no_read_workqueue, "no-read-workqueue"),
+        (entry_options.no_write_workqueue, "no-write-workqueue"),
+    ] {
+        if set {
+            options.push(option.to_string());
+        }
+    }
+
+    format!(
+        "{} UUID={} {} {}\n",
+        mapping_name(entry, None),
+        entry.uuid(),
+        key_file,
+        options.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use expectest::prelude::*;
+    use peroxide_cryptsetup::db::{ActivationOptions, DbEntry, DbType, PeroxideDb, VolumeId, YubikeyEntryType};
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn test_db() -> PeroxideDb {
+        let mut db = PeroxideDb::new(DbType::Operation);
+        db.entries.push(DbEntry::PassphraseEntry {
+            volume_id: VolumeId::of(Some("passphrase-disk".to_string()), Uuid::from_u128(2)),
+        });
+        db.entries.push(DbEntry::KeyfileEntry {
+            key_file: PathBuf::from("secret.key"),
+            key_offset: None,
+            key_size: None,
+            volume_id: VolumeId::of(Some("keyfile-disk".to_string()), Uuid::from_u128(1)),
+        });
+        db.entries.push(DbEntry::YubikeyEntry {
+            entry_type: YubikeyEntryType::ChallengeResponse,
+            slot: 1,
+            serial: None,
+            volume_id: VolumeId::of(None, Uuid::from_u128(3)),
+        });
+        db
+    }
+
+    #[test]
+    fn test_crypttab() {
+        let params = CrypttabParams { discard: false };
+        let expected =
+            "uuid_00000000-0000-0000-0000-000000000003 UUID=00000000-0000-0000-0000-000000000003 none luks,noauto\n\
+             keyfile-disk UUID=00000000-0000-0000-0000-000000000001 /secure/keys/secret.key luks\n\
+             passphrase-disk UUID=00000000-0000-0000-0000-000000000002 none luks\n";
+        expect!(crypttab(&test_db(), Path::new("/secure/keys"), &params)).to(be_equal_to(expected));
+    }
+
+    #[test]
+    fn test_crypttab_discard() {
+        let params = CrypttabParams { discard: true };
+        let mut db = PeroxideDb::new(DbType::Operation);
+        db.entries.push(DbEntry::KeyfileEntry {
+            key_file: PathBuf::from("/absolute/secret.key"),
+            key_offset: None,
+            key_size: None,
+            volume_id: VolumeId::of(Some("keyfile-disk".to_string()), Uuid::nil()),
+        });
+        expect!(crypttab(&db, Path::new("/secure/keys"), &params)).to(be_equal_to(
+            "keyfile-disk UUID=00000000-0000-0000-0000-000000000000 /absolute/secret.key luks,discard\n",
+        ));
+    }
+
+    #[test]
+    fn test_crypttab_entry_activation_options() {
+        let params = CrypttabParams { discard: false };
+        let mut volume_id = VolumeId::of(Some("ssd".to_string()), Uuid::nil());
+        volume_id.set_activation_options(ActivationOptions {
+            allow_discards: true,
+            no_write_workqueue: true,
+            ..ActivationOptions::default()
+        });
+        let mut db = PeroxideDb::new(DbType::Operation);
+        db.entries.push(DbEntry::PassphraseEntry { volume_id });
+        expect!(crypttab(&db, Path::new("/secure/keys"), &params)).to(be_equal_to(
+            "ssd UUID=00000000-0000-0000-0000-000000000000 none luks,discard,no-write-workqueue\n",
+        ));
+    }
+
+    #[test]
+    fn test_crypttab_keyfile_offset_size() {
+        let params = CrypttabParams { discard: false };
+        let mut db = PeroxideDb::new(DbType::Operation);
+        db.entries.push(DbEntry::KeyfileEntry {
+            key_file: PathBuf::from("/dev/sdz1"),
+            key_offset: Some(4096),
+            key_size: Some(512),
+            volume_id: VolumeId::of(Some("keyfile-disk".to_string()), Uuid::nil()),
+        });
+        expect!(crypttab(&db, Path::new("/secure/keys"), &params)).to(be_equal_to(
+            "keyfile-disk UUID=00000000-0000-0000-0000-000000000000 /dev/sdz1 luks,keyfile-offset=4096,keyfile-size=512\n",
+        ));
+    }
+}
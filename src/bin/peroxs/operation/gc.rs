@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::io;
+
+use snafu::prelude::*;
+use uuid::Uuid;
+
+use peroxide_cryptsetup::context::{Context, PeroxideDbOps};
+use peroxide_cryptsetup::db::{DbEntry, PeroxideDb};
+use peroxide_cryptsetup::device::{BlockDeviceScan, Disks};
+
+use crate::operation::{confirm, CommandOutput, ContextSnafu, DiskRecord, Result};
+
+#[derive(Debug)]
+pub struct Params {
+    /// Flag to remove the entries (instead of only listing them)
+    pub prune: bool,
+    /// Flag to skip the confirmation before removing the entries
+    pub confirmed: bool,
+}
+
+/// List (or remove) the database entries of disks that are not present, e.g. because they were destroyed
+///
+/// The keyslot of an entry cannot be checked without its disk, so only the absence of the disk is considered: an entry
+/// of a disk that is present is never removed.
+pub fn gc<C: Context>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    let scan = BlockDeviceScan::new();
+    let is_present = |uuid: &Uuid| Disks::disk_uuid_path_with_scan(uuid, &scan).is_ok();
+
+    if !params.prune {
+        let db = ctx.open_db().context(ContextSnafu)?;
+        let disks = stale_entries(&db, is_present).iter().map(DiskRecord::of).collect();
+        return Ok(CommandOutput::Gc { disks, pruned: false });
+    }
+
+    let mut db = ctx.open_db_for_update().context(ContextSnafu)?;
+    let stale = stale_entries(&db, is_present);
+    if !stale.is_empty() {
+        if !params.confirmed {
+            let stdin = io::stdin();
+            confirm(
+                &format!(
+                    "Removing {} entries of disks that are not present cannot be undone (their keys are lost if the disks come back). Type 'yes' to continue:",
+                    stale.len()
+                ),
+                &format!("remove {} entries", stale.len()),
+                &["yes"],
+                ttypass::stdin_is_tty(),
+                &mut stdin.lock(),
+            )?;
+        }
+        db.entries.retain(|entry| !stale.contains(entry));
+        ctx.save_db(&db).context(ContextSnafu)?;
+    }
+
+    Ok(CommandOutput::Gc {
+        disks: stale.iter().map(DiskRecord::of).collect(),
+        pruned: true,
+    })
+}
+
+/// The entries whose disk is not present (each disk is only looked up once, even if it has several entries)
+fn stale_entries<F: FnMut(&Uuid) -> bool>(db: &PeroxideDb, mut is_present: F) -> Vec<DbEntry> {
+    let mut present = HashMap::new();
+    db.entries
+        .iter()
+        .filter(|entry| {
+            !*present
+                .entry(entry.uuid().to_owned())
+                .or_insert_with(|| is_present(entry.uuid()))
+        })
+        .cloned()
+        .collect()
+}
+
+pub fn print_summary(records: &[DiskRecord], pruned: bool) {
+    if records.is_empty() {
+        println!("All the disks in the database are present");
+        return;
+    }
+
+    if pruned {
+        println!("Removed the entries of disks that are not present:");
+    } else {
+        println!("Entries of disks that are not present (remove them with --prune):");
+    }
+    for record in records {
+        match record.name {
+            Some(ref name) => println!("  {} ({})", name, record.uuid),
+            None => println!("  {}", record.uuid),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use expectest::prelude::*;
+    use peroxide_cryptsetup::db::{DbType, VolumeId};
+
+    use super::*;
+
+    fn db() -> PeroxideDb {
+        let mut db = PeroxideDb::new(DbType::Operation);
+        db.entries.push(DbEntry::KeyfileEntry {
+            key_file: PathBuf::from("secret.key"),
+            key_offset: None,
+            key_size: None,
+            volume_id: VolumeId::of(Some("present".to_string()), Uuid::from_u128(1)),
+        });
+        db.entries.push(DbEntry::PassphraseEntry {
+            volume_id: VolumeId::of(Some("destroyed".to_string()), Uuid::from_u128(2)),
+        });
+        db.entries.push(DbEntry::PassphraseEntry {
+            volume_id: VolumeId::of(None, Uuid::from_u128(2)),
+        });
+        db
+    }
+
+    #[test]
+    fn test_stale_entries_keeps_present_disks() {
+        let db = db();
+        let mut lookups = vec![];
+        let stale = stale_entries(&db, |uuid| {
+            lookups.push(uuid.to_owned());
+            uuid == &Uuid::from_u128(1)
+        });
+
+        expect!(stale).to(be_equal_to(vec![db.entries[1].clone(), db.entries[2].clone()]));
+        // the disk with two entries is only looked up once
+        expect!(lookups).to(be_equal_to(vec![Uuid::from_u128(1), Uuid::from_u128(2)]));
+    }
+
+    #[test]
+    fn test_stale_entries_when_all_present() {
+        expect!(stale_entries(&db(), |_| true).iter()).to(be_empty());
+    }
+}
@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::input::generate_key_file;
+
+use crate::operation::{CommandOutput, InputSnafu, Result, ValidationSnafu};
+
+#[derive(Debug)]
+pub struct Params {
+    /// Path of the new keyfile
+    pub path: PathBuf,
+    /// Number of random bytes in the keyfile
+    pub bytes: usize,
+}
+
+/// Generate a keyfile of random bytes for enrolling disks with (no disk or database is needed)
+pub fn genkey(params: Params) -> Result<CommandOutput> {
+    ensure!(
+        params.bytes > 0,
+        ValidationSnafu {
+            message: "The number of bytes of the keyfile must be greater than 0".to_string(),
+        }
+    );
+    // checked up front for a clearer error, the file is still only created if it does not exist
+    ensure!(
+        !params.path.exists(),
+        ValidationSnafu {
+            message: format!("{} already exists, refusing to overwrite it", params.path.display()),
+        }
+    );
+
+    generate_key_file(&params.path, params.bytes).context(InputSnafu)?;
+    Ok(CommandOutput::Genkey {
+        path: params.path,
+        bytes: params.bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use expectest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_genkey_refuses_empty_or_existing_keyfiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret.key");
+        expect!(genkey(Params {
+            path: path.clone(),
+            bytes: 0,
+        }))
+        .to(be_err());
+        expect!(path.exists()).to(be_false());
+
+        fs::write(&path, b"existing").unwrap();
+        expect!(genkey(Params {
+            path: path.clone(),
+            bytes: 4096,
+        }))
+        .to(be_err());
+        expect!(fs::read(&path).unwrap()).to(be_equal_to(b"existing".to_vec()));
+    }
+}
@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{Context, PeroxideDbOps};
+use peroxide_cryptsetup::device::{ensure_header_backup_uuid, LuksVolumeOps};
+
+use crate::operation::{disk_path_for, find_entry_for, CommandOutput, ContextSnafu, DeviceSnafu, Result};
+use crate::DiskReference;
+
+#[derive(Debug)]
+pub struct BackupParams {
+    /// Device path, UUID or name of the device whose header we want to back up
+    pub disk_reference: DiskReference,
+    /// File to write the header to (it must not exist yet)
+    pub out: PathBuf,
+}
+
+#[derive(Debug)]
+pub struct RestoreParams {
+    /// Device path, UUID or name of the device whose header we want to overwrite
+    pub disk_reference: DiskReference,
+    /// Header backup file of the device
+    pub from: PathBuf,
+}
+
+/// Write a copy of the LUKS header of an enrolled device to a file (the database is not changed)
+pub fn backup<C: Context>(ctx: &C, params: BackupParams) -> Result<CommandOutput> {
+    let db = ctx.open_db().context(ContextSnafu)?;
+    let entry = find_entry_for(&db, &params.disk_reference)?;
+    let path = disk_path_for(&db, &params.disk_reference)?;
+    path.luks_header_backup(&params.out).context(DeviceSnafu)?;
+
+    Ok(CommandOutput::HeaderBackup {
+        uuid: entry.uuid().to_owned(),
+        path: params.out,
+    })
+}
+
+/// Overwrite the LUKS header of an enrolled device with a backup of it, which must have the UUID of the database entry
+pub fn restore<C: Context>(ctx: &C, params: RestoreParams) -> Result<CommandOutput> {
+    let db = ctx.open_db().context(ContextSnafu)?;
+    let entry = find_entry_for(&db, &params.disk_reference)?;
+    ensure_header_backup_uuid(&params.from, entry.uuid()).context(DeviceSnafu)?;
+    let path = disk_path_for(&db, &params.disk_reference)?;
+    path.luks_header_restore(&params.from).context(DeviceSnafu)?;
+
+    Ok(CommandOutput::HeaderRestore {
+        uuid: entry.uuid().to_owned(),
+        path,
+    })
+}
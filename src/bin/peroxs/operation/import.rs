@@ -0,0 +1,216 @@
+use std::path::{Path, PathBuf};
+
+use serde_derive::Serialize;
+use snafu::prelude::*;
+use uuid::Uuid;
+
+use peroxide_cryptsetup::context::{Context, PeroxideDbOps};
+use peroxide_cryptsetup::db::{DbEntry, PeroxideDb};
+
+use crate::operation::{CommandOutput, ContextSnafu, DatabaseSnafu, IoSnafu, Result, ValidationSnafu};
+
+#[derive(Debug)]
+pub struct Params {
+    /// Path of the database to import the entries of
+    pub other_db: PathBuf,
+    /// Flag to merge databases of different types
+    pub force: bool,
+}
+
+/// Outcome of merging entries into a database
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct MergeSummary {
+    /// Entries that were added
+    pub added: Vec<Uuid>,
+    /// Entries that were skipped because an identical entry already exists
+    pub identical: Vec<Uuid>,
+    /// Entries that were skipped because a different entry already exists for the same uuid
+    pub conflicting: Vec<Uuid>,
+}
+
+pub fn import<C: Context>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    let mut db = ctx.open_db_for_update().context(ContextSnafu)?;
+    let other = PeroxideDb::open_at(&params.other_db).context(DatabaseSnafu)?;
+
+    if db.db_type != other.db_type && !params.force {
+        return Err(ValidationSnafu {
+            message: format!(
+                "Cannot import a {:?} database into a {:?} database (use --force to import anyway)",
+                other.db_type, db.db_type
+            ),
+        }
+        .build());
+    }
+
+    // relative keyfile paths are relative to the directory of the database they come from
+    let other_dir = params
+        .other_db
+        .canonicalize()
+        .context(IoSnafu)?
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("/"));
+    let entries = other
+        .entries
+        .into_iter()
+        .map(|entry| with_absolute_key_file(entry, &other_dir))
+        .collect();
+
+    let summary = merge(&mut db, entries);
+
+    if !summary.added.is_empty() {
+        ctx.save_db(&db).context(ContextSnafu)?;
+    }
+
+    Ok(CommandOutput::Import(summary))
+}
+
+pub fn print_summary(summary: &MergeSummary) {
+    for uuid in summary.conflicting.iter() {
+        println!("Conflict: a different entry already exists for uuid {}, skipped", uuid);
+    }
+    println!(
+        "Added {} entries, skipped {} identical and {} conflicting entries",
+        summary.added.len(),
+        summary.identical.len(),
+        summary.conflicting.len()
+    );
+}
+
+/// Merge entries into the database, skipping entries whose uuid already exists in the database
+///
+/// A recovery entry shares the uuid of the entry of its disk, so it is only compared with the recovery entry of the
+/// disk (and the other entries only with the non-recovery one).
+pub fn merge(db: &mut PeroxideDb, entries: Vec<DbEntry>) -> MergeSummary {
+    let mut summary = MergeSummary::default();
+    for entry in entries {
+        let uuid = entry.uuid().to_owned();
+        let recovery = entry.volume_id().is_recovery();
+        match db
+            .entries
+            .iter()
+            .find(|e| e.uuid() == &uuid && e.volume_id().is_recovery() == recovery)
+        {
+            None => {
+                db.entries.push(entry);
+                summary.added.push(uuid);
+            }
+            Some(existing) if existing == &entry => summary.identical.push(uuid),
+            Some(_) => summary.conflicting.push(uuid),
+        }
+    }
+    summary
+}
+
+pub(crate) fn with_absolute_key_file(entry: DbEntry, db_dir: &Path) -> DbEntry {
+    match entry {
+        DbEntry::KeyfileEntry {
+            key_file,
+            key_offset,
+            key_size,
+            volume_id,
+        } if key_file.is_relative() => DbEntry::KeyfileEntry {
+            key_file: db_dir.join(key_file),
+            key_offset,
+            key_size,
+            volume_id,
+        },
+        DbEntry::CompositeEntry { parts, volume_id } => DbEntry::CompositeEntry {
+            parts: parts
+                .into_iter()
+                .map(|part| with_absolute_key_file(part, db_dir))
+                .collect(),
+            volume_id,
+        },
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use expectest::prelude::*;
+    use peroxide_cryptsetup::db::{DbType, VolumeId};
+
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let existing = DbEntry::PassphraseEntry {
+            volume_id: VolumeId::of(Some("existing".to_string()), Uuid::from_u128(1)),
+        };
+        let conflicting = DbEntry::PassphraseEntry {
+            volume_id: VolumeId::of(Some("renamed".to_string()), Uuid::from_u128(2)),
+        };
+        let new = DbEntry::KeyfileEntry {
+            key_file: PathBuf::from("/keys/secret.key"),
+            key_offset: None,
+            key_size: None,
+            volume_id: VolumeId::of(None, Uuid::from_u128(3)),
+        };
+
+        let mut db = PeroxideDb::new(DbType::Operation);
+        db.entries.push(existing.clone());
+        db.entries.push(DbEntry::PassphraseEntry {
+            volume_id: VolumeId::of(Some("original".to_string()), Uuid::from_u128(2)),
+        });
+
+        let summary = merge(&mut db, vec![existing, conflicting, new.clone()]);
+
+        expect!(summary).to(be_equal_to(MergeSummary {
+            added: vec![Uuid::from_u128(3)],
+            identical: vec![Uuid::from_u128(1)],
+            conflicting: vec![Uuid::from_u128(2)],
+        }));
+        expect!(db.entries.len()).to(be_equal_to(3));
+        expect!(db.entries.last()).to(be_some().value(&new));
+    }
+
+    #[test]
+    fn test_merge_recovery_entries() {
+        let uuid = Uuid::from_u128(1);
+        // recovery entries are only created by enrolling, so they are read like they are from another database
+        let recovery = |name: &str| -> DbEntry {
+            serde_json::from_str(&format!(
+                r#"{{"PassphraseEntry":{{"volume_id":{{"name":"{}","id":{{"uuid":"{}"}},"recovery":true}}}}}}"#,
+                name, uuid
+            ))
+            .unwrap()
+        };
+
+        let mut db = PeroxideDb::new(DbType::Operation);
+        db.entries.push(DbEntry::PassphraseEntry {
+            volume_id: VolumeId::of(Some("disk".to_string()), uuid),
+        });
+
+        let summary = merge(&mut db, vec![recovery("disk")]);
+        expect!(summary).to(be_equal_to(MergeSummary {
+            added: vec![uuid],
+            identical: vec![],
+            conflicting: vec![],
+        }));
+
+        let summary = merge(&mut db, vec![recovery("disk"), recovery("renamed")]);
+        expect!(summary).to(be_equal_to(MergeSummary {
+            added: vec![],
+            identical: vec![uuid],
+            conflicting: vec![uuid],
+        }));
+        expect!(db.entries.len()).to(be_equal_to(2));
+    }
+
+    #[test]
+    fn test_with_absolute_key_file() {
+        let entry = DbEntry::KeyfileEntry {
+            key_file: PathBuf::from("secret.key"),
+            key_offset: None,
+            key_size: None,
+            volume_id: VolumeId::of(None, Uuid::nil()),
+        };
+        expect!(with_absolute_key_file(entry, Path::new("/other/db/dir"))).to(be_equal_to(DbEntry::KeyfileEntry {
+            key_file: PathBuf::from("/other/db/dir/secret.key"),
+            key_offset: None,
+            key_size: None,
+            volume_id: VolumeId::of(None, Uuid::nil()),
+        }));
+    }
+}
@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{Context, PeroxideDbOps};
+use peroxide_cryptsetup::device::LuksVolumeOps;
+
+use crate::operation::{disk_path_for, CommandOutput, ContextSnafu, DeviceSnafu, Result, ValidationSnafu};
+use crate::DiskReference;
+
+#[derive(Debug)]
+pub struct SetParams {
+    /// Device path, UUID or name of the device whose header we want to change
+    pub disk_reference: DiskReference,
+    /// New label of the device
+    pub label: Option<String>,
+    /// New subsystem of the device
+    pub subsystem: Option<String>,
+}
+
+/// Change the label and/or subsystem in the LUKS2 header of a device (the database is not changed)
+pub fn set<C: Context>(ctx: &C, params: SetParams) -> Result<CommandOutput> {
+    ensure!(
+        params.label.is_some() || params.subsystem.is_some(),
+        ValidationSnafu {
+            message: "At least one of --label or --subsystem must be given".to_string(),
+        }
+    );
+
+    let db = ctx.open_db().context(ContextSnafu)?;
+    let path: PathBuf = disk_path_for(&db, &params.disk_reference)?;
+    path.luks2_set_label(params.label.as_deref(), params.subsystem.as_deref())
+        .context(DeviceSnafu)?;
+
+    Ok(CommandOutput::Label {
+        path,
+        label: params.label,
+        subsystem: params.subsystem,
+    })
+}
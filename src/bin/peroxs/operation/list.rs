@@ -1,86 +1,234 @@
-use peroxide_cryptsetup::context::{Context, PeroxideDbOps};
-use peroxide_cryptsetup::db::{DbEntry, YubikeyEntryType};
-use peroxide_cryptsetup::device::{Disks, DmSetupDeviceInfo};
+use std::path::PathBuf;
+
+use peroxide_cryptsetup::context::{entries_with_status, Context, EntryStatus, PeroxideDbOps};
+use peroxide_cryptsetup::db::{DbEntry, FormatInfo, PeroxideDb, YubikeyEntryType};
+use peroxide_cryptsetup::device::{
+    BlockDeviceScan, Disks, KeyslotUsage, LuksVolumeOps, OpenedDevices, ReencryptStatus,
+};
 use prettytable::{format, Table};
+use serde_derive::Serialize;
 use snafu::prelude::*;
+use uuid::Uuid;
+
+use crate::operation::{CommandOutput, ContextSnafu, Result};
 
-use crate::operation::{ContextSnafu, DeviceSnafu, Result};
+/// Shortest prefix of a UUID shown in the table (its first group)
+const MIN_UUID_PREFIX_LENGTH: usize = 8;
 
 #[derive(Debug)]
 pub struct Params {
     /// Flag to list only available disks
     pub only_available: bool,
+    /// Flag to show the full UUIDs in the table (instead of the shortest prefixes that tell the disks apart)
+    pub full_uuid: bool,
 }
 
-pub fn list<C: Context>(ctx: &C, params: Params) -> Result<()> {
-    let db = ctx.open_db().context(ContextSnafu)?;
+/// A disk in the database and its status on the current system
+#[derive(Debug, Serialize)]
+pub struct ListRecord {
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub typ: &'static str,
+    pub uuid: Uuid,
+    /// Path of the disk (if present)
+    pub device: Option<PathBuf>,
+    /// Name of the active mapping (if active)
+    pub mapping: Option<String>,
+    /// Cipher parameters (if the disk was formatted during enrollment)
+    pub format: Option<FormatInfo>,
+    /// Time the key was enrolled or registered (RFC 3339, if known)
+    pub enrolled_at: Option<String>,
+    pub present: bool,
+    /// Whether the disk has a LUKS header (only if present)
+    pub luks: bool,
+    /// Whether a reencryption of the disk is in progress (only if present and its header could be read)
+    pub reencrypting: bool,
+    /// Keyslots in use (only if present and its header could be read)
+    pub keyslots: Option<KeyslotUsage>,
+    pub active: bool,
+}
 
-    let active_mappings = Disks::scan_sysfs_for_active_crypt_devices().context(DeviceSnafu)?;
+pub fn list<C: Context>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    let db = ctx.open_db().context(ContextSnafu)?;
 
-    // sort entries by name, then by uuid
-    let mut entries = db.entries.clone();
-    entries.sort_by_key(|entry| entry.volume_id().clone());
+    let disks = list_records(&db, params.only_available);
+    // the prefixes are unique among all the entries, so that they stay the same whether or not a disk is present
+    let uuid_length = if params.full_uuid {
+        None
+    } else {
+        let uuids = db
+            .entries
+            .iter()
+            .map(|entry| entry.uuid().to_owned())
+            .collect::<Vec<_>>();
+        Some(unique_prefix_length(&uuids))
+    };
+    Ok(CommandOutput::List { disks, uuid_length })
+}
 
-    let mut table = Table::new();
-    table.add_row(row![b->"Name", b->"Type", b->"Uuid", b->"Device", b->"Mapping"]);
+/// Length of the shortest UUID prefix (but at least `MIN_UUID_PREFIX_LENGTH`) that is different for all the UUIDs
+fn unique_prefix_length(uuids: &[Uuid]) -> usize {
+    let mut uuids = uuids.iter().map(|uuid| uuid.to_string()).collect::<Vec<_>>();
+    uuids.sort();
+    uuids.dedup();
 
-    for entry in entries.iter() {
-        add_table_entry(&params, &mut table, entry, &active_mappings);
-    }
+    // the longest common prefix is always between neighbours once sorted
+    let longest_common = uuids
+        .windows(2)
+        .map(|pair| pair[0].bytes().zip(pair[1].bytes()).take_while(|(a, b)| a == b).count())
+        .max()
+        .unwrap_or(0);
+    (longest_common + 1).max(MIN_UUID_PREFIX_LENGTH)
+}
 
-    table.set_format(*format::consts::FORMAT_CLEAN);
-    table.printstd();
+/// Gather the status of the disks in the database (sorted by name, then by uuid)
+fn list_records(db: &PeroxideDb, only_available: bool) -> Vec<ListRecord> {
+    let mut statuses = entries_with_status(db, &Disks, &BlockDeviceScan::new());
+    statuses.sort_by_key(|status| status.entry.volume_id().clone());
 
-    Ok(())
+    // a disk with several entries (e.g. a recovery passphrase) is only opened once
+    let devices = OpenedDevices::new();
+    statuses
+        .into_iter()
+        .map(|status| list_record(&status, &devices))
+        .filter(|record| !only_available || record.present)
+        .collect()
 }
 
-fn add_table_entry(
-    params: &Params,
-    table: &mut Table,
-    entry: &DbEntry,
-    active_mappings: &Vec<DmSetupDeviceInfo>,
-) -> () {
+fn list_record(status: &EntryStatus, devices: &OpenedDevices) -> ListRecord {
+    let entry = &status.entry;
     let id = entry.volume_id();
-    let name = id.name.clone().unwrap_or("".to_string());
-    let uuid = id.uuid().to_string();
-    let typ = match entry {
-        &DbEntry::KeyfileEntry { .. } => "keyfile",
-        &DbEntry::PassphraseEntry { .. } => "passphrase",
-        &DbEntry::YubikeyEntry { ref entry_type, .. } => match entry_type {
-            &YubikeyEntryType::ChallengeResponse => "yubikey",
-            &YubikeyEntryType::HybridChallengeResponse => "yubikey hybrid",
+    let typ = match *entry {
+        DbEntry::KeyfileEntry { .. } => "keyfile",
+        DbEntry::PassphraseEntry { ref volume_id } if volume_id.is_recovery() => "recovery",
+        DbEntry::PassphraseEntry { .. } => "passphrase",
+        DbEntry::YubikeyEntry { ref entry_type, .. } => match *entry_type {
+            YubikeyEntryType::ChallengeResponse => "yubikey",
+            YubikeyEntryType::HybridChallengeResponse => "yubikey hybrid",
         },
+        DbEntry::Fido2Entry { .. } => "fido2",
+        DbEntry::CompositeEntry { .. } => "composite",
     };
 
-    let path_opt = Disks::disk_uuid_path(id.uuid())
-        .ok()
-        .and_then(|p| p.canonicalize().ok());
-    let path_cell = path_opt
+    let device = status.path.as_ref().and_then(|p| p.canonicalize().ok());
+
+    let luks = device.as_ref().map_or(false, |p| p.is_luks());
+    let reencrypting = luks
+        && device.as_ref().map_or(false, |p| {
+            matches!(
+                p.luks_reencrypt_status(),
+                Ok(ReencryptStatus::InProgress | ReencryptStatus::Interrupted)
+            )
+        });
+    let keyslots = device
         .as_ref()
-        .map(|p| cell!(Fg -> p.to_string_lossy()))
-        .unwrap_or(cell!(Fr -> "not present"));
+        .filter(|_| luks)
+        .and_then(|p| devices.device(p).luks_keyslot_usage().ok());
 
-    let mapping_name = if Disks::is_device_active(name.as_str()) {
-        Some(name.clone())
-    } else if let Some(mapping) = active_mappings.iter().find(|&m| &m.underlying_uuid == id.uuid()) {
-        Some(mapping.name.clone())
-    } else {
-        None
-    };
+    ListRecord {
+        name: id.name.clone(),
+        typ,
+        uuid: id.uuid().to_owned(),
+        present: device.is_some(),
+        luks,
+        reencrypting,
+        keyslots,
+        active: status.active,
+        device,
+        mapping: status.mapping.clone(),
+        format: id.format_info().cloned(),
+        enrolled_at: id.enrolled_at().map(str::to_string),
+    }
+}
 
-    let mapping_cell = if let Some(name) = mapping_name {
-        cell!(Fg -> name )
-    } else {
-        cell!(Fr -> "inactive")
-    };
+/// Print the records as a table, with the UUIDs cut to the given length (if any)
+pub fn print_table(records: &[ListRecord], uuid_length: Option<usize>) {
+    let mut table = Table::new();
+    table.add_row(
+        row![b->"Name", b->"Type", b->"Uuid", b->"Cipher", b->"Enrolled", b->"Device", b->"Keyslots", b->"Mapping"],
+    );
 
-    if params.only_available && path_opt.is_none() {
-        ()
-    } else {
-        // rows are: name,type,uuid,disk,mapping
+    for record in records {
+        let path_cell = match record.device {
+            Some(ref p) if record.reencrypting => cell!(Fy -> format!("{} (reencrypting)", p.to_string_lossy())),
+            Some(ref p) if record.luks => cell!(Fg -> p.to_string_lossy()),
+            Some(ref p) => cell!(Fr -> format!("{} (not LUKS)", p.to_string_lossy())),
+            None => cell!(Fr -> "not present"),
+        };
+
+        let keyslots_cell = match record.keyslots {
+            Some(ref usage) if usage.is_full() => cell!(Fy -> format!("{} (full)", usage)),
+            Some(ref usage) => cell!(usage),
+            None => cell!(""),
+        };
+
+        let mapping_cell = if let Some(ref name) = record.mapping {
+            cell!(Fg -> name)
+        } else {
+            cell!(Fr -> "inactive")
+        };
+
+        let cipher = record
+            .format
+            .as_ref()
+            .map(|f| format!("{}-{} ({} bits, {})", f.cipher, f.cipher_mode, f.mk_bits, f.hash))
+            .unwrap_or("".to_string());
 
-        let row = table.add_row(row!(name, typ, uuid));
+        let enrolled_at = record.enrolled_at.clone().unwrap_or("".to_string());
+
+        // rows are: name,type,uuid,cipher,enrolled,disk,keyslots,mapping
+        let name = record.name.clone().unwrap_or("".to_string());
+        let uuid = record.uuid.to_string();
+        let uuid = match uuid_length {
+            Some(length) => &uuid[..length.min(uuid.len())],
+            None => &uuid,
+        };
+        let row = table.add_row(row!(name, record.typ, uuid, cipher, enrolled_at));
         row.add_cell(path_cell);
+        row.add_cell(keyslots_cell);
         row.add_cell(mapping_cell);
     }
+
+    table.set_format(*format::consts::FORMAT_CLEAN);
+    table.printstd();
+}
+
+#[cfg(test)]
+mod tests {
+    use expectest::prelude::*;
+
+    use super::*;
+
+    fn uuid(s: &str) -> Uuid {
+        Uuid::parse_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_unique_prefix_length_single_entry() {
+        expect!(unique_prefix_length(&[uuid("0a1b2c3d-0000-0000-0000-000000000000")]))
+            .to(be_equal_to(MIN_UUID_PREFIX_LENGTH));
+        expect!(unique_prefix_length(&[])).to(be_equal_to(MIN_UUID_PREFIX_LENGTH));
+    }
+
+    #[test]
+    fn test_unique_prefix_length_collision() {
+        let uuids = [
+            uuid("0a1b2c3d-4e5f-0000-0000-000000000000"),
+            uuid("0a1b2c3d-4e6f-0000-0000-000000000000"),
+            uuid("ffffffff-0000-0000-0000-000000000000"),
+        ];
+        // the first 11 characters are shared, so 12 are needed
+        expect!(unique_prefix_length(&uuids)).to(be_equal_to(12));
+    }
+
+    #[test]
+    fn test_unique_prefix_length_ignores_duplicates() {
+        // a disk with several entries (e.g. a recovery passphrase) has the same uuid in all of them
+        let uuids = [
+            uuid("0a1b2c3d-0000-0000-0000-000000000000"),
+            uuid("0a1b2c3d-0000-0000-0000-000000000000"),
+            uuid("1a1b2c3d-0000-0000-0000-000000000000"),
+        ];
+        expect!(unique_prefix_length(&uuids)).to(be_equal_to(MIN_UUID_PREFIX_LENGTH));
+    }
 }
@@ -0,0 +1,43 @@
+use std::io;
+
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{Context, DeviceOps, PeroxideDbOps};
+
+use crate::operation::{confirm, disk_path_for, CommandOutput, ContextSnafu, DiskRecord, Result};
+use crate::DiskReference;
+
+#[derive(Debug)]
+pub struct Params {
+    /// Device path, UUID or name of the LUKS1 device we want to convert
+    pub disk_reference: DiskReference,
+    /// Flag to skip the confirmation before converting the header
+    pub confirmed: bool,
+}
+
+pub fn migrate<C: Context + DeviceOps>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    let mut db = ctx.open_db_for_update().context(ContextSnafu)?;
+    let path = disk_path_for(&db, &params.disk_reference)?;
+
+    if !params.confirmed {
+        let stdin = io::stdin();
+        confirm(
+            &format!(
+                "Converting {} to LUKS2 cannot be undone by peroxs (back up its header first). Type 'yes' to continue:",
+                path.display()
+            ),
+            &format!("convert {} to LUKS2", path.display()),
+            &["yes"],
+            ttypass::stdin_is_tty(),
+            &mut stdin.lock(),
+        )?;
+    }
+
+    let entry = ctx.migrate(&mut db, &path).context(ContextSnafu)?;
+
+    Ok(CommandOutput::Migrate {
+        disk: DiskRecord::of(&entry),
+        keyslot: entry.volume_id().keyslot(),
+        token_id: entry.volume_id().luks2_token_id(),
+    })
+}
@@ -1,39 +1,407 @@
-use std::path::PathBuf;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
 use std::result;
 use std::str::FromStr;
+use std::time::Duration;
 
+use serde_derive::Serialize;
 use snafu::{prelude::*, Backtrace};
-use uuid;
 
-use peroxide_cryptsetup::context::Error as ContextError;
-use peroxide_cryptsetup::device::{Disks, Error as DeviceError};
+use peroxide_cryptsetup::context::{
+    disk_path_for_name, unique_entry_by_name, Context, DatabaseOps, Error as ContextError,
+};
+use peroxide_cryptsetup::db::{ActivationOptions, DbEntry, Error as DbError, PeroxideDb};
+use peroxide_cryptsetup::device::{Disks, Error as DeviceError, Keyslot, LuksVolumeOps};
+use peroxide_cryptsetup::input::Error as InputError;
+use peroxide_cryptsetup::messages;
+
+use crate::operation::audit::AuditFinding;
+use crate::operation::benchmark::BenchmarkRecord;
+use crate::operation::boot_open::BootOpenRecord;
+use crate::operation::enroll::{EnrollRecord, FormatRecord, ManifestRecord};
+use crate::operation::import::MergeSummary;
+use crate::operation::list::ListRecord;
+use crate::operation::open::OpenTestRecord;
+use crate::operation::present::PresentRecord;
+use crate::operation::status::StatusRecord;
+use crate::operation::verify::VerifyRecord;
+#[cfg(feature = "yubikey")]
+use crate::operation::yubikey::YubikeyRecord;
+use crate::DiskReference;
 
 #[derive(Debug, Snafu)]
+#[allow(clippy::enum_variant_names)]
 pub enum OperationError {
+    #[snafu(display("The audit found {count} high-severity issues"))]
+    AuditFailedError { count: usize, backtrace: Backtrace },
+    #[snafu(
+        display("Could not parse the configuration file {}", path.display()),
+        visibility(pub(crate))
+    )]
+    ConfigError {
+        path: PathBuf,
+        source: toml::de::Error,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("{source}"))]
     ContextError {
         #[snafu(backtrace)]
         source: ContextError,
     },
+    #[snafu(display("{source}"))]
+    DatabaseError {
+        #[snafu(backtrace)]
+        source: DbError,
+    },
+    #[snafu(visibility(pub(crate)))]
     DeviceError {
         #[snafu(backtrace)]
         source: DeviceError,
     },
-    #[snafu(display("Validation failed: {message}"))]
+    #[snafu(display("{count} disks of the manifest could not be enrolled"))]
+    EnrollmentFailedError { count: usize, backtrace: Backtrace },
+    InputError {
+        #[snafu(backtrace)]
+        source: InputError,
+    },
+    #[snafu(display("I/O error"), visibility(pub(crate)))]
+    IoError { source: io::Error, backtrace: Backtrace },
+    #[snafu(display("{count} database entries are inconsistent with their disks"))]
+    InconsistentEntriesError { count: usize, backtrace: Backtrace },
+    #[snafu(
+        display("Could not parse the manifest {}", path.display()),
+        visibility(pub(crate))
+    )]
+    ManifestError {
+        path: PathBuf,
+        source: serde_json::Error,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("JSON serialization error"))]
+    SerialisationError {
+        source: serde_json::Error,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("{count} disks could not be opened"))]
+    OpenFailedError { count: usize, backtrace: Backtrace },
+    #[snafu(display("The keys of {count} disks could not be verified"))]
+    KeyTestFailedError { count: usize, backtrace: Backtrace },
+    #[snafu(display("Validation failed: {message}"), visibility(pub(crate)))]
     ValidationError { message: String, backtrace: Backtrace },
 }
 
 pub type Result<T> = result::Result<T, OperationError>;
 
-#[derive(Debug, Clone)]
+impl OperationError {
+    /// Stable name of the kind of error (for machine-readable error output)
+    pub fn kind(&self) -> &'static str {
+        match self {
+            OperationError::AuditFailedError { .. } => "audit_failed",
+            OperationError::ConfigError { .. } => "config",
+            OperationError::ContextError { source } => source.kind(),
+            OperationError::DatabaseError {
+                source: DbError::DatabaseNotWritableError { .. },
+            } => "database_not_writable",
+            OperationError::DatabaseError { .. } => "database",
+            OperationError::DeviceError { .. } => "device",
+            OperationError::EnrollmentFailedError { .. } => "enrollment_failed",
+            OperationError::InputError { .. } => "input",
+            OperationError::IoError { .. } => "io",
+            OperationError::InconsistentEntriesError { .. } => "inconsistent_entries",
+            OperationError::KeyTestFailedError { .. } => "key_test_failed",
+            OperationError::ManifestError { .. } => "manifest",
+            OperationError::OpenFailedError { .. } => "open_failed",
+            OperationError::SerialisationError { .. } => "serialisation",
+            OperationError::ValidationError { .. } => "validation",
+        }
+    }
+}
+
+/// A disk in the database that was changed by an operation
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct DiskRecord {
+    pub name: Option<String>,
+    pub uuid: uuid::Uuid,
+}
+
+impl DiskRecord {
+    pub fn of(entry: &DbEntry) -> DiskRecord {
+        DiskRecord {
+            name: entry.volume_id().name.clone(),
+            uuid: entry.uuid().to_owned(),
+        }
+    }
+}
+
+/// The result of an operation, which is printed once (either in the human form or as JSON) by `main`
+#[derive(Debug, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum CommandOutput {
+    Audit {
+        findings: Vec<AuditFinding>,
+        /// Number of high-severity findings (which make the audit fail)
+        high: usize,
+    },
+    Backup {
+        path: PathBuf,
+        entries: usize,
+    },
+    Benchmark {
+        pbkdf: BenchmarkRecord,
+    },
+    BootOpen {
+        disks: Vec<BootOpenRecord>,
+        failed: usize,
+    },
+    Close {
+        mappings: Vec<String>,
+    },
+    Completions {
+        script: String,
+    },
+    Crypttab {
+        crypttab: String,
+    },
+    Enroll {
+        disks: Vec<EnrollRecord>,
+    },
+    EnrollManifest {
+        disks: Vec<ManifestRecord>,
+        failed: usize,
+    },
+    Format {
+        disks: Vec<FormatRecord>,
+    },
+    Gc {
+        disks: Vec<DiskRecord>,
+        /// Whether the entries were removed (or only listed)
+        pruned: bool,
+    },
+    Genkey {
+        path: PathBuf,
+        bytes: usize,
+    },
+    HeaderBackup {
+        uuid: uuid::Uuid,
+        /// File the header was written to
+        path: PathBuf,
+    },
+    HeaderRestore {
+        uuid: uuid::Uuid,
+        /// Device whose header was overwritten
+        path: PathBuf,
+    },
+    Import(MergeSummary),
+    Init {
+        path: PathBuf,
+    },
+    Label {
+        path: PathBuf,
+        label: Option<String>,
+        subsystem: Option<String>,
+    },
+    List {
+        disks: Vec<ListRecord>,
+        /// Length the UUIDs are cut to in the table (the JSON output always has the full UUIDs)
+        #[serde(skip)]
+        uuid_length: Option<usize>,
+    },
+    Migrate {
+        disk: DiskRecord,
+        keyslot: Option<Keyslot>,
+        token_id: Option<i32>,
+    },
+    Open {
+        mappings: Vec<String>,
+    },
+    OpenTest {
+        disks: Vec<OpenTestRecord>,
+        failed: usize,
+    },
+    Present {
+        disks: Vec<PresentRecord>,
+        missing: usize,
+    },
+    Register {
+        disks: Vec<DiskRecord>,
+    },
+    Remove {
+        disk: DiskRecord,
+    },
+    RemoveKeyslot {
+        uuid: uuid::Uuid,
+        keyslot: Keyslot,
+        /// Keyslot of the key that authorized destroying the keyslot
+        authorized_by: Keyslot,
+        /// Database entries of the key in the destroyed keyslot (which were removed)
+        removed: Vec<DiskRecord>,
+    },
+    Rename {
+        disk: DiskRecord,
+    },
+    Resume {
+        mapping: String,
+    },
+    Rotate {
+        disk: DiskRecord,
+        keyslot: Option<Keyslot>,
+    },
+    SetActivationOptions {
+        disk: DiskRecord,
+        /// The flags the disk is opened with from now on (none if they were all turned off)
+        options: Option<ActivationOptions>,
+    },
+    SetAutoOpen {
+        disk: DiskRecord,
+        auto_open: bool,
+    },
+    Status {
+        mappings: Vec<StatusRecord>,
+    },
+    Suspend {
+        mapping: String,
+    },
+    TestKey {
+        uuid: uuid::Uuid,
+        /// Keyslot unlocked by the key (none if it does not unlock any)
+        keyslot: Option<Keyslot>,
+    },
+    Verify {
+        disks: Vec<VerifyRecord>,
+        inconsistent: usize,
+    },
+    WipeKeyslot {
+        uuid: uuid::Uuid,
+        keyslot: Keyslot,
+        /// Database entries of the key in the destroyed keyslot (which were removed)
+        removed: Vec<DiskRecord>,
+    },
+    #[cfg(feature = "yubikey")]
+    Yubikeys {
+        yubikeys: Vec<YubikeyRecord>,
+    },
+}
+
+impl CommandOutput {
+    /// Print the output as JSON or in the human form (which is empty for the commands that only change things)
+    ///
+    /// A verification that found inconsistent entries (or a manifest enrollment, boot-open or key test where some disks
+    /// failed) fails, but only once the results have been printed.
+    pub fn print(&self, json: bool) -> Result<()> {
+        if json {
+            let json = serde_json::to_string_pretty(self).context(SerialisationSnafu)?;
+            println!("{}", json);
+        } else {
+            match self {
+                CommandOutput::Audit { findings, .. } => audit::print_report(findings),
+                CommandOutput::Backup { path, entries } => {
+                    println!("Saved backup database with {} entries to {}", entries, path.display())
+                }
+                CommandOutput::Benchmark { pbkdf } => benchmark::print_table(pbkdf),
+                CommandOutput::BootOpen { disks, .. } => boot_open::print_summary(disks),
+                CommandOutput::Completions { script } => print!("{}", script),
+                CommandOutput::Crypttab { crypttab } => print!("{}", crypttab),
+                CommandOutput::Enroll { disks } => enroll::print_summary(disks),
+                CommandOutput::EnrollManifest { disks, .. } => enroll::print_manifest_summary(disks),
+                CommandOutput::Format { disks } => enroll::print_format_summary(disks),
+                CommandOutput::Gc { disks, pruned } => gc::print_summary(disks, *pruned),
+                CommandOutput::Genkey { path, bytes } => {
+                    messages::detail(format!("Wrote {} random bytes to {}", bytes, path.display()))
+                }
+                CommandOutput::HeaderBackup { uuid, path } => {
+                    messages::detail(format!("Saved the header of {} to {}", uuid, path.display()))
+                }
+                CommandOutput::HeaderRestore { uuid, path } => {
+                    messages::detail(format!("Restored the header of {} on {}", uuid, path.display()))
+                }
+                CommandOutput::Import(summary) => import::print_summary(summary),
+                CommandOutput::List { disks, uuid_length } => list::print_table(disks, *uuid_length),
+                CommandOutput::OpenTest { disks, .. } => open::print_test_summary(disks),
+                CommandOutput::Present { disks, .. } => disks
+                    .iter()
+                    .filter(|record| !record.present)
+                    .for_each(|record| messages::detail(format!("{} is not present", record.disk))),
+                CommandOutput::Status { mappings } => status::print_table(mappings),
+                CommandOutput::TestKey { uuid, keyslot } => match keyslot {
+                    Some(keyslot) => println!("The key unlocks keyslot {} of {}", keyslot, uuid),
+                    None => println!("The key does not unlock any keyslot of {}", uuid),
+                },
+                CommandOutput::Verify { disks, .. } => verify::print_table(disks),
+                #[cfg(feature = "yubikey")]
+                CommandOutput::Yubikeys { yubikeys } => yubikey::print_table(yubikeys),
+                _ => {}
+            }
+        }
+
+        match self {
+            CommandOutput::Audit { high, .. } if *high > 0 => AuditFailedSnafu { count: *high }.fail(),
+            CommandOutput::Verify { inconsistent, .. } if *inconsistent > 0 => {
+                InconsistentEntriesSnafu { count: *inconsistent }.fail()
+            }
+            CommandOutput::EnrollManifest { failed, .. } if *failed > 0 => {
+                EnrollmentFailedSnafu { count: *failed }.fail()
+            }
+            CommandOutput::BootOpen { failed, .. } if *failed > 0 => OpenFailedSnafu { count: *failed }.fail(),
+            CommandOutput::OpenTest { failed, .. } if *failed > 0 => KeyTestFailedSnafu { count: *failed }.fail(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Exit code of a command that succeeded (non-zero only for `present` when a disk is missing, which is not an error)
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CommandOutput::Present { missing, .. } if *missing > 0 => 2,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum PathOrUuid {
     Path(PathBuf),
     Uuid(uuid::Uuid),
+    /// Label of the disk (`LABEL=<label>`)
+    Label(String),
+    /// UUID of the partition of the disk (`PARTUUID=<partuuid>`)
+    PartUuid(String),
+}
+
+/// Split a crypttab-style selector (e.g. `LABEL=backup`) into its prefix and value
+fn selector(s: &str) -> Option<(&str, &str)> {
+    s.split_once('=')
+        .filter(|(prefix, _)| !prefix.is_empty() && prefix.chars().all(|c| c.is_ascii_uppercase()))
 }
 
 impl FromStr for PathOrUuid {
     type Err = OperationError;
 
     fn from_str(s: &str) -> Result<Self> {
+        if let Some((prefix, value)) = selector(s) {
+            ensure!(
+                !value.is_empty(),
+                ValidationSnafu {
+                    message: format!("Selector '{}' has no value", s),
+                }
+            );
+            return match prefix {
+                "UUID" => uuid::Uuid::from_str(value).map(PathOrUuid::Uuid).map_err(|_| {
+                    ValidationSnafu {
+                        message: format!("'{}' is not a valid uuid", value),
+                    }
+                    .build()
+                }),
+                "LABEL" => Ok(PathOrUuid::Label(value.to_string())),
+                "PARTUUID" => Ok(PathOrUuid::PartUuid(value.to_string())),
+                other => ValidationSnafu {
+                    message: format!(
+                        "Unknown selector `{}=` in '{}' (expected a path, a uuid, UUID=<uuid>, LABEL=<label> or PARTUUID=<partuuid>)",
+                        other, s
+                    ),
+                }
+                .fail(),
+            };
+        }
+
         let uuid_opt = uuid::Uuid::from_str(s).ok();
         let path_opt = PathBuf::from_str(s).ok();
 
@@ -51,17 +419,195 @@ impl FromStr for PathOrUuid {
 }
 
 impl PathOrUuid {
-    /// Convert a UUID of a disk to a physical path
+    /// Convert a UUID, label or partition UUID of a disk to a physical path
     pub fn to_path(&self) -> Result<PathBuf> {
         match self {
-            PathOrUuid::Uuid(uuid) => Disks::disk_uuid_path(&uuid).context(DeviceSnafu),
+            PathOrUuid::Uuid(uuid) => Disks::disk_uuid_path(uuid).context(DeviceSnafu),
             PathOrUuid::Path(path) => Ok(path.clone()),
+            PathOrUuid::Label(label) => Disks::disk_label_path(label).context(DeviceSnafu),
+            PathOrUuid::PartUuid(partuuid) => Disks::disk_partuuid_path(partuuid).context(DeviceSnafu),
         }
     }
 }
 
+/// Directory of the database of the context, which relative keyfile paths in it are relative to
+fn db_dir<C: Context>(ctx: &C) -> Result<PathBuf> {
+    Ok(ctx
+        .db_location()
+        .canonicalize()
+        .context(IoSnafu)?
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("/")))
+}
+
+fn path_or_uuid_for(db: &PeroxideDb, disk_ref: &DiskReference) -> Result<PathOrUuid> {
+    match unique_entry_by_name(db, &disk_ref.0).context(ContextSnafu)? {
+        Some(entry) => Ok(PathOrUuid::Uuid(entry.volume_id().uuid().to_owned())),
+        None => PathOrUuid::from_str(&disk_ref.0),
+    }
+}
+
+/// Find the device path for a disk reference (an entry name, a LUKS UUID or a device path)
+pub fn disk_path_for(db: &PeroxideDb, disk_ref: &DiskReference) -> Result<PathBuf> {
+    // entry names are resolved like `MainContext::open_by_name` does, so that both find the same disk
+    if unique_entry_by_name(db, &disk_ref.0).context(ContextSnafu)?.is_some() {
+        return disk_path_for_name(db, &disk_ref.0).context(ContextSnafu);
+    }
+    PathOrUuid::from_str(&disk_ref.0)?.to_path()
+}
+
+/// Like `disk_path_for`, but wait up to `timeout` for a disk referenced by its UUID (or entry name) to appear
+pub fn wait_for_disk_path(db: &PeroxideDb, disk_ref: &DiskReference, timeout: Duration) -> Result<PathBuf> {
+    match path_or_uuid_for(db, disk_ref)? {
+        PathOrUuid::Uuid(uuid) => Disks::wait_for_disk_uuid(&uuid, timeout).context(DeviceSnafu),
+        other => other.to_path(),
+    }
+}
+
+/// Find the database entry for a disk reference (an entry name, a LUKS UUID or a device path)
+pub fn find_entry_for<'a>(db: &'a PeroxideDb, disk_ref: &DiskReference) -> Result<&'a DbEntry> {
+    if let Some(entry) = unique_entry_by_name(db, &disk_ref.0).context(ContextSnafu)? {
+        return Ok(entry);
+    }
+
+    let uuid = match PathOrUuid::from_str(&disk_ref.0)? {
+        PathOrUuid::Uuid(uuid) => uuid,
+        other => other.to_path()?.luks_uuid().context(DeviceSnafu)?,
+    };
+
+    db.find_entry(&uuid).context(ValidationSnafu {
+        message: format!("No database entry found for '{}'", disk_ref.0),
+    })
+}
+
+/// Ask on the terminal before an action that cannot be undone (described by `refusal`, e.g. "destroy keyslot 1 of
+/// /dev/sda"), which goes ahead only if the answer is one of `accepted` (ignoring case, e.g. 'yes' or the UUID of the
+/// disk). The action is refused if there is no terminal to ask on.
+pub fn confirm<R: BufRead>(prompt: &str, refusal: &str, accepted: &[&str], is_tty: bool, input: &mut R) -> Result<()> {
+    ensure!(
+        is_tty,
+        ValidationSnafu {
+            message: format!(
+                "Refusing to {} without confirmation (use --yes when standard input is not a terminal)",
+                refusal
+            ),
+        }
+    );
+
+    print!("{} ", prompt);
+    io::stdout().flush().context(IoSnafu)?;
+    let mut answer = String::new();
+    input.read_line(&mut answer).context(IoSnafu)?;
+
+    let answer = answer.trim();
+    ensure!(
+        accepted.iter().any(|accepted| answer.eq_ignore_ascii_case(accepted)),
+        ValidationSnafu {
+            message: format!("Refusing to {}, as it was not confirmed", refusal),
+        }
+    );
+    Ok(())
+}
+
+pub mod audit;
+pub mod benchmark;
+pub mod boot_open;
+pub mod close;
+pub mod db;
 pub mod enroll;
+pub mod export;
+pub mod gc;
+pub mod genkey;
+pub mod header;
+pub mod import;
+pub mod label;
 pub mod list;
+pub mod migrate;
 pub mod newdb;
 pub mod open;
+pub mod present;
 pub mod register;
+pub mod remove;
+pub mod rename;
+pub mod resume;
+pub mod rotate;
+pub mod set_activation_options;
+pub mod set_auto_open;
+pub mod status;
+pub mod suspend;
+pub mod test_key;
+pub mod verify;
+pub mod wipe_keyslot;
+#[cfg(feature = "yubikey")]
+pub mod yubikey;
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use expectest::prelude::*;
+
+    use super::*;
+
+    fn confirm_with(is_tty: bool, answer: &str) -> Result<()> {
+        let uuid = "00000000-0000-0000-0000-000000001234";
+        confirm(
+            "Type the UUID or 'yes':",
+            "destroy the disk",
+            &["yes", uuid],
+            is_tty,
+            &mut Cursor::new(answer),
+        )
+    }
+
+    #[test]
+    fn test_confirm_accepts_only_the_accepted_answers() {
+        expect!(confirm_with(true, "yes\n")).to(be_ok());
+        expect!(confirm_with(true, "00000000-0000-0000-0000-000000001234\n")).to(be_ok());
+        expect!(confirm_with(true, "00000000-0000-0000-0000-00000000ABCD\n")).to(be_err());
+        expect!(confirm_with(true, "y\n")).to(be_err());
+        expect!(confirm_with(true, "")).to(be_err());
+    }
+
+    #[test]
+    fn test_confirm_refuses_without_tty() {
+        expect!(confirm_with(false, "yes\n")).to(be_err());
+    }
+
+    #[test]
+    fn test_command_output_json_must_be_tagged_with_the_command() {
+        let output = CommandOutput::Import(MergeSummary {
+            added: vec![uuid::Uuid::from_u128(1)],
+            ..MergeSummary::default()
+        });
+        let expected = serde_json::json!({
+            "command": "import",
+            "added": ["00000000-0000-0000-0000-000000000001"],
+            "identical": [],
+            "conflicting": [],
+        });
+        expect!(serde_json::to_value(&output).ok()).to(be_some().value(expected));
+    }
+
+    #[test]
+    fn test_path_or_uuid_selectors() {
+        let uuid = uuid::Uuid::from_u128(1);
+        expect!(PathOrUuid::from_str("/dev/sdz1").ok())
+            .to(be_some().value(PathOrUuid::Path(PathBuf::from("/dev/sdz1"))));
+        expect!(PathOrUuid::from_str(&uuid.to_string()).ok()).to(be_some().value(PathOrUuid::Uuid(uuid)));
+        expect!(PathOrUuid::from_str(&format!("UUID={}", uuid)).ok()).to(be_some().value(PathOrUuid::Uuid(uuid)));
+        expect!(PathOrUuid::from_str("LABEL=backup").ok()).to(be_some().value(PathOrUuid::Label("backup".to_string())));
+        expect!(PathOrUuid::from_str("PARTUUID=0fc63daf-01").ok())
+            .to(be_some().value(PathOrUuid::PartUuid("0fc63daf-01".to_string())));
+        // paths can contain `=` as long as they do not look like a selector
+        expect!(PathOrUuid::from_str("/mnt/a=b").ok()).to(be_some().value(PathOrUuid::Path(PathBuf::from("/mnt/a=b"))));
+    }
+
+    #[test]
+    fn test_path_or_uuid_unknown_selector_must_be_rejected() {
+        expect!(PathOrUuid::from_str("PARTLABEL=backup")).to(be_err());
+        expect!(PathOrUuid::from_str("LABEL=")).to(be_err());
+        expect!(PathOrUuid::from_str("UUID=not-a-uuid")).to(be_err());
+    }
+}
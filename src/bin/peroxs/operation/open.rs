@@ -1,11 +1,19 @@
-use std::str::FromStr;
+use std::path::PathBuf;
+use std::time::Duration;
 
+use serde_derive::Serialize;
 use snafu::prelude::*;
+use uuid::Uuid;
 use vec1::Vec1;
 
-use peroxide_cryptsetup::context::{Context, DatabaseOps, DeviceOps, PeroxideDbOps};
+use peroxide_cryptsetup::context::{Context, DeviceOps, PeroxideDbOps};
+use peroxide_cryptsetup::device::{ActivationFlags, Keyslot};
+use peroxide_cryptsetup::input::key_file;
 
-use crate::operation::{ContextSnafu, DeviceSnafu, Disks, PathOrUuid, Result, ValidationSnafu};
+use crate::name_template::{name_override, NameTemplate};
+use crate::operation::{
+    disk_path_for, find_entry_for, wait_for_disk_path, CommandOutput, ContextSnafu, InputSnafu, Result, ValidationSnafu,
+};
 use crate::DiskReference;
 
 #[derive(Debug)]
@@ -14,35 +22,135 @@ pub struct Params {
     pub disk_references: Vec<DiskReference>,
     /// Name override (if a single device is present)
     pub name: Option<String>,
+    /// Template for the names of the devices (instead of `name`)
+    pub name_template: Option<NameTemplate>,
+    /// Flags to activate the devices with
+    pub flags: ActivationFlags,
+    /// How long to wait for disks that are not present (yet), if at all
+    pub wait: Option<Duration>,
+    /// File with the key to open the devices with, whatever the input of their entries
+    pub key_file: Option<PathBuf>,
+    /// Flag to only check that the key of each device unlocks it (without activating any of them)
+    pub test_only: bool,
 }
 
-pub fn open<C: Context + DeviceOps>(ctx: &C, params: Params) -> Result<()> {
+/// The result of checking the key of a device with `--test-only`
+#[derive(Debug, Serialize)]
+pub struct OpenTestRecord {
+    pub disk: String,
+    pub uuid: Option<Uuid>,
+    /// Keyslot unlocked by the key (if it unlocks one)
+    pub keyslot: Option<Keyslot>,
+    /// Why the key could not be checked or did not unlock the device
+    pub error: Option<String>,
+}
+
+pub fn open<C: Context + DeviceOps>(ctx: &C, params: Params) -> Result<CommandOutput> {
     let db = ctx.open_db().context(ContextSnafu)?;
+    // the key file is checked before waiting for any disk
+    let key = params
+        .key_file
+        .as_deref()
+        .map(key_file)
+        .transpose()
+        .context(InputSnafu)?;
 
     // TODO: check for existing mapping
 
     let paths = params
         .disk_references
-        .into_iter()
-        .map(|disk_ref| {
-            db.find_entry_by_name(&disk_ref.0)
-                .map(|e| Ok(PathOrUuid::Uuid(e.volume_id().uuid().to_owned())))
-                .unwrap_or_else(|| PathOrUuid::from_str(&disk_ref.0))
-                .and_then(|path_or| match path_or {
-                    PathOrUuid::Uuid(uuid) => Ok(Disks::disk_uuid_path(&uuid).context(DeviceSnafu)?),
-                    PathOrUuid::Path(path) => Ok(path),
-                })
+        .iter()
+        .map(|disk_ref| match params.wait {
+            Some(timeout) => wait_for_disk_path(&db, disk_ref, timeout),
+            None => disk_path_for(&db, disk_ref),
         })
         .collect::<Result<Vec<_>>>()?;
 
-    if paths.len() == 0 {
-        return Err(ValidationSnafu {
-            message: format!("Cannot open 0 devices"),
+    if paths.is_empty() {
+        Err(ValidationSnafu {
+            message: "Cannot open 0 devices".to_string(),
         }
-        .build());
+        .build())
+    } else if params.test_only {
+        let disks = params
+            .disk_references
+            .iter()
+            .zip(ctx.test_open_disks(&db, paths.as_slice(), key.as_ref()))
+            .map(|(disk_ref, res)| match res {
+                Ok((uuid, keyslot)) => OpenTestRecord {
+                    disk: disk_ref.0.clone(),
+                    uuid: Some(uuid),
+                    keyslot: Some(keyslot),
+                    error: None,
+                },
+                Err(e) => OpenTestRecord {
+                    disk: disk_ref.0.clone(),
+                    uuid: None,
+                    keyslot: None,
+                    error: Some(e.to_string()),
+                },
+            })
+            .collect::<Vec<_>>();
+        let failed = disks.iter().filter(|disk| disk.keyslot.is_none()).count();
+        Ok(CommandOutput::OpenTest { disks, failed })
     } else {
+        // expand the name template up front, so that invalid names fail before any device is activated
+        let entries = match params.name_template {
+            Some(_) => params
+                .disk_references
+                .iter()
+                .map(|disk_ref| find_entry_for(&db, disk_ref))
+                .collect::<Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+        let name_override = name_override(params.name, params.name_template.as_ref(), &entries)?;
         let path_vec1 = Vec1::try_from_vec(paths).expect("non-empty vec");
-        let _ = ctx.open_disks(&db, path_vec1, params.name).context(ContextSnafu)?;
-        Ok(())
+        let mappings = match key {
+            Some(ref key) => ctx.open_disks_with_key(&db, path_vec1, name_override, params.flags, key),
+            None => ctx.open_disks(&db, path_vec1, name_override, params.flags),
+        }
+        .context(ContextSnafu)?;
+        Ok(CommandOutput::Open {
+            mappings: mappings.into_vec(),
+        })
+    }
+}
+
+/// Print a line for every device whose key was checked
+pub fn print_test_summary(records: &[OpenTestRecord]) {
+    for record in records {
+        println!("{}", test_summary_line(record));
+    }
+}
+
+fn test_summary_line(record: &OpenTestRecord) -> String {
+    match record.keyslot {
+        Some(keyslot) => format!("The key of {} unlocks keyslot {}", record.disk, keyslot),
+        None => format!(
+            "The key of {} could not be verified: {}",
+            record.disk,
+            record.error.as_deref().unwrap_or_default()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use expectest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_test_summary_line() {
+        let mut record = OpenTestRecord {
+            disk: "data1".to_string(),
+            uuid: Some(Uuid::nil()),
+            keyslot: Some(2),
+            error: None,
+        };
+        expect!(test_summary_line(&record)).to(be_equal_to("The key of data1 unlocks keyslot 2"));
+        record.keyslot = None;
+        record.error = Some("Key input error".to_string());
+        expect!(test_summary_line(&record)).to(be_equal_to("The key of data1 could not be verified: Key input error"));
     }
 }
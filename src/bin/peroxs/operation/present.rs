@@ -0,0 +1,92 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_derive::Serialize;
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{Context, PeroxideDbOps};
+use peroxide_cryptsetup::device::{Disks, DISK_WAIT_INTERVAL};
+
+use crate::operation::{path_or_uuid_for, CommandOutput, ContextSnafu, PathOrUuid, Result};
+use crate::DiskReference;
+
+#[derive(Debug)]
+pub struct Params {
+    /// Device paths, UUIDs or names of the devices that should be attached
+    pub disk_references: Vec<DiskReference>,
+    /// How long to wait for the devices that are not attached (yet), if at all
+    pub wait: Option<Duration>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PresentRecord {
+    pub disk: String,
+    pub present: bool,
+}
+
+/// Check whether the disks are attached (waiting for them to appear if asked to), without opening them
+pub fn present<C: Context>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    let db = ctx.open_db().context(ContextSnafu)?;
+    // the references are resolved once, the disks are looked up again on every attempt
+    let disks = params
+        .disk_references
+        .iter()
+        .map(|disk_ref| path_or_uuid_for(&db, disk_ref).map(|disk| (disk_ref, disk)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let start = Instant::now();
+    let mut missing = missing_disks(&disks);
+    while let Some(timeout) = params.wait {
+        if missing.is_empty() || start.elapsed() >= timeout {
+            break;
+        }
+        thread::sleep(DISK_WAIT_INTERVAL);
+        missing = missing_disks(&disks);
+    }
+
+    Ok(CommandOutput::Present {
+        disks: disks
+            .iter()
+            .map(|(disk_ref, _)| PresentRecord {
+                disk: disk_ref.0.clone(),
+                present: !missing.contains(&disk_ref.0),
+            })
+            .collect(),
+        missing: missing.len(),
+    })
+}
+
+/// The references of the disks that are not attached
+fn missing_disks(disks: &[(&DiskReference, PathOrUuid)]) -> Vec<String> {
+    disks
+        .iter()
+        .filter(|(_, disk)| !is_present(disk))
+        .map(|(disk_ref, _)| disk_ref.0.clone())
+        .collect()
+}
+
+fn is_present(disk: &PathOrUuid) -> bool {
+    match disk {
+        PathOrUuid::Uuid(uuid) => Disks::disk_uuid_path(uuid).is_ok(),
+        other => other.to_path().map(|path| path.exists()).unwrap_or(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use expectest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_is_present_for_paths() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        expect!(is_present(&PathOrUuid::Path(file.path().to_path_buf()))).to(be_true());
+        expect!(is_present(&PathOrUuid::Path(PathBuf::from(
+            "/dev/peroxs-test-no-such-disk"
+        ))))
+        .to(be_false());
+    }
+}
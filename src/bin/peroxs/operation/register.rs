@@ -1,12 +1,16 @@
-use std::path::PathBuf;
+use std::fs::File;
+use std::path::{Path, PathBuf};
 
 use snafu::prelude::*;
 
-use peroxide_cryptsetup::context::{Context, PeroxideDbOps};
+use peroxide_cryptsetup::context::{warn_db_not_saved, Context, PeroxideDbOps};
 use peroxide_cryptsetup::db::{DbEntry, DbEntryType, VolumeId};
 use peroxide_cryptsetup::device::LuksVolumeOps;
+use peroxide_cryptsetup::input::resolve_key_file;
 
-use crate::operation::{ContextSnafu, DeviceSnafu, PathOrUuid, Result, ValidationSnafu};
+use crate::operation::{
+    db_dir, CommandOutput, ContextSnafu, DeviceSnafu, DiskRecord, PathOrUuid, Result, ValidationSnafu,
+};
 
 #[derive(Debug)]
 pub struct Params {
@@ -14,37 +18,87 @@ pub struct Params {
     pub device_paths_or_uuids: Vec<PathOrUuid>,
     /// Entry type to register (keyfile, passphrase, etc.)
     pub entry_type: DbEntryType,
-    /// Key file path (optional)
+    /// Key file path (optional, a relative path is relative to the directory of the database)
     pub keyfile: Option<PathBuf>,
+    /// Offset of the key in the key file (optional)
+    pub key_offset: Option<u64>,
+    /// Size of the key in the key file (optional)
+    pub key_size: Option<usize>,
     /// Name to register with
     pub name: Option<String>,
+    /// Flag to save the database with the new entries (not set for one-off operations)
+    pub save_db: bool,
 }
 
-pub fn register<C: Context>(ctx: &C, params: Params) -> Result<()> {
-    let mut db = ctx.open_db().context(ContextSnafu)?;
+pub fn register<C: Context>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    let mut db = ctx.open_db_for_update().context(ContextSnafu)?;
 
+    let key_file = match params.keyfile {
+        Some(ref key_file) => Some(stored_key_file(key_file, &db_dir(ctx)?)?),
+        None => None,
+    };
     let entries = params
         .device_paths_or_uuids
         .iter()
-        .map(|p| p.to_path().and_then(|p| to_entry(p, &params)))
+        .map(|p| p.to_path().and_then(|p| to_entry(p, key_file.clone(), &params)))
         .collect::<Result<Vec<_>>>()?;
 
+    let disks = entries.iter().map(DiskRecord::of).collect();
     for entry in entries.into_iter() {
         db.entries.push(entry);
     }
 
-    ctx.save_db(&db).context(ContextSnafu)?;
-    Ok(())
+    if params.save_db {
+        ctx.save_db(&db).context(ContextSnafu)?;
+    } else {
+        warn_db_not_saved();
+    }
+    Ok(CommandOutput::Register { disks })
+}
+
+/// Check that the keyfile is found the way opening the disk will look for it, and return the path to store in the entry
+///
+/// The path is relative to the directory of the database if the keyfile is in it (so that both can be moved together),
+/// and absolute otherwise.
+fn stored_key_file(key_file: &Path, db_dir: &Path) -> Result<PathBuf> {
+    let not_readable = |reason: String| {
+        ValidationSnafu {
+            message: format!(
+                "The keyfile {} cannot be read as {} ({}): a relative keyfile path is relative to the directory of the database ({}), not to the current directory, when the disk is opened",
+                key_file.display(),
+                db_dir.join(key_file).display(),
+                reason,
+                db_dir.display()
+            ),
+        }
+        .build()
+    };
+    let resolved = resolve_key_file(key_file, db_dir).map_err(|e| not_readable(e.to_string()))?;
+    File::open(&resolved).map_err(|e| not_readable(e.to_string()))?;
+
+    Ok(resolved.strip_prefix(db_dir).map(Path::to_path_buf).unwrap_or(resolved))
 }
 
-fn to_entry(disk_path: PathBuf, params: &Params) -> Result<DbEntry> {
+fn to_entry(disk_path: PathBuf, key_file: Option<PathBuf>, params: &Params) -> Result<DbEntry> {
+    ensure!(
+        disk_path.is_luks(),
+        ValidationSnafu {
+            message: format!(
+                "{} is not a LUKS container (or its header could not be read)",
+                disk_path.display()
+            ),
+        }
+    );
     let uuid = disk_path.luks_uuid().context(DeviceSnafu)?;
-    let volume_id = VolumeId::of(params.name.clone(), uuid);
+    let mut volume_id = VolumeId::of(params.name.clone(), uuid);
+    volume_id.set_enrolled_now();
 
     match params.entry_type {
         DbEntryType::Keyfile => Ok(DbEntry::KeyfileEntry {
             volume_id,
-            key_file: params.keyfile.clone().expect("Expected keyfile to be passed in"),
+            key_file: key_file.expect("Expected keyfile to be passed in"),
+            key_offset: params.key_offset,
+            key_size: params.key_size,
         }),
         DbEntryType::Passphrase => Ok(DbEntry::PassphraseEntry { volume_id }),
         other => Err(ValidationSnafu {
@@ -53,3 +107,37 @@ fn to_entry(disk_path: PathBuf, params: &Params) -> Result<DbEntry> {
         .build()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use expectest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_stored_key_file_is_relative_to_the_db_dir() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let db_dir = db_dir.path().canonicalize().unwrap();
+        fs::create_dir(db_dir.join("keys")).unwrap();
+        fs::write(db_dir.join("keys").join("secret.key"), b"secret").unwrap();
+
+        expect!(stored_key_file(Path::new("keys/secret.key"), &db_dir))
+            .to(be_ok().value(PathBuf::from("keys/secret.key")));
+        expect!(stored_key_file(&db_dir.join("keys/../keys/secret.key"), &db_dir))
+            .to(be_ok().value(PathBuf::from("keys/secret.key")));
+        // a relative path is never looked up in the current directory
+        expect!(stored_key_file(Path::new("secret.key"), &db_dir)).to(be_err());
+    }
+
+    #[test]
+    fn test_stored_key_file_outside_the_db_dir_is_absolute() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let key_dir = tempfile::tempdir().unwrap();
+        let key_file = key_dir.path().canonicalize().unwrap().join("secret.key");
+        fs::write(&key_file, b"secret").unwrap();
+
+        expect!(stored_key_file(&key_file, &db_dir.path().canonicalize().unwrap())).to(be_ok().value(key_file));
+    }
+}
@@ -0,0 +1,37 @@
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{Context, DeviceOps, PeroxideDbOps};
+use peroxide_cryptsetup::device::{Keyslot, LuksVolumeOps};
+
+use crate::operation::{disk_path_for, CommandOutput, ContextSnafu, DeviceSnafu, DiskRecord, Result};
+use crate::DiskReference;
+
+#[derive(Debug)]
+pub struct Params {
+    /// Device path, UUID or name of the device we want to remove the enrolled key of
+    pub disk_reference: DiskReference,
+    /// Keyslot to destroy with the key of another keyslot (instead of the enrolled key and its keyslot)
+    pub keyslot: Option<Keyslot>,
+}
+
+pub fn remove<C: Context + DeviceOps>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    let mut db = ctx.open_db_for_update().context(ContextSnafu)?;
+    let path = disk_path_for(&db, &params.disk_reference)?;
+
+    if let Some(keyslot) = params.keyslot {
+        let uuid = path.luks_uuid().context(DeviceSnafu)?;
+        let (authorized_by, removed) = ctx.kill_keyslot(&mut db, &path, keyslot).context(ContextSnafu)?;
+        return Ok(CommandOutput::RemoveKeyslot {
+            uuid,
+            keyslot,
+            authorized_by,
+            removed: removed.iter().map(DiskRecord::of).collect(),
+        });
+    }
+
+    let entry = ctx.remove_enrolment(&mut db, path).context(ContextSnafu)?;
+
+    Ok(CommandOutput::Remove {
+        disk: DiskRecord::of(&entry),
+    })
+}
@@ -0,0 +1,43 @@
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{Context, DatabaseOps, PeroxideDbOps};
+
+use crate::operation::{find_entry_for, CommandOutput, ContextSnafu, DiskRecord, Result, ValidationSnafu};
+use crate::DiskReference;
+
+#[derive(Debug)]
+pub struct Params {
+    /// Device path, UUID or current name of the device we want to rename
+    pub disk_reference: DiskReference,
+    /// New name of the device in the database
+    pub new_name: String,
+}
+
+pub fn rename<C: Context>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    let mut db = ctx.open_db_for_update().context(ContextSnafu)?;
+    let uuid = find_entry_for(&db, &params.disk_reference)?.uuid().to_owned();
+
+    // validate: names are used to look up devices, so they must stay unique
+    if let Some(other) = db.find_entry_by_name(&params.new_name) {
+        if other.uuid() != &uuid {
+            return Err(ValidationSnafu {
+                message: format!(
+                    "Name `{}` is already used by the device with uuid {}",
+                    params.new_name,
+                    other.uuid()
+                ),
+            }
+            .build());
+        }
+    }
+
+    // TODO: `--update-label` to also change the LUKS2 header label needs `crypt_set_label` to be exposed by
+    //       cryptsetup-rs (as `Luks2CryptDevice::set_label`) first
+    let entry = db.find_entry_mut(&uuid).expect("Expected entry to exist");
+    entry.volume_id_mut().name = Some(params.new_name);
+    let disk = DiskRecord::of(entry);
+
+    ctx.save_db(&db).context(ContextSnafu)?;
+
+    Ok(CommandOutput::Rename { disk })
+}
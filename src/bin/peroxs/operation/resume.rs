@@ -0,0 +1,22 @@
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{Context, DeviceOps, PeroxideDbOps};
+
+use crate::operation::{find_entry_for, CommandOutput, ContextSnafu, Result};
+use crate::DiskReference;
+
+#[derive(Debug)]
+pub struct Params {
+    /// Device path, UUID or name of the device we want to resume
+    pub disk_reference: DiskReference,
+    /// Name override (the same one that was used when opening the device)
+    pub name: Option<String>,
+}
+
+pub fn resume<C: Context + DeviceOps>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    let db = ctx.open_db().context(ContextSnafu)?;
+    let entry = find_entry_for(&db, &params.disk_reference)?;
+
+    let mapping = ctx.resume(entry, params.name).context(ContextSnafu)?;
+    Ok(CommandOutput::Resume { mapping })
+}
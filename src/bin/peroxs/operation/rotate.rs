@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{Context, DeviceOps, FormatContainerParams, PeroxideDbOps};
+
+use crate::operation::{disk_path_for, CommandOutput, ContextSnafu, DiskRecord, Result};
+use crate::DiskReference;
+
+#[derive(Debug)]
+pub struct Params {
+    /// Device path, UUID or name of the device whose key we want to replace
+    pub disk_reference: DiskReference,
+    /// Number of milliseconds to wait for the PBKDF function iterations of the new keyslot
+    pub iteration_ms: u32,
+    /// Parameters for the new keyslot (only the PBKDF parameters are used)
+    pub format_params: FormatContainerParams,
+    /// Keyfile with the new key (only for keyfile entries)
+    pub new_key_file: Option<PathBuf>,
+}
+
+pub fn rotate<C: Context + DeviceOps>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    let mut db = ctx.open_db_for_update().context(ContextSnafu)?;
+    let path = disk_path_for(&db, &params.disk_reference)?;
+
+    let entry = ctx
+        .rotate_key(
+            &mut db,
+            path,
+            params.iteration_ms,
+            params.format_params,
+            params.new_key_file,
+        )
+        .context(ContextSnafu)?;
+
+    Ok(CommandOutput::Rotate {
+        disk: DiskRecord::of(&entry),
+        keyslot: entry.volume_id().keyslot(),
+    })
+}
@@ -0,0 +1,29 @@
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{Context, DatabaseOps, PeroxideDbOps};
+use peroxide_cryptsetup::db::ActivationOptions;
+
+use crate::operation::{find_entry_for, CommandOutput, ContextSnafu, DiskRecord, Result};
+use crate::DiskReference;
+
+#[derive(Debug)]
+pub struct Params {
+    /// Device path, UUID or name of the device to change
+    pub disk_reference: DiskReference,
+    /// Flags the device is always opened with (all off removes them from the entry)
+    pub options: ActivationOptions,
+}
+
+pub fn set_activation_options<C: Context>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    let mut db = ctx.open_db_for_update().context(ContextSnafu)?;
+    let uuid = find_entry_for(&db, &params.disk_reference)?.uuid().to_owned();
+
+    let entry = db.find_entry_mut(&uuid).expect("Expected entry to exist");
+    entry.volume_id_mut().set_activation_options(params.options);
+    let disk = DiskRecord::of(entry);
+    let options = entry.volume_id().activation_options();
+
+    ctx.save_db(&db).context(ContextSnafu)?;
+
+    Ok(CommandOutput::SetActivationOptions { disk, options })
+}
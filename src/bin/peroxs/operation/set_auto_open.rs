@@ -0,0 +1,30 @@
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{Context, DatabaseOps, PeroxideDbOps};
+
+use crate::operation::{find_entry_for, CommandOutput, ContextSnafu, DiskRecord, Result};
+use crate::DiskReference;
+
+#[derive(Debug)]
+pub struct Params {
+    /// Device path, UUID or name of the device to change
+    pub disk_reference: DiskReference,
+    /// Whether `boot-open` opens the device
+    pub auto_open: bool,
+}
+
+pub fn set_auto_open<C: Context>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    let mut db = ctx.open_db_for_update().context(ContextSnafu)?;
+    let uuid = find_entry_for(&db, &params.disk_reference)?.uuid().to_owned();
+
+    let entry = db.find_entry_mut(&uuid).expect("Expected entry to exist");
+    entry.volume_id_mut().set_auto_open(params.auto_open);
+    let disk = DiskRecord::of(entry);
+
+    ctx.save_db(&db).context(ContextSnafu)?;
+
+    Ok(CommandOutput::SetAutoOpen {
+        disk,
+        auto_open: params.auto_open,
+    })
+}
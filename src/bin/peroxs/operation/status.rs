@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+
+use log::Level;
+use peroxide_cryptsetup::device::{Disks, LuksVolumeOps, ReencryptStatus};
+use peroxide_cryptsetup::disk_log;
+use prettytable::{format, Table};
+use serde_derive::Serialize;
+use snafu::prelude::*;
+use uuid::Uuid;
+
+use crate::operation::{CommandOutput, DeviceSnafu, Result, ValidationSnafu};
+
+#[derive(Debug)]
+pub struct Params {
+    /// Name of the active mapping to show (all active mappings are shown otherwise)
+    pub name: Option<String>,
+}
+
+/// An active mapping and the parameters of its LUKS device
+#[derive(Debug, Serialize)]
+pub struct StatusRecord {
+    pub name: String,
+    pub dm_name: String,
+    pub device: PathBuf,
+    pub uuid: Uuid,
+    /// Cipher of the device (if its header could be read)
+    pub cipher: Option<String>,
+    /// Size of the volume key in bits (if the header could be read)
+    pub key_bits: Option<usize>,
+    /// Whether the device is being reencrypted (if the header could be read)
+    pub reencrypt: Option<ReencryptStatus>,
+}
+
+pub fn status(params: Params) -> Result<CommandOutput> {
+    let mut mappings = Disks::scan_sysfs_for_active_crypt_devices().context(DeviceSnafu)?;
+    if let Some(ref name) = params.name {
+        mappings.retain(|m| &m.name == name);
+        if mappings.is_empty() {
+            return Err(ValidationSnafu {
+                message: format!("No active mapping named `{}` found", name),
+            }
+            .build());
+        }
+    }
+    mappings.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let records = mappings
+        .into_iter()
+        .map(|mapping| {
+            // the header may not be readable (e.g. without permissions), which should not hide the other mappings
+            let info = match mapping.underlying.luks_volume_info() {
+                Ok(info) => Some(info),
+                Err(e) => {
+                    disk_log!(
+                        Level::Debug,
+                        "status",
+                        mapping.underlying_uuid,
+                        "Could not read LUKS header of {}: {}",
+                        mapping.underlying.display(),
+                        e
+                    );
+                    None
+                }
+            };
+            let reencrypt = info
+                .as_ref()
+                .and_then(|_| mapping.underlying.luks_reencrypt_status().ok());
+            StatusRecord {
+                name: mapping.name,
+                dm_name: mapping.dm_name,
+                device: mapping.underlying,
+                uuid: mapping.underlying_uuid,
+                cipher: info.as_ref().map(|info| info.cipher.clone()),
+                key_bits: info.map(|info| info.key_bits),
+                reencrypt,
+            }
+        })
+        .collect();
+
+    Ok(CommandOutput::Status { mappings: records })
+}
+
+pub fn print_table(records: &[StatusRecord]) {
+    let mut table = Table::new();
+    table.add_row(row![b->"Name", b->"Dm name", b->"Device", b->"Uuid", b->"Cipher", b->"Key size", b->"Reencryption"]);
+
+    for record in records {
+        // rows are: name,dm name,device,uuid,cipher,key size,reencryption
+        let row = table.add_row(row!(record.name, record.dm_name, record.device.display(), record.uuid));
+
+        match (&record.cipher, record.key_bits) {
+            (Some(cipher), Some(key_bits)) => {
+                row.add_cell(cell!(cipher));
+                row.add_cell(cell!(format!("{} bits", key_bits)));
+            }
+            _ => {
+                row.add_cell(cell!(Fr -> "unknown"));
+                row.add_cell(cell!(Fr -> "unknown"));
+            }
+        }
+        row.add_cell(match record.reencrypt {
+            Some(ReencryptStatus::None) => cell!("none"),
+            Some(ReencryptStatus::InProgress) => cell!(Fy -> "in progress"),
+            Some(ReencryptStatus::Interrupted) => cell!(Fr -> "interrupted"),
+            None => cell!(Fr -> "unknown"),
+        });
+    }
+
+    table.set_format(*format::consts::FORMAT_CLEAN);
+    table.printstd();
+}
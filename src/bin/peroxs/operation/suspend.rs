@@ -0,0 +1,16 @@
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{Context, DeviceOps};
+
+use crate::operation::{CommandOutput, ContextSnafu, Result};
+
+#[derive(Debug)]
+pub struct Params {
+    /// Name of the active mapping to suspend
+    pub name: String,
+}
+
+pub fn suspend<C: Context + DeviceOps>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    ctx.suspend(&params.name).context(ContextSnafu)?;
+    Ok(CommandOutput::Suspend { mapping: params.name })
+}
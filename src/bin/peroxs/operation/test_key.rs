@@ -0,0 +1,21 @@
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{Context, DeviceOps, PeroxideDbOps};
+
+use crate::operation::{disk_path_for, CommandOutput, ContextSnafu, Result};
+use crate::DiskReference;
+
+#[derive(Debug)]
+pub struct Params {
+    /// Device path, UUID or name of the device to test the key against
+    pub disk_reference: DiskReference,
+}
+
+pub fn test_key<C: Context + DeviceOps>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    let db = ctx.open_db().context(ContextSnafu)?;
+    let path = disk_path_for(&db, &params.disk_reference)?;
+
+    let (uuid, keyslot) = ctx.test_key(&db, &path).context(ContextSnafu)?;
+
+    Ok(CommandOutput::TestKey { uuid, keyslot })
+}
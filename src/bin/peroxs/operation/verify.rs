@@ -0,0 +1,159 @@
+use std::path::PathBuf;
+
+use peroxide_cryptsetup::context::{Context, PeroxideDbOps};
+use peroxide_cryptsetup::db::DbEntry;
+use peroxide_cryptsetup::device::{
+    BlockDeviceScan, Disks, KeyslotUsage, LuksVolumeOps, OpenedDevices, ReencryptStatus,
+};
+use prettytable::{format, Table};
+use serde_derive::Serialize;
+use snafu::prelude::*;
+use uuid::Uuid;
+
+use crate::operation::{CommandOutput, ContextSnafu, Result};
+
+/// Consistency of a database entry with the disk it refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VerifyStatus {
+    Ok,
+    MissingDisk,
+    NotLuks,
+    Unreadable,
+    UuidMismatch,
+    TokenMissing,
+    /// The disk is being reencrypted (which is not an inconsistency, but no key can be enrolled until it has finished)
+    Reencrypting,
+}
+
+impl VerifyStatus {
+    fn label(&self) -> &'static str {
+        match self {
+            VerifyStatus::Ok => "OK",
+            VerifyStatus::MissingDisk => "missing-disk",
+            VerifyStatus::NotLuks => "not-luks",
+            VerifyStatus::Unreadable => "unreadable",
+            VerifyStatus::UuidMismatch => "uuid-mismatch",
+            VerifyStatus::TokenMissing => "token-missing",
+            VerifyStatus::Reencrypting => "reencrypting",
+        }
+    }
+}
+
+/// A database entry and its consistency with the disk it refers to
+#[derive(Debug, Serialize)]
+pub struct VerifyRecord {
+    pub name: Option<String>,
+    pub uuid: Uuid,
+    /// Path of the disk (if present)
+    pub device: Option<PathBuf>,
+    pub status: VerifyStatus,
+    /// Keyslots in use (only if the header of the disk could be read)
+    pub keyslots: Option<KeyslotUsage>,
+}
+
+pub fn verify<C: Context>(ctx: &C) -> Result<CommandOutput> {
+    let db = ctx.open_db().context(ContextSnafu)?;
+
+    // sort entries by name, then by uuid
+    let mut entries = db.entries.clone();
+    entries.sort_by_key(|entry| entry.volume_id().clone());
+
+    let scan = BlockDeviceScan::new();
+    // a disk with several entries (e.g. a recovery passphrase) is only opened once
+    let devices = OpenedDevices::new();
+    let disks = entries
+        .iter()
+        .map(|entry| {
+            let (device, status) = verify_entry(entry, &scan);
+            let keyslots = device
+                .as_ref()
+                .filter(|_| !matches!(status, VerifyStatus::NotLuks | VerifyStatus::Unreadable))
+                .and_then(|p| devices.device(p).luks_keyslot_usage().ok());
+            VerifyRecord {
+                name: entry.volume_id().name.clone(),
+                uuid: entry.uuid().to_owned(),
+                device,
+                status,
+                keyslots,
+            }
+        })
+        .collect::<Vec<_>>();
+    // a disk that is not attached is not an inconsistency by itself
+    let inconsistent = disks
+        .iter()
+        .filter(|disk| {
+            !matches!(
+                disk.status,
+                VerifyStatus::Ok | VerifyStatus::MissingDisk | VerifyStatus::Reencrypting
+            )
+        })
+        .count();
+
+    Ok(CommandOutput::Verify { disks, inconsistent })
+}
+
+pub fn print_table(records: &[VerifyRecord]) {
+    let mut table = Table::new();
+    table.add_row(row![b->"Name", b->"Uuid", b->"Device", b->"Keyslots", b->"Status"]);
+
+    for record in records {
+        let name = record.name.clone().unwrap_or("".to_string());
+        let path_cell = record
+            .device
+            .as_ref()
+            .map(|p| cell!(p.to_string_lossy()))
+            .unwrap_or(cell!(Fr -> "not present"));
+        let keyslots_cell = match record.keyslots {
+            Some(ref usage) if usage.is_full() => cell!(Fy -> format!("{} (full)", usage)),
+            Some(ref usage) => cell!(usage),
+            None => cell!(""),
+        };
+        let status_cell = match record.status {
+            VerifyStatus::Ok => cell!(Fg -> record.status.label()),
+            VerifyStatus::Reencrypting => cell!(Fy -> record.status.label()),
+            _ => cell!(Fr -> record.status.label()),
+        };
+
+        // rows are: name,uuid,disk,keyslots,status
+        let row = table.add_row(row!(name, record.uuid.to_string()));
+        row.add_cell(path_cell);
+        row.add_cell(keyslots_cell);
+        row.add_cell(status_cell);
+    }
+
+    table.set_format(*format::consts::FORMAT_CLEAN);
+    table.printstd();
+}
+
+fn verify_entry(entry: &DbEntry, scan: &BlockDeviceScan) -> (Option<PathBuf>, VerifyStatus) {
+    let path = match Disks::disk_uuid_path_with_scan(entry.uuid(), scan) {
+        Ok(path) => path.canonicalize().unwrap_or(path),
+        Err(_) => return (None, VerifyStatus::MissingDisk),
+    };
+
+    let status = match path.luks_uuid() {
+        // a disk without a LUKS header is told apart from one that cryptsetup failed to load
+        Err(_) if !path.is_luks() => VerifyStatus::NotLuks,
+        Err(_) => VerifyStatus::Unreadable,
+        Ok(uuid) if &uuid != entry.uuid() => VerifyStatus::UuidMismatch,
+        Ok(_) => match entry.volume_id().luks2_token_id() {
+            Some(token_id) => match path.luks2_token_exists(token_id) {
+                Ok(true) => VerifyStatus::Ok,
+                Ok(false) => VerifyStatus::TokenMissing,
+                Err(_) => VerifyStatus::Unreadable,
+            },
+            None => VerifyStatus::Ok,
+        },
+    };
+    // the entry is consistent with the disk, but the header may be in the middle of being reencrypted
+    let status = match (status, path.luks_reencrypt_status()) {
+        (VerifyStatus::Ok, Ok(ReencryptStatus::InProgress | ReencryptStatus::Interrupted)) => {
+            VerifyStatus::Reencrypting
+        }
+        (VerifyStatus::Ok, Err(_)) => VerifyStatus::Unreadable,
+        (status, _) => status,
+    };
+
+    (Some(path), status)
+}
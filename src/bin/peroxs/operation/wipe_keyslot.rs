@@ -0,0 +1,49 @@
+use std::io;
+
+use snafu::prelude::*;
+
+use peroxide_cryptsetup::context::{Context, DeviceOps, PeroxideDbOps};
+use peroxide_cryptsetup::device::{Keyslot, LuksVolumeOps};
+
+use crate::operation::{confirm, disk_path_for, CommandOutput, ContextSnafu, DeviceSnafu, DiskRecord, Result};
+use crate::DiskReference;
+
+#[derive(Debug)]
+pub struct Params {
+    /// Device path, UUID or name of the device whose keyslot we want to destroy
+    pub disk_reference: DiskReference,
+    /// Keyslot to destroy
+    pub keyslot: Keyslot,
+    /// Flag to skip the confirmation before destroying the keyslot
+    pub confirmed: bool,
+}
+
+pub fn wipe_keyslot<C: Context + DeviceOps>(ctx: &C, params: Params) -> Result<CommandOutput> {
+    let mut db = ctx.open_db_for_update().context(ContextSnafu)?;
+    let path = disk_path_for(&db, &params.disk_reference)?;
+    let uuid = path.luks_uuid().context(DeviceSnafu)?;
+
+    if !params.confirmed {
+        let stdin = io::stdin();
+        confirm(
+            &format!(
+                "Destroying keyslot {} of {} cannot be undone. Type its UUID ({}) or 'yes' to continue:",
+                params.keyslot,
+                path.display(),
+                uuid
+            ),
+            &format!("destroy keyslot {} of {}", params.keyslot, path.display()),
+            &["yes", &uuid.to_string()],
+            ttypass::stdin_is_tty(),
+            &mut stdin.lock(),
+        )?;
+    }
+
+    let removed = ctx.wipe_keyslot(&mut db, &path, params.keyslot).context(ContextSnafu)?;
+
+    Ok(CommandOutput::WipeKeyslot {
+        uuid,
+        keyslot: params.keyslot,
+        removed: removed.iter().map(DiskRecord::of).collect(),
+    })
+}
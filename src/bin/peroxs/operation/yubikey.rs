@@ -0,0 +1,61 @@
+use peroxide_cryptsetup::db::YubikeySlot;
+use peroxide_cryptsetup::input::yubikey_devices;
+use prettytable::{format, Table};
+use serde_derive::Serialize;
+use snafu::prelude::*;
+
+use crate::operation::{CommandOutput, InputSnafu, Result};
+
+/// An attached Yubikey
+#[derive(Debug, Serialize)]
+pub struct YubikeyRecord {
+    /// Serial of the Yubikey (not all Yubikeys expose it)
+    pub serial: Option<u32>,
+    /// Firmware version as `major.minor.build`
+    pub version: String,
+    pub configured_slots: Vec<YubikeySlot>,
+}
+
+pub fn list_yubikeys() -> Result<CommandOutput> {
+    let devices = yubikey_devices().context(InputSnafu)?;
+    let yubikeys = devices
+        .into_iter()
+        .map(|device| {
+            let (major, minor, build) = device.version;
+            YubikeyRecord {
+                serial: device.serial,
+                version: format!("{}.{}.{}", major, minor, build),
+                configured_slots: device.configured_slots,
+            }
+        })
+        .collect();
+
+    Ok(CommandOutput::Yubikeys { yubikeys })
+}
+
+pub fn print_table(records: &[YubikeyRecord]) {
+    if records.is_empty() {
+        println!("No Yubikeys found");
+        return;
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![b->"Serial", b->"Version", b->"Configured slots"]);
+
+    for record in records {
+        let serial = record
+            .serial
+            .map(|serial| serial.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        let slots = record
+            .configured_slots
+            .iter()
+            .map(|slot| slot.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        table.add_row(row!(serial, record.version, slots));
+    }
+
+    table.set_format(*format::consts::FORMAT_CLEAN);
+    table.printstd();
+}
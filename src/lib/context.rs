@@ -1,42 +1,146 @@
+//! Operations on the database and on the disks in it (the high-level flows behind the `peroxs` commands)
+//!
+//! Opening an enrolled disk by the name of its database entry, prompting for its key as `peroxs open` does:
+//!
+//! ```no_run
+//! use std::path::PathBuf;
+//!
+//! use peroxide_cryptsetup::context::{MainContext, PeroxideDbOps};
+//!
+//! let ctx = MainContext::new(PathBuf::from("/etc/peroxs/peroxs-db.json"));
+//! let db = ctx.open_db().expect("database");
+//! let mapping = ctx.open_by_name(&db, "backup").expect("disk opened");
+//! println!("Opened /dev/mapper/{}", mapping);
+//! ```
+
+use std::cell::RefCell;
+use std::fmt;
 use std::path::{Path, PathBuf};
 use std::result;
 use std::time::Duration;
 
 use cryptsetup_rs;
 pub use cryptsetup_rs::Luks1CryptDeviceHandle as Luks1Device;
+use log::Level;
 use secstr::SecStr;
 use snafu::{prelude::*, Backtrace};
 use uuid::Uuid;
 use vec1::Vec1;
 
-use crate::db::{DbEntry, Error as DbError, PeroxideDb, VolumeId, YubikeyEntryType, YubikeySlot};
+use crate::db::{
+    DbEntry, DbLock, Error as DbError, FormatInfo, LockMode, PeroxideDb, VolumeId, YubikeyEntryType, YubikeySlot,
+};
 pub use crate::device::FormatContainerParams;
-use crate::device::{Disks, Error as DeviceError, FormatResult, LuksVolumeOps};
-use crate::input::{get_key_for, BackupPrompt, Error as InputError, KeyInputConfig};
+use crate::device::{
+    ensure_not_reencrypting, luks_uuid_via_blkid, ActivationFlags, BlockDeviceScan, DiskInspector, Disks,
+    DmSetupDeviceInfo, Error as DeviceError, FormatResult, Keyslot, KeyslotPriority, LuksVersion, LuksVolumeOps,
+    OpenedDevices, PreviousKey,
+};
+use crate::input::{
+    get_key_for, new_fido2_credential, new_recovery_passphrase, save_recovery_passphrase, volume_key_file,
+    yubikey_serial, BackupPrompt, Error as InputError, Fido2Credential, InputName, KeyInput, KeyInputConfig,
+    PassphraseInput,
+};
+use crate::messages;
 
 pub type Result<T> = result::Result<T, Error>;
 
+impl Error {
+    /// Stable name of the kind of error (for machine-readable error output)
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::DatabaseError {
+                source: DbError::DatabaseNotWritableError { .. },
+            } => "database_not_writable",
+            Error::DatabaseError { .. } => "database",
+            Error::DatabaseNotWritableError { .. } => "database_not_writable",
+            Error::DeviceAlreadyActivatedError { .. } => "device_already_activated",
+            Error::DeviceNotActivatedError { .. } => "device_not_activated",
+            Error::DeviceAlreadySuspendedError { .. } => "device_already_suspended",
+            Error::DeviceNotSuspendedError { .. } => "device_not_suspended",
+            Error::MappingNotFoundError { .. } => "mapping_not_found",
+            Error::DeviceAlreadyFormattedError { .. } => "device_already_formatted",
+            Error::NotAllDisksAlreadyFormattedError { .. } => "not_all_disks_already_formatted",
+            Error::DiskIdDuplicatesFoundError { .. } => "disk_id_duplicates_found",
+            Error::FormatOnlyKeyError { .. } => "format_only_key",
+            Error::EntryAlreadyExists { .. } => "entry_already_exists",
+            Error::DiskEntryNotFound { .. } => "disk_entry_not_found",
+            Error::EntryNameNotFound { .. } => "entry_name_not_found",
+            Error::AmbiguousEntryNameError { .. } => "ambiguous_entry_name",
+            Error::DeviceError { .. } => "device",
+            Error::FeatureNotAvailableError { .. } => "feature_not_available",
+            Error::KeyslotNotActiveError { .. } => "keyslot_not_active",
+            Error::KeyslotNotFoundError { .. } => "keyslot_not_found",
+            Error::LastKeyslotError { .. } => "last_keyslot",
+            Error::LuksVersionMismatchError { .. } => "luks_version_mismatch",
+            Error::SameKeyslotError { .. } => "same_keyslot",
+            Error::KeyInputError { .. } => "key_input",
+            Error::NewKeyfileRequiredError { .. } => "new_keyfile_required",
+            Error::NewKeyfileUnexpectedError { .. } => "new_keyfile_unexpected",
+            Error::KeyNotRotatableError { .. } => "key_not_rotatable",
+            Error::VolumeNotFoundError { .. } => "volume_not_found",
+            Error::WrongKeyError { .. } => "wrong_key",
+            Error::WrongKeyslotKeyError { .. } => "wrong_keyslot_key",
+        }
+    }
+
+    /// Check whether the key was rejected by the disk (as opposed to failing for any other reason)
+    pub fn is_wrong_key(&self) -> bool {
+        match self {
+            Error::DeviceError { source } => source.is_wrong_key(),
+            Error::WrongKeyError { .. } | Error::WrongKeyslotKeyError { .. } => true,
+            _ => false,
+        }
+    }
+}
+
 pub type DeviceMapperName = String;
 
+/// Default number of attempts at entering a passphrase interactively when activating a disk
+pub const DEFAULT_ACTIVATION_TRIES: u32 = 3;
+
 #[derive(Debug, Snafu)]
 pub enum Error {
-    #[snafu(display("Database error"))]
+    #[snafu(display("{source}"))]
     DatabaseError {
         #[snafu(backtrace)]
         source: DbError,
     },
+    #[snafu(display("The database `{}` is read from standard input, so it cannot be changed", path.display()))]
+    DatabaseNotWritableError { path: PathBuf, backtrace: Backtrace },
     #[snafu(display("Device `{name}` already activated"))]
     DeviceAlreadyActivatedError { name: String, backtrace: Backtrace },
+    #[snafu(display("Device `{name}` is not activated"))]
+    DeviceNotActivatedError { name: String, backtrace: Backtrace },
+    #[snafu(display("Device `{name}` is already suspended"))]
+    DeviceAlreadySuspendedError { name: String, backtrace: Backtrace },
+    #[snafu(display("Device `{name}` is not suspended"))]
+    DeviceNotSuspendedError { name: String, backtrace: Backtrace },
+    #[snafu(display("Underlying LUKS device for mapping `{name}` was not found"))]
+    MappingNotFoundError { name: String, backtrace: Backtrace },
     #[snafu(display("Device with uuid `{uuid}` is already formatted"))]
     DeviceAlreadyFormattedError { uuid: Uuid, backtrace: Backtrace },
     #[snafu(display("Not all disks have been formatted for this operation"))]
     NotAllDisksAlreadyFormattedError { backtrace: Backtrace },
     #[snafu(display("Disk uuid duplicates found"))]
     DiskIdDuplicatesFoundError { backtrace: Backtrace },
+    #[snafu(display("Only a keyfile or a passphrase can format a disk without enrolling it"))]
+    FormatOnlyKeyError { backtrace: Backtrace },
     #[snafu(display("Disk entry already exists for uuid `{uuid}`"))]
     EntryAlreadyExists { uuid: Uuid, backtrace: Backtrace },
     #[snafu(display("Disk entry not found for uuid `{uuid}`"))]
     DiskEntryNotFound { uuid: Uuid, backtrace: Backtrace },
+    #[snafu(display("Disk entry not found for name `{name}`"))]
+    EntryNameNotFound { name: String, backtrace: Backtrace },
+    #[snafu(display(
+        "Entries of several disks are named `{name}` ({}), use the UUID of the disk instead",
+        uuids.iter().map(Uuid::to_string).collect::<Vec<_>>().join(", ")
+    ))]
+    AmbiguousEntryNameError {
+        name: String,
+        uuids: Vec<Uuid>,
+        backtrace: Backtrace,
+    },
     #[snafu(display("Device error"))]
     DeviceError {
         #[snafu(backtrace)]
@@ -44,30 +148,103 @@ pub enum Error {
     },
     #[snafu(display("The selected feature is not available"))]
     FeatureNotAvailableError { backtrace: Backtrace },
+    #[snafu(display("Keyslot {keyslot} of the disk with uuid `{uuid}` is not in use"))]
+    KeyslotNotActiveError {
+        uuid: Uuid,
+        keyslot: Keyslot,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("The keyslot of the key for the disk with uuid `{uuid}` could not be determined"))]
+    KeyslotNotFoundError { uuid: Uuid, backtrace: Backtrace },
+    #[snafu(display("Refusing to remove the last keyslot of the disk with uuid `{uuid}`"))]
+    LastKeyslotError { uuid: Uuid, backtrace: Backtrace },
+    #[snafu(display("The disk with uuid `{uuid}` is {actual:?}, but its database entry is for {expected:?}"))]
+    LuksVersionMismatchError {
+        uuid: Uuid,
+        expected: LuksVersion,
+        actual: LuksVersion,
+        backtrace: Backtrace,
+    },
+    #[snafu(display(
+        "The key for the disk with uuid `{uuid}` is in keyslot {keyslot}, which is the one to remove (a key of another keyslot is needed)"
+    ))]
+    SameKeyslotError {
+        uuid: Uuid,
+        keyslot: Keyslot,
+        backtrace: Backtrace,
+    },
+    #[snafu(display(
+        "The disk with uuid `{uuid}` is enrolled with a keyfile, so a new keyfile is needed to replace its key"
+    ))]
+    NewKeyfileRequiredError { uuid: Uuid, backtrace: Backtrace },
+    #[snafu(display(
+        "The disk with uuid `{uuid}` is not enrolled with a keyfile, so its new key is not read from one"
+    ))]
+    NewKeyfileUnexpectedError { uuid: Uuid, backtrace: Backtrace },
+    #[snafu(display(
+        "The key of the disk with uuid `{uuid}` is derived from a challenge or credential that does not change, so rotating it would keep the same key (enroll a new key and remove this one instead)"
+    ))]
+    KeyNotRotatableError { uuid: Uuid, backtrace: Backtrace },
     #[snafu(display("Key input error"))]
     KeyInputError {
         #[snafu(backtrace)]
         source: InputError,
     },
     #[snafu(display("The volume `{volume_id}` was not found on the current system"))]
-    VolumeNotFoundError { volume_id: VolumeId, backtrace: Backtrace },
+    VolumeNotFoundError {
+        volume_id: Box<VolumeId>,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("The key does not unlock any keyslot of the disk with uuid `{uuid}`"))]
+    WrongKeyError { uuid: Uuid, backtrace: Backtrace },
+    #[snafu(display("The key does not unlock keyslot {keyslot} of the disk with uuid `{uuid}`"))]
+    WrongKeyslotKeyError {
+        uuid: Uuid,
+        keyslot: Keyslot,
+        backtrace: Backtrace,
+    },
 }
 
 pub trait Context {
     fn db_location(&self) -> &Path;
+
+    /// Lock the database (if the context locks it at all) until the context is dropped
+    fn lock_db(&self, _mode: LockMode) -> Result<()> {
+        Ok(())
+    }
 }
 
 impl Context for MainContext {
     fn db_location(&self) -> &Path {
         self.db_path.as_ref()
     }
+
+    fn lock_db(&self, mode: LockMode) -> Result<()> {
+        // a missing database is not read, so no lock file is left behind for it
+        if self.no_lock
+            || PeroxideDb::is_stdio_path(&self.db_path)
+            || (mode == LockMode::Shared && !self.db_path.exists())
+        {
+            return Ok(());
+        }
+        let mut held = self.db_lock.borrow_mut();
+        if held.as_ref().map_or(false, |lock| lock.mode() >= mode) {
+            return Ok(());
+        }
+        // a shared lock is released before the exclusive one is taken, which would wait for it otherwise
+        *held = None;
+        *held = Some(DbLock::acquire(&self.db_path, mode).context(DatabaseSnafu)?);
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum EntryParams {
-    Keyfile(PathBuf),
+    /// Key file with the (optional) offset and size of the key in it
+    Keyfile(PathBuf, Option<u64>, Option<usize>),
     Passphrase,
     Yubikey(YubikeySlot, YubikeyEntryType),
+    Fido2,
 }
 
 #[derive(Debug, Clone)]
@@ -78,32 +255,87 @@ pub struct DiskEnrolmentParams {
     pub force_format: bool,
     pub format_params: FormatContainerParams,
     pub iteration_ms: u32, // TODO: try to remove this from here
+    /// Keyslot to add the key to (the first free one if not set)
+    pub keyslot: Option<Keyslot>,
+    /// LUKS2 priority of the new keyslot (the default priority if not set)
+    pub priority: Option<KeyslotPriority>,
+    /// File with the volume key of the disk, used instead of an existing key to add the new one
+    pub volume_key_file: Option<PathBuf>,
+    /// Where to put a generated recovery passphrase that is added to another keyslot (none is added if not set)
+    pub recovery: Option<RecoveryOutput>,
+}
+
+/// Where the recovery passphrase generated at enrollment is shown (it is not stored anywhere else)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecoveryOutput {
+    /// Printed once on standard output
+    Print,
+    /// Written to a new file that only its owner can read
+    File(PathBuf),
 }
 
+/// Database access for a context, which holds a shared lock on the database while it is read and an exclusive one
+/// from the time it is opened for an update until the context is dropped (so that an operation that saves it several
+/// times never works on a copy that another process may have changed in between)
 pub trait PeroxideDbOps {
     fn open_db(&self) -> Result<PeroxideDb>;
+    /// Open the database to change it and save it again (which is refused for a database read from standard input)
+    fn open_db_for_update(&self) -> Result<PeroxideDb>;
     fn save_db(&self, db: &PeroxideDb) -> Result<()>;
 }
 
 impl<C: Context> PeroxideDbOps for C {
     fn open_db(&self) -> Result<PeroxideDb> {
+        self.lock_db(LockMode::Shared)?;
         PeroxideDb::open_at(self.db_location()).context(DatabaseSnafu)
     }
 
+    fn open_db_for_update(&self) -> Result<PeroxideDb> {
+        ensure!(
+            !PeroxideDb::is_stdio_path(self.db_location()),
+            DatabaseNotWritableSnafu {
+                path: self.db_location()
+            }
+        );
+        self.lock_db(LockMode::Exclusive)?;
+        self.open_db()
+    }
+
     fn save_db(&self, db: &PeroxideDb) -> Result<()> {
+        self.lock_db(LockMode::Exclusive)?;
         db.save_to(self.db_location()).context(DatabaseSnafu)
     }
 }
 
-fn entry_from(volume_id: VolumeId, params: EntryParams) -> DbEntry {
+fn entry_from(
+    volume_id: VolumeId,
+    params: EntryParams,
+    fido2_credential: Option<Fido2Credential>,
+    yubikey_serial: Option<u32>,
+) -> DbEntry {
     match params {
         EntryParams::Passphrase => DbEntry::PassphraseEntry { volume_id },
-        EntryParams::Keyfile(key_file) => DbEntry::KeyfileEntry { key_file, volume_id },
+        EntryParams::Keyfile(key_file, key_offset, key_size) => DbEntry::KeyfileEntry {
+            key_file,
+            key_offset,
+            key_size,
+            volume_id,
+        },
         EntryParams::Yubikey(slot, entry_type) => DbEntry::YubikeyEntry {
             entry_type,
             slot,
+            serial: yubikey_serial,
             volume_id,
         },
+        EntryParams::Fido2 => {
+            let Fido2Credential { credential_id, salt } =
+                fido2_credential.expect("Expected FIDO2 credential to be created before enrollment");
+            DbEntry::Fido2Entry {
+                credential_id,
+                salt,
+                volume_id,
+            }
+        }
     }
 }
 
@@ -112,6 +344,8 @@ fn format_container<P: AsRef<Path>>(
     entry: &mut DbEntry,
     params: &mut FormatContainerParams,
     key: &SecStr,
+    maybe_keyslot: Option<Keyslot>,
+    maybe_priority: Option<KeyslotPriority>,
 ) -> Result<u8> {
     match params {
         FormatContainerParams::Luks1 { uuid, .. } => {
@@ -130,7 +364,12 @@ fn format_container<P: AsRef<Path>>(
         }
     };
 
-    match disk_path.luks_format_with_key(key, params).context(DeviceSnafu)? {
+    let result = disk_path
+        .luks_format_with_key(key, params, maybe_keyslot, maybe_priority)
+        .context(DeviceSnafu)?;
+    entry.volume_id_mut().format_info = Some(format_info(params));
+
+    match result {
         FormatResult::Luks1 { keyslot } => Ok(keyslot),
         FormatResult::Luks2 { keyslot, token_id } => {
             entry.volume_id_mut().luks2_token_id = token_id;
@@ -139,6 +378,134 @@ fn format_container<P: AsRef<Path>>(
     }
 }
 
+fn format_info(params: &FormatContainerParams) -> FormatInfo {
+    match params {
+        FormatContainerParams::Luks1 {
+            cipher,
+            cipher_mode,
+            hash,
+            mk_bits,
+            ..
+        }
+        | FormatContainerParams::Luks2 {
+            cipher,
+            cipher_mode,
+            hash,
+            mk_bits,
+            ..
+        } => FormatInfo {
+            cipher: cipher.clone(),
+            cipher_mode: cipher_mode.clone(),
+            hash: hash.clone(),
+            mk_bits: *mk_bits,
+        },
+    }
+}
+
+/// Find the keyslot of the key of an entry: either recorded in the entry or the one its LUKS2 token is assigned to
+fn entry_keyslot<D: LuksVolumeOps>(device: &D, entry: &DbEntry) -> Result<Keyslot> {
+    check_luks_version(device, entry)?;
+    if let Some(keyslot) = entry.volume_id().keyslot {
+        return Ok(keyslot);
+    }
+
+    let token_keyslot = match entry.volume_id().luks2_token_id {
+        Some(token_id) => device.luks2_token_keyslot(token_id).context(DeviceSnafu)?,
+        None => None,
+    };
+
+    token_keyslot.context(KeyslotNotFoundSnafu {
+        uuid: entry.uuid().to_owned(),
+    })
+}
+
+/// Warn that the database was not updated (any keyslot that was added or replaced is not recorded in it)
+pub fn warn_db_not_saved() {
+    eprintln!("WARNING: the database was not updated (--no-db-save)");
+}
+
+/// Whether a new key can be prompted for the entry: the challenge of a plain Yubikey entry and the credential of a FIDO2
+/// entry stay the same, so they would only give the previous key again
+fn key_can_be_rotated(entry: &DbEntry) -> bool {
+    match *entry {
+        // a keyfile entry is rotated to a new key file
+        DbEntry::KeyfileEntry { .. } | DbEntry::PassphraseEntry { .. } => true,
+        DbEntry::YubikeyEntry { entry_type, .. } => entry_type == YubikeyEntryType::HybridChallengeResponse,
+        DbEntry::Fido2Entry { .. } => false,
+        // the key files of the parts are read again as they are, so only the other parts can change the key
+        DbEntry::CompositeEntry { ref parts, .. } => parts
+            .iter()
+            .any(|part| !matches!(part, DbEntry::KeyfileEntry { .. }) && key_can_be_rotated(part)),
+    }
+}
+
+/// Check that the device is of the LUKS version the entry was enrolled for (only entries with a LUKS2 token tell)
+fn check_luks_version<D: LuksVolumeOps>(device: &D, entry: &DbEntry) -> Result<()> {
+    if entry.volume_id().luks2_token_id.is_none() {
+        return Ok(());
+    }
+
+    let actual = device.luks_version().context(DeviceSnafu)?;
+    ensure!(
+        actual == LuksVersion::Luks2,
+        LuksVersionMismatchSnafu {
+            uuid: entry.uuid().to_owned(),
+            expected: LuksVersion::Luks2,
+            actual,
+        }
+    );
+    Ok(())
+}
+
+/// The flags to activate the disk of an entry with: the given ones plus the options stored in the entry (unless they are
+/// ignored)
+fn entry_activation_flags(flags: ActivationFlags, entry: &DbEntry) -> ActivationFlags {
+    match entry.volume_id().activation_options() {
+        Some(options) if !flags.ignore_entry_options => ActivationFlags {
+            allow_discards: flags.allow_discards || options.allow_discards,
+            read_only: flags.read_only || options.read_only,
+            no_read_workqueue: flags.no_read_workqueue || options.no_read_workqueue,
+            no_write_workqueue: flags.no_write_workqueue || options.no_write_workqueue,
+            ..flags
+        },
+        _ => flags,
+    }
+}
+
+/// The keys of a batch of disks that are enrolled together, prompted for once and then used for every disk
+///
+/// The keys are prompted for with the first entry of the batch. This is the same key for every disk of the batch for
+/// all entry types but one: the hybrid Yubikey mode salts the challenge with the UUID of the disk, so the key cannot
+/// be derived again for the other disks. Instead the key derived for the first disk is used for all of them, which is
+/// why a batch of hybrid Yubikey disks has to be opened together and in the same order.
+struct EnrolmentKeys {
+    /// Key of an existing keyslot of the disks or their volume key (none if the disks are being formatted)
+    old_key: Option<PreviousKey>,
+    /// Key that is being enrolled
+    new_key: SecStr,
+}
+
+impl EnrolmentKeys {
+    fn prompt<Ctx: DeviceOps, BCtx: DeviceOps>(
+        ctx: &Ctx,
+        backup_db: Option<BackupPrompt<BCtx>>,
+        first_entry: &DbEntry,
+        format: bool,
+        volume_key_path: Option<&Path>,
+    ) -> Result<EnrolmentKeys> {
+        let old_key = if format {
+            None
+        } else if let Some(path) = volume_key_path {
+            Some(PreviousKey::VolumeKey(volume_key_file(path).context(KeyInputSnafu)?))
+        } else {
+            let key = prompt_old_key(ctx, backup_db, first_entry.volume_id())?;
+            Some(PreviousKey::Key(key))
+        };
+        let new_key = prompt_new_key(ctx, first_entry)?;
+        Ok(EnrolmentKeys { old_key, new_key })
+    }
+}
+
 fn prompt_old_key<Ctx: DeviceOps, BCtx: DeviceOps>(
     ctx: &Ctx,
     backup_db: Option<BackupPrompt<BCtx>>,
@@ -154,8 +521,106 @@ fn prompt_old_key<Ctx: DeviceOps, BCtx: DeviceOps>(
     }
 }
 
+/// Generate a recovery passphrase and print it or save it to its file
+fn show_recovery_passphrase(output: &RecoveryOutput) -> Result<SecStr> {
+    let passphrase = new_recovery_passphrase().context(KeyInputSnafu)?;
+    match output {
+        RecoveryOutput::Print => println!(
+            "Recovery passphrase (it is only shown once, keep it somewhere safe): {}",
+            String::from_utf8_lossy(passphrase.unsecure())
+        ),
+        RecoveryOutput::File(path) => save_recovery_passphrase(path, &passphrase).context(KeyInputSnafu)?,
+    }
+    Ok(passphrase)
+}
+
+/// Add the recovery passphrase to a new keyslot of every disk (using the key that was just enrolled), returning the
+/// entries that record the keyslots
+fn add_recovery_keyslots<P: AsRef<Path>>(
+    entries_with_path: &Vec1<(P, DbEntry)>,
+    new_key: &SecStr,
+    passphrase: &SecStr,
+    params: &DiskEnrolmentParams,
+) -> Result<Vec<DbEntry>> {
+    let prev_key = PreviousKey::Key(new_key.clone());
+    entries_with_path
+        .iter()
+        .map(|(disk_path, entry)| {
+            let keyslot = disk_path
+                .as_ref()
+                .luks_add_key(
+                    params.iteration_ms as usize,
+                    passphrase,
+                    &prev_key,
+                    &params.format_params,
+                    None,
+                    None,
+                )
+                .context(DeviceSnafu)?;
+            let mut volume_id = VolumeId::of(None, entry.uuid().to_owned());
+            volume_id.keyslot = Some(keyslot);
+            volume_id.recovery = true;
+            volume_id.set_enrolled_now();
+            Ok(DbEntry::PassphraseEntry { volume_id })
+        })
+        .collect()
+}
+
+/// Find the database entry with the given name, failing if entries of different disks have that name (so that the
+/// wrong disk is never picked)
+pub fn unique_entry_by_name<'a>(db: &'a PeroxideDb, name: &str) -> Result<Option<&'a DbEntry>> {
+    let entries = db.find_entries_by_name(name);
+    // several entries of the same disk (e.g. a keyfile and a passphrase) are not ambiguous
+    let mut uuids: Vec<Uuid> = vec![];
+    for entry in entries.iter() {
+        if !uuids.contains(entry.uuid()) {
+            uuids.push(entry.uuid().to_owned());
+        }
+    }
+    ensure!(uuids.len() <= 1, AmbiguousEntryNameSnafu { name, uuids });
+    Ok(entries.first().copied())
+}
+
+/// Find the path of the disk of the database entry with the given name
+pub fn disk_path_for_name(db: &PeroxideDb, name: &str) -> Result<PathBuf> {
+    let entry = unique_entry_by_name(db, name)?.context(EntryNameNotFoundSnafu { name })?;
+    Disks::disk_uuid_path(entry.uuid()).context(DeviceSnafu)
+}
+
+/// Device mapper name for an entry: the name override, otherwise the name in the database or one derived from the uuid
+pub fn mapping_name(entry: &DbEntry, name_override: Option<String>) -> DeviceMapperName {
+    name_override
+        .or(entry.volume_id().name.clone())
+        .unwrap_or_else(|| format!("uuid_{}", entry.volume_id().uuid()))
+}
+
+/// Name override for the disk at `idx` when several disks are activated at once (all share the same prefix)
+pub fn indexed_name_override(name_override: Option<&String>, idx: usize) -> Option<String> {
+    name_override.map(|name| format!("{}_{}", name, idx))
+}
+
+/// Device mapper names to activate disks under instead of the ones in the database
+#[derive(Debug, Clone)]
+pub enum NameOverride {
+    /// The name of a single disk, or the prefix of the names of several disks (followed by `_<index>`)
+    Prefix(String),
+    /// The name of every disk, in the order of the disks
+    PerDisk(Vec<DeviceMapperName>),
+}
+
+impl NameOverride {
+    /// Name override for the disk at `idx` of the `count` disks that are activated together
+    pub fn for_disk(&self, idx: usize, count: usize) -> Option<String> {
+        match self {
+            NameOverride::Prefix(name) if count == 1 => Some(name.clone()),
+            NameOverride::Prefix(name) => indexed_name_override(Some(name), idx),
+            NameOverride::PerDisk(names) => names.get(idx).cloned(),
+        }
+    }
+}
+
 fn prompt_new_key<Ctx: DeviceOps>(ctx: &Ctx, entry: &DbEntry) -> Result<SecStr> {
-    ctx.prompt_key(&entry, None, true)
+    ctx.prompt_key(entry, None, true)
 }
 
 pub trait DeviceOps {
@@ -165,6 +630,7 @@ pub trait DeviceOps {
         entry: &DbEntry,
         name_override: Option<String>,
         path_override: Option<P>,
+        flags: ActivationFlags,
     ) -> Result<DeviceMapperName>;
 
     /// Active a disk with a given key
@@ -174,8 +640,18 @@ pub trait DeviceOps {
         key: &SecStr,
         name_override: Option<String>,
         path_override: Option<P>,
+        flags: ActivationFlags,
     ) -> Result<DeviceMapperName>;
 
+    /// Deactivate a disk that is currently mapped under the given name
+    fn deactivate(&self, name: &str) -> Result<()>;
+
+    /// Suspend a disk that is currently mapped under the given name (the key is wiped from the kernel until resumed)
+    fn suspend(&self, name: &str) -> Result<()>;
+
+    /// Resume a suspended disk by prompting for its key (returns the name of the mapping)
+    fn resume(&self, entry: &DbEntry, name_override: Option<String>) -> Result<DeviceMapperName>;
+
     /// Prompt for a key with a custom prompt
     fn prompt_key(&self, entry: &DbEntry, prompt_override: Option<String>, is_new: bool) -> Result<SecStr>;
 
@@ -197,18 +673,99 @@ pub trait DeviceOps {
         backup_db: Option<BackupPrompt<BCtx>>,
     ) -> Result<Vec1<DbEntry>>;
 
+    /// Enroll a set of disks like `enroll_disks`, but only add their entries to the database without saving it (so that
+    /// several sets of disks are saved at once with `save_enrolled_db`)
+    fn enroll_disks_unsaved<P: AsRef<Path>, BCtx: DeviceOps>(
+        &self,
+        db: &mut PeroxideDb,
+        paths: Vec1<P>,
+        params: DiskEnrolmentParams,
+        backup_db: Option<BackupPrompt<BCtx>>,
+    ) -> Result<Vec1<DbEntry>>;
+
+    /// Save the database with the entries of `enroll_disks_unsaved` (nothing is saved in dry-run mode)
+    fn save_enrolled_db(&self, db: &PeroxideDb) -> Result<()>;
+
+    /// Format a set of disks with a new key like `enroll_disks`, but without adding entries to any database (returns
+    /// the UUID that every disk was formatted with)
+    fn format_disks<P: AsRef<Path>>(&self, paths: Vec1<P>, params: DiskEnrolmentParams) -> Result<Vec1<(P, Uuid)>>;
+
     fn open_disks<P: AsRef<Path>>(
         &self,
         db: &PeroxideDb,
         paths: Vec1<P>,
-        name_override: Option<String>,
+        name_override: Option<NameOverride>,
+        flags: ActivationFlags,
+    ) -> Result<Vec1<DeviceMapperName>>;
+
+    /// Open the disks like `open_disks`, but with the given key instead of the one their entries would prompt for
+    fn open_disks_with_key<P: AsRef<Path>>(
+        &self,
+        db: &PeroxideDb,
+        paths: Vec1<P>,
+        name_override: Option<NameOverride>,
+        flags: ActivationFlags,
+        key: &SecStr,
     ) -> Result<Vec1<DeviceMapperName>>;
 
+    /// Open the disks of several entries one after the other (a disk that fails does not stop the others)
+    ///
+    /// The passphrase that opened a disk is tried first on the disks of the other passphrase entries, so that disks
+    /// sharing a passphrase only prompt for it once.
+    fn open_entries(&self, entries: &[&DbEntry], flags: ActivationFlags) -> Vec<Result<DeviceMapperName>>;
+
+    /// Prompt for the key of each disk like opening it would (or use the given key) and find the keyslot it unlocks,
+    /// without activating any of the disks (a disk that fails does not stop the others)
+    fn test_open_disks<P: AsRef<Path>>(
+        &self,
+        db: &PeroxideDb,
+        paths: &[P],
+        given_key: Option<&SecStr>,
+    ) -> Vec<Result<(Uuid, Keyslot)>>;
+
+    /// Replace the key of an enrolled disk (the previous keyslot is only removed once the new one has been added)
+    ///
+    /// The new key of a keyfile entry is read from a new keyfile, which the entry refers to afterwards.
+    fn rotate_key<P: AsRef<Path>>(
+        &self,
+        db: &mut PeroxideDb,
+        disk_path: P,
+        iteration_ms: u32,
+        params: FormatContainerParams,
+        new_key_file: Option<PathBuf>,
+    ) -> Result<DbEntry>;
+
+    /// Remove the key of an enrolled disk from the device and then its entry from the database
+    fn remove_enrolment<P: AsRef<Path>>(&self, db: &mut PeroxideDb, disk_path: P) -> Result<DbEntry>;
+
+    /// Destroy a keyslot of a disk once a key of another keyslot has been checked, like `cryptsetup luksKillSlot` (returns
+    /// the keyslot of that key and the database entries of the key in the destroyed keyslot, which are removed from the
+    /// database)
+    fn kill_keyslot<P: AsRef<Path>>(
+        &self,
+        db: &mut PeroxideDb,
+        disk_path: P,
+        keyslot: Keyslot,
+    ) -> Result<(Keyslot, Vec<DbEntry>)>;
+
+    /// Destroy a keyslot of a disk without any of its keys (returns the database entries of the key in that keyslot,
+    /// which are removed from the database)
+    fn wipe_keyslot<P: AsRef<Path>>(&self, db: &mut PeroxideDb, disk_path: P, keyslot: Keyslot)
+        -> Result<Vec<DbEntry>>;
+
+    /// Convert an enrolled LUKS1 disk to LUKS2 in place once its key has been checked, adding a peroxide token to the
+    /// keyslot of the key (returns the updated database entry)
+    fn migrate<P: AsRef<Path>>(&self, db: &mut PeroxideDb, disk_path: P) -> Result<DbEntry>;
+
+    /// Prompt for a key of a disk (like its database entry does, or for a passphrase if it is not enrolled) and find
+    /// the keyslot that it unlocks, if any (returns the LUKS UUID of the disk as well)
+    fn test_key<P: AsRef<Path>>(&self, db: &PeroxideDb, disk_path: P) -> Result<(Uuid, Option<Keyslot>)>;
+
     /// Check if device is active already (by using the name in the entry or the name override)
-    fn is_active(entry: &DbEntry, name_override: Option<String>) -> bool;
+    fn is_active(&self, entry: &DbEntry, name_override: Option<String>) -> bool;
 
     /// Check if device is present
-    fn is_present(entry: &DbEntry) -> bool;
+    fn is_present(&self, entry: &DbEntry) -> bool;
 }
 
 impl DeviceOps for MainContext {
@@ -217,17 +774,13 @@ impl DeviceOps for MainContext {
         entry: &DbEntry,
         name_override: Option<String>,
         path_override: Option<P>,
+        flags: ActivationFlags,
     ) -> Result<DeviceMapperName> {
-        let key = get_key_for(
-            entry,
-            &self.key_input_config,
-            &self.db_path.parent().expect("parent path"),
-            name_override.clone(),
-            None,
-            false,
-        )
-        .context(KeyInputSnafu)?;
-        self.activate_with_key(entry, &key, name_override, path_override)
+        let path_override = path_override.as_ref().map(|p| p.as_ref());
+        self.activate_with_retries(entry, name_override.clone(), |key| {
+            self.activate_with_key(entry, key, name_override.clone(), path_override, flags)
+        })
+        .map(|(name, _)| name)
     }
 
     fn activate_with_key<P: AsRef<Path>>(
@@ -236,16 +789,18 @@ impl DeviceOps for MainContext {
         key: &SecStr,
         name_override: Option<String>,
         path_override: Option<P>,
+        flags: ActivationFlags,
     ) -> Result<DeviceMapperName> {
-        let name = name_override
-            .or(entry.volume_id().name.clone())
-            .unwrap_or_else(|| format!("uuid_{}", entry.volume_id().uuid()));
-
-        if Disks::is_device_active(name.as_str()) {
-            return Err(DeviceAlreadyActivatedSnafu { name }.build());
-        }
+        let name = mapping_name(entry, name_override);
 
-        let default_path = Disks::disk_uuid_path(entry.volume_id().uuid()).ok();
+        // the uuid lookup may need to scan the block devices, so it is only done without an override
+        let default_path = match path_override {
+            Some(_) => None,
+            None => self
+                .disks
+                .disk_uuid_path(entry.volume_id().uuid(), &self.block_device_scan)
+                .ok(),
+        };
         // lim count(as_ref) -> ∞
         let path_opt = path_override
             .as_ref()
@@ -253,28 +808,60 @@ impl DeviceOps for MainContext {
             .or(default_path.as_ref().map(|p| p.as_ref()));
 
         if let Some(device_path) = path_opt {
-            device_path
-                .luks_activate(name.as_str(), key)
-                .map(move |_| name)
-                .context(DeviceSnafu)
+            self.activate_device(&device_path, device_path, entry, name, key, flags)
         } else {
             Err(VolumeNotFoundSnafu {
-                volume_id: entry.volume_id().clone(),
+                volume_id: Box::new(entry.volume_id().clone()),
             }
             .build())
         }
     }
 
+    fn deactivate(&self, name: &str) -> Result<()> {
+        if !self.disks.is_device_active(name) {
+            return Err(DeviceNotActivatedSnafu { name }.build());
+        }
+
+        if let Some(mapping) = self.disks.active_device_info(name).context(DeviceSnafu)? {
+            mapping.underlying.luks_deactivate(name).context(DeviceSnafu)
+        } else {
+            Err(MappingNotFoundSnafu { name }.build())
+        }
+    }
+
+    fn suspend(&self, name: &str) -> Result<()> {
+        let mapping = self
+            .disks
+            .active_device_info(name)
+            .context(DeviceSnafu)?
+            .context(DeviceNotActivatedSnafu { name })?;
+        if self.disks.is_device_suspended(name).context(DeviceSnafu)? {
+            return Err(DeviceAlreadySuspendedSnafu { name }.build());
+        }
+
+        mapping.underlying.luks_suspend(name).context(DeviceSnafu)
+    }
+
+    fn resume(&self, entry: &DbEntry, name_override: Option<String>) -> Result<DeviceMapperName> {
+        let name = mapping_name(entry, name_override);
+        let mapping = self
+            .disks
+            .active_device_info(&name)
+            .context(DeviceSnafu)?
+            .filter(|m| &m.underlying_uuid == entry.uuid())
+            .context(MappingNotFoundSnafu { name: name.clone() })?;
+        // validate: only a suspended device can be resumed (before prompting for the key)
+        if !self.disks.is_device_suspended(&name).context(DeviceSnafu)? {
+            return Err(DeviceNotSuspendedSnafu { name }.build());
+        }
+
+        let key = self.prompt_key(entry, None, false)?;
+        mapping.underlying.luks_resume(&name, &key).context(DeviceSnafu)?;
+        Ok(name)
+    }
+
     fn prompt_key(&self, entry: &DbEntry, prompt_override: Option<String>, is_new: bool) -> Result<SecStr> {
-        get_key_for(
-            entry,
-            &self.key_input_config,
-            &self.db_path.parent().expect("parent path"),
-            None,
-            prompt_override,
-            is_new,
-        )
-        .context(KeyInputSnafu {})
+        self.entry_key(entry, None, prompt_override, is_new)
     }
 
     fn enroll_disk<P: AsRef<Path>, BCtx: DeviceOps>(
@@ -294,10 +881,23 @@ impl DeviceOps for MainContext {
         paths: Vec1<P>,
         params: DiskEnrolmentParams,
         backup_db: Option<BackupPrompt<BCtx>>,
+    ) -> Result<Vec1<DbEntry>> {
+        let entries = self.enroll_disks_unsaved(db, paths, params, backup_db)?;
+        self.save_enrolled_db(db)?;
+        Ok(entries)
+    }
+
+    fn enroll_disks_unsaved<P: AsRef<Path>, BCtx: DeviceOps>(
+        &self,
+        db: &mut PeroxideDb,
+        paths: Vec1<P>,
+        params: DiskEnrolmentParams,
+        backup_db: Option<BackupPrompt<BCtx>>,
     ) -> Result<Vec1<DbEntry>> {
         let path_count = paths.len();
         let paths_with_existing_uuids = paths.mapped(|p| {
-            let uuid_opt = p.luks_uuid().ok();
+            // the devices that are not formatted yet cannot be loaded, so only their header is probed
+            let uuid_opt = luks_uuid_via_blkid(p.as_ref()).ok();
             (p, uuid_opt)
         });
 
@@ -306,10 +906,10 @@ impl DeviceOps for MainContext {
             if let Some(uuid) = uuid_opt {
                 if db.entry_exists(uuid) {
                     // validate: entry cannot exist twice
-                    return Err(EntryAlreadyExistsSnafu { uuid: uuid.clone() }.build());
+                    return Err(EntryAlreadyExistsSnafu { uuid: *uuid }.build());
                 } else if params.format && !params.force_format {
                     // validate: container should not be already formatted
-                    return Err(DeviceAlreadyFormattedSnafu { uuid: uuid.clone() }.build());
+                    return Err(DeviceAlreadyFormattedSnafu { uuid: *uuid }.build());
                 }
                 count_formatted += 1;
             }
@@ -320,13 +920,17 @@ impl DeviceOps for MainContext {
             return Err(NotAllDisksAlreadyFormattedSnafu.build());
         }
 
+        if !params.format {
+            // validate: no keyslot can be added to a header that is being reencrypted
+            for (p, _) in paths_with_existing_uuids.iter() {
+                ensure_not_reencrypting(p.as_ref()).context(DeviceSnafu)?;
+            }
+        }
+
         let paths_with_volume_ids = paths_with_existing_uuids.mapped(|(p, uuid_opt)| {
             // don't give the same name to all the disks if len(disks) > 1
             let name_opt = if path_count == 1 { params.name.clone() } else { None };
-            (
-                p,
-                VolumeId::of(name_opt, uuid_opt.clone().unwrap_or_else(|| Uuid::new_v4())),
-            )
+            (p, VolumeId::of(name_opt, uuid_opt.unwrap_or_else(Uuid::new_v4)))
         });
 
         {
@@ -345,97 +949,577 @@ impl DeviceOps for MainContext {
         // 2. prompt for old/new key(s)
         // 3. add the entry to the db
 
-        let mut entries_with_path =
-            paths_with_volume_ids.mapped(|(p, volume_id)| (p, entry_from(volume_id, params.entry.clone())));
-        // TODO: first entry is used for all the enrollment, this matters especially for Yubikey UUID handling as it's order dependent
-        let first_entry = &entries_with_path.first().1;
-
-        let _keyslots = if params.format {
-            let new_key = prompt_new_key(self, first_entry)?;
-            entries_with_path.try_mapped_mut(|(disk_path, entry)| {
-                format_container(disk_path, entry, &mut params.format_params.clone(), &new_key)
-            })?
+        // a FIDO2 credential is created once so that all the disks share the same key
+        let fido2_credential = if let EntryParams::Fido2 = params.entry {
+            Some(new_fido2_credential(&self.key_input_config).context(KeyInputSnafu)?)
+        } else {
+            None
+        };
+        // the Yubikey serial is recorded so that the same key is used when opening the disks
+        let serial = if let EntryParams::Yubikey(..) = params.entry {
+            yubikey_serial()
         } else {
-            let prev_key = prompt_old_key(self, backup_db, first_entry.volume_id())?;
-            let new_key = prompt_new_key(self, first_entry)?;
-
-            entries_with_path.try_mapped_ref(|(disk_path, _)| {
-                (*disk_path)
-                    .luks_add_key(params.iteration_ms as usize, &new_key, &prev_key, &params.format_params)
-                    .context(DeviceSnafu)
-            })?
+            None
+        };
+
+        let mut entries_with_path = paths_with_volume_ids.mapped(|(p, volume_id)| {
+            let entry = entry_from(volume_id, params.entry.clone(), fido2_credential.clone(), serial);
+            (p, entry)
+        });
+        let keys = EnrolmentKeys::prompt(
+            self,
+            backup_db,
+            &entries_with_path.first().1,
+            params.format,
+            params.volume_key_file.as_deref(),
+        )?;
+
+        // the recovery passphrase is shown before any disk is changed, so that failing to save it changes nothing
+        let recovery_passphrase = match params.recovery {
+            Some(ref output) if !self.dry_run => Some(show_recovery_passphrase(output)?),
+            _ => None,
         };
 
+        // in dry-run mode the keys are still prompted for (and looked up in the backup db), but nothing is written
+        if self.dry_run {
+            for (disk_path, entry) in entries_with_path.iter() {
+                if params.recovery.is_some() {
+                    println!(
+                        "[dry-run] would add a recovery passphrase to {}",
+                        disk_path.as_ref().display()
+                    );
+                }
+                if params.format {
+                    println!(
+                        "[dry-run] would format {} with uuid {} using the new key",
+                        disk_path.as_ref().display(),
+                        entry.uuid()
+                    );
+                } else {
+                    println!("[dry-run] would add the new key to {}", disk_path.as_ref().display());
+                }
+            }
+        } else {
+            entries_with_path.try_mapped_mut(|(disk_path, entry)| -> Result<()> {
+                // deriving the key of the new keyslot can take seconds (argon2 with a lot of memory)
+                let progress = messages::ProgressGuard::start(format!(
+                    "Adding the new key to {}...",
+                    disk_path.as_ref().display()
+                ));
+                let keyslot = match keys.old_key {
+                    Some(ref old_key) => (*disk_path)
+                        .luks_add_key(
+                            params.iteration_ms as usize,
+                            &keys.new_key,
+                            old_key,
+                            &params.format_params,
+                            params.keyslot,
+                            params.priority,
+                        )
+                        .context(DeviceSnafu)?,
+                    None => format_container(
+                        disk_path,
+                        entry,
+                        &mut params.format_params.clone(),
+                        &keys.new_key,
+                        params.keyslot,
+                        params.priority,
+                    )?,
+                };
+                drop(progress);
+                // the keyslot is recorded so that rotation and removal target it deterministically
+                entry.volume_id_mut().keyslot = Some(keyslot);
+                entry.volume_id_mut().keyslot_priority = params.priority;
+                entry.volume_id_mut().set_enrolled_now();
+                disk_log!(
+                    Level::Info,
+                    "enroll",
+                    entry.uuid(),
+                    "Added the new key to keyslot {} of {}",
+                    keyslot,
+                    disk_path.as_ref().display()
+                );
+                Ok(())
+            })?;
+        }
+
+        let recovery_entries = match recovery_passphrase {
+            Some(ref passphrase) => add_recovery_keyslots(&entries_with_path, &keys.new_key, passphrase, &params)?,
+            None => vec![],
+        };
         let entries = entries_with_path.mapped(|e| e.1);
-        db.entries.extend_from_slice(entries.as_slice());
-        self.save_db(&db)?;
+        if self.dry_run {
+            for entry in entries.iter() {
+                println!("[dry-run] would add {} to the database", entry.volume_id());
+            }
+        } else {
+            db.entries.extend_from_slice(entries.as_slice());
+            db.entries.extend(recovery_entries);
+        }
 
         Ok(entries)
     }
 
+    fn save_enrolled_db(&self, db: &PeroxideDb) -> Result<()> {
+        if self.dry_run {
+            Ok(())
+        } else {
+            self.save_db_unless_disabled(db)
+        }
+    }
+
+    fn format_disks<P: AsRef<Path>>(&self, paths: Vec1<P>, params: DiskEnrolmentParams) -> Result<Vec1<(P, Uuid)>> {
+        // the key of the other entry types cannot be derived again without the entry
+        ensure!(
+            matches!(params.entry, EntryParams::Keyfile(..) | EntryParams::Passphrase),
+            FormatOnlyKeySnafu
+        );
+
+        for path in paths.iter() {
+            if let Ok(uuid) = luks_uuid_via_blkid(path.as_ref()) {
+                // validate: container should not be already formatted
+                ensure!(params.force_format, DeviceAlreadyFormattedSnafu { uuid });
+            }
+        }
+
+        let path_count = paths.len();
+        let mut entries_with_path = paths.mapped(|p| {
+            let name_opt = if path_count == 1 { params.name.clone() } else { None };
+            let entry = entry_from(VolumeId::of(name_opt, Uuid::new_v4()), params.entry.clone(), None, None);
+            (p, entry)
+        });
+        let key = prompt_new_key(self, &entries_with_path.first().1)?;
+
+        if self.dry_run {
+            for (disk_path, entry) in entries_with_path.iter() {
+                println!(
+                    "[dry-run] would format {} with uuid {} using the new key",
+                    disk_path.as_ref().display(),
+                    entry.uuid()
+                );
+            }
+        } else {
+            entries_with_path.try_mapped_mut(|(disk_path, entry)| -> Result<()> {
+                let progress =
+                    messages::ProgressGuard::start(format!("Formatting {}...", disk_path.as_ref().display()));
+                let keyslot = format_container(
+                    disk_path,
+                    entry,
+                    &mut params.format_params.clone(),
+                    &key,
+                    params.keyslot,
+                    params.priority,
+                )?;
+                drop(progress);
+                disk_log!(
+                    Level::Info,
+                    "format",
+                    entry.uuid(),
+                    "Formatted {} with the new key in keyslot {}",
+                    disk_path.as_ref().display(),
+                    keyslot
+                );
+                Ok(())
+            })?;
+        }
+
+        Ok(entries_with_path.mapped(|(p, entry)| (p, entry.uuid().to_owned())))
+    }
+
     fn open_disks<P: AsRef<Path>>(
         &self,
         db: &PeroxideDb,
         paths: Vec1<P>,
-        name_override: Option<String>,
+        name_override: Option<NameOverride>,
+        flags: ActivationFlags,
     ) -> Result<Vec1<DeviceMapperName>> {
-        let paths_with_uuid = paths
-            .try_mapped(|p| p.luks_uuid().map(|uuid| (p, uuid)))
+        self.open_disks_using(db, paths, name_override, flags, None)
+    }
+
+    fn open_disks_with_key<P: AsRef<Path>>(
+        &self,
+        db: &PeroxideDb,
+        paths: Vec1<P>,
+        name_override: Option<NameOverride>,
+        flags: ActivationFlags,
+        key: &SecStr,
+    ) -> Result<Vec1<DeviceMapperName>> {
+        self.open_disks_using(db, paths, name_override, flags, Some(key))
+    }
+
+    fn open_entries(&self, entries: &[&DbEntry], flags: ActivationFlags) -> Vec<Result<DeviceMapperName>> {
+        let mut shared_passphrase: Option<SecStr> = None;
+        let mut results = Vec::with_capacity(entries.len());
+        for entry in entries {
+            if !matches!(entry, DbEntry::PassphraseEntry { .. }) {
+                results.push(self.activate(entry, None, None::<&Path>, flags));
+                continue;
+            }
+
+            let activate = |key: &SecStr| self.activate_with_key(entry, key, None, None::<&Path>, flags);
+            if let Some(ref passphrase) = shared_passphrase {
+                match activate(passphrase) {
+                    Err(Error::DeviceError { ref source }) if source.is_wrong_key() => disk_log!(
+                        Level::Debug,
+                        "open",
+                        entry.uuid(),
+                        "The passphrase of the previous disk does not open this one"
+                    ),
+                    res => {
+                        results.push(res);
+                        continue;
+                    }
+                }
+            }
+            results.push(
+                self.activate_with_retries(entry, None, activate)
+                    .map(|(name, passphrase)| {
+                        shared_passphrase = Some(passphrase);
+                        name
+                    }),
+            );
+        }
+        results
+    }
+
+    fn test_open_disks<P: AsRef<Path>>(
+        &self,
+        db: &PeroxideDb,
+        paths: &[P],
+        given_key: Option<&SecStr>,
+    ) -> Vec<Result<(Uuid, Keyslot)>> {
+        let devices = OpenedDevices::new();
+        paths
+            .iter()
+            .map(|path| {
+                let device = devices.device(path);
+                let uuid = device.luks_uuid().context(DeviceSnafu)?;
+                let entry = db.find_entry(&uuid).context(DiskEntryNotFoundSnafu { uuid })?;
+                check_luks_version(&device, entry)?;
+
+                // a key that does not unlock any keyslot is a wrong key, so that a passphrase can be entered again
+                let test = |key: &SecStr| -> Result<Keyslot> {
+                    match device.luks_test_key(key).context(DeviceSnafu)? {
+                        Some(keyslot) => Ok(keyslot),
+                        None => WrongKeySnafu { uuid }.fail(),
+                    }
+                };
+                let keyslot = match given_key {
+                    Some(key) => test(key)?,
+                    None => self.activate_with_retries(entry, None, test)?.0,
+                };
+                disk_log!(Level::Debug, "open", uuid, "The key unlocks keyslot {}", keyslot);
+                Ok((uuid, keyslot))
+            })
+            .collect()
+    }
+
+    fn rotate_key<P: AsRef<Path>>(
+        &self,
+        db: &mut PeroxideDb,
+        disk_path: P,
+        iteration_ms: u32,
+        mut params: FormatContainerParams,
+        new_key_file: Option<PathBuf>,
+    ) -> Result<DbEntry> {
+        // the device is opened once for all the steps below (and closed when `devices` is dropped)
+        let devices = OpenedDevices::new();
+        let device = devices.device(&disk_path);
+        let uuid = device.luks_uuid().context(DeviceSnafu)?;
+        let prev_entry = db.find_entry(&uuid).context(DiskEntryNotFoundSnafu { uuid })?.clone();
+        // validate: a new keyslot with the same key would leave the key that is replaced working
+        ensure!(key_can_be_rotated(&prev_entry), KeyNotRotatableSnafu { uuid });
+        let known_keyslot = match entry_keyslot(&device, &prev_entry) {
+            Ok(keyslot) => Some(keyslot),
+            Err(Error::KeyslotNotFoundError { .. }) => None,
+            Err(e) => return Err(e),
+        };
+
+        // reading the same keyfile again would only give the previous key
+        let mut entry = match (&prev_entry, new_key_file) {
+            (DbEntry::KeyfileEntry { volume_id, .. }, Some(key_file)) => DbEntry::KeyfileEntry {
+                key_file,
+                key_offset: None,
+                key_size: None,
+                volume_id: volume_id.clone(),
+            },
+            (DbEntry::KeyfileEntry { .. }, None) => return NewKeyfileRequiredSnafu { uuid }.fail(),
+            (_, Some(_)) => return NewKeyfileUnexpectedSnafu { uuid }.fail(),
+            (_, None) => prev_entry.clone(),
+        };
+
+        let prev_key = self.prompt_key(&prev_entry, None, false)?;
+        let prev_keyslot = match known_keyslot {
+            // the key must be that of the keyslot, any other key of the disk would remove a keyslot it does not own
+            Some(keyslot) => {
+                ensure!(
+                    device
+                        .luks_key_unlocks_keyslot(&prev_key, keyslot)
+                        .context(DeviceSnafu)?,
+                    WrongKeyslotKeySnafu { uuid, keyslot }
+                );
+                keyslot
+            }
+            // the keyslot is not known up front (e.g. LUKS1), so find out which one the key unlocks
+            None => device.luks_keyslot_for_key(&prev_key).context(DeviceSnafu)?,
+        };
+        let new_key = prompt_new_key(self, &entry)?;
+
+        // keep the token (if any) assigned to the new keyslot
+        if let FormatContainerParams::Luks2 { token_id, .. } = &mut params {
+            *token_id = entry.volume_id().luks2_token_id;
+        }
+
+        // if adding the new key fails, the previous key is left intact (the new keyslot keeps the priority)
+        let priority = entry.volume_id().keyslot_priority();
+        let keyslot = device
+            .luks_add_key(
+                iteration_ms as usize,
+                &new_key,
+                &PreviousKey::Key(prev_key),
+                &params,
+                None,
+                priority,
+            )
             .context(DeviceSnafu)?;
-        let uuids = {
-            let mut uuids = paths_with_uuid.mapped_ref(|pu| pu.1.to_owned());
-            uuids.sort();
-            uuids.dedup();
-            uuids
+        device.luks_remove_keyslot(prev_keyslot).context(DeviceSnafu)?;
+        disk_log!(
+            Level::Info,
+            "rotate",
+            uuid,
+            "Replaced keyslot {} with keyslot {}",
+            prev_keyslot,
+            keyslot
+        );
+
+        entry.volume_id_mut().keyslot = Some(keyslot);
+        if let Some(db_entry) = db.find_entry_mut(&uuid) {
+            *db_entry = entry.clone();
+        }
+        self.save_db_unless_disabled(db)?;
+
+        Ok(entry)
+    }
+
+    fn remove_enrolment<P: AsRef<Path>>(&self, db: &mut PeroxideDb, disk_path: P) -> Result<DbEntry> {
+        let devices = OpenedDevices::new();
+        let device = devices.device(&disk_path);
+        let uuid = device.luks_uuid().context(DeviceSnafu)?;
+        let entry = db.find_entry(&uuid).context(DiskEntryNotFoundSnafu { uuid })?.clone();
+
+        let key = self.prompt_key(&entry, None, false)?;
+        let keyslot = match entry_keyslot(&device, &entry) {
+            // the key must be that of the keyslot, any other key of the disk would remove a keyslot it does not own
+            Ok(keyslot) => {
+                ensure!(
+                    device.luks_key_unlocks_keyslot(&key, keyslot).context(DeviceSnafu)?,
+                    WrongKeyslotKeySnafu { uuid, keyslot }
+                );
+                keyslot
+            }
+            // the keyslot is not known up front (e.g. LUKS1), so find out which one the key unlocks
+            Err(Error::KeyslotNotFoundError { .. }) => device.luks_keyslot_for_key(&key).context(DeviceSnafu)?,
+            Err(e) => return Err(e),
         };
 
-        if uuids.len() != paths_with_uuid.len() {
-            return Err(DiskIdDuplicatesFoundSnafu.build());
+        // validate: removing the last keyslot would make the disk impossible to unlock
+        let active_keyslots = device.luks_active_keyslots().context(DeviceSnafu)?;
+        if active_keyslots.len() <= 1 {
+            return Err(LastKeyslotSnafu { uuid }.build());
         }
 
-        let paths_with_disk_entries = paths_with_uuid.try_mapped(|pu| match db.find_entry(&pu.1) {
-            Some(entry) => Ok((pu.0, entry)),
-            None => Err(DiskEntryNotFoundSnafu { uuid: pu.1.clone() }.build()),
-        })?;
+        device.luks_remove_keyslot(keyslot).context(DeviceSnafu)?;
+        disk_log!(Level::Info, "remove", uuid, "Removed keyslot {}", keyslot);
 
-        if paths_with_disk_entries.len() == 1 {
-            let ((first_path, first_entry), _) = paths_with_disk_entries.split_off_first();
-            self.activate(first_entry, name_override, Some(first_path))
-                .map(Vec1::new)
-        } else {
-            // activate all the entries with the first key
-            // todo: document that this means yubikey disks have all the same key (because tied to uuid of the disk)
-            let key = self.prompt_key(&paths_with_disk_entries.first().1, None, false)?;
+        // the recovery keyslot (if any) is still on the disk, so its entry is kept
+        db.entries.retain(|e| e.uuid() != &uuid || e.volume_id().is_recovery());
+        self.save_db(db)?;
+
+        Ok(entry)
+    }
+
+    fn kill_keyslot<P: AsRef<Path>>(
+        &self,
+        db: &mut PeroxideDb,
+        disk_path: P,
+        keyslot: Keyslot,
+    ) -> Result<(Keyslot, Vec<DbEntry>)> {
+        let devices = OpenedDevices::new();
+        let device = devices.device(&disk_path);
+        let uuid = device.luks_uuid().context(DeviceSnafu)?;
+
+        // validate: the keyslot has to be in use, and destroying the last one would make the disk impossible to unlock
+        let active_keyslots = device.luks_active_keyslots().context(DeviceSnafu)?;
+        ensure!(
+            active_keyslots.contains(&keyslot),
+            KeyslotNotActiveSnafu { uuid, keyslot }
+        );
+        ensure!(active_keyslots.len() > 1, LastKeyslotSnafu { uuid });
 
-            let res = paths_with_disk_entries
-                .into_iter()
-                .enumerate()
-                .map(|(idx, (path, db_entry))| {
-                    // if override name is provided, all disks will start with the same prefix and will be identified by index
-                    let name = name_override.as_ref().map(|name| format!("{}_{}", name, idx));
-                    self.activate_with_key(&db_entry, &key, name, Some(path))
-                })
-                .collect::<Result<Vec<DeviceMapperName>>>()?;
+        // the key of an entry that is known to be in another keyslot is used, otherwise any passphrase of the disk
+        let entry = db
+            .entries
+            .iter()
+            .find(|e| {
+                e.uuid() == &uuid
+                    && e.volume_id()
+                        .keyslot()
+                        .map_or(false, |k| k != keyslot && active_keyslots.contains(&k))
+            })
+            .cloned()
+            .unwrap_or_else(|| DbEntry::PassphraseEntry {
+                volume_id: VolumeId::of(None, uuid),
+            });
+        let test = |key: &SecStr| -> Result<Keyslot> {
+            match device.luks_test_key(key).context(DeviceSnafu)? {
+                Some(keyslot) => Ok(keyslot),
+                None => WrongKeySnafu { uuid }.fail(),
+            }
+        };
+        let (authorized_by, _) = self.activate_with_retries(&entry, None, test)?;
+        ensure!(authorized_by != keyslot, SameKeyslotSnafu { uuid, keyslot });
+
+        device.luks_remove_keyslot(keyslot).context(DeviceSnafu)?;
+        disk_log!(
+            Level::Info,
+            "remove",
+            uuid,
+            "Destroyed keyslot {} with the key of keyslot {}",
+            keyslot,
+            authorized_by
+        );
 
-            Ok(Vec1::try_from_vec(res).expect("non-empty vec"))
+        // entries without a recorded keyslot are kept, as it is not known whether their key was in this one
+        let (removed, kept): (Vec<DbEntry>, Vec<DbEntry>) = db
+            .entries
+            .drain(..)
+            .partition(|e| e.uuid() == &uuid && e.volume_id().keyslot() == Some(keyslot));
+        db.entries = kept;
+        if !removed.is_empty() {
+            self.save_db(db)?;
         }
+
+        Ok((authorized_by, removed))
     }
 
-    fn is_active(entry: &DbEntry, name_override: Option<String>) -> bool {
-        let name_opt = entry.volume_id().name.to_owned().or(name_override);
-        if let Some(name) = name_opt {
-            Disks::is_device_active(&name)
-        } else {
-            false
+    fn wipe_keyslot<P: AsRef<Path>>(
+        &self,
+        db: &mut PeroxideDb,
+        disk_path: P,
+        keyslot: Keyslot,
+    ) -> Result<Vec<DbEntry>> {
+        let devices = OpenedDevices::new();
+        let device = devices.device(&disk_path);
+        let uuid = device.luks_uuid().context(DeviceSnafu)?;
+
+        // validate: the keyslot has to be in use, and destroying the last one would make the disk impossible to unlock
+        let active_keyslots = device.luks_active_keyslots().context(DeviceSnafu)?;
+        ensure!(
+            active_keyslots.contains(&keyslot),
+            KeyslotNotActiveSnafu { uuid, keyslot }
+        );
+        ensure!(active_keyslots.len() > 1, LastKeyslotSnafu { uuid });
+
+        device.luks_remove_keyslot(keyslot).context(DeviceSnafu)?;
+        disk_log!(Level::Info, "wipe-keyslot", uuid, "Destroyed keyslot {}", keyslot);
+
+        // entries without a recorded keyslot are kept, as it is not known whether their key was in this one
+        let (removed, kept): (Vec<DbEntry>, Vec<DbEntry>) = db
+            .entries
+            .drain(..)
+            .partition(|e| e.uuid() == &uuid && e.volume_id().keyslot() == Some(keyslot));
+        db.entries = kept;
+        if !removed.is_empty() {
+            self.save_db(db)?;
         }
+
+        Ok(removed)
     }
 
-    fn is_present(entry: &DbEntry) -> bool {
-        if let Ok(_) = Disks::disk_uuid_path(entry.uuid()) {
-            true
-        } else {
-            false
+    fn migrate<P: AsRef<Path>>(&self, db: &mut PeroxideDb, disk_path: P) -> Result<DbEntry> {
+        let devices = OpenedDevices::new();
+        let device = devices.device(&disk_path);
+        let uuid = device.luks_uuid().context(DeviceSnafu)?;
+        let mut entry = db.find_entry(&uuid).context(DiskEntryNotFoundSnafu { uuid })?.clone();
+
+        // validate: the header cannot be rewritten under an active mapping, whatever its name
+        let active_mappings = self.disks.scan_active().context(DeviceSnafu)?;
+        if let Some(mapping) = active_mappings.into_iter().find(|m| m.underlying_uuid == uuid) {
+            return Err(DeviceAlreadyActivatedSnafu { name: mapping.name }.build());
+        }
+        let actual = device.luks_version().context(DeviceSnafu)?;
+        ensure!(
+            actual == LuksVersion::Luks1,
+            LuksVersionMismatchSnafu {
+                uuid,
+                expected: LuksVersion::Luks1,
+                actual,
+            }
+        );
+
+        // the key proves that the disk belongs to the entry before its header is changed
+        let key = self.prompt_key(&entry, None, false)?;
+        let keyslot = device.luks_keyslot_for_key(&key).context(DeviceSnafu)?;
+
+        if self.dry_run {
+            println!(
+                "[dry-run] would convert {} to LUKS2 and add a token to keyslot {}",
+                disk_path.as_ref().display(),
+                keyslot
+            );
+            return Ok(entry);
         }
+
+        device.luks_convert_to_luks2().context(DeviceSnafu)?;
+        // the disk can be opened without a token, so the entry is only updated once the token is there
+        let token_id = device.luks2_add_token(keyslot).context(DeviceSnafu)?;
+        disk_log!(
+            Level::Info,
+            "migrate",
+            uuid,
+            "Converted to LUKS2 and added token {} for keyslot {}",
+            token_id,
+            keyslot
+        );
+
+        entry.volume_id_mut().keyslot = Some(keyslot);
+        entry.volume_id_mut().luks2_token_id = Some(token_id);
+        if let Some(db_entry) = db.find_entry_mut(&uuid) {
+            *db_entry = entry.clone();
+        }
+        self.save_db_unless_disabled(db)?;
+
+        Ok(entry)
+    }
+
+    fn test_key<P: AsRef<Path>>(&self, db: &PeroxideDb, disk_path: P) -> Result<(Uuid, Option<Keyslot>)> {
+        let devices = OpenedDevices::new();
+        let device = devices.device(&disk_path);
+        let uuid = device.luks_uuid().context(DeviceSnafu)?;
+        let entry = db
+            .find_entry(&uuid)
+            .cloned()
+            .unwrap_or_else(|| DbEntry::PassphraseEntry {
+                volume_id: VolumeId::of(None, uuid),
+            });
+
+        let key = self.prompt_key(&entry, None, false)?;
+        let keyslot = device.luks_test_key(&key).context(DeviceSnafu)?;
+        disk_log!(Level::Debug, "test-key", uuid, "The key unlocks keyslot {:?}", keyslot);
+        Ok((uuid, keyslot))
+    }
+
+    fn is_active(&self, entry: &DbEntry, name_override: Option<String>) -> bool {
+        let name_opt = entry.volume_id().name.to_owned().or(name_override);
+        match name_opt.map(|name| self.disks.active_device_info(&name)) {
+            // a mapping of that name for another disk means that the entry is stale
+            Some(Ok(Some(mapping))) => &mapping.underlying_uuid == entry.uuid(),
+            _ => false,
+        }
+    }
+
+    fn is_present(&self, entry: &DbEntry) -> bool {
+        self.disks.disk_uuid_path(entry.uuid(), &self.block_device_scan).is_ok()
     }
 }
 
@@ -446,8 +1530,14 @@ pub trait DatabaseOps {
     /// Find an entry by uuid
     fn find_entry(&self, uuid: &Uuid) -> Option<&DbEntry>;
 
+    /// Find an entry by uuid (for modification)
+    fn find_entry_mut(&mut self, uuid: &Uuid) -> Option<&mut DbEntry>;
+
     /// Find an entry by name
     fn find_entry_by_name(&self, name: &str) -> Option<&DbEntry>;
+
+    /// Find all the entries with the name (names are not unique, e.g. after importing another database)
+    fn find_entries_by_name(&self, name: &str) -> Vec<&DbEntry>;
 }
 
 impl DatabaseOps for PeroxideDb {
@@ -456,13 +1546,27 @@ impl DatabaseOps for PeroxideDb {
     }
 
     fn find_entry(&self, uuid: &Uuid) -> Option<&DbEntry> {
-        self.entries.iter().find(|&e| e.volume_id().uuid() == uuid)
+        // recovery entries share the uuid of the entry of their disk, but are never the one used to open it
+        self.entries
+            .iter()
+            .find(|&e| e.volume_id().uuid() == uuid && !e.volume_id().is_recovery())
+    }
+
+    fn find_entry_mut(&mut self, uuid: &Uuid) -> Option<&mut DbEntry> {
+        self.entries
+            .iter_mut()
+            .find(|e| e.volume_id().uuid() == uuid && !e.volume_id().is_recovery())
     }
 
     fn find_entry_by_name(&self, name: &str) -> Option<&DbEntry> {
+        self.find_entries_by_name(name).into_iter().next()
+    }
+
+    fn find_entries_by_name(&self, name: &str) -> Vec<&DbEntry> {
         self.entries
             .iter()
-            .find(|&e| matches!(&e.volume_id().name, Some(n) if n == name))
+            .filter(|&e| matches!(&e.volume_id().name, Some(n) if n == name) && !e.volume_id().is_recovery())
+            .collect()
     }
 }
 
@@ -470,6 +1574,32 @@ impl DatabaseOps for PeroxideDb {
 pub struct MainContext {
     pub db_path: PathBuf,
     pub key_input_config: KeyInputConfig,
+    /// Flag to only print what enrolling or opening disks would do (keys are still prompted for)
+    pub dry_run: bool,
+    /// Flag to change the disks when enrolling or rotating keys, but not save the database
+    pub no_db_save: bool,
+    /// Flag to use the database without locking it (e.g. when the lock file cannot be created)
+    pub no_lock: bool,
+    /// Number of attempts at entering the passphrase of a passphrase entry interactively when activating it
+    pub activation_tries: u32,
+    /// Lookups of the disks and their mappings (the real ones unless replaced with `set_disk_inspector`)
+    disks: Box<dyn DiskInspector>,
+    /// Input of the keys of all entries, whatever their type (the input of each entry type unless set with
+    /// `set_key_input`)
+    key_input: Option<KeyInputOverride>,
+    /// Block devices scanned for LUKS headers when a disk is not found by uuid (shared by the disks of an operation)
+    block_device_scan: BlockDeviceScan,
+    /// Lock on the database, held from the time it is opened until the context is dropped
+    db_lock: RefCell<Option<DbLock>>,
+}
+
+/// Input of the keys of all entries of a context (key inputs are not `Debug`, unlike the context)
+struct KeyInputOverride(Box<dyn KeyInput>);
+
+impl fmt::Debug for KeyInputOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyInputOverride")
+    }
 }
 
 impl MainContext {
@@ -478,11 +1608,718 @@ impl MainContext {
             db_path,
             key_input_config: KeyInputConfig {
                 password_input_timeout: Some(Duration::new(30, 0)),
+                passphrase_input: PassphraseInput::Prompt,
+                use_pinentry: true,
             },
+            dry_run: false,
+            no_db_save: false,
+            no_lock: false,
+            activation_tries: DEFAULT_ACTIVATION_TRIES,
+            disks: Box::new(Disks),
+            key_input: None,
+            block_device_scan: BlockDeviceScan::new(),
+            db_lock: RefCell::new(None),
+        }
+    }
+
+    /// Look up the disks and their mappings with the inspector instead of on the system (e.g. to test with fake disks)
+    pub fn set_disk_inspector(&mut self, disks: Box<dyn DiskInspector>) {
+        self.disks = disks;
+    }
+
+    /// Read the keys of all entries with the input instead of the one of their type (e.g. to test without prompting)
+    pub fn set_key_input(&mut self, key_input: Box<dyn KeyInput>) {
+        self.key_input = Some(KeyInputOverride(key_input));
+    }
+
+    /// Save the database after its disks were changed, unless saving was disabled (which only prints a warning)
+    fn save_db_unless_disabled(&self, db: &PeroxideDb) -> Result<()> {
+        if self.no_db_save {
+            warn_db_not_saved();
+            Ok(())
+        } else {
+            self.save_db(db)
         }
     }
 
     pub fn trace_on() {
         cryptsetup_rs::enable_debug(true);
     }
+
+    /// Version of the libcryptsetup library that peroxs was built against (if pkg-config found it at build time)
+    pub fn cryptsetup_version() -> Option<String> {
+        option_env!("PEROXS_LIBCRYPTSETUP_VERSION").map(str::to_string)
+    }
+
+    /// The entries of the database with the status of their disks on the system (in the order of the database)
+    pub fn entries_with_status(&self, db: &PeroxideDb) -> Vec<EntryStatus> {
+        entries_with_status(db, self.disks.as_ref(), &self.block_device_scan)
+    }
+
+    /// Open the disk of the database entry with the given name (prompting for its key), under that name
+    pub fn open_by_name(&self, db: &PeroxideDb, name: &str) -> Result<DeviceMapperName> {
+        let path = disk_path_for_name(db, name)?;
+        let mappings = self.open_disks(db, Vec1::new(path), None, ActivationFlags::default())?;
+        Ok(mappings.first().to_owned())
+    }
+
+    /// Open the disks with the key prompted for with the first of them, or with the given key (for all of them)
+    fn open_disks_using<P: AsRef<Path>>(
+        &self,
+        db: &PeroxideDb,
+        paths: Vec1<P>,
+        name_override: Option<NameOverride>,
+        flags: ActivationFlags,
+        given_key: Option<&SecStr>,
+    ) -> Result<Vec1<DeviceMapperName>> {
+        // each device is opened once for reading its uuid and activating it (and closed when `devices` is dropped)
+        let devices = OpenedDevices::new();
+        let paths_with_uuid = paths
+            .try_mapped(|p| devices.device(&p).luks_uuid().map(|uuid| (p, uuid)))
+            .context(DeviceSnafu)?;
+        let uuids = {
+            let mut uuids = paths_with_uuid.mapped_ref(|pu| pu.1.to_owned());
+            uuids.sort();
+            uuids.dedup();
+            uuids
+        };
+
+        if uuids.len() != paths_with_uuid.len() {
+            return Err(DiskIdDuplicatesFoundSnafu.build());
+        }
+
+        let paths_with_disk_entries = paths_with_uuid.try_mapped(|pu| match db.find_entry(&pu.1) {
+            Some(entry) => Ok((pu.0, entry)),
+            None => Err(DiskEntryNotFoundSnafu { uuid: pu.1 }.build()),
+        })?;
+
+        let count = paths_with_disk_entries.len();
+        // with a name prefix, all disks will start with the same prefix and will be identified by index
+        let name_for = |idx| name_override.as_ref().and_then(|n| n.for_disk(idx, count));
+        let ((first_path, first_entry), rest) = paths_with_disk_entries.split_off_first();
+
+        // the key is prompted for with the name of the mapping only when opening a single disk
+        let first_name = name_for(0);
+        let prompt_name = if count == 1 { first_name.clone() } else { None };
+        let activate_first = |key: &SecStr| {
+            let device = devices.device(&first_path);
+            self.activate_device(
+                &device,
+                first_path.as_ref(),
+                first_entry,
+                mapping_name(first_entry, first_name.clone()),
+                key,
+                flags,
+            )
+        };
+        let (first_mapping, key) = match given_key {
+            Some(key) => (activate_first(key)?, key.clone()),
+            None => self.activate_with_retries(first_entry, prompt_name, activate_first)?,
+        };
+
+        // activate all the other entries with the first key
+        // todo: document that this means yubikey disks have all the same key (because tied to uuid of the disk)
+        let mut mappings = Vec1::new(first_mapping);
+        for (idx, (path, db_entry)) in rest.into_iter().enumerate() {
+            let device = devices.device(&path);
+            mappings.push(self.activate_device(
+                &device,
+                path.as_ref(),
+                db_entry,
+                mapping_name(db_entry, name_for(idx + 1)),
+                &key,
+                flags,
+            )?);
+        }
+        Ok(mappings)
+    }
+
+    /// Prompt for the key to activate the disk of an entry with
+    fn activation_key(&self, entry: &DbEntry, name_override: Option<String>) -> Result<SecStr> {
+        self.entry_key(entry, name_override, None, false)
+    }
+
+    /// Read the key of an entry with the key input that replaces all the others if one is set, and with the input of
+    /// the entry type otherwise
+    fn entry_key(
+        &self,
+        entry: &DbEntry,
+        name_override: Option<String>,
+        prompt_override: Option<String>,
+        is_new: bool,
+    ) -> Result<SecStr> {
+        match self.key_input {
+            Some(KeyInputOverride(ref key_input)) => {
+                key_input.get_key(&InputName::of_entry(entry, name_override, prompt_override), is_new)
+            }
+            None => get_key_for(
+                entry,
+                &self.key_input_config,
+                self.db_path.parent().expect("parent path"),
+                name_override,
+                prompt_override,
+                is_new,
+            ),
+        }
+        .context(KeyInputSnafu)
+    }
+
+    /// Prompt for the key of an entry and activate its disk with it (or do anything else that needs the key), prompting
+    /// again after a wrong passphrase
+    ///
+    /// Only passphrases entered interactively are prompted for again (up to `activation_tries` times, each with the
+    /// full input timeout): a keyfile or a token would give the same wrong key every time.
+    fn activate_with_retries<T, F>(
+        &self,
+        entry: &DbEntry,
+        name_override: Option<String>,
+        activate: F,
+    ) -> Result<(T, SecStr)>
+    where
+        F: Fn(&SecStr) -> Result<T>,
+    {
+        let tries = if self.is_interactive_passphrase(entry) {
+            self.activation_tries.max(1)
+        } else {
+            1
+        };
+        let mut attempt = 1;
+        loop {
+            let key = self.activation_key(entry, name_override.clone())?;
+            match activate(&key) {
+                Err(e) if e.is_wrong_key() && attempt < tries => {
+                    disk_log!(
+                        Level::Debug,
+                        "open",
+                        entry.uuid(),
+                        "Wrong key (attempt {} of {})",
+                        attempt,
+                        tries
+                    );
+                    messages::status(format!(
+                        "No key available with this passphrase, please try again ({} of {} attempts left)",
+                        tries - attempt,
+                        tries
+                    ));
+                    attempt += 1;
+                }
+                res => return res.map(|activated| (activated, key)),
+            }
+        }
+    }
+
+    fn is_interactive_passphrase(&self, entry: &DbEntry) -> bool {
+        matches!(entry, DbEntry::PassphraseEntry { .. })
+            && !matches!(
+                self.key_input_config.passphrase_input,
+                PassphraseInput::Stdin | PassphraseInput::Fd(_) | PassphraseInput::Env(_)
+            )
+    }
+
+    /// Activate the device of a disk (found at `device_path`) under the given name with the key
+    fn activate_device<D: LuksVolumeOps>(
+        &self,
+        device: &D,
+        device_path: &Path,
+        entry: &DbEntry,
+        name: DeviceMapperName,
+        key: &SecStr,
+        flags: ActivationFlags,
+    ) -> Result<DeviceMapperName> {
+        if self.disks.is_device_active(name.as_str()) {
+            return Err(DeviceAlreadyActivatedSnafu { name }.build());
+        }
+        check_luks_version(device, entry)?;
+        let flags = entry_activation_flags(flags, entry);
+
+        if self.dry_run {
+            println!(
+                "[dry-run] would activate {} as {} with {:?}",
+                device_path.display(),
+                name,
+                flags
+            );
+            return Ok(name);
+        }
+        disk_log!(
+            Level::Debug,
+            "open",
+            entry.uuid(),
+            "Activating {} as {} with {:?}",
+            device_path.display(),
+            name,
+            flags
+        );
+        if let Err(e) = device.luks_activate_with_flags(name.as_str(), key, flags) {
+            disk_log!(
+                Level::Error,
+                "open",
+                entry.uuid(),
+                "Could not activate {}: {}",
+                device_path.display(),
+                e
+            );
+            return Err(e).context(DeviceSnafu);
+        }
+        messages::detail(format!("Activated {} as /dev/mapper/{}", device_path.display(), name));
+        Ok(name)
+    }
+}
+
+/// A database entry and the status of its disk on the system
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryStatus {
+    pub entry: DbEntry,
+    /// Path of the disk (if present)
+    pub path: Option<PathBuf>,
+    pub present: bool,
+    pub active: bool,
+    /// Name of the active mapping of the disk (if active)
+    pub mapping: Option<String>,
+}
+
+/// The entries of the database with the status of their disks, as looked up by the inspector
+///
+/// A disk is active if it is mapped under its name in the database, or else under any name (all the active mappings
+/// are only scanned for once, and only if needed). Failing lookups count as the disk not being present or active.
+pub fn entries_with_status<D: DiskInspector + ?Sized>(
+    db: &PeroxideDb,
+    disks: &D,
+    scan: &BlockDeviceScan,
+) -> Vec<EntryStatus> {
+    let mut active_mappings: Option<Vec<DmSetupDeviceInfo>> = None;
+    db.entries
+        .iter()
+        .map(|entry| {
+            let mapping = named_mapping(disks, entry).or_else(|| {
+                active_mappings
+                    .get_or_insert_with(|| {
+                        disks.scan_active().unwrap_or_else(|e| {
+                            debug!("Could not scan the active mappings: {}", e);
+                            vec![]
+                        })
+                    })
+                    .iter()
+                    .find(|m| &m.underlying_uuid == entry.uuid())
+                    .map(|m| m.name.clone())
+            });
+            let path = disks.disk_uuid_path(entry.uuid(), scan).ok();
+            EntryStatus {
+                entry: entry.clone(),
+                present: path.is_some(),
+                path,
+                active: mapping.is_some(),
+                mapping,
+            }
+        })
+        .collect()
+}
+
+/// Name of the mapping of a disk if it is active under its name in the database (and not a stale one of another disk)
+fn named_mapping<D: DiskInspector + ?Sized>(disks: &D, entry: &DbEntry) -> Option<String> {
+    let name = entry.volume_id().name.as_ref()?;
+    match disks.active_device_info(name) {
+        Ok(mapping) => mapping.filter(|m| &m.underlying_uuid == entry.uuid()).map(|m| m.name),
+        Err(e) => {
+            debug!("Could not look up the mapping {}: {}", name, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::sync::{mpsc, Mutex};
+    use std::thread;
+
+    use expectest::prelude::*;
+    use log::{LevelFilter, Log, Metadata, Record};
+    use snafu::GenerateImplicitData;
+
+    use crate::db::{ActivationOptions, DbType};
+    use crate::device::tests::{assert_can_activate_devices, luks1_image};
+    use crate::input::Result as InputResult;
+
+    use super::*;
+
+    /// Logger that keeps the lines logged by the tests
+    struct CapturingLogger {
+        lines: Mutex<Vec<String>>,
+    }
+
+    impl Log for CapturingLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.lines.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger {
+        lines: Mutex::new(Vec::new()),
+    };
+
+    /// Key input that records whether each key it was asked for is a new one (returning the old or the new key)
+    struct CountingKeyInput {
+        reads: Rc<RefCell<Vec<bool>>>,
+    }
+
+    impl KeyInput for CountingKeyInput {
+        fn get_key(&self, _name: &InputName, is_new: bool) -> InputResult<SecStr> {
+            self.reads.borrow_mut().push(is_new);
+            Ok(SecStr::from(if is_new { "batch-new" } else { "batch-old" }))
+        }
+    }
+
+    #[test]
+    fn test_database_on_stdin_cannot_be_updated() {
+        let ctx = MainContext::new(PathBuf::from("-"));
+        expect!(ctx.open_db_for_update()).to(be_err());
+    }
+
+    #[test]
+    fn test_database_is_locked_until_the_context_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = MainContext::new(dir.path().join("peroxs-db.json"));
+        let lock_mode = || ctx.db_lock.borrow().as_ref().map(DbLock::mode);
+
+        expect!(ctx.save_db(&PeroxideDb::new(DbType::Operation))).to(be_ok());
+        expect!(lock_mode()).to(be_some().value(LockMode::Exclusive));
+
+        let db = ctx.open_db_for_update().unwrap();
+        expect!(lock_mode()).to(be_some().value(LockMode::Exclusive));
+        // reading the database again keeps the exclusive lock
+        expect!(ctx.open_db()).to(be_ok());
+        expect!(lock_mode()).to(be_some().value(LockMode::Exclusive));
+        expect!(ctx.save_db(&db)).to(be_ok());
+        expect!(lock_mode()).to(be_some().value(LockMode::Exclusive));
+    }
+
+    #[test]
+    fn test_database_stays_locked_between_saves() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("peroxs-db.json");
+        PeroxideDb::new(DbType::Operation).save_to(&db_path).unwrap();
+        let ctx = MainContext::new(db_path.clone());
+        let db = ctx.open_db_for_update().unwrap();
+        expect!(ctx.save_db(&db)).to(be_ok());
+
+        // another process that wants to change the database waits until the whole operation is over
+        let (tx, rx) = mpsc::channel();
+        let waiter = thread::spawn(move || {
+            let lock = DbLock::acquire(&db_path, LockMode::Exclusive);
+            tx.send(lock.is_ok()).unwrap();
+        });
+        expect!(rx.recv_timeout(Duration::from_millis(200))).to(be_err());
+        expect!(ctx.save_db(&db)).to(be_ok());
+        expect!(rx.recv_timeout(Duration::from_millis(200))).to(be_err());
+
+        drop(ctx);
+        expect!(rx.recv_timeout(Duration::from_secs(5))).to(be_ok().value(true));
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    fn test_entry_activation_flags_add_the_entry_options() {
+        let mut entry = DbEntry::PassphraseEntry {
+            volume_id: VolumeId::of(None, Uuid::nil()),
+        };
+        entry.volume_id_mut().set_activation_options(ActivationOptions {
+            allow_discards: true,
+            ..ActivationOptions::default()
+        });
+        let read_only = ActivationFlags {
+            read_only: true,
+            ..ActivationFlags::default()
+        };
+
+        let flags = entry_activation_flags(read_only, &entry);
+        expect!(flags.allow_discards && flags.read_only).to(be_true());
+        let ignored = ActivationFlags {
+            ignore_entry_options: true,
+            ..read_only
+        };
+        expect!(entry_activation_flags(ignored, &entry).allow_discards).to(be_false());
+    }
+
+    /// Disks that are present and mapped without touching the system
+    #[derive(Debug)]
+    struct FakeDisks {
+        present: Vec<Uuid>,
+        mappings: Vec<DmSetupDeviceInfo>,
+    }
+
+    impl DiskInspector for FakeDisks {
+        fn disk_uuid_path(&self, uuid: &Uuid, _scan: &BlockDeviceScan) -> crate::device::Result<PathBuf> {
+            match self.present.contains(uuid) {
+                true => Ok(PathBuf::from(format!("/dev/disk/by-uuid/{}", uuid))),
+                false => Err(DeviceError::DiskNotFoundError {
+                    uuid: uuid.to_owned(),
+                    by_uuid_dir: PathBuf::from("/dev/disk/by-uuid"),
+                    backtrace: Backtrace::generate(),
+                }),
+            }
+        }
+
+        fn active_device_info(&self, name: &str) -> crate::device::Result<Option<DmSetupDeviceInfo>> {
+            Ok(self.mappings.iter().find(|m| m.name == name).cloned())
+        }
+
+        fn scan_active(&self) -> crate::device::Result<Vec<DmSetupDeviceInfo>> {
+            Ok(self.mappings.clone())
+        }
+
+        fn is_device_active(&self, name: &str) -> bool {
+            self.mappings.iter().any(|m| m.name == name)
+        }
+
+        fn is_device_suspended(&self, _name: &str) -> crate::device::Result<bool> {
+            Ok(false)
+        }
+    }
+
+    fn fake_mapping(name: &str, uuid: u128) -> DmSetupDeviceInfo {
+        DmSetupDeviceInfo {
+            dm_name: "dm-0".to_string(),
+            name: name.to_string(),
+            underlying: PathBuf::from("/dev/sda1"),
+            underlying_uuid: Uuid::from_u128(uuid),
+        }
+    }
+
+    fn fake_context(disks: FakeDisks) -> MainContext {
+        let mut ctx = MainContext::new(PathBuf::from("peroxs-db.json"));
+        ctx.set_disk_inspector(Box::new(disks));
+        ctx
+    }
+
+    #[test]
+    #[ignore = "needs root and device-mapper"]
+    fn test_enroll_batch_prompts_for_the_keys_once() {
+        assert_can_activate_devices();
+        let dir = tempfile::tempdir().unwrap();
+        let paths = Vec1::try_from_vec(vec![dir.path().join("disk-0"), dir.path().join("disk-1")]).unwrap();
+        for path in paths.iter() {
+            luks1_image(path, &SecStr::from("batch-old"));
+        }
+        let reads = Rc::new(RefCell::new(vec![]));
+        let mut ctx = MainContext::new(dir.path().join("peroxs-db.json"));
+        ctx.set_key_input(Box::new(CountingKeyInput { reads: reads.clone() }));
+        let params = DiskEnrolmentParams {
+            name: None,
+            entry: EntryParams::Passphrase,
+            format: false,
+            force_format: false,
+            format_params: FormatContainerParams::Luks1 {
+                iteration_ms: 1000,
+                cipher: "aes".to_string(),
+                cipher_mode: "xts-plain64".to_string(),
+                hash: "sha256".to_string(),
+                mk_bits: 256,
+                uuid: None,
+            },
+            iteration_ms: 1,
+            keyslot: None,
+            priority: None,
+            volume_key_file: None,
+            recovery: None,
+        };
+
+        let mut db = PeroxideDb::new(DbType::Operation);
+        let entries = ctx.enroll_disks::<_, MainContext>(&mut db, paths.clone(), params, None);
+        expect!(entries.map(|entries| entries.len())).to(be_ok().value(2));
+        // the existing key and the new key are each read once for both disks
+        expect!(reads.borrow().clone()).to(be_equal_to(vec![false, true]));
+        for path in paths.iter() {
+            expect!(path.luks_test_key(&SecStr::from("batch-new"))).to(be_ok().value(Some(1)));
+        }
+    }
+
+    #[test]
+    fn test_rotate_refuses_keys_that_would_stay_the_same() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk");
+        luks1_image(&path, &SecStr::from("rotate"));
+        let uuid = luks_uuid_via_blkid(&path).unwrap();
+        let ctx = MainContext::new(dir.path().join("peroxs-db.json"));
+        let params = FormatContainerParams::Luks1 {
+            iteration_ms: 1000,
+            cipher: "aes".to_string(),
+            cipher_mode: "xts-plain64".to_string(),
+            hash: "sha256".to_string(),
+            mk_bits: 256,
+            uuid: None,
+        };
+
+        let yubikey = DbEntry::YubikeyEntry {
+            entry_type: YubikeyEntryType::ChallengeResponse,
+            slot: 2,
+            serial: None,
+            volume_id: VolumeId::of(None, uuid),
+        };
+        let fido2 = DbEntry::Fido2Entry {
+            credential_id: vec![1, 2, 3],
+            salt: vec![4, 5, 6],
+            volume_id: VolumeId::of(None, uuid),
+        };
+        for entry in [yubikey, fido2] {
+            let mut db = PeroxideDb::new(DbType::Operation);
+            db.entries.push(entry);
+            let rotated = ctx.rotate_key(&mut db, &path, 1000, params.clone(), None);
+            expect!(matches!(rotated, Err(Error::KeyNotRotatableError { .. }))).to(be_true());
+        }
+    }
+
+    #[test]
+    fn test_key_can_be_rotated_if_a_new_secret_is_prompted_for() {
+        let passphrase = DbEntry::PassphraseEntry {
+            volume_id: VolumeId::of(None, Uuid::nil()),
+        };
+        let yubikey = |entry_type| DbEntry::YubikeyEntry {
+            entry_type,
+            slot: 2,
+            serial: None,
+            volume_id: VolumeId::of(None, Uuid::nil()),
+        };
+        let keyfile = DbEntry::KeyfileEntry {
+            key_file: PathBuf::from("disk.key"),
+            key_offset: None,
+            key_size: None,
+            volume_id: VolumeId::of(None, Uuid::nil()),
+        };
+        let composite = |parts| DbEntry::CompositeEntry {
+            parts,
+            volume_id: VolumeId::of(None, Uuid::nil()),
+        };
+
+        expect!(key_can_be_rotated(&passphrase)).to(be_true());
+        expect!(key_can_be_rotated(&keyfile)).to(be_true());
+        expect!(key_can_be_rotated(&yubikey(YubikeyEntryType::HybridChallengeResponse))).to(be_true());
+        expect!(key_can_be_rotated(&yubikey(YubikeyEntryType::ChallengeResponse))).to(be_false());
+        expect!(key_can_be_rotated(&composite(vec![keyfile.clone(), passphrase]))).to(be_true());
+        expect!(key_can_be_rotated(&composite(vec![
+            keyfile,
+            yubikey(YubikeyEntryType::ChallengeResponse)
+        ])))
+        .to(be_false());
+    }
+
+    #[test]
+    fn test_entries_with_the_same_name_are_ambiguous() {
+        let mut db = PeroxideDb::new(DbType::Operation);
+        for uuid in [1, 1, 2] {
+            db.entries.push(DbEntry::PassphraseEntry {
+                volume_id: VolumeId::of(Some("data".to_string()), Uuid::from_u128(uuid)),
+            });
+        }
+        db.entries.push(DbEntry::PassphraseEntry {
+            volume_id: VolumeId::of(Some("home".to_string()), Uuid::from_u128(3)),
+        });
+
+        expect!(db.find_entries_by_name("data").len()).to(be_equal_to(3));
+        match unique_entry_by_name(&db, "data") {
+            Err(Error::AmbiguousEntryNameError { uuids, .. }) => {
+                expect!(uuids).to(be_equal_to(vec![Uuid::from_u128(1), Uuid::from_u128(2)]));
+            }
+            other => panic!("Expected an ambiguous entry name error, got {:?}", other),
+        }
+        expect!(unique_entry_by_name(&db, "home").map(|e| e.map(DbEntry::uuid).cloned()))
+            .to(be_ok().value(Some(Uuid::from_u128(3))));
+        expect!(unique_entry_by_name(&db, "other").map(|e| e.is_none())).to(be_ok().value(true));
+    }
+
+    #[test]
+    fn test_entries_with_status() {
+        let mut db = PeroxideDb::new(DbType::Operation);
+        for (name, uuid) in [("home", 1), ("backup", 2), ("stale", 3), ("absent", 4)] {
+            db.entries.push(DbEntry::PassphraseEntry {
+                volume_id: VolumeId::of(Some(name.to_string()), Uuid::from_u128(uuid)),
+            });
+        }
+        let disks = FakeDisks {
+            present: vec![Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3)],
+            // the backup disk is mapped under the name of the stale entry, whose own disk is not active
+            mappings: vec![fake_mapping("home", 1), fake_mapping("stale", 2)],
+        };
+
+        let statuses = entries_with_status(&db, &disks, &BlockDeviceScan::new());
+        let summary = statuses
+            .iter()
+            .map(|s| (s.present, s.active, s.mapping.as_deref()))
+            .collect::<Vec<_>>();
+        expect!(summary).to(be_equal_to(vec![
+            (true, true, Some("home")),
+            (true, true, Some("stale")),
+            (true, false, None),
+            (false, false, None),
+        ]));
+        expect!(&statuses[0].entry).to(be_equal_to(&db.entries[0]));
+    }
+
+    #[test]
+    fn test_activate_refuses_a_mapping_name_in_use() {
+        let ctx = fake_context(FakeDisks {
+            present: vec![Uuid::from_u128(1)],
+            mappings: vec![fake_mapping("home", 2)],
+        });
+        let entry = DbEntry::PassphraseEntry {
+            volume_id: VolumeId::of(Some("home".to_string()), Uuid::from_u128(1)),
+        };
+        let path = Path::new("/nonexistent/peroxs-test-disk");
+
+        let res = ctx.activate_device(
+            &path,
+            path,
+            &entry,
+            "home".to_string(),
+            &SecStr::from("secret"),
+            ActivationFlags::default(),
+        );
+        expect!(res.map_err(|e| e.kind())).to(be_err().value("device_already_activated"));
+        // the mapping of another disk under the name of the entry does not make the entry active
+        expect!(ctx.is_active(&entry, None)).to(be_false());
+        expect!(ctx.is_present(&entry)).to(be_true());
+    }
+
+    #[test]
+    fn test_close_and_suspend_need_an_active_mapping() {
+        let ctx = fake_context(FakeDisks {
+            present: vec![],
+            mappings: vec![],
+        });
+        expect!(ctx.deactivate("home").map_err(|e| e.kind())).to(be_err().value("device_not_activated"));
+        expect!(ctx.suspend("home").map_err(|e| e.kind())).to(be_err().value("device_not_activated"));
+    }
+
+    #[test]
+    fn test_failing_open_logs_the_disk_uuid() {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(LevelFilter::Trace);
+
+        let uuid = Uuid::from_u128(0x5eed);
+        let entry = DbEntry::PassphraseEntry {
+            volume_id: VolumeId::of(None, uuid),
+        };
+        let path = Path::new("/nonexistent/peroxs-test-disk");
+        let ctx = MainContext::new(PathBuf::from("peroxs-db.json"));
+
+        let res = ctx.activate_device(
+            &path,
+            path,
+            &entry,
+            "peroxs-test-mapping".to_string(),
+            &SecStr::from("secret"),
+            ActivationFlags::default(),
+        );
+        expect!(res).to(be_err());
+
+        let prefix = format!("[open {}] Could not activate", uuid);
+        let lines = LOGGER.lines.lock().unwrap();
+        expect!(lines.iter().any(|line| line.starts_with(&prefix))).to(be_true());
+    }
 }
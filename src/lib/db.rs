@@ -1,37 +1,65 @@
 use std::env::current_dir;
 use std::fmt;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::result;
 use std::str::FromStr;
+use std::time::SystemTime;
 
 use serde_json;
 use snafu::{prelude::*, Backtrace, IntoError};
 use uuid::Uuid;
 
+use crate::device::KeyslotPriority;
+use crate::messages;
+
 /// Current database version (used for future forward-compatibility)
 pub const DB_VERSION: u16 = 1;
 
 /// Default database name
-pub const PEROXIDE_DB_NAME: &'static str = "peroxs-db.json";
+pub const PEROXIDE_DB_NAME: &str = "peroxs-db.json";
+
+/// Database path that means standard input (when opening) or standard output (when saving)
+pub const STDIO_DB_PATH: &str = "-";
+
+/// Suffix of the file next to the database that is locked while the database is used (e.g. `peroxs-db.json.lock`)
+pub const LOCK_FILE_SUFFIX: &str = ".lock";
 
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("Database not found at `{}`", path.display()))]
     DatabaseNotFoundError { path: PathBuf, backtrace: Backtrace },
+    #[snafu(display(
+        "The database `{}` cannot be written: run peroxs with the privileges to change it (e.g. with sudo) or choose another path with --database",
+        path.display()
+    ))]
+    DatabaseNotWritableError {
+        path: PathBuf,
+        source: io::Error,
+        backtrace: Backtrace,
+    },
     #[snafu(display("I/O error on `{}`", path.display()))]
     IoError {
         path: PathBuf,
         source: io::Error,
         backtrace: Backtrace,
     },
+    #[snafu(display("Could not lock the database with `{}`", path.display()))]
+    LockError {
+        path: PathBuf,
+        source: io::Error,
+        backtrace: Backtrace,
+    },
     #[snafu(display("JSON serialization error"))]
     SerialisationError {
         source: serde_json::Error,
         backtrace: Backtrace,
     },
+    #[snafu(display("Composite entry for {uuid} contains another composite entry"))]
+    NestedCompositeEntryError { uuid: Uuid, backtrace: Backtrace },
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -83,6 +111,7 @@ pub enum DbEntryType {
     Keyfile,
     Passphrase,
     Yubikey,
+    Fido2,
 }
 
 // FIXME move this to newtype
@@ -99,6 +128,14 @@ pub enum YubikeyEntryType {
 pub enum DbEntry {
     KeyfileEntry {
         key_file: PathBuf,
+        // offset of the key in the key file (e.g. when the key is read from a region of a device)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        key_offset: Option<u64>,
+        // size of the key in the key file (the rest of the file is read if not set)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        key_size: Option<usize>,
         volume_id: VolumeId,
     },
     PassphraseEntry {
@@ -107,8 +144,41 @@ pub enum DbEntry {
     YubikeyEntry {
         entry_type: YubikeyEntryType,
         slot: YubikeySlot,
+        // serial of the Yubikey used at enrollment (if it exposes one)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        serial: Option<u32>,
         volume_id: VolumeId,
     },
+    Fido2Entry {
+        #[serde(with = "base64_bytes")]
+        credential_id: Vec<u8>,
+        #[serde(with = "base64_bytes")]
+        salt: Vec<u8>,
+        volume_id: VolumeId,
+    },
+    /// Entry whose key is combined from the keys of several other entries (e.g. a keyfile and a passphrase)
+    ///
+    /// The parts cannot be composite entries themselves.
+    CompositeEntry {
+        parts: Vec<DbEntry>,
+        volume_id: VolumeId,
+    },
+}
+
+/// Serialisation of binary fields (e.g. FIDO2 credential ids) as base64 strings
+mod base64_bytes {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        base64::decode(&encoded).map_err(D::Error::custom)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Ord, PartialOrd)]
@@ -121,6 +191,64 @@ pub struct VolumeId {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
     pub(crate) luks2_token_id: Option<i32>,
+    // LUKS keyslot of the key (if known)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub(crate) keyslot: Option<u8>,
+    // LUKS2 priority of the keyslot (if not the default)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub(crate) keyslot_priority: Option<KeyslotPriority>,
+    // parameters the LUKS container was formatted with (if formatted during enrollment)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub(crate) format_info: Option<FormatInfo>,
+    // time the key was enrolled or registered (RFC 3339, not known for entries from older versions)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub(crate) enrolled_at: Option<String>,
+    // whether this is the entry of a generated recovery passphrase (kept alongside the entry used to open the disk)
+    #[serde(skip_serializing_if = "is_false")]
+    #[serde(default)]
+    pub(crate) recovery: bool,
+    // whether the disk is opened by `peroxs boot-open` (e.g. from a systemd service at boot)
+    #[serde(skip_serializing_if = "is_false")]
+    #[serde(default)]
+    pub(crate) auto_open: bool,
+    // activation flags the disk is always opened with (e.g. discards for an SSD)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub(crate) activation_options: Option<ActivationOptions>,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+/// Activation flags that are added to the ones given when the disk of an entry is opened
+#[derive(Serialize, Deserialize, Debug, Default, Eq, PartialEq, Clone, Copy, Ord, PartialOrd)]
+pub struct ActivationOptions {
+    #[serde(skip_serializing_if = "is_false")]
+    #[serde(default)]
+    pub allow_discards: bool,
+    #[serde(skip_serializing_if = "is_false")]
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(skip_serializing_if = "is_false")]
+    #[serde(default)]
+    pub no_read_workqueue: bool,
+    #[serde(skip_serializing_if = "is_false")]
+    #[serde(default)]
+    pub no_write_workqueue: bool,
+}
+
+/// Cipher parameters of a LUKS container (recorded when it is formatted, so they are known without the disk)
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Ord, PartialOrd)]
+pub struct FormatInfo {
+    pub cipher: String,
+    pub cipher_mode: String,
+    pub hash: String,
+    pub mk_bits: usize,
 }
 
 impl VolumeId {
@@ -129,6 +257,13 @@ impl VolumeId {
             name,
             id: VolumeUuid { uuid: Uuid::new_v4() },
             luks2_token_id: None,
+            keyslot: None,
+            keyslot_priority: None,
+            format_info: None,
+            enrolled_at: None,
+            recovery: false,
+            auto_open: false,
+            activation_options: None,
         }
     }
 
@@ -137,12 +272,66 @@ impl VolumeId {
             name,
             id: VolumeUuid { uuid },
             luks2_token_id: None,
+            keyslot: None,
+            keyslot_priority: None,
+            format_info: None,
+            enrolled_at: None,
+            recovery: false,
+            auto_open: false,
+            activation_options: None,
         }
     }
 
     pub fn uuid(&self) -> &Uuid {
         &self.id.uuid
     }
+
+    pub fn luks2_token_id(&self) -> Option<i32> {
+        self.luks2_token_id
+    }
+
+    pub fn keyslot(&self) -> Option<u8> {
+        self.keyslot
+    }
+
+    pub fn keyslot_priority(&self) -> Option<KeyslotPriority> {
+        self.keyslot_priority
+    }
+
+    pub fn format_info(&self) -> Option<&FormatInfo> {
+        self.format_info.as_ref()
+    }
+
+    pub fn enrolled_at(&self) -> Option<&str> {
+        self.enrolled_at.as_deref()
+    }
+
+    pub fn is_recovery(&self) -> bool {
+        self.recovery
+    }
+
+    pub fn is_auto_open(&self) -> bool {
+        self.auto_open
+    }
+
+    /// Set whether the disk is opened by `peroxs boot-open`
+    pub fn set_auto_open(&mut self, auto_open: bool) {
+        self.auto_open = auto_open;
+    }
+
+    pub fn activation_options(&self) -> Option<ActivationOptions> {
+        self.activation_options
+    }
+
+    /// Set the activation flags the disk is always opened with (none if all of them are off)
+    pub fn set_activation_options(&mut self, options: ActivationOptions) {
+        self.activation_options = Some(options).filter(|options| options != &ActivationOptions::default());
+    }
+
+    /// Record the current time as the time the key was enrolled
+    pub fn set_enrolled_now(&mut self) {
+        self.enrolled_at = Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string());
+    }
 }
 
 impl fmt::Display for VolumeId {
@@ -179,11 +368,23 @@ impl PeroxideDb {
 
     /// Open a JSON-encoded database
     pub fn open<R: Read>(reader: R) -> Result<PeroxideDb> {
-        serde_json::de::from_reader(reader).context(SerialisationSnafu)
+        let db: PeroxideDb = serde_json::de::from_reader(reader).context(SerialisationSnafu)?;
+        for entry in db.entries.iter() {
+            entry.validate()?;
+        }
+        Ok(db)
     }
 
-    /// Open a JSON-encoded database at the specified path
+    /// Check whether a database path means standard input/output
+    pub fn is_stdio_path<P: AsRef<Path>>(path: P) -> bool {
+        path.as_ref() == Path::new(STDIO_DB_PATH)
+    }
+
+    /// Open a JSON-encoded database at the specified path (`-` reads it from standard input)
     pub fn open_at<P: AsRef<Path>>(path: P) -> Result<PeroxideDb> {
+        if PeroxideDb::is_stdio_path(&path) {
+            return PeroxideDb::open(io::stdin().lock());
+        }
         PeroxideDb::open(File::open(path.as_ref()).map_err(|e| (path, e))?)
     }
 
@@ -192,9 +393,112 @@ impl PeroxideDb {
         serde_json::to_writer(writer, self).context(SerialisationSnafu)
     }
 
-    /// Write a JSON-encoded database to the specified path
+    /// Write a JSON-encoded database to the specified path (`-` writes it to standard output)
     pub fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        self.save(&mut File::create(path.as_ref()).map_err(|e| (path, e))?)
+        if PeroxideDb::is_stdio_path(&path) {
+            let mut stdout = io::stdout().lock();
+            self.save(&mut stdout)?;
+            return writeln!(stdout).map_err(|e| (path, e).into());
+        }
+        self.save(&mut File::create(path.as_ref()).map_err(|e| write_error(path.as_ref(), e))?)
+    }
+}
+
+/// Whether an I/O error means that the file cannot be written at all (the permissions or a read-only filesystem)
+fn is_not_writable(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::PermissionDenied || e.raw_os_error() == Some(libc::EROFS)
+}
+
+/// The error of writing the database at the path
+fn write_error(path: &Path, e: io::Error) -> Error {
+    if is_not_writable(&e) {
+        DatabaseNotWritableSnafu { path }.into_error(e)
+    } else {
+        (path, e).into()
+    }
+}
+
+/// Kind of advisory lock on a database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockMode {
+    /// Any number of processes read the database
+    Shared,
+    /// One process changes the database (and no other process reads it in the meantime)
+    Exclusive,
+}
+
+/// An advisory lock (`flock`) on the lock file of a database, which is released when it is dropped
+///
+/// The lock file is left behind, as removing it would let the next two processes lock different files.
+#[derive(Debug)]
+pub struct DbLock {
+    mode: LockMode,
+    // closing the file releases the lock
+    _file: File,
+}
+
+impl DbLock {
+    /// Path of the lock file of the database at the path
+    pub fn lock_path(db_path: &Path) -> PathBuf {
+        let mut path = db_path.as_os_str().to_owned();
+        path.push(LOCK_FILE_SUFFIX);
+        PathBuf::from(path)
+    }
+
+    /// Lock the database at the path, waiting for the other processes that hold a conflicting lock to release it
+    pub fn acquire(db_path: &Path, mode: LockMode) -> Result<DbLock> {
+        let path = DbLock::lock_path(db_path);
+        let file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+        {
+            Ok(file) => file,
+            // a shared lock does not need write access, so an existing lock file is enough (e.g. on a read-only mount)
+            Err(_) if mode == LockMode::Shared && path.is_file() => {
+                File::open(&path).context(LockSnafu { path: &path })?
+            }
+            // the lock file is next to the database, so the database could not be saved either
+            Err(e) if mode == LockMode::Exclusive && is_not_writable(&e) => return Err(write_error(db_path, e)),
+            Err(e) => return Err(LockSnafu { path }.into_error(e)),
+        };
+
+        let operation = match mode {
+            LockMode::Shared => libc::LOCK_SH,
+            LockMode::Exclusive => libc::LOCK_EX,
+        };
+        match flock(&file, operation | libc::LOCK_NB) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                messages::status(format!(
+                    "Waiting for another peroxs process to release the lock on {}",
+                    db_path.display()
+                ));
+                flock(&file, operation).context(LockSnafu { path: &path })?;
+            }
+            Err(e) => return Err(LockSnafu { path }.into_error(e)),
+        }
+
+        Ok(DbLock { mode, _file: file })
+    }
+
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+}
+
+/// Apply a `flock` operation to the file (retrying when interrupted by a signal)
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
     }
 }
 
@@ -204,11 +508,13 @@ impl DbEntry {
             DbEntry::KeyfileEntry { ref volume_id, .. } => volume_id,
             DbEntry::PassphraseEntry { ref volume_id, .. } => volume_id,
             DbEntry::YubikeyEntry { ref volume_id, .. } => volume_id,
+            DbEntry::Fido2Entry { ref volume_id, .. } => volume_id,
+            DbEntry::CompositeEntry { ref volume_id, .. } => volume_id,
         }
     }
 
     pub fn uuid(&self) -> &Uuid {
-        &self.volume_id().uuid()
+        self.volume_id().uuid()
     }
 
     pub fn volume_id_mut(&mut self) -> &mut VolumeId {
@@ -216,13 +522,29 @@ impl DbEntry {
             DbEntry::KeyfileEntry { ref mut volume_id, .. } => volume_id,
             DbEntry::PassphraseEntry { ref mut volume_id, .. } => volume_id,
             DbEntry::YubikeyEntry { ref mut volume_id, .. } => volume_id,
+            DbEntry::Fido2Entry { ref mut volume_id, .. } => volume_id,
+            DbEntry::CompositeEntry { ref mut volume_id, .. } => volume_id,
         }
     }
+
+    /// Check that the entry is well-formed (composite entries are only allowed one level deep)
+    pub fn validate(&self) -> Result<()> {
+        if let DbEntry::CompositeEntry { ref parts, .. } = *self {
+            ensure!(
+                !parts.iter().any(|part| matches!(part, DbEntry::CompositeEntry { .. })),
+                NestedCompositeEntrySnafu { uuid: *self.uuid() }
+            );
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
     use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
 
     use expectest::prelude::*;
     use serde_json;
@@ -230,6 +552,87 @@ pub mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_save_to_read_only_dir_is_not_writable() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        // root can write to the directory whatever its permissions are
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o555)).unwrap();
+        let db_path = dir.path().join("peroxs-db.json");
+
+        let saved = PeroxideDb::new(DbType::Operation).save_to(&db_path);
+        expect!(matches!(saved, Err(Error::DatabaseNotWritableError { .. }))).to(be_true());
+        let locked = DbLock::acquire(&db_path, LockMode::Exclusive);
+        expect!(matches!(locked, Err(Error::DatabaseNotWritableError { .. }))).to(be_true());
+
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn test_read_only_filesystem_is_not_writable() {
+        let path = Path::new("/mnt/ro/peroxs-db.json");
+        let read_only = io::Error::from_raw_os_error(libc::EROFS);
+        expect!(is_not_writable(&read_only)).to(be_true());
+        expect!(matches!(
+            write_error(path, read_only),
+            Error::DatabaseNotWritableError { .. }
+        ))
+        .to(be_true());
+
+        let denied = io::Error::from_raw_os_error(libc::EACCES);
+        expect!(is_not_writable(&denied)).to(be_true());
+        expect!(matches!(
+            write_error(path, denied),
+            Error::DatabaseNotWritableError { .. }
+        ))
+        .to(be_true());
+
+        // any other error is a plain I/O error of the path
+        let full = io::Error::from_raw_os_error(libc::ENOSPC);
+        expect!(is_not_writable(&full)).to(be_false());
+        expect!(matches!(
+            write_error(path, full),
+            Error::DatabaseNotWritableError { .. }
+        ))
+        .to(be_false());
+    }
+
+    #[test]
+    fn test_db_lock_waits_for_exclusive_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("peroxs-db.json");
+        let lock = DbLock::acquire(&db_path, LockMode::Exclusive).unwrap();
+        expect!(dir.path().join("peroxs-db.json.lock").is_file()).to(be_true());
+
+        // flock locks belong to the open file, so the lock file opened again conflicts just like in another process
+        let (tx, rx) = mpsc::channel();
+        let path = db_path.clone();
+        let waiter = thread::spawn(move || {
+            let lock = DbLock::acquire(&path, LockMode::Shared);
+            tx.send(lock.map(|lock| lock.mode()).ok()).unwrap();
+        });
+        expect!(rx.recv_timeout(Duration::from_millis(200))).to(be_err());
+
+        drop(lock);
+        expect!(rx.recv_timeout(Duration::from_secs(5))).to(be_ok().value(Some(LockMode::Shared)));
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    fn test_db_shared_locks_do_not_wait() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("peroxs-db.json");
+        let first = DbLock::acquire(&db_path, LockMode::Shared);
+        let second = DbLock::acquire(&db_path, LockMode::Shared);
+        expect!(first).to(be_ok());
+        expect!(second).to(be_ok());
+    }
+
     #[test]
     fn test_serialize_db_type() {
         expect!(serde_json::to_string(&DbType::Operation)).to(be_ok().value(r#""Operation""#.to_string()));
@@ -255,15 +658,56 @@ pub mod tests {
         .to(be_ok().value(r#"{"name":"foobar","id":{"uuid":"00000000-0000-0000-0000-000000000000"}}"#.to_string()));
     }
 
+    #[test]
+    fn test_serialize_auto_open_volume_id() {
+        let mut volume_id = VolumeId::of(None, Uuid::nil());
+        volume_id.set_auto_open(true);
+        let json = r#"{"name":null,"id":{"uuid":"00000000-0000-0000-0000-000000000000"},"auto_open":true}"#;
+        expect!(serde_json::to_string(&volume_id)).to(be_ok().value(json.to_string()));
+        expect!(serde_json::from_str::<VolumeId>(json)).to(be_ok().value(volume_id));
+    }
+
+    #[test]
+    fn test_serialize_activation_options_volume_id() {
+        let mut volume_id = VolumeId::of(None, Uuid::nil());
+        volume_id.set_activation_options(ActivationOptions {
+            allow_discards: true,
+            no_read_workqueue: true,
+            ..ActivationOptions::default()
+        });
+        let json = r#"{"name":null,"id":{"uuid":"00000000-0000-0000-0000-000000000000"},"activation_options":{"allow_discards":true,"no_read_workqueue":true}}"#;
+        expect!(serde_json::to_string(&volume_id)).to(be_ok().value(json.to_string()));
+        expect!(serde_json::from_str::<VolumeId>(json)).to(be_ok().value(volume_id.clone()));
+
+        // turning all the options off removes them
+        volume_id.set_activation_options(ActivationOptions::default());
+        expect!(volume_id.activation_options()).to(be_none());
+    }
+
     #[test]
     fn test_serialize_keyfile_entry() {
         let entry = DbEntry::KeyfileEntry {
             key_file: PathBuf::from("/path/to/keyfile"),
+            key_offset: None,
+            key_size: None,
             volume_id: VolumeId::of(None, Uuid::nil()),
         };
         expect!(serde_json::to_string(&entry)).to(be_ok().value(r#"{"KeyfileEntry":{"key_file":"/path/to/keyfile","volume_id":{"name":null,"id":{"uuid":"00000000-0000-0000-0000-000000000000"}}}}"#.to_string()));
     }
 
+    #[test]
+    fn test_serialize_keyfile_entry_offset_size() {
+        let entry = DbEntry::KeyfileEntry {
+            key_file: PathBuf::from("/dev/sdz1"),
+            key_offset: Some(4096),
+            key_size: Some(512),
+            volume_id: VolumeId::of(None, Uuid::nil()),
+        };
+        let json = r#"{"KeyfileEntry":{"key_file":"/dev/sdz1","key_offset":4096,"key_size":512,"volume_id":{"name":null,"id":{"uuid":"00000000-0000-0000-0000-000000000000"}}}}"#;
+        expect!(serde_json::to_string(&entry)).to(be_ok().value(json.to_string()));
+        expect!(serde_json::from_str::<DbEntry>(json)).to(be_ok().value(entry));
+    }
+
     #[test]
     fn test_serialize_passphrase_entry() {
         let entry = DbEntry::PassphraseEntry {
@@ -290,16 +734,174 @@ pub mod tests {
         ));
     }
 
+    #[test]
+    fn test_serialize_passphrase_entry_keyslot() {
+        let volume_id = {
+            let mut id = VolumeId::of(None, Uuid::nil());
+            id.keyslot = Some(3);
+            id
+        };
+
+        let entry = DbEntry::PassphraseEntry { volume_id };
+        expect!(serde_json::to_string(&entry)).to(be_ok().value(
+            r#"{"PassphraseEntry":{"volume_id":{"name":null,"id":{"uuid":"00000000-0000-0000-0000-000000000000"},"keyslot":3}}}"#
+                .to_string(),
+        ));
+    }
+
+    #[test]
+    fn test_serialize_passphrase_entry_keyslot_priority() {
+        let volume_id = {
+            let mut id = VolumeId::of(None, Uuid::nil());
+            id.keyslot = Some(7);
+            id.keyslot_priority = Some(KeyslotPriority::Ignore);
+            id
+        };
+
+        let entry = DbEntry::PassphraseEntry { volume_id };
+        expect!(serde_json::to_string(&entry)).to(be_ok().value(
+            r#"{"PassphraseEntry":{"volume_id":{"name":null,"id":{"uuid":"00000000-0000-0000-0000-000000000000"},"keyslot":7,"keyslot_priority":"Ignore"}}}"#
+                .to_string(),
+        ));
+    }
+
+    #[test]
+    fn test_serialize_passphrase_entry_format_info() {
+        let volume_id = {
+            let mut id = VolumeId::of(None, Uuid::nil());
+            id.format_info = Some(FormatInfo {
+                cipher: "aes".to_string(),
+                cipher_mode: "xts-plain64".to_string(),
+                hash: "sha256".to_string(),
+                mk_bits: 512,
+            });
+            id
+        };
+
+        let entry = DbEntry::PassphraseEntry { volume_id };
+        let json = r#"{"PassphraseEntry":{"volume_id":{"name":null,"id":{"uuid":"00000000-0000-0000-0000-000000000000"},"format_info":{"cipher":"aes","cipher_mode":"xts-plain64","hash":"sha256","mk_bits":512}}}}"#;
+        expect!(serde_json::to_string(&entry)).to(be_ok().value(json.to_string()));
+        expect!(serde_json::from_str::<DbEntry>(json)).to(be_ok().value(entry));
+    }
+
+    #[test]
+    fn test_serialize_passphrase_entry_enrolled_at() {
+        let volume_id = {
+            let mut id = VolumeId::of(None, Uuid::nil());
+            id.enrolled_at = Some("2022-09-30T12:34:56Z".to_string());
+            id
+        };
+
+        let entry = DbEntry::PassphraseEntry { volume_id };
+        let json = r#"{"PassphraseEntry":{"volume_id":{"name":null,"id":{"uuid":"00000000-0000-0000-0000-000000000000"},"enrolled_at":"2022-09-30T12:34:56Z"}}}"#;
+        expect!(serde_json::to_string(&entry)).to(be_ok().value(json.to_string()));
+        expect!(serde_json::from_str::<DbEntry>(json)).to(be_ok().value(entry));
+    }
+
+    #[test]
+    fn test_serialize_passphrase_entry_recovery() {
+        let volume_id = {
+            let mut id = VolumeId::of(None, Uuid::nil());
+            id.keyslot = Some(1);
+            id.recovery = true;
+            id
+        };
+
+        let entry = DbEntry::PassphraseEntry { volume_id };
+        let json = r#"{"PassphraseEntry":{"volume_id":{"name":null,"id":{"uuid":"00000000-0000-0000-0000-000000000000"},"keyslot":1,"recovery":true}}}"#;
+        expect!(serde_json::to_string(&entry)).to(be_ok().value(json.to_string()));
+        expect!(serde_json::from_str::<DbEntry>(json)).to(be_ok().value(entry));
+    }
+
+    #[test]
+    fn test_set_enrolled_now_is_rfc3339() {
+        let mut id = VolumeId::of(None, Uuid::nil());
+        id.set_enrolled_now();
+        let enrolled_at = id.enrolled_at().expect("enrolled_at").to_string();
+        expect!(humantime::parse_rfc3339(&enrolled_at)).to(be_ok());
+    }
+
+    #[test]
+    fn test_serialize_composite_entry() {
+        let entry = DbEntry::CompositeEntry {
+            parts: vec![
+                DbEntry::KeyfileEntry {
+                    key_file: PathBuf::from("/path/to/keyfile"),
+                    key_offset: None,
+                    key_size: None,
+                    volume_id: VolumeId::of(None, Uuid::nil()),
+                },
+                DbEntry::PassphraseEntry {
+                    volume_id: VolumeId::of(None, Uuid::nil()),
+                },
+            ],
+            volume_id: VolumeId::of(None, Uuid::nil()),
+        };
+        let json = r#"{"CompositeEntry":{"parts":[{"KeyfileEntry":{"key_file":"/path/to/keyfile","volume_id":{"name":null,"id":{"uuid":"00000000-0000-0000-0000-000000000000"}}}},{"PassphraseEntry":{"volume_id":{"name":null,"id":{"uuid":"00000000-0000-0000-0000-000000000000"}}}}],"volume_id":{"name":null,"id":{"uuid":"00000000-0000-0000-0000-000000000000"}}}}"#;
+        expect!(serde_json::to_string(&entry)).to(be_ok().value(json.to_string()));
+        expect!(serde_json::from_str::<DbEntry>(json)).to(be_ok().value(entry));
+    }
+
+    #[test]
+    fn test_nested_composite_entry_must_be_rejected() {
+        let passphrase = DbEntry::PassphraseEntry {
+            volume_id: VolumeId::of(None, Uuid::nil()),
+        };
+        let nested = DbEntry::CompositeEntry {
+            parts: vec![
+                passphrase.clone(),
+                DbEntry::CompositeEntry {
+                    parts: vec![passphrase],
+                    volume_id: VolumeId::of(None, Uuid::nil()),
+                },
+            ],
+            volume_id: VolumeId::of(None, Uuid::nil()),
+        };
+        let db = PeroxideDb {
+            entries: vec![nested],
+            db_type: DbType::Operation,
+            version: DB_VERSION,
+        };
+        let json = serde_json::to_vec(&db).unwrap();
+        expect!(PeroxideDb::open(json.as_slice())).to(be_err());
+    }
+
     #[test]
     fn test_serialize_yubikey_entry() {
         let entry = DbEntry::YubikeyEntry {
             entry_type: YubikeyEntryType::HybridChallengeResponse,
             slot: 1,
+            serial: None,
             volume_id: VolumeId::of(None, Uuid::nil()),
         };
         expect!(serde_json::to_string(&entry)).to(be_ok().value(r#"{"YubikeyEntry":{"entry_type":"HybridChallengeResponse","slot":1,"volume_id":{"name":null,"id":{"uuid":"00000000-0000-0000-0000-000000000000"}}}}"#.to_string()));
     }
 
+    #[test]
+    fn test_serialize_yubikey_entry_serial() {
+        let entry = DbEntry::YubikeyEntry {
+            entry_type: YubikeyEntryType::ChallengeResponse,
+            slot: 2,
+            serial: Some(1234567),
+            volume_id: VolumeId::of(None, Uuid::nil()),
+        };
+        let json = r#"{"YubikeyEntry":{"entry_type":"ChallengeResponse","slot":2,"serial":1234567,"volume_id":{"name":null,"id":{"uuid":"00000000-0000-0000-0000-000000000000"}}}}"#;
+        expect!(serde_json::to_string(&entry)).to(be_ok().value(json.to_string()));
+        expect!(serde_json::from_str::<DbEntry>(json)).to(be_ok().value(entry));
+    }
+
+    #[test]
+    fn test_serialize_fido2_entry() {
+        let entry = DbEntry::Fido2Entry {
+            credential_id: vec![1, 2, 3, 4],
+            salt: vec![0xff; 4],
+            volume_id: VolumeId::of(None, Uuid::nil()),
+        };
+        let json = r#"{"Fido2Entry":{"credential_id":"AQIDBA==","salt":"/////w==","volume_id":{"name":null,"id":{"uuid":"00000000-0000-0000-0000-000000000000"}}}}"#;
+        expect!(serde_json::to_string(&entry)).to(be_ok().value(json.to_string()));
+        expect!(serde_json::from_str::<DbEntry>(json)).to(be_ok().value(entry));
+    }
+
     #[test]
     fn test_serialize_database() {
         let db = PeroxideDb::new(DbType::Operation);
@@ -312,6 +914,8 @@ pub mod tests {
         let mut db = PeroxideDb::new(DbType::Backup);
         db.entries.push(DbEntry::KeyfileEntry {
             key_file: PathBuf::from("keyfile.key"),
+            key_offset: None,
+            key_size: None,
             volume_id: VolumeId::of(Some("test-disk".to_string()), Uuid::nil()),
         });
         expect!(serde_json::from_str::<PeroxideDb>(db_json)).to(be_ok().value(db.clone()));
@@ -1,21 +1,40 @@
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::convert::From;
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fmt;
 use std::fs;
 use std::io;
-use std::io::ErrorKind;
+use std::io::{ErrorKind, Read};
 use std::os::unix::prelude::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::ptr;
 use std::result;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use cryptsetup_rs;
 pub use cryptsetup_rs::Keyslot;
 use cryptsetup_rs::{
-    api::crypt_pbkdf_algo_type, luks_uuid, CryptDevice, Luks2CryptDevice, Luks2Token, Luks2TokenId, LuksCryptDevice,
+    api::{crypt_pbkdf_algo_type, crypt_token_info},
+    crypt_device_type, crypt_keyslot_info,
+    device::{Luks2FormatPbkdf, RawDevice},
+    CryptDevice, Luks1CryptDeviceHandle, Luks2CryptDevice, Luks2CryptDeviceHandle, Luks2Token, Luks2TokenId,
+    LuksCryptDevice,
 };
 use errno;
+use libc::{c_char, c_int, c_void};
+use libcryptsetup_sys as raw;
+use log::Level;
 use secstr::SecStr;
 use snafu::{prelude::*, Backtrace, IntoError};
 use uuid::Uuid;
 
+use crate::messages;
+
 #[derive(Debug, Snafu)]
 pub enum Error {
     /// Error that originates from underlying cryptsetup library
@@ -27,6 +46,64 @@ pub enum Error {
     /// Error that originates from trying to read a device
     #[snafu(display("Device read error: {message}"))]
     DeviceReadError { message: String, backtrace: Backtrace },
+    /// Disk could not be found by any of the lookup strategies
+    #[snafu(display(
+        "Disk with uuid {uuid} not found: it is not under {} and no readable block device has that LUKS uuid",
+        by_uuid_dir.display()
+    ))]
+    DiskNotFoundError {
+        uuid: Uuid,
+        by_uuid_dir: PathBuf,
+        backtrace: Backtrace,
+    },
+    /// Disk could not be found by its label or partition UUID
+    #[snafu(display("Disk not found: {} does not exist", path.display()))]
+    DiskLinkNotFoundError { path: PathBuf, backtrace: Backtrace },
+    /// Requested keyslot does not exist for the LUKS version
+    #[snafu(display("Keyslot {keyslot} is out of range (must be below {keyslot_count})"))]
+    KeyslotOutOfRangeError {
+        keyslot: Keyslot,
+        keyslot_count: Keyslot,
+        backtrace: Backtrace,
+    },
+    /// Requested keyslot already has a key
+    #[snafu(display("Keyslot {keyslot} is already in use"))]
+    KeyslotInUseError { keyslot: Keyslot, backtrace: Backtrace },
+    /// Volume key does not have the size of the volume key of the device
+    #[snafu(display(
+        "The volume key is {actual} bytes long, but the volume key of the device is {expected} bytes long"
+    ))]
+    VolumeKeyLengthError {
+        expected: usize,
+        actual: usize,
+        backtrace: Backtrace,
+    },
+    /// Keyslot priority requested for a LUKS1 device
+    #[snafu(display("Keyslot priorities are only supported by LUKS2"))]
+    KeyslotPriorityUnsupportedError { backtrace: Backtrace },
+    /// Volume key in the kernel keyring requested for a LUKS1 device
+    #[snafu(display("Keeping the volume key in the kernel keyring is only supported by LUKS2"))]
+    KeyringUnsupportedError { backtrace: Backtrace },
+    /// Header label or subsystem requested for a LUKS1 device
+    #[snafu(display("Labels are only supported by LUKS2"))]
+    LabelUnsupportedError { backtrace: Backtrace },
+    /// Token requested for a LUKS1 device
+    #[snafu(display("Tokens are only supported by LUKS2"))]
+    TokenUnsupportedError { backtrace: Backtrace },
+    /// The LUKS version in the header is not the one cryptsetup opened the device as
+    #[snafu(display("The header of {} is {header:?}, but cryptsetup opened it as {detected:?}", path.display()))]
+    LuksVersionMismatchError {
+        path: PathBuf,
+        header: LuksVersion,
+        detected: LuksVersion,
+        backtrace: Backtrace,
+    },
+    /// A reencryption of the device was started and has not finished, so its keyslots must not be changed
+    #[snafu(display(
+        "{} is being reencrypted, finish the reencryption first (`cryptsetup reencrypt --resume-only`)",
+        path.display()
+    ))]
+    ReencryptionInProgressError { path: PathBuf, backtrace: Backtrace },
     /// Error that originates from some other kind of IO
     #[snafu(display("Unknown I/O error"))]
     IoError { source: io::Error, backtrace: Backtrace },
@@ -37,6 +114,16 @@ pub enum Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// Error code returned by cryptsetup when no keyslot can be unlocked with a key
+const WRONG_KEY_ERRNO: i32 = 1; // EPERM
+
+impl Error {
+    /// Check whether cryptsetup rejected the key (as opposed to failing for any other reason)
+    pub fn is_wrong_key(&self) -> bool {
+        matches!(self, Error::CryptsetupError { underlying, .. } if underlying.0 == WRONG_KEY_ERRNO)
+    }
+}
+
 impl From<cryptsetup_rs::Error> for Error {
     fn from(e: cryptsetup_rs::Error) -> Self {
         match e {
@@ -54,14 +141,46 @@ impl From<cryptsetup_rs::Error> for Error {
     }
 }
 
-// this assumes a udev-like /dev layout
-const DISK_BY_UUID: &'static str = "/dev/disk/by-uuid";
-const TOKEN_NAME: &'static str = "peroxide";
-const SYSFS_VIRTUAL_BLOCK_DIR: &'static str = "/sys/devices/virtual/block";
-const DEVFS_BLOCK_DIR: &'static str = "/dev/block";
+// this assumes a udev-like /dev layout (unless overridden by `DISK_BY_UUID_ENV`)
+const DISK_BY_UUID: &str = "/dev/disk/by-uuid";
+/// Environment variable that overrides the directory of the disk-by-uuid symlinks (for non-udev layouts)
+pub const DISK_BY_UUID_ENV: &str = "PEROXS_DISK_BY_UUID";
+const DISK_BY_LABEL: &str = "/dev/disk/by-label";
+const DISK_BY_PARTUUID: &str = "/dev/disk/by-partuuid";
+const SYSFS_CLASS_BLOCK_DIR: &str = "/sys/class/block";
+const DEVFS_DIR: &str = "/dev";
+const TOKEN_NAME: &str = "peroxide";
+const SYSFS_VIRTUAL_BLOCK_DIR: &str = "/sys/devices/virtual/block";
+const DEVFS_BLOCK_DIR: &str = "/dev/block";
+const DEVFS_MAPPER_DIR: &str = "/dev/mapper";
+const UDEV_DATA_DIR: &str = "/run/udev/data";
 
 const UUID_LENGTH: usize = 36;
 
+/// Interval between the lookups of a disk that has not appeared yet
+pub const DISK_WAIT_INTERVAL: Duration = Duration::from_millis(500);
+
+// activation flags of libcryptsetup (`CRYPT_ACTIVATE_*` in libcryptsetup.h)
+const CRYPT_ACTIVATE_READONLY: u32 = 1 << 0;
+const CRYPT_ACTIVATE_ALLOW_DISCARDS: u32 = 1 << 3;
+const CRYPT_ACTIVATE_KEYRING_KEY: u32 = 1 << 11;
+const CRYPT_ACTIVATE_NO_READ_WORKQUEUE: u32 = 1 << 24;
+const CRYPT_ACTIVATE_NO_WRITE_WORKQUEUE: u32 = 1 << 25;
+
+const LUKS1_KEYSLOT_COUNT: Keyslot = 8;
+const LUKS2_KEYSLOT_COUNT: Keyslot = 32;
+/// Sector size of LUKS2 devices if none is given (that of the format builder of cryptsetup-rs)
+const LUKS2_DEFAULT_SECTOR_SIZE: u32 = 512;
+
+/// Magic bytes at the start of a LUKS header (the primary one for LUKS2), followed by the version as a big-endian u16
+const LUKS_MAGIC: &[u8] = b"LUKS\xba\xbe";
+const LUKS_VERSION_PROBE_LENGTH: usize = 8;
+// password and salt of the PBKDF benchmark (the ones of `cryptsetup benchmark`)
+const BENCHMARK_PASSWORD: &[u8] = b"foobarfo";
+const BENCHMARK_SALT: &[u8] = b"0123456789abcdef0123456789abcdef";
+// hash of a pbkdf2 benchmark (argon2 does not use one)
+const BENCHMARK_HASH: &str = "sha256";
+
 // always use the argon2id variant
 const LUKS2_PBKDF_TYPE: crypt_pbkdf_algo_type = crypt_pbkdf_algo_type::argon2id;
 
@@ -86,9 +205,14 @@ pub enum FormatContainerParams {
         parallel_threads: u32,
         sector_size: Option<u32>,
         data_alignment: Option<u32>,
+        /// Size of the JSON metadata area in bytes (the default of cryptsetup if not set)
+        metadata_size: Option<u64>,
+        /// Size of the binary keyslots area in bytes (the default of cryptsetup if not set)
+        keyslots_size: Option<u64>,
         save_label_in_header: bool,
         uuid: Option<Uuid>,
         label: Option<String>,
+        subsystem: Option<String>,
         token_id: Option<Luks2TokenId>,
     },
 }
@@ -103,308 +227,2122 @@ pub enum FormatResult {
     },
 }
 
+/// Parameters of an existing LUKS volume, as read from its header
+#[derive(Debug, Clone)]
+pub struct LuksVolumeInfo {
+    /// Cipher in the name-mode form (e.g. `aes-xts-plain64`)
+    pub cipher: String,
+    /// Size of the volume key
+    pub key_bits: usize,
+}
+
+/// Version of the on-disk format of a LUKS device
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LuksVersion {
+    Luks1,
+    Luks2,
+}
+
+impl LuksVersion {
+    fn device_type(&self) -> crypt_device_type {
+        match self {
+            LuksVersion::Luks1 => crypt_device_type::LUKS1,
+            LuksVersion::Luks2 => crypt_device_type::LUKS2,
+        }
+    }
+
+    /// Detect the version from the start of a LUKS header (`None` if it is not a primary LUKS header)
+    fn from_header(header: &[u8]) -> Option<LuksVersion> {
+        if header.len() < LUKS_VERSION_PROBE_LENGTH || &header[..LUKS_MAGIC.len()] != LUKS_MAGIC {
+            return None;
+        }
+        match u16::from_be_bytes([header[6], header[7]]) {
+            1 => Some(LuksVersion::Luks1),
+            2 => Some(LuksVersion::Luks2),
+            _ => None,
+        }
+    }
+
+    /// Read the version from the header of the device at the path
+    fn probe(path: &Path) -> Result<Option<LuksVersion>> {
+        let mut header = [0u8; LUKS_VERSION_PROBE_LENGTH];
+        match fs::File::open(path).and_then(|mut file| file.read_exact(&mut header)) {
+            Ok(()) => Ok(LuksVersion::from_header(&header)),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(IoSnafu.into_error(e)),
+        }
+    }
+}
+
+/// Reencryption state of a LUKS device
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReencryptStatus {
+    /// No reencryption is in progress (always the case for LUKS1, which cannot be reencrypted online)
+    None,
+    /// A LUKS2 reencryption was started and has not finished
+    InProgress,
+    /// A LUKS2 reencryption was interrupted and has to be recovered before it can be resumed
+    Interrupted,
+}
+
+impl ReencryptStatus {
+    fn from_info(info: c_int) -> Result<ReencryptStatus> {
+        match info {
+            CRYPT_REENCRYPT_NONE => Ok(ReencryptStatus::None),
+            CRYPT_REENCRYPT_CLEAN => Ok(ReencryptStatus::InProgress),
+            CRYPT_REENCRYPT_CRASH => Ok(ReencryptStatus::Interrupted),
+            _ => DeviceReadSnafu {
+                message: "Invalid LUKS2 reencryption metadata",
+            }
+            .fail(),
+        }
+    }
+}
+
+/// Status of a keyslot of a LUKS device
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyslotStatus {
+    Inactive,
+    Active,
+    /// The only active keyslot left (removing it would make the data unrecoverable)
+    ActiveLast,
+}
+
+impl KeyslotStatus {
+    fn from_info(info: crypt_keyslot_info) -> KeyslotStatus {
+        match info {
+            crypt_keyslot_info::CRYPT_SLOT_ACTIVE => KeyslotStatus::Active,
+            crypt_keyslot_info::CRYPT_SLOT_ACTIVE_LAST => KeyslotStatus::ActiveLast,
+            _ => KeyslotStatus::Inactive,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        *self != KeyslotStatus::Inactive
+    }
+}
+
+/// The keyslots of a LUKS device that are in use, out of the ones its version has
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyslotUsage {
+    pub used: Vec<Keyslot>,
+    /// Number of keyslots of the LUKS version (8 for LUKS1, 32 for LUKS2)
+    pub count: Keyslot,
+}
+
+impl KeyslotUsage {
+    /// Whether no key can be added anymore
+    pub fn is_full(&self) -> bool {
+        self.used.len() >= self.count as usize
+    }
+}
+
+impl fmt::Display for KeyslotUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.used.len(), self.count)
+    }
+}
+
+/// Key derivation (PBKDF) parameters of a keyslot, as applied by cryptsetup when the key was added
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyslotPbkdf {
+    /// PBKDF algorithm (e.g. `pbkdf2` or `argon2id`)
+    pub algorithm: String,
+    /// Time the derivation was benchmarked to take
+    pub time_ms: u32,
+    /// Number of iterations
+    pub iterations: u32,
+    /// Memory cost (argon2 only)
+    pub max_memory_kb: u32,
+    /// Number of parallel threads (argon2 only)
+    pub parallel_threads: u32,
+}
+
+impl KeyslotPbkdf {
+    fn from_raw(pbkdf: &raw::crypt_pbkdf_type) -> KeyslotPbkdf {
+        let algorithm = if pbkdf.type_.is_null() {
+            "unknown".to_string()
+        } else {
+            unsafe { CStr::from_ptr(pbkdf.type_) }.to_string_lossy().into_owned()
+        };
+        KeyslotPbkdf {
+            algorithm,
+            time_ms: pbkdf.time_ms,
+            iterations: pbkdf.iterations,
+            max_memory_kb: pbkdf.max_memory_kb,
+            parallel_threads: pbkdf.parallel_threads,
+        }
+    }
+}
+
+/// Target of a PBKDF benchmark (see `benchmark_pbkdf`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PbkdfBenchmarkParams {
+    /// PBKDF algorithm to benchmark (e.g. `argon2id`)
+    pub algorithm: String,
+    /// Time one key derivation should take
+    pub time_ms: u32,
+    /// Upper bound of the memory cost (argon2 only)
+    pub max_memory_kb: u32,
+    /// Number of parallel threads (argon2 only)
+    pub parallel_threads: u32,
+    /// Size of the volume key that the derived key protects
+    pub key_bits: usize,
+}
+
+/// Flags for activating a LUKS device (the defaults activate a read-write mapping without discards)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ActivationFlags {
+    /// Pass TRIM/discard requests through to the underlying device (`CRYPT_ACTIVATE_ALLOW_DISCARDS`)
+    pub allow_discards: bool,
+    /// Activate a read-only mapping (`CRYPT_ACTIVATE_READONLY`)
+    pub read_only: bool,
+    /// Load the volume key into the kernel keyring instead of the dm-crypt table (`CRYPT_ACTIVATE_KEYRING_KEY`, LUKS2
+    /// only), so that later operations on the mapping (e.g. a resize) do not need the key again
+    pub keyring: bool,
+    /// Bypass the dm-crypt workqueue for reads (`CRYPT_ACTIVATE_NO_READ_WORKQUEUE`, e.g. for fast SSDs)
+    pub no_read_workqueue: bool,
+    /// Bypass the dm-crypt workqueue for writes (`CRYPT_ACTIVATE_NO_WRITE_WORKQUEUE`)
+    pub no_write_workqueue: bool,
+    /// Do not add the activation options stored in the database entry of the disk (not a cryptsetup flag)
+    pub ignore_entry_options: bool,
+}
+
+/// Credential that unlocks an existing LUKS device when adding a new key to it
+#[derive(Debug)]
+pub enum PreviousKey {
+    /// Key of an existing keyslot
+    Key(SecStr),
+    /// The volume (master) key of the device itself (e.g. when migrating from other tooling)
+    VolumeKey(SecStr),
+}
+
+/// Priority of a LUKS2 keyslot, i.e. when it is tried while unlocking without an explicit keyslot
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Ord, PartialOrd)]
+pub enum KeyslotPriority {
+    /// Only tried when the keyslot is asked for explicitly (e.g. a recovery key)
+    Ignore,
+    /// Tried in keyslot order (the default)
+    Normal,
+    /// Tried before the other keyslots
+    Prefer,
+}
+
+impl FromStr for KeyslotPriority {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ignore" => Ok(KeyslotPriority::Ignore),
+            "normal" => Ok(KeyslotPriority::Normal),
+            "prefer" => Ok(KeyslotPriority::Prefer),
+            other => OtherSnafu {
+                message: format!("'{}' is not a keyslot priority (ignore, normal or prefer)", other),
+            }
+            .fail(),
+        }
+    }
+}
+
+impl ActivationFlags {
+    /// Check whether no flags are set
+    pub fn is_empty(&self) -> bool {
+        self.bits() == 0
+    }
+
+    /// The `CRYPT_ACTIVATE_*` bitmask of the flags, as passed to `crypt_activate_by_passphrase`
+    pub fn bits(&self) -> u32 {
+        [
+            (self.read_only, CRYPT_ACTIVATE_READONLY),
+            (self.allow_discards, CRYPT_ACTIVATE_ALLOW_DISCARDS),
+            (self.keyring, CRYPT_ACTIVATE_KEYRING_KEY),
+            (self.no_read_workqueue, CRYPT_ACTIVATE_NO_READ_WORKQUEUE),
+            (self.no_write_workqueue, CRYPT_ACTIVATE_NO_WRITE_WORKQUEUE),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |bits, (_, bit)| bits | bit)
+    }
+}
+
 pub trait LuksVolumeOps {
     /// Activate the LUKS device with the given name
-    fn luks_activate(&self, name: &str, key: &SecStr) -> Result<Keyslot>;
+    fn luks_activate(&self, name: &str, key: &SecStr) -> Result<Keyslot> {
+        self.luks_activate_with_flags(name, key, ActivationFlags::default())
+    }
+
+    /// Activate the LUKS device with the given name and activation flags
+    fn luks_activate_with_flags(&self, name: &str, key: &SecStr, flags: ActivationFlags) -> Result<Keyslot>;
+
+    /// Deactivate the LUKS device mapped under the given name
+    fn luks_deactivate(&self, name: &str) -> Result<()>;
 
-    /// Add new key to LUKS device (given another key)
+    /// Suspend the LUKS device mapped under the given name (IO is frozen and the key is wiped from the kernel)
+    fn luks_suspend(&self, name: &str) -> Result<()>;
+
+    /// Resume the suspended LUKS device mapped under the given name with the key
+    fn luks_resume(&self, name: &str, key: &SecStr) -> Result<Keyslot>;
+
+    /// Add new key to LUKS device (given another key or the volume key), in the given keyslot or the first free one and with the given
+    /// priority (LUKS2 only)
     fn luks_add_key(
         &self,
         iteration_ms: usize,
         new_key: &SecStr,
-        prev_key: &SecStr,
+        prev_key: &PreviousKey,
         params: &FormatContainerParams,
+        maybe_keyslot: Option<Keyslot>,
+        maybe_priority: Option<KeyslotPriority>,
     ) -> Result<Keyslot>;
 
-    // Format a new LUKS device with the given key
-    fn luks_format_with_key(&self, key: &SecStr, params: &FormatContainerParams) -> Result<FormatResult>;
+    /// Remove (destroy) a keyslot of the LUKS device
+    fn luks_remove_keyslot(&self, keyslot: Keyslot) -> Result<()>;
 
-    /// Read the UUID of an existing LUKS1 device
-    fn luks_uuid(&self) -> Result<Uuid>;
-}
+    /// Find the keyslot that the given LUKS2 token is assigned to (always `None` for LUKS1 devices)
+    fn luks2_token_keyslot(&self, token_id: Luks2TokenId) -> Result<Option<Keyslot>>;
 
-impl<P: AsRef<Path>> LuksVolumeOps for P {
-    fn luks_activate(&self, name: &str, key: &SecStr) -> Result<Keyslot> {
-        let keyslot = cryptsetup_rs::open(self)?.luks()?.either(
-            |mut luks1| luks1.activate(name, key.unsecure()),
-            |mut luks2| luks2.activate(name, key.unsecure()),
-        )?;
-        Ok(keyslot)
+    /// Check whether a LUKS2 token exists on the device (always `false` for LUKS1 devices)
+    fn luks2_token_exists(&self, token_id: Luks2TokenId) -> Result<bool>;
+
+    /// Find the keyslot unlocked by the key (only the key is checked, the device is not activated)
+    fn luks_keyslot_for_key(&self, key: &SecStr) -> Result<Keyslot>;
+
+    /// Check whether the key unlocks the given keyslot (only the key is checked, the device is not activated)
+    fn luks_key_unlocks_keyslot(&self, key: &SecStr, keyslot: Keyslot) -> Result<bool>;
+
+    /// Find the keyslot unlocked by the key, if any (`None` if it does not unlock any of them)
+    fn luks_test_key(&self, key: &SecStr) -> Result<Option<Keyslot>> {
+        match self.luks_keyslot_for_key(key) {
+            Ok(keyslot) => Ok(Some(keyslot)),
+            Err(e) if e.is_wrong_key() => Ok(None),
+            Err(e) => Err(e),
+        }
     }
 
-    fn luks_add_key(
+    /// List the keyslots of the LUKS device that are in use
+    fn luks_active_keyslots(&self) -> Result<Vec<Keyslot>>;
+
+    /// Read the status of a keyslot of the LUKS device
+    fn luks_keyslot_status(&self, keyslot: Keyslot) -> Result<KeyslotStatus>;
+
+    /// List the keyslots of the LUKS device that are in use, together with the number of keyslots it has
+    fn luks_keyslot_usage(&self) -> Result<KeyslotUsage>;
+
+    // Format a new LUKS device with the given key (in the given keyslot or the first one, with the given priority)
+    fn luks_format_with_key(
         &self,
-        iteration_ms: usize,
-        new_key: &SecStr,
-        prev_key: &SecStr,
+        key: &SecStr,
         params: &FormatContainerParams,
-    ) -> Result<Keyslot> {
-        // note: impl trait in closure would help: https://github.com/rust-lang/rust/issues/63065
-        cryptsetup_rs::open(self)?.luks()?.either(
-            |mut luks1| {
-                luks1.set_iteration_time(iteration_ms as u64);
-                luks1
-                    .add_keyslot(new_key.unsecure(), Some(prev_key.unsecure()), None)
-                    .map_err(From::from)
-            },
-            |mut luks2| {
-                luks2.set_iteration_time(iteration_ms as u64);
+        maybe_keyslot: Option<Keyslot>,
+        maybe_priority: Option<KeyslotPriority>,
+    ) -> Result<FormatResult>;
 
-                let token_id = match params {
-                    FormatContainerParams::Luks2 {
-                        hash,
-                        time_ms,
-                        iterations,
-                        max_memory_kb,
-                        parallel_threads,
-                        token_id,
-                        ..
-                    } => {
-                        // always use argon2id
-                        luks2.set_pbkdf_params(
-                            LUKS2_PBKDF_TYPE,
-                            hash,
-                            *time_ms,
-                            *iterations,
-                            *max_memory_kb,
-                            *parallel_threads,
-                        )?;
-                        token_id
-                    }
-                    _ => &None,
-                };
+    /// Read the UUID of an existing LUKS device
+    fn luks_uuid(&self) -> Result<Uuid>;
 
-                let keyslot = luks2.add_keyslot(new_key.unsecure(), Some(prev_key.unsecure()), None)?;
-                if let Some(token_id) = token_id {
-                    luks2.assign_token_to_keyslot(*token_id, Some(keyslot))?;
-                }
+    /// Read the cipher and key size of an existing LUKS device
+    fn luks_volume_info(&self) -> Result<LuksVolumeInfo>;
 
-                Ok(keyslot)
-            },
-        )
-    }
+    /// Read the LUKS version of an existing LUKS device
+    fn luks_version(&self) -> Result<LuksVersion>;
 
-    fn luks_format_with_key(&self, key: &SecStr, params: &FormatContainerParams) -> Result<FormatResult> {
-        match params {
-            FormatContainerParams::Luks1 {
-                iteration_ms,
-                cipher,
-                cipher_mode,
-                hash,
-                mk_bits,
-                uuid,
-            } => {
-                let mut device = cryptsetup_rs::format(self)?
-                    .iteration_time(*iteration_ms as u64)
-                    .luks1(cipher, cipher_mode, hash, *mk_bits, uuid.as_ref())?;
-                device.set_iteration_time(*iteration_ms as u64);
-                let keyslot = device.add_keyslot(key.unsecure(), None, None)?;
+    /// Check whether the device has a LUKS1 or LUKS2 header by its magic bytes, without loading it with cryptsetup
+    /// (`false` if it cannot be read)
+    fn is_luks(&self) -> bool;
 
-                Ok(FormatResult::Luks1 { keyslot })
-            }
-            FormatContainerParams::Luks2 {
-                cipher,
-                cipher_mode,
-                mk_bits,
-                hash,
-                time_ms,
-                iterations,
-                max_memory_kb,
-                parallel_threads,
-                sector_size,
-                data_alignment,
-                save_label_in_header: _save_label_in_header,
-                uuid,
-                label,
-                token_id,
-            } => {
-                let mut format_builder = cryptsetup_rs::format(self)?
-                    .luks2(
-                        cipher,
-                        cipher_mode,
-                        *mk_bits,
-                        uuid.as_ref(),
-                        *data_alignment,
-                        *sector_size,
-                    )
-                    .argon2id(hash, *time_ms, *iterations, *max_memory_kb, *parallel_threads);
-
-                if let Some(label) = label {
-                    format_builder = format_builder.label(label);
-                }
+    /// Check whether a reencryption of an existing LUKS device is in progress (LUKS1 headers are not loaded with
+    /// cryptsetup, as they cannot be reencrypted online)
+    fn luks_reencrypt_status(&self) -> Result<ReencryptStatus>;
 
-                let mut device = format_builder.start()?;
-                let key = device.add_keyslot(key.unsecure(), None, None)?;
+    /// Read the key derivation parameters of a keyslot of an existing LUKS device
+    fn luks_keyslot_pbkdf(&self, keyslot: Keyslot) -> Result<KeyslotPbkdf>;
 
-                // always add a luks 2 token to the keyslot
-                let token = Luks2Token {
-                    type_: TOKEN_NAME.to_string(),
-                    keyslots: vec![key.to_string()],
-                    other: serde_json::Map::new(),
-                };
-
-                let tok = if let Some(token_id) = token_id {
-                    device.add_token_with_id(&token, *token_id)?;
-                    *token_id
-                } else {
-                    device.add_token(&token)?
-                };
+    /// Change the label and/or subsystem in the header of an existing LUKS2 device (`None` keeps the current value)
+    fn luks2_set_label(&self, label: Option<&str>, subsystem: Option<&str>) -> Result<()>;
 
-                Ok(FormatResult::Luks2 {
-                    keyslot: key,
-                    token_id: Some(tok),
-                })
-            }
-        }
-    }
+    /// Convert the header of an existing LUKS1 device to LUKS2 in place (the keyslots and the data are kept)
+    fn luks_convert_to_luks2(&self) -> Result<()>;
 
-    fn luks_uuid(&self) -> Result<Uuid> {
-        luks_uuid(self.as_ref()).map_err(From::from)
-    }
-}
+    /// Add a peroxide token assigned to a keyslot of an existing LUKS2 device, returning the id of the token
+    fn luks2_add_token(&self, keyslot: Keyslot) -> Result<Luks2TokenId>;
 
-/// Information gathered about mapped disks from sysfs
-#[derive(Debug)]
-pub struct DmSetupDeviceInfo {
-    /// dm-N name of the device
-    pub dm_name: String,
-    /// Mapped name of the device
-    pub name: String,
-    /// Underlying block path
-    pub underlying: PathBuf,
-    /// LUKS UUID
-    pub underlying_uuid: Uuid,
+    /// Write a copy of the header (including the keyslots) of an existing LUKS device to a new file
+    fn luks_header_backup(&self, out: &Path) -> Result<()>;
+
+    /// Overwrite the header of a device with a header backup file (the UUID of the backup is not checked, see
+    /// `ensure_header_backup_uuid`)
+    fn luks_header_restore(&self, from: &Path) -> Result<()>;
 }
 
-pub struct Disks;
+/// A LUKS device opened with cryptsetup (its header is read once, when it is opened)
+pub struct OpenedDevice {
+    handle: LuksHandle,
+    /// Loaded on first use and kept until the device is dropped (see `OpenedDevice::raw`)
+    raw: Option<RawContext>,
+}
 
-impl Disks {
-    fn parse_uuid_from(path: &Path) -> Option<Uuid> {
-        path.file_name()
-            .and_then(|file_name| file_name.to_str())
-            .and_then(|file_name| Uuid::parse_str(file_name).ok())
-    }
+enum LuksHandle {
+    Luks1(Luks1CryptDeviceHandle),
+    Luks2(Luks2CryptDeviceHandle),
+}
 
-    /// Return a list of all the disk UUIDs that are visible under `/dev/disk/by-uuid/`
-    pub fn all_disk_uuids() -> Result<Vec<Uuid>> {
-        // assume udev
-        fs::read_dir(Path::new(DISK_BY_UUID))
-            .and_then(|entries| {
-                entries
-                    .filter(Disks::has_full_uuid)
-                    .map(|entry| {
-                        entry.map(|e| e.path()).and_then(|p| {
-                            Disks::parse_uuid_from(&p).ok_or(io::Error::new(ErrorKind::Other, "Uuid parsing failed"))
-                        })
-                    })
-                    .collect()
-            })
-            .context(IoSnafu)
-    }
+impl OpenedDevice {
+    /// Open the LUKS device at the path
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<OpenedDevice> {
+        let handle = cryptsetup_rs::open(path.as_ref())?
+            .luks()?
+            .either(LuksHandle::Luks1, LuksHandle::Luks2);
+        let mut device = OpenedDevice { handle, raw: None };
 
-    /// Convert a UUID into a path under `/dev/disk/by-uuid/` if the disk with that UUID exists
-    pub fn disk_uuid_path(uuid: &Uuid) -> Result<PathBuf> {
-        let path = Path::new(DISK_BY_UUID).join(uuid.hyphenated().to_string());
-
-        fs::symlink_metadata(&path)
-            .and_then(|meta| {
-                let ft = meta.file_type();
-                if ft.is_file() || ft.is_symlink() {
-                    Ok(path)
-                } else {
-                    Err(io::Error::new(
-                        io::ErrorKind::NotFound,
-                        format!("Disk path {} is not a file", path.display()),
-                    ))
+        // every other operation branches on the handle type, so a wrong guess must not get any further than this
+        // (a LUKS2 device with a damaged primary header is opened from its secondary header and cannot be checked)
+        let detected = device.version();
+        if let Some(header) = LuksVersion::probe(path.as_ref())? {
+            ensure!(
+                header == detected,
+                LuksVersionMismatchSnafu {
+                    path: path.as_ref(),
+                    header,
+                    detected,
                 }
-            })
-            .context(IoSnafu)
+            );
+        }
+        disk_log!(
+            Level::Debug,
+            "open-device",
+            device.uuid(),
+            "Opened {} as {:?}",
+            path.as_ref().display(),
+            detected
+        );
+        Ok(device)
     }
 
-    /// Test whether a device name is in use already (i.e. it is actively mapped)
-    pub fn is_device_active(name: &str) -> bool {
-        debug!("checking device active {}", name);
-        match cryptsetup_rs::api::status(name) {
-            cryptsetup_rs::api::crypt_status_info::CRYPT_ACTIVE => true,
-            cryptsetup_rs::api::crypt_status_info::CRYPT_BUSY => true,
-            _ => false,
+    fn version(&self) -> LuksVersion {
+        match self.handle {
+            LuksHandle::Luks1(_) => LuksVersion::Luks1,
+            LuksHandle::Luks2(_) => LuksVersion::Luks2,
         }
     }
 
-    // todo: consider adding this to the context + higher-level convenience methods
-    /// Scan sysfs for active devices and return a list of found devices
-    pub fn scan_sysfs_for_active_crypt_devices() -> Result<Vec<DmSetupDeviceInfo>> {
-        if !PathBuf::from(SYSFS_VIRTUAL_BLOCK_DIR).is_dir() {
-            return Ok(vec![]);
+    fn either<T, F1, F2>(&mut self, luks1: F1, luks2: F2) -> T
+    where
+        F1: FnOnce(&mut Luks1CryptDeviceHandle) -> T,
+        F2: FnOnce(&mut Luks2CryptDeviceHandle) -> T,
+    {
+        match &mut self.handle {
+            LuksHandle::Luks1(device) => luks1(device),
+            LuksHandle::Luks2(device) => luks2(device),
         }
+    }
 
-        let dm_paths = fs::read_dir(SYSFS_VIRTUAL_BLOCK_DIR)
-            .context(IoSnafu)?
-            .into_iter()
-            .filter_map(|res| res.ok())
-            .filter(|e| e.path().is_dir() && e.path().file_name().map_or(false, |c| c.as_bytes().starts_with(b"dm-")))
-            .map(|e| e.path())
-            .collect::<Vec<_>>();
-
-        let mut res = vec![];
-        for path in dm_paths {
-            let name = fs::read_to_string(path.join("dm/name")).context(IoSnafu)?;
-            let slave_dirs = fs::read_dir(path.join("slaves"))
-                .context(IoSnafu)?
-                .into_iter()
-                .filter_map(|res| res.ok())
-                .filter(|e| e.path().is_symlink())
-                .filter_map(|e| e.path().canonicalize().ok())
-                .collect::<Vec<_>>();
-
-            if slave_dirs.len() == 1 {
-                let dev_name = fs::read_to_string(slave_dirs.get(0).unwrap().join("dev")).context(IoSnafu)?;
-                let dev_path = PathBuf::from(DEVFS_BLOCK_DIR)
-                    .join(dev_name.trim_end())
-                    .canonicalize()
-                    .context(IoSnafu)?;
-
-                // if we fail to read the luks uuid - either we don't have permission or it's not a luks disk, so don't add to list
-                if let Ok(uuid) = luks_uuid(&dev_path) {
-                    res.push(DmSetupDeviceInfo {
-                        dm_name: path.file_name().unwrap().to_string_lossy().to_string(),
-                        name: name.trim_end().to_string(),
-                        underlying: dev_path,
-                        underlying_uuid: uuid,
-                    })
-                }
-            }
-        }
+    fn uuid(&mut self) -> Uuid {
+        self.either(|luks1| luks1.uuid(), |luks2| luks2.uuid())
+    }
 
-        debug!("found sysfs mappings: {:?}", res);
+    fn activate(&mut self, name: &str, key: &SecStr, flags: ActivationFlags) -> Result<Keyslot> {
+        ensure!(
+            !flags.keyring || self.version() == LuksVersion::Luks2,
+            KeyringUnsupportedSnafu
+        );
+        // `CryptDevice::activate` of cryptsetup-rs always passes `0` as the flags
+        self.raw()?.activate(Some(name), None, key.unsecure(), flags.bits())
+    }
 
-        Ok(res)
+    /// Check which keyslot the key unlocks (only trying the given one if any) without activating the device
+    fn test_key(&mut self, key: &SecStr, maybe_keyslot: Option<Keyslot>) -> Result<Keyslot> {
+        self.raw()?.activate(None, maybe_keyslot, key.unsecure(), 0)
     }
 
-    // FAT32/NTFS disks do not have a UUID of the proper length - exclude them as they cannot be
-    // LUKS disks
-    fn has_full_uuid(e: &io::Result<fs::DirEntry>) -> bool {
-        e.as_ref()
-            .map(|entry| entry.path().file_name().unwrap().len() == UUID_LENGTH)
-            .unwrap_or(false)
+    fn deactivate(self, name: &str) -> Result<()> {
+        match self.handle {
+            LuksHandle::Luks1(luks1) => luks1.deactivate(name),
+            LuksHandle::Luks2(luks2) => luks2.deactivate(name),
+        }?;
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use expectest::prelude::*;
+    fn add_key(
+        &mut self,
+        iteration_ms: usize,
+        new_key: &SecStr,
+        prev_key: &PreviousKey,
+        params: &FormatContainerParams,
+        maybe_keyslot: Option<Keyslot>,
+        maybe_priority: Option<KeyslotPriority>,
+    ) -> Result<Keyslot> {
+        self.either(
+            |luks1| {
+                ensure!(maybe_priority.is_none(), KeyslotPriorityUnsupportedSnafu);
+                check_free_keyslot(luks1, maybe_keyslot, LUKS1_KEYSLOT_COUNT)
+            },
+            |luks2| check_free_keyslot(luks2, maybe_keyslot, LUKS2_KEYSLOT_COUNT),
+        )?;
 
-    use super::*;
+        // the keyslot is added through a context of its own, as cryptsetup-rs cannot add one with a given volume key
+        let version = self.version();
+        let raw = self.raw()?;
+        raw.set_iteration_time(iteration_ms as u64);
+        let token_id = match (version, params) {
+            (
+                LuksVersion::Luks2,
+                FormatContainerParams::Luks2 {
+                    hash,
+                    time_ms,
+                    iterations,
+                    max_memory_kb,
+                    parallel_threads,
+                    token_id,
+                    ..
+                },
+            ) => {
+                // always use argon2id
+                raw.set_pbkdf_params(&Luks2FormatPbkdf {
+                    type_: LUKS2_PBKDF_TYPE,
+                    hash,
+                    time_ms: *time_ms,
+                    iterations: *iterations,
+                    max_memory_kb: *max_memory_kb,
+                    parallel_threads: *parallel_threads,
+                    flags: 0,
+                })?;
+                *token_id
+            }
+            _ => None,
+        };
 
-    #[test]
-    fn test_all_disks_uuids_must_return_something() {
-        let maybe_uuids = Disks::all_disk_uuids();
-        expect!(maybe_uuids).to(be_ok());
+        let keyslot = match prev_key {
+            PreviousKey::Key(key) => raw.add_keyslot(new_key.unsecure(), key.unsecure(), maybe_keyslot)?,
+            PreviousKey::VolumeKey(volume_key) => {
+                let expected = raw.volume_key_size();
+                let actual = volume_key.unsecure().len();
+                ensure!(actual == expected, VolumeKeyLengthSnafu { expected, actual });
+                raw.add_keyslot_by_volume_key(new_key.unsecure(), Some(volume_key.unsecure()), maybe_keyslot)?
+            }
+        };
+        if let Some(token_id) = token_id {
+            raw.assign_token_to_keyslot(token_id, keyslot)?;
+        }
+        if let Some(priority) = maybe_priority {
+            raw.set_keyslot_priority(keyslot, priority)?;
+        }
+        Ok(keyslot)
     }
 
-    #[test]
-    fn test_scan_sysfs_for_active_crypt_devices_must_be_ok() {
-        let maybe_devices = Disks::scan_sysfs_for_active_crypt_devices();
-        expect!(maybe_devices).to(be_ok());
+    fn remove_keyslot(&mut self, keyslot: Keyslot) -> Result<()> {
+        self.either(
+            |luks1| luks1.destroy_keyslot(keyslot),
+            |luks2| luks2.destroy_keyslot(keyslot),
+        )?;
+        // the keyslot is destroyed through the handle, so the context would be stale
+        self.raw = None;
+        Ok(())
+    }
+
+    fn token_keyslot(&mut self, token_id: Luks2TokenId) -> Result<Option<Keyslot>> {
+        let keyslot = self.either(
+            |_luks1| Ok(None),
+            |luks2| {
+                luks2
+                    .get_token(token_id)
+                    .map(|token| token.keyslots.iter().find_map(|k| k.parse::<Keyslot>().ok()))
+            },
+        )?;
+        Ok(keyslot)
+    }
+
+    fn token_exists(&mut self, token_id: Luks2TokenId) -> bool {
+        self.either(
+            |_luks1| false,
+            |luks2| {
+                !matches!(
+                    luks2.token_status(token_id),
+                    (crypt_token_info::CRYPT_TOKEN_INVALID, _) | (crypt_token_info::CRYPT_TOKEN_INACTIVE, _)
+                )
+            },
+        )
+    }
+
+    fn active_keyslots(&mut self) -> Vec<Keyslot> {
+        self.either(
+            |luks1| active_keyslots(luks1, LUKS1_KEYSLOT_COUNT),
+            |luks2| active_keyslots(luks2, LUKS2_KEYSLOT_COUNT),
+        )
+    }
+
+    fn keyslot_status(&mut self, keyslot: Keyslot) -> Result<KeyslotStatus> {
+        self.either(
+            |luks1| {
+                check_keyslot_range(Some(keyslot), LUKS1_KEYSLOT_COUNT)?;
+                Ok(KeyslotStatus::from_info(luks1.keyslot_status(keyslot)))
+            },
+            |luks2| {
+                check_keyslot_range(Some(keyslot), LUKS2_KEYSLOT_COUNT)?;
+                Ok(KeyslotStatus::from_info(luks2.keyslot_status(keyslot)))
+            },
+        )
+    }
+
+    fn keyslot_usage(&mut self) -> KeyslotUsage {
+        let count = match self.version() {
+            LuksVersion::Luks1 => LUKS1_KEYSLOT_COUNT,
+            LuksVersion::Luks2 => LUKS2_KEYSLOT_COUNT,
+        };
+        KeyslotUsage {
+            used: self.active_keyslots(),
+            count,
+        }
+    }
+
+    fn volume_info(&mut self) -> LuksVolumeInfo {
+        self.either(|luks1| volume_info(luks1), |luks2| volume_info(luks2))
+    }
+
+    fn keyslot_pbkdf(&mut self, keyslot: Keyslot) -> Result<KeyslotPbkdf> {
+        let keyslot_count = match self.version() {
+            LuksVersion::Luks1 => LUKS1_KEYSLOT_COUNT,
+            LuksVersion::Luks2 => LUKS2_KEYSLOT_COUNT,
+        };
+        check_keyslot_range(Some(keyslot), keyslot_count)?;
+        self.raw()?.keyslot_pbkdf(keyslot)
+    }
+
+    fn set_label(&mut self, label: Option<&str>, subsystem: Option<&str>) -> Result<()> {
+        ensure!(self.version() == LuksVersion::Luks2, LabelUnsupportedSnafu);
+        self.raw()?.set_label(label, subsystem)
+    }
+
+    fn convert_to_luks2(&mut self) -> Result<()> {
+        self.raw()?.convert(crypt_device_type::LUKS2)
+    }
+
+    fn header_backup(&mut self, out: &Path) -> Result<()> {
+        let version = self.version();
+        self.raw()?.header_backup(version, out)
+    }
+
+    fn add_token(&mut self, keyslot: Keyslot) -> Result<Luks2TokenId> {
+        let token_id = self.either(
+            |_luks1| TokenUnsupportedSnafu.fail(),
+            |luks2| Ok(luks2.add_token(&peroxide_token(keyslot))?),
+        )?;
+        // the token is added through the handle, so the context would be stale
+        self.raw = None;
+        Ok(token_id)
+    }
+
+    /// A libcryptsetup context of its own for the device (see `RawContext`), loaded the first time it is needed
+    fn raw(&mut self) -> Result<&mut RawContext> {
+        if self.raw.is_none() {
+            let path = self.either(|luks1| luks1.path().to_path_buf(), |luks2| luks2.path().to_path_buf());
+            self.raw = Some(RawContext::load(&path, self.version())?);
+        }
+        Ok(self.raw.as_mut().expect("raw context is loaded"))
+    }
+}
+
+/// A libcryptsetup context of its own, for the calls that cryptsetup-rs does not wrap (it is freed when dropped)
+///
+/// The header is loaded again for it, so an `OpenedDevice` of the same device does not see the changes made through it.
+struct RawContext {
+    cd: RawDevice,
+}
+
+impl RawContext {
+    /// Load the LUKS header of the device at the path
+    fn load(path: &Path, version: LuksVersion) -> Result<RawContext> {
+        let context = RawContext::init(path)?;
+        cryptsetup_rs::device::load(&context.cd, version.device_type())?;
+        Ok(context)
+    }
+
+    /// A context for the device at the path, without loading its header (e.g. to format it)
+    fn init(path: &Path) -> Result<RawContext> {
+        Ok(RawContext {
+            cd: cryptsetup_rs::device::init(path)?,
+        })
+    }
+
+    /// Load the LUKS header of the device under an active mapping
+    fn by_name(name: &str) -> Result<RawContext> {
+        Ok(RawContext {
+            cd: cryptsetup_rs::device::init_by_name(name)?,
+        })
+    }
+
+    /// Unlock a keyslot (any of them if `None`) with the key, activating the device under the name if one is given and
+    /// only checking the key otherwise
+    fn activate(&mut self, name: Option<&str>, keyslot: Option<Keyslot>, key: &[u8], flags: u32) -> Result<Keyslot> {
+        let c_name = name.map(c_string).transpose()?;
+        let res = unsafe {
+            raw::crypt_activate_by_passphrase(
+                self.cd,
+                c_name.as_ref().map_or(ptr::null(), |name| name.as_ptr()),
+                c_keyslot(keyslot),
+                key.as_ptr() as *const c_char,
+                key.len(),
+                flags,
+            )
+        };
+        Ok(check_crypt_result(res)? as Keyslot)
+    }
+
+    /// Suspend the mapping (IO is frozen and the volume key is wiped from the kernel)
+    fn suspend(&mut self, name: &str) -> Result<()> {
+        let c_name = c_string(name)?;
+        let res = unsafe { raw::crypt_suspend(self.cd, c_name.as_ptr()) };
+        check_crypt_result(res)?;
+        Ok(())
+    }
+
+    /// Resume the suspended mapping with the key of any keyslot, returning the keyslot it unlocked
+    fn resume(&mut self, name: &str, key: &[u8]) -> Result<Keyslot> {
+        let c_name = c_string(name)?;
+        let res = unsafe {
+            raw::crypt_resume_by_passphrase(
+                self.cd,
+                c_name.as_ptr(),
+                c_keyslot(None),
+                key.as_ptr() as *const c_char,
+                key.len(),
+            )
+        };
+        Ok(check_crypt_result(res)? as Keyslot)
+    }
+
+    /// Read the PBKDF parameters of a keyslot (LUKS1 keyslots are always `pbkdf2`, with only the iterations set)
+    fn keyslot_pbkdf(&mut self, keyslot: Keyslot) -> Result<KeyslotPbkdf> {
+        let mut pbkdf = raw::crypt_pbkdf_type {
+            type_: ptr::null(),
+            hash: ptr::null(),
+            time_ms: 0,
+            iterations: 0,
+            max_memory_kb: 0,
+            parallel_threads: 0,
+            flags: 0,
+        };
+        let res = unsafe { raw::crypt_keyslot_get_pbkdf(self.cd, c_keyslot(Some(keyslot)), &mut pbkdf) };
+        check_crypt_result(res)?;
+        Ok(KeyslotPbkdf::from_raw(&pbkdf))
+    }
+
+    /// Change the label and/or subsystem in the LUKS2 header (`None` keeps the current value)
+    fn set_label(&mut self, label: Option<&str>, subsystem: Option<&str>) -> Result<()> {
+        let c_label = match label {
+            Some(label) => c_string(label)?,
+            None => owned_c_string(unsafe { crypt_get_label(self.cd) }),
+        };
+        let c_subsystem = match subsystem {
+            Some(subsystem) => c_string(subsystem)?,
+            None => owned_c_string(unsafe { crypt_get_subsystem(self.cd) }),
+        };
+        let res = unsafe { raw::crypt_set_label(self.cd, c_label.as_ptr(), c_subsystem.as_ptr()) };
+        check_crypt_result(res)?;
+        Ok(())
+    }
+
+    /// Convert the header in place to another LUKS version
+    fn convert(&mut self, device_type: crypt_device_type) -> Result<()> {
+        let c_type = c_string(device_type.to_str())?;
+        let res = unsafe { raw::crypt_convert(self.cd, c_type.as_ptr(), ptr::null_mut()) };
+        check_crypt_result(res)?;
+        Ok(())
+    }
+
+    /// Write the loaded header to a new file
+    fn header_backup(&mut self, version: LuksVersion, out: &Path) -> Result<()> {
+        let c_type = c_string(version.device_type().to_str())?;
+        let c_out = c_path(out)?;
+        let res = unsafe { raw::crypt_header_backup(self.cd, c_type.as_ptr(), c_out.as_ptr()) };
+        check_crypt_result(res)?;
+        Ok(())
+    }
+
+    /// Overwrite the header of the device with the header in the backup file (the device need not have a valid header)
+    fn header_restore(&mut self, version: LuksVersion, from: &Path) -> Result<()> {
+        let c_type = c_string(version.device_type().to_str())?;
+        let c_from = c_path(from)?;
+        let res = unsafe { raw::crypt_header_restore(self.cd, c_type.as_ptr(), c_from.as_ptr()) };
+        check_crypt_result(res)?;
+        Ok(())
+    }
+
+    /// Read the state of a LUKS2 reencryption from the loaded header
+    fn reencrypt_status(&mut self) -> Result<ReencryptStatus> {
+        let info = unsafe { crypt_reencrypt_status(self.cd, ptr::null_mut()) };
+        ReencryptStatus::from_info(info)
+    }
+
+    #[allow(deprecated)]
+    fn set_iteration_time(&mut self, iteration_ms: u64) {
+        cryptsetup_rs::device::set_iteration_time(&mut self.cd, iteration_ms);
+    }
+
+    fn set_pbkdf_params(&mut self, pbkdf: &Luks2FormatPbkdf<'_>) -> Result<()> {
+        Ok(cryptsetup_rs::device::luks2_set_pbkdf_type(&mut self.cd, pbkdf)?)
+    }
+
+    fn volume_key_size(&self) -> usize {
+        cryptsetup_rs::device::volume_key_size(&self.cd) as usize
+    }
+
+    /// Add a keyslot (the first free one if `None`) with the new key, unlocking the device with the key of another one
+    fn add_keyslot(&mut self, new_key: &[u8], prev_key: &[u8], maybe_keyslot: Option<Keyslot>) -> Result<Keyslot> {
+        Ok(cryptsetup_rs::device::luks_add_keyslot(
+            &mut self.cd,
+            new_key,
+            Some(prev_key),
+            maybe_keyslot,
+        )?)
+    }
+
+    /// Add a keyslot (the first free one if `None`) with the new key, given the volume key of the device (that of a
+    /// device that was just formatted with this context if `None`)
+    fn add_keyslot_by_volume_key(
+        &mut self,
+        new_key: &[u8],
+        maybe_volume_key: Option<&[u8]>,
+        maybe_keyslot: Option<Keyslot>,
+    ) -> Result<Keyslot> {
+        let (c_volume_key, c_volume_key_len) = match maybe_volume_key {
+            Some(volume_key) => (volume_key.as_ptr() as *const c_char, volume_key.len()),
+            None => (ptr::null(), 0),
+        };
+        let res = unsafe {
+            raw::crypt_keyslot_add_by_volume_key(
+                self.cd,
+                c_keyslot(maybe_keyslot),
+                c_volume_key,
+                c_volume_key_len,
+                new_key.as_ptr() as *const c_char,
+                new_key.len(),
+            )
+        };
+        Ok(check_crypt_result(res)? as Keyslot)
+    }
+
+    /// Add a LUKS2 token (with the given id, or the first free one if `None`), returning its id
+    fn add_token(&mut self, token: &Luks2Token, maybe_token_id: Option<Luks2TokenId>) -> Result<Luks2TokenId> {
+        let json = String::try_from(token).map_err(|e| {
+            OtherSnafu {
+                message: format!("Invalid token JSON: {}", e),
+            }
+            .build()
+        })?;
+        Ok(cryptsetup_rs::device::luks2_token_json_allocate(
+            &mut self.cd,
+            &json,
+            maybe_token_id,
+        )?)
+    }
+
+    /// Set the sizes of the LUKS2 metadata and keyslots areas of a device that is formatted next (`0` for the default)
+    fn set_metadata_size(&mut self, metadata_size: u64, keyslots_size: u64) -> Result<()> {
+        let res = unsafe { raw::crypt_set_metadata_size(self.cd, metadata_size, keyslots_size) };
+        check_crypt_result(res)?;
+        Ok(())
+    }
+
+    fn assign_token_to_keyslot(&mut self, token_id: Luks2TokenId, keyslot: Keyslot) -> Result<()> {
+        Ok(cryptsetup_rs::device::luks2_token_assign_keyslot(
+            &mut self.cd,
+            token_id,
+            Some(keyslot),
+        )?)
+    }
+
+    /// Change the priority of a LUKS2 keyslot
+    fn set_keyslot_priority(&mut self, keyslot: Keyslot, priority: KeyslotPriority) -> Result<()> {
+        let c_priority = match priority {
+            KeyslotPriority::Ignore => raw::crypt_keyslot_priority::CRYPT_SLOT_PRIORITY_IGNORE,
+            KeyslotPriority::Normal => raw::crypt_keyslot_priority::CRYPT_SLOT_PRIORITY_NORMAL,
+            KeyslotPriority::Prefer => raw::crypt_keyslot_priority::CRYPT_SLOT_PRIORITY_PREFER,
+        };
+        let res = unsafe { raw::crypt_keyslot_set_priority(self.cd, c_keyslot(Some(keyslot)), c_priority) };
+        check_crypt_result(res)?;
+        Ok(())
+    }
+}
+
+impl Drop for RawContext {
+    fn drop(&mut self) {
+        cryptsetup_rs::device::free(&mut self.cd);
+    }
+}
+
+// part of libcryptsetup since 2.0, but not declared by libcryptsetup-sys
+extern "C" {
+    fn crypt_get_label(cd: *mut raw::crypt_device) -> *const c_char;
+    fn crypt_get_subsystem(cd: *mut raw::crypt_device) -> *const c_char;
+}
+
+// part of libcryptsetup since 2.2, but not declared by libcryptsetup-sys (the values of `crypt_reencrypt_info`)
+const CRYPT_REENCRYPT_NONE: c_int = 0;
+const CRYPT_REENCRYPT_CLEAN: c_int = 1;
+const CRYPT_REENCRYPT_CRASH: c_int = 2;
+
+extern "C" {
+    // the parameters of the reencryption are not read, so they are declared as an opaque pointer
+    fn crypt_reencrypt_status(cd: *mut raw::crypt_device, params: *mut c_void) -> c_int;
+}
+
+/// Fail with the error code of a libcryptsetup call (which returns a negative errno), passing other results through
+fn check_crypt_result(res: c_int) -> Result<c_int> {
+    if res < 0 {
+        CryptsetupSnafu {
+            underlying: errno::Errno(-res),
+        }
+        .fail()
+    } else {
+        Ok(res)
+    }
+}
+
+fn c_keyslot(maybe_keyslot: Option<Keyslot>) -> c_int {
+    maybe_keyslot.map_or(raw::CRYPT_ANY_SLOT, c_int::from)
+}
+
+/// Copy a string returned by libcryptsetup (empty if there is none)
+fn owned_c_string(c_str: *const c_char) -> CString {
+    if c_str.is_null() {
+        CString::default()
+    } else {
+        CString::from(unsafe { CStr::from_ptr(c_str) })
+    }
+}
+
+fn c_string(s: &str) -> Result<CString> {
+    CString::new(s).map_err(|_| {
+        OtherSnafu {
+            message: format!("{:?} contains a NUL byte", s),
+        }
+        .build()
+    })
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+        OtherSnafu {
+            message: format!("{} contains a NUL byte", path.display()),
+        }
+        .build()
+    })
+}
+
+/// Read the UUID of a LUKS1 or LUKS2 device from its header with blkid, without loading the device with cryptsetup
+///
+/// This is cheap and needs no cryptsetup context, so it is used whenever many devices are probed (e.g. when scanning
+/// the block devices or the active mappings).
+pub fn luks_uuid_via_blkid(path: &Path) -> Result<Uuid> {
+    cryptsetup_rs::luks_uuid(path).map_err(From::from)
+}
+
+/// Fail if the header backup file is not a LUKS header of the disk with the UUID (restoring it would make the disk
+/// unreadable with its keys)
+pub fn ensure_header_backup_uuid(from: &Path, uuid: &Uuid) -> Result<()> {
+    let backup_uuid = luks_uuid_via_blkid(from)?;
+    ensure!(
+        backup_uuid == *uuid,
+        OtherSnafu {
+            message: format!(
+                "The header backup {} is of the disk with uuid `{}`, not `{}`",
+                from.display(),
+                backup_uuid,
+                uuid
+            ),
+        }
+    );
+    Ok(())
+}
+
+/// Fail if a reencryption of the LUKS device at the path is in progress (keyslots must not be added to its header
+/// until it has finished)
+pub fn ensure_not_reencrypting(path: &Path) -> Result<()> {
+    ensure!(
+        path.luks_reencrypt_status()? == ReencryptStatus::None,
+        ReencryptionInProgressSnafu { path }
+    );
+    Ok(())
+}
+
+/// Benchmark a PBKDF on this machine, returning the iterations (and memory cost) that make one key derivation take the
+/// target time
+pub fn benchmark_pbkdf(params: &PbkdfBenchmarkParams) -> Result<KeyslotPbkdf> {
+    let c_algorithm = c_string(&params.algorithm)?;
+    let c_hash = c_string(BENCHMARK_HASH)?;
+    // the iterations are what the benchmark finds, the memory cost is only lowered from the upper bound
+    let mut pbkdf = raw::crypt_pbkdf_type {
+        type_: c_algorithm.as_ptr(),
+        hash: c_hash.as_ptr(),
+        time_ms: params.time_ms,
+        iterations: 0,
+        max_memory_kb: params.max_memory_kb,
+        parallel_threads: params.parallel_threads,
+        flags: 0,
+    };
+    // no device is needed, the same way `cryptsetup benchmark` does it
+    let res = unsafe {
+        raw::crypt_benchmark_pbkdf(
+            ptr::null_mut(),
+            &mut pbkdf,
+            BENCHMARK_PASSWORD.as_ptr() as *const c_char,
+            BENCHMARK_PASSWORD.len(),
+            BENCHMARK_SALT.as_ptr() as *const c_char,
+            BENCHMARK_SALT.len(),
+            params.key_bits / 8,
+            benchmark_progress,
+            ptr::null_mut(),
+        )
+    };
+    check_crypt_result(res)?;
+    Ok(KeyslotPbkdf::from_raw(&pbkdf))
+}
+
+/// Progress callback of `crypt_benchmark_pbkdf` (a non-zero result would stop the benchmark)
+extern "C" fn benchmark_progress(_time_ms: u32, _usrptr: *mut c_void) -> c_int {
+    0
+}
+
+/// The LUKS2 token that marks a keyslot as enrolled by peroxide
+fn peroxide_token(keyslot: Keyslot) -> Luks2Token {
+    Luks2Token {
+        type_: TOKEN_NAME.to_string(),
+        keyslots: vec![keyslot.to_string()],
+        other: serde_json::Map::new(),
+    }
+}
+
+impl<P: AsRef<Path>> LuksVolumeOps for P {
+    fn luks_activate_with_flags(&self, name: &str, key: &SecStr, flags: ActivationFlags) -> Result<Keyslot> {
+        OpenedDevice::open(self)?.activate(name, key, flags)
+    }
+
+    fn luks_deactivate(&self, name: &str) -> Result<()> {
+        OpenedDevice::open(self)?.deactivate(name)
+    }
+
+    fn luks_suspend(&self, name: &str) -> Result<()> {
+        RawContext::by_name(name)?.suspend(name)
+    }
+
+    fn luks_resume(&self, name: &str, key: &SecStr) -> Result<Keyslot> {
+        RawContext::by_name(name)?.resume(name, key.unsecure())
+    }
+
+    fn luks_add_key(
+        &self,
+        iteration_ms: usize,
+        new_key: &SecStr,
+        prev_key: &PreviousKey,
+        params: &FormatContainerParams,
+        maybe_keyslot: Option<Keyslot>,
+        maybe_priority: Option<KeyslotPriority>,
+    ) -> Result<Keyslot> {
+        OpenedDevice::open(self)?.add_key(iteration_ms, new_key, prev_key, params, maybe_keyslot, maybe_priority)
+    }
+
+    fn luks_remove_keyslot(&self, keyslot: Keyslot) -> Result<()> {
+        OpenedDevice::open(self)?.remove_keyslot(keyslot)
+    }
+
+    fn luks2_token_keyslot(&self, token_id: Luks2TokenId) -> Result<Option<Keyslot>> {
+        OpenedDevice::open(self)?.token_keyslot(token_id)
+    }
+
+    fn luks2_token_exists(&self, token_id: Luks2TokenId) -> Result<bool> {
+        Ok(OpenedDevice::open(self)?.token_exists(token_id))
+    }
+
+    fn luks_keyslot_for_key(&self, key: &SecStr) -> Result<Keyslot> {
+        OpenedDevice::open(self)?.test_key(key, None)
+    }
+
+    fn luks_key_unlocks_keyslot(&self, key: &SecStr, keyslot: Keyslot) -> Result<bool> {
+        unlocks_keyslot(OpenedDevice::open(self)?.test_key(key, Some(keyslot)))
+    }
+
+    fn luks_active_keyslots(&self) -> Result<Vec<Keyslot>> {
+        Ok(OpenedDevice::open(self)?.active_keyslots())
+    }
+
+    fn luks_keyslot_status(&self, keyslot: Keyslot) -> Result<KeyslotStatus> {
+        OpenedDevice::open(self)?.keyslot_status(keyslot)
+    }
+
+    fn luks_keyslot_usage(&self) -> Result<KeyslotUsage> {
+        Ok(OpenedDevice::open(self)?.keyslot_usage())
+    }
+
+    fn luks_format_with_key(
+        &self,
+        key: &SecStr,
+        params: &FormatContainerParams,
+        maybe_keyslot: Option<Keyslot>,
+        maybe_priority: Option<KeyslotPriority>,
+    ) -> Result<FormatResult> {
+        match params {
+            FormatContainerParams::Luks1 {
+                iteration_ms,
+                cipher,
+                cipher_mode,
+                hash,
+                mk_bits,
+                uuid,
+            } => {
+                check_keyslot_range(maybe_keyslot, LUKS1_KEYSLOT_COUNT)?;
+                ensure!(maybe_priority.is_none(), KeyslotPriorityUnsupportedSnafu);
+                let mut device = cryptsetup_rs::format(self)?
+                    .iteration_time(*iteration_ms as u64)
+                    .luks1(cipher, cipher_mode, hash, *mk_bits, uuid.as_ref())?;
+                device.set_iteration_time(*iteration_ms as u64);
+                let keyslot = device.add_keyslot(key.unsecure(), None, maybe_keyslot)?;
+
+                Ok(FormatResult::Luks1 { keyslot })
+            }
+            FormatContainerParams::Luks2 {
+                cipher,
+                cipher_mode,
+                mk_bits,
+                hash,
+                time_ms,
+                iterations,
+                max_memory_kb,
+                parallel_threads,
+                sector_size,
+                data_alignment,
+                metadata_size,
+                keyslots_size,
+                save_label_in_header: _save_label_in_header,
+                uuid,
+                label,
+                subsystem,
+                token_id,
+            } => {
+                check_keyslot_range(maybe_keyslot, LUKS2_KEYSLOT_COUNT)?;
+                // the format builder of cryptsetup-rs cannot set the metadata size, so a context of its own is used
+                let mut raw = RawContext::init(self.as_ref())?;
+                if metadata_size.is_some() || keyslots_size.is_some() {
+                    // `0` keeps the default of cryptsetup
+                    raw.set_metadata_size(metadata_size.unwrap_or(0), keyslots_size.unwrap_or(0))?;
+                }
+                cryptsetup_rs::device::luks2_format(
+                    &mut raw.cd,
+                    cipher,
+                    cipher_mode,
+                    *mk_bits,
+                    data_alignment.unwrap_or(0) as usize,
+                    sector_size.unwrap_or(LUKS2_DEFAULT_SECTOR_SIZE),
+                    label.as_deref(),
+                    subsystem.as_deref(),
+                    None,
+                    uuid.as_ref(),
+                    Some(&Luks2FormatPbkdf {
+                        type_: LUKS2_PBKDF_TYPE,
+                        hash,
+                        time_ms: *time_ms,
+                        iterations: *iterations,
+                        max_memory_kb: *max_memory_kb,
+                        parallel_threads: *parallel_threads,
+                        flags: 0,
+                    }),
+                    None,
+                )?;
+                // the volume key of a device that was just formatted is still in the context
+                let key = raw.add_keyslot_by_volume_key(key.unsecure(), None, maybe_keyslot)?;
+
+                // always add a luks 2 token to the keyslot
+                let tok = raw.add_token(&peroxide_token(key), *token_id)?;
+
+                if let Some(priority) = maybe_priority {
+                    raw.set_keyslot_priority(key, priority)?;
+                }
+
+                Ok(FormatResult::Luks2 {
+                    keyslot: key,
+                    token_id: Some(tok),
+                })
+            }
+        }
+    }
+
+    fn luks_uuid(&self) -> Result<Uuid> {
+        luks_uuid_via_blkid(self.as_ref())
+    }
+
+    fn luks_volume_info(&self) -> Result<LuksVolumeInfo> {
+        Ok(OpenedDevice::open(self)?.volume_info())
+    }
+
+    fn luks_version(&self) -> Result<LuksVersion> {
+        Ok(OpenedDevice::open(self)?.version())
+    }
+
+    fn is_luks(&self) -> bool {
+        matches!(LuksVersion::probe(self.as_ref()), Ok(Some(_)))
+    }
+
+    fn luks_reencrypt_status(&self) -> Result<ReencryptStatus> {
+        match LuksVersion::probe(self.as_ref())? {
+            Some(LuksVersion::Luks2) => RawContext::load(self.as_ref(), LuksVersion::Luks2)?.reencrypt_status(),
+            Some(LuksVersion::Luks1) => Ok(ReencryptStatus::None),
+            None => DeviceReadSnafu {
+                message: format!("{} does not have a LUKS header", self.as_ref().display()),
+            }
+            .fail(),
+        }
+    }
+
+    fn luks_keyslot_pbkdf(&self, keyslot: Keyslot) -> Result<KeyslotPbkdf> {
+        OpenedDevice::open(self)?.keyslot_pbkdf(keyslot)
+    }
+
+    fn luks2_set_label(&self, label: Option<&str>, subsystem: Option<&str>) -> Result<()> {
+        OpenedDevice::open(self)?.set_label(label, subsystem)
+    }
+
+    fn luks_convert_to_luks2(&self) -> Result<()> {
+        OpenedDevice::open(self)?.convert_to_luks2()
+    }
+
+    fn luks2_add_token(&self, keyslot: Keyslot) -> Result<Luks2TokenId> {
+        OpenedDevice::open(self)?.add_token(keyslot)
+    }
+
+    fn luks_header_backup(&self, out: &Path) -> Result<()> {
+        OpenedDevice::open(self)?.header_backup(out)
+    }
+
+    fn luks_header_restore(&self, from: &Path) -> Result<()> {
+        let version = LuksVersion::probe(from)?.context(DeviceReadSnafu {
+            message: format!("{} does not have a LUKS header", from.display()),
+        })?;
+        // the header of the device is not loaded, it may well be the reason for restoring it
+        RawContext::init(self.as_ref())?.header_restore(version, from)
+    }
+}
+
+/// The devices opened during an operation, keyed by their canonical path so that each one is only opened once
+///
+/// The devices are closed when the cache is dropped, so it should not outlive the operation.
+#[derive(Default)]
+pub struct OpenedDevices {
+    devices: RefCell<HashMap<PathBuf, OpenedDevice>>,
+}
+
+impl OpenedDevices {
+    pub fn new() -> OpenedDevices {
+        OpenedDevices::default()
+    }
+
+    /// The device at the path (it is opened on first use)
+    pub fn device<P: AsRef<Path>>(&self, path: P) -> CachedDevice<'_> {
+        CachedDevice {
+            devices: self,
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    fn with_device<T, F: FnOnce(&mut OpenedDevice) -> Result<T>>(&self, path: &Path, f: F) -> Result<T> {
+        let canonical_path = fs::canonicalize(path).context(IoSnafu)?;
+        let mut devices = self.devices.borrow_mut();
+        let device = match devices.entry(canonical_path) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(OpenedDevice::open(path)?),
+        };
+        f(device)
+    }
+
+    fn take(&self, path: &Path) -> Result<Option<OpenedDevice>> {
+        let canonical_path = fs::canonicalize(path).context(IoSnafu)?;
+        Ok(self.devices.borrow_mut().remove(&canonical_path))
+    }
+}
+
+/// A device of an `OpenedDevices` cache
+pub struct CachedDevice<'a> {
+    devices: &'a OpenedDevices,
+    path: PathBuf,
+}
+
+impl<'a> CachedDevice<'a> {
+    pub fn path(&self) -> &Path {
+        self.path.as_ref()
+    }
+}
+
+impl<'a> LuksVolumeOps for CachedDevice<'a> {
+    fn luks_activate_with_flags(&self, name: &str, key: &SecStr, flags: ActivationFlags) -> Result<Keyslot> {
+        self.devices
+            .with_device(&self.path, |device| device.activate(name, key, flags))
+    }
+
+    fn luks_deactivate(&self, name: &str) -> Result<()> {
+        // deactivating consumes the handle, so the device leaves the cache
+        match self.devices.take(&self.path)? {
+            Some(device) => device.deactivate(name),
+            None => self.path.luks_deactivate(name),
+        }
+    }
+
+    fn luks_suspend(&self, name: &str) -> Result<()> {
+        self.path.luks_suspend(name)
+    }
+
+    fn luks_resume(&self, name: &str, key: &SecStr) -> Result<Keyslot> {
+        self.path.luks_resume(name, key)
+    }
+
+    fn luks_add_key(
+        &self,
+        iteration_ms: usize,
+        new_key: &SecStr,
+        prev_key: &PreviousKey,
+        params: &FormatContainerParams,
+        maybe_keyslot: Option<Keyslot>,
+        maybe_priority: Option<KeyslotPriority>,
+    ) -> Result<Keyslot> {
+        let keyslot = self.devices.with_device(&self.path, |device| {
+            device.add_key(iteration_ms, new_key, prev_key, params, maybe_keyslot, maybe_priority)
+        })?;
+        // the keyslot is added through a context of its own, so a handle opened before would be stale
+        let _ = self.devices.take(&self.path)?;
+        Ok(keyslot)
+    }
+
+    fn luks_remove_keyslot(&self, keyslot: Keyslot) -> Result<()> {
+        self.devices
+            .with_device(&self.path, |device| device.remove_keyslot(keyslot))
+    }
+
+    fn luks2_token_keyslot(&self, token_id: Luks2TokenId) -> Result<Option<Keyslot>> {
+        self.devices
+            .with_device(&self.path, |device| device.token_keyslot(token_id))
+    }
+
+    fn luks2_token_exists(&self, token_id: Luks2TokenId) -> Result<bool> {
+        self.devices
+            .with_device(&self.path, |device| Ok(device.token_exists(token_id)))
+    }
+
+    fn luks_keyslot_for_key(&self, key: &SecStr) -> Result<Keyslot> {
+        self.devices
+            .with_device(&self.path, |device| device.test_key(key, None))
+    }
+
+    fn luks_key_unlocks_keyslot(&self, key: &SecStr, keyslot: Keyslot) -> Result<bool> {
+        self.devices.with_device(&self.path, |device| {
+            unlocks_keyslot(device.test_key(key, Some(keyslot)))
+        })
+    }
+
+    fn luks_active_keyslots(&self) -> Result<Vec<Keyslot>> {
+        self.devices
+            .with_device(&self.path, |device| Ok(device.active_keyslots()))
+    }
+
+    fn luks_keyslot_status(&self, keyslot: Keyslot) -> Result<KeyslotStatus> {
+        self.devices
+            .with_device(&self.path, |device| device.keyslot_status(keyslot))
+    }
+
+    fn luks_keyslot_usage(&self) -> Result<KeyslotUsage> {
+        self.devices
+            .with_device(&self.path, |device| Ok(device.keyslot_usage()))
+    }
+
+    fn luks_format_with_key(
+        &self,
+        key: &SecStr,
+        params: &FormatContainerParams,
+        maybe_keyslot: Option<Keyslot>,
+        maybe_priority: Option<KeyslotPriority>,
+    ) -> Result<FormatResult> {
+        // formatting rewrites the header, so a handle opened before would be stale
+        let _ = self.devices.take(&self.path)?;
+        self.path
+            .luks_format_with_key(key, params, maybe_keyslot, maybe_priority)
+    }
+
+    fn luks_uuid(&self) -> Result<Uuid> {
+        self.devices.with_device(&self.path, |device| Ok(device.uuid()))
+    }
+
+    fn luks_volume_info(&self) -> Result<LuksVolumeInfo> {
+        self.devices.with_device(&self.path, |device| Ok(device.volume_info()))
+    }
+
+    fn luks_version(&self) -> Result<LuksVersion> {
+        self.devices.with_device(&self.path, |device| Ok(device.version()))
+    }
+
+    fn is_luks(&self) -> bool {
+        // the header is read directly even if the device is already open
+        self.path.is_luks()
+    }
+
+    fn luks_reencrypt_status(&self) -> Result<ReencryptStatus> {
+        self.path.luks_reencrypt_status()
+    }
+
+    fn luks_keyslot_pbkdf(&self, keyslot: Keyslot) -> Result<KeyslotPbkdf> {
+        self.devices
+            .with_device(&self.path, |device| device.keyslot_pbkdf(keyslot))
+    }
+
+    fn luks2_set_label(&self, label: Option<&str>, subsystem: Option<&str>) -> Result<()> {
+        self.devices
+            .with_device(&self.path, |device| device.set_label(label, subsystem))?;
+        // the label is set through a context of its own, so the handle would be stale
+        let _ = self.devices.take(&self.path)?;
+        Ok(())
+    }
+
+    fn luks_convert_to_luks2(&self) -> Result<()> {
+        // converting rewrites the header, so a handle opened before would be stale
+        let _ = self.devices.take(&self.path)?;
+        self.path.luks_convert_to_luks2()
+    }
+
+    fn luks2_add_token(&self, keyslot: Keyslot) -> Result<Luks2TokenId> {
+        self.devices.with_device(&self.path, |device| device.add_token(keyslot))
+    }
+
+    fn luks_header_backup(&self, out: &Path) -> Result<()> {
+        self.devices.with_device(&self.path, |device| device.header_backup(out))
+    }
+
+    fn luks_header_restore(&self, from: &Path) -> Result<()> {
+        // restoring rewrites the header, so a handle opened before would be stale
+        let _ = self.devices.take(&self.path)?;
+        self.path.luks_header_restore(from)
+    }
+}
+
+fn volume_info<D: CryptDevice>(device: &D) -> LuksVolumeInfo {
+    let cipher = match (device.cipher(), device.cipher_mode()) {
+        ("", _) => "unknown".to_string(),
+        (cipher, "") => cipher.to_string(),
+        (cipher, mode) => format!("{}-{}", cipher, mode),
+    };
+    LuksVolumeInfo {
+        cipher,
+        key_bits: device.volume_key_size() as usize * 8,
+    }
+}
+
+/// Map the result of testing a key against a keyslot to whether the key unlocks it
+fn unlocks_keyslot(res: Result<Keyslot>) -> Result<bool> {
+    match res {
+        Ok(_) => Ok(true),
+        Err(e) if e.is_wrong_key() => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Escape a name the way udev does for the names of its links (e.g. a space in a label becomes `\x20`)
+fn udev_escape(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "#+-.:=@_".contains(c) || !c.is_ascii() {
+                c.to_string()
+            } else {
+                format!("\\x{:02x}", c as u32)
+            }
+        })
+        .collect()
+}
+
+/// Read the short serial (`ID_SERIAL_SHORT`, without the vendor and model) from the udev database entry of a device
+fn udev_serial(data: &str) -> Option<String> {
+    data.lines()
+        .filter_map(|line| line.strip_prefix("E:ID_SERIAL_SHORT="))
+        .map(str::trim)
+        .find(|serial| !serial.is_empty())
+        .map(str::to_string)
+}
+
+/// Check that a requested keyslot exists for the LUKS version (a device can only have `keyslot_count` keyslots)
+fn check_keyslot_range(maybe_keyslot: Option<Keyslot>, keyslot_count: Keyslot) -> Result<()> {
+    match maybe_keyslot {
+        Some(keyslot) if keyslot >= keyslot_count => KeyslotOutOfRangeSnafu { keyslot, keyslot_count }.fail(),
+        _ => Ok(()),
+    }
+}
+
+/// Check that a requested keyslot exists and does not have a key already
+fn check_free_keyslot<D: LuksCryptDevice>(
+    device: &D,
+    maybe_keyslot: Option<Keyslot>,
+    keyslot_count: Keyslot,
+) -> Result<()> {
+    check_keyslot_range(maybe_keyslot, keyslot_count)?;
+    match maybe_keyslot {
+        Some(keyslot) if active_keyslots(device, keyslot_count).contains(&keyslot) => {
+            KeyslotInUseSnafu { keyslot }.fail()
+        }
+        _ => Ok(()),
+    }
+}
+
+fn active_keyslots<D: LuksCryptDevice>(device: &D, keyslot_count: Keyslot) -> Vec<Keyslot> {
+    (0..keyslot_count)
+        .filter(|&keyslot| KeyslotStatus::from_info(device.keyslot_status(keyslot)).is_active())
+        .collect()
+}
+
+/// Information gathered about mapped disks from sysfs
+#[derive(Debug, Clone)]
+pub struct DmSetupDeviceInfo {
+    /// dm-N name of the device
+    pub dm_name: String,
+    /// Mapped name of the device
+    pub name: String,
+    /// Underlying block path
+    pub underlying: PathBuf,
+    /// LUKS UUID
+    pub underlying_uuid: Uuid,
+}
+
+/// LUKS devices found by reading the headers of all block devices (the scan is only done once, when first needed, so
+/// that the lookups of several disks in one operation share it)
+#[derive(Debug, Default)]
+pub struct BlockDeviceScan {
+    devices: RefCell<Option<Vec<(PathBuf, Uuid)>>>,
+}
+
+impl BlockDeviceScan {
+    pub fn new() -> BlockDeviceScan {
+        BlockDeviceScan::default()
+    }
+
+    /// Find the block device with the given LUKS UUID
+    pub fn find(&self, uuid: &Uuid) -> Option<PathBuf> {
+        self.devices
+            .borrow_mut()
+            .get_or_insert_with(Disks::scan_block_devices_for_luks)
+            .iter()
+            .find(|(_, luks_uuid)| luks_uuid == uuid)
+            .map(|(path, _)| path.clone())
+    }
+}
+
+#[derive(Debug)]
+pub struct Disks;
+
+/// Lookups of disks and of their mappings on the system, so that the code deciding what to do with them can be tested
+/// with fake disks (`Disks` looks them up for real)
+pub trait DiskInspector: fmt::Debug {
+    /// Find the path of the disk with the given UUID (like `Disks::disk_uuid_path_with_scan`)
+    fn disk_uuid_path(&self, uuid: &Uuid, scan: &BlockDeviceScan) -> Result<PathBuf>;
+    /// Find the active mapping with the given name (like `Disks::active_device_info`)
+    fn active_device_info(&self, name: &str) -> Result<Option<DmSetupDeviceInfo>>;
+    /// List all the active mappings of LUKS disks (like `Disks::scan_sysfs_for_active_crypt_devices`)
+    fn scan_active(&self) -> Result<Vec<DmSetupDeviceInfo>>;
+    /// Test whether a mapping name is in use (like `Disks::is_device_active`)
+    fn is_device_active(&self, name: &str) -> bool;
+    /// Check whether the mapping with the given name is suspended (like `Disks::is_device_suspended`)
+    fn is_device_suspended(&self, name: &str) -> Result<bool>;
+}
+
+impl DiskInspector for Disks {
+    fn disk_uuid_path(&self, uuid: &Uuid, scan: &BlockDeviceScan) -> Result<PathBuf> {
+        Disks::disk_uuid_path_with_scan(uuid, scan)
+    }
+
+    fn active_device_info(&self, name: &str) -> Result<Option<DmSetupDeviceInfo>> {
+        Disks::active_device_info(name)
+    }
+
+    fn scan_active(&self) -> Result<Vec<DmSetupDeviceInfo>> {
+        Disks::scan_sysfs_for_active_crypt_devices()
+    }
+
+    fn is_device_active(&self, name: &str) -> bool {
+        Disks::is_device_active(name)
+    }
+
+    fn is_device_suspended(&self, name: &str) -> Result<bool> {
+        Disks::is_device_suspended(name)
+    }
+}
+
+impl Disks {
+    fn parse_uuid_from(path: &Path) -> Option<Uuid> {
+        path.file_name()
+            .and_then(|file_name| file_name.to_str())
+            .and_then(|file_name| Uuid::parse_str(file_name).ok())
+    }
+
+    /// Directory of the disk-by-uuid symlinks (`/dev/disk/by-uuid/` unless overridden by `PEROXS_DISK_BY_UUID`)
+    pub fn disk_by_uuid_dir() -> PathBuf {
+        env::var_os(DISK_BY_UUID_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DISK_BY_UUID))
+    }
+
+    /// Return a list of all the disk UUIDs that are visible under the disk-by-uuid directory (or, if there is no such
+    /// directory, of the LUKS devices found by scanning the block devices)
+    pub fn all_disk_uuids() -> Result<Vec<Uuid>> {
+        let dir = Disks::disk_by_uuid_dir();
+        if !dir.is_dir() {
+            debug!("{} not found, scanning block devices", dir.display());
+            let uuids = Disks::scan_block_devices_for_luks().into_iter().map(|(_, uuid)| uuid);
+            return Ok(uuids.collect());
+        }
+
+        fs::read_dir(&dir)
+            .and_then(|entries| {
+                entries
+                    .filter(Disks::has_full_uuid)
+                    .map(|entry| {
+                        entry.map(|e| e.path()).and_then(|p| {
+                            Disks::parse_uuid_from(&p).ok_or(io::Error::new(ErrorKind::Other, "Uuid parsing failed"))
+                        })
+                    })
+                    .collect()
+            })
+            .context(IoSnafu)
+    }
+
+    /// Convert a UUID into a path under the disk-by-uuid directory if the disk with that UUID exists there, otherwise
+    /// into the path of the block device with that LUKS UUID
+    pub fn disk_uuid_path(uuid: &Uuid) -> Result<PathBuf> {
+        Disks::disk_uuid_path_with_scan(uuid, &BlockDeviceScan::new())
+    }
+
+    /// Find the path of a disk by its label (the LUKS2 label for LUKS devices) under the disk-by-label directory
+    pub fn disk_label_path(label: &str) -> Result<PathBuf> {
+        Disks::disk_link_path(Path::new(DISK_BY_LABEL), label)
+    }
+
+    /// Find the path of a disk by the UUID of its GPT partition (or the MBR disk id) under the disk-by-partuuid
+    /// directory
+    pub fn disk_partuuid_path(partuuid: &str) -> Result<PathBuf> {
+        Disks::disk_link_path(Path::new(DISK_BY_PARTUUID), &partuuid.to_lowercase())
+    }
+
+    fn disk_link_path(dir: &Path, name: &str) -> Result<PathBuf> {
+        let path = dir.join(udev_escape(name));
+        ensure!(fs::symlink_metadata(&path).is_ok(), DiskLinkNotFoundSnafu { path });
+        Ok(path)
+    }
+
+    /// Like `disk_uuid_path`, but falling back to an (already done or shared) scan of the block devices
+    pub fn disk_uuid_path_with_scan(uuid: &Uuid, scan: &BlockDeviceScan) -> Result<PathBuf> {
+        let dir = Disks::disk_by_uuid_dir();
+        if let Some(path) = Disks::disk_uuid_path_in(&dir, uuid) {
+            return Ok(path);
+        }
+
+        disk_log!(
+            Level::Debug,
+            "find-disk",
+            uuid,
+            "Not found under {}, scanning block devices",
+            dir.display()
+        );
+        scan.find(uuid).context(DiskNotFoundSnafu {
+            uuid: uuid.to_owned(),
+            by_uuid_dir: dir,
+        })
+    }
+
+    /// Like `disk_uuid_path`, but if the disk is not found keep looking for it until it appears or `timeout` elapses
+    /// (e.g. for hotplugged disks whose by-uuid link has not been created by udev yet)
+    pub fn wait_for_disk_uuid(uuid: &Uuid, timeout: Duration) -> Result<PathBuf> {
+        let start = Instant::now();
+        let mut announced = false;
+        loop {
+            // every attempt needs a fresh scan, the device may have appeared since the last one
+            match Disks::disk_uuid_path(uuid) {
+                Ok(path) => return Ok(path),
+                Err(e) if start.elapsed() >= timeout => return Err(e),
+                Err(_) => {
+                    if !announced {
+                        messages::status(format!(
+                            "Waiting up to {}s for disk {} to appear...",
+                            timeout.as_secs(),
+                            uuid
+                        ));
+                        announced = true;
+                    }
+                    thread::sleep(DISK_WAIT_INTERVAL);
+                }
+            }
+        }
+    }
+
+    fn disk_uuid_path_in(dir: &Path, uuid: &Uuid) -> Option<PathBuf> {
+        let path = dir.join(uuid.hyphenated().to_string());
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_file() || meta.file_type().is_symlink() => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Read the serial number of the drive of a block device (the whole disk of a partition) from sysfs, or from the
+    /// udev database for the drives that only udev knows the serial of (`None` if neither has it, e.g. for loop devices)
+    pub fn block_serial(path: &Path) -> Option<String> {
+        let dev = path.canonicalize().ok()?;
+        let sys_dir = Path::new(SYSFS_CLASS_BLOCK_DIR)
+            .join(dev.file_name()?)
+            .canonicalize()
+            .ok()?;
+        // the sysfs directory of a partition is under the one of its disk
+        let disk_dir = if sys_dir.join("partition").exists() {
+            sys_dir.parent()?.to_path_buf()
+        } else {
+            sys_dir
+        };
+        Disks::sysfs_serial(&disk_dir).or_else(|| {
+            // the udev database is keyed by the major:minor number of the device
+            let dev_number = fs::read_to_string(disk_dir.join("dev")).ok()?;
+            let data = fs::read_to_string(Path::new(UDEV_DATA_DIR).join(format!("b{}", dev_number.trim()))).ok()?;
+            udev_serial(&data)
+        })
+    }
+
+    /// Read the serial of a disk from its sysfs directory (NVMe and SCSI drives have it on the device, virtio disks on
+    /// the disk itself)
+    fn sysfs_serial(disk_dir: &Path) -> Option<String> {
+        ["device/serial", "serial"]
+            .iter()
+            .filter_map(|file| fs::read_to_string(disk_dir.join(file)).ok())
+            .map(|serial| serial.trim().to_string())
+            .find(|serial| !serial.is_empty())
+    }
+
+    /// Find the LUKS devices by reading the header of every block device (devices that cannot be read are skipped)
+    fn scan_block_devices_for_luks() -> Vec<(PathBuf, Uuid)> {
+        let entries = match fs::read_dir(SYSFS_CLASS_BLOCK_DIR) {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!("Cannot list block devices in {}: {}", SYSFS_CLASS_BLOCK_DIR, e);
+                return vec![];
+            }
+        };
+
+        entries
+            .filter_map(|res| res.ok())
+            .map(|e| Path::new(DEVFS_DIR).join(e.file_name()))
+            .filter_map(|dev_path| luks_uuid_via_blkid(&dev_path).ok().map(|uuid| (dev_path, uuid)))
+            .collect()
+    }
+
+    /// Test whether a device name is in use already (i.e. it is actively mapped)
+    pub fn is_device_active(name: &str) -> bool {
+        debug!("checking device active {}", name);
+        matches!(
+            cryptsetup_rs::api::status(name),
+            cryptsetup_rs::api::crypt_status_info::CRYPT_ACTIVE | cryptsetup_rs::api::crypt_status_info::CRYPT_BUSY
+        )
+    }
+
+    // todo: consider adding this to the context + higher-level convenience methods
+    /// Scan sysfs for active devices and return a list of found devices
+    pub fn scan_sysfs_for_active_crypt_devices() -> Result<Vec<DmSetupDeviceInfo>> {
+        if !PathBuf::from(SYSFS_VIRTUAL_BLOCK_DIR).is_dir() {
+            return Ok(vec![]);
+        }
+
+        let dm_paths = fs::read_dir(SYSFS_VIRTUAL_BLOCK_DIR)
+            .context(IoSnafu)?
+            .filter_map(|res| res.ok())
+            .filter(|e| e.path().is_dir() && e.path().file_name().map_or(false, |c| c.as_bytes().starts_with(b"dm-")))
+            .map(|e| e.path())
+            .collect::<Vec<_>>();
+
+        let mut res = vec![];
+        for path in dm_paths {
+            if let Some(info) = Disks::dm_device_info(&path)? {
+                res.push(info);
+            }
+        }
+
+        debug!("found sysfs mappings: {:?}", res);
+
+        Ok(res)
+    }
+
+    /// Find the active mapping with the given name (without scanning all the other mappings like
+    /// `scan_sysfs_for_active_crypt_devices` does)
+    pub fn active_device_info(name: &str) -> Result<Option<DmSetupDeviceInfo>> {
+        let dm_path = match Disks::dm_sysfs_path(name) {
+            Some(dm_path) => dm_path,
+            None => return Ok(None),
+        };
+
+        let info = Disks::dm_device_info(&dm_path)?;
+        debug!("found sysfs mapping for {}: {:?}", name, info);
+        Ok(info.filter(|info| info.name == name))
+    }
+
+    /// Check whether the mapping with the given name is suspended
+    pub fn is_device_suspended(name: &str) -> Result<bool> {
+        match Disks::dm_sysfs_path(name) {
+            Some(dm_path) => {
+                let suspended = fs::read_to_string(dm_path.join("dm/suspended")).context(IoSnafu)?;
+                Ok(suspended.trim_end() == "1")
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Find the sysfs directory of the mapping with the given name (if it exists)
+    fn dm_sysfs_path(name: &str) -> Option<PathBuf> {
+        // the device mapper node resolves to the dm-N device, which has the same name under sysfs
+        let dm_dev = PathBuf::from(DEVFS_MAPPER_DIR).join(name).canonicalize().ok()?;
+        let dm_path = PathBuf::from(SYSFS_VIRTUAL_BLOCK_DIR).join(dm_dev.file_name()?);
+        if dm_path.is_dir() {
+            Some(dm_path)
+        } else {
+            None
+        }
+    }
+
+    /// Read the mapping of a dm-N device under sysfs (if it is a mapping of a single LUKS device)
+    fn dm_device_info(path: &Path) -> Result<Option<DmSetupDeviceInfo>> {
+        let name = fs::read_to_string(path.join("dm/name")).context(IoSnafu)?;
+        let slave_dirs = fs::read_dir(path.join("slaves"))
+            .context(IoSnafu)?
+            .filter_map(|res| res.ok())
+            .filter(|e| e.path().is_symlink())
+            .filter_map(|e| e.path().canonicalize().ok())
+            .collect::<Vec<_>>();
+
+        if slave_dirs.len() != 1 {
+            return Ok(None);
+        }
+
+        let dev_name = fs::read_to_string(slave_dirs[0].join("dev")).context(IoSnafu)?;
+        let dev_path = PathBuf::from(DEVFS_BLOCK_DIR)
+            .join(dev_name.trim_end())
+            .canonicalize()
+            .context(IoSnafu)?;
+
+        // if we fail to read the luks uuid - either we don't have permission or it's not a luks disk, so don't add to list
+        Ok(luks_uuid_via_blkid(&dev_path).ok().map(|uuid| DmSetupDeviceInfo {
+            dm_name: path.file_name().unwrap().to_string_lossy().to_string(),
+            name: name.trim_end().to_string(),
+            underlying: dev_path,
+            underlying_uuid: uuid,
+        }))
+    }
+
+    // FAT32/NTFS disks do not have a UUID of the proper length - exclude them as they cannot be
+    // LUKS disks
+    fn has_full_uuid(e: &io::Result<fs::DirEntry>) -> bool {
+        e.as_ref()
+            .map(|entry| entry.path().file_name().unwrap().len() == UUID_LENGTH)
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use expectest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_keyslot_usage_of_keyslot_statuses() {
+        let statuses = [
+            crypt_keyslot_info::CRYPT_SLOT_ACTIVE,
+            crypt_keyslot_info::CRYPT_SLOT_INACTIVE,
+            crypt_keyslot_info::CRYPT_SLOT_ACTIVE_LAST,
+            crypt_keyslot_info::CRYPT_SLOT_INVALID,
+        ]
+        .iter()
+        .map(|info| KeyslotStatus::from_info(*info))
+        .collect::<Vec<_>>();
+        expect!(statuses.iter().map(KeyslotStatus::is_active).collect::<Vec<_>>())
+            .to(be_equal_to(vec![true, false, true, false]));
+
+        let usage = KeyslotUsage {
+            used: vec![0, 2],
+            count: LUKS1_KEYSLOT_COUNT,
+        };
+        expect!(usage.to_string()).to(be_equal_to("2/8"));
+        expect!(usage.is_full()).to(be_false());
+        let full = KeyslotUsage {
+            used: (0..LUKS1_KEYSLOT_COUNT).collect(),
+            count: LUKS1_KEYSLOT_COUNT,
+        };
+        expect!(full.is_full()).to(be_true());
+    }
+
+    #[test]
+    fn test_activation_flags_bits() {
+        expect!(ActivationFlags::default().bits()).to(be_equal_to(0));
+        expect!(ActivationFlags::default().is_empty()).to(be_true());
+
+        let flags = ActivationFlags {
+            allow_discards: true,
+            read_only: true,
+            no_write_workqueue: true,
+            ..ActivationFlags::default()
+        };
+        expect!(flags.bits()).to(be_equal_to(
+            CRYPT_ACTIVATE_ALLOW_DISCARDS | CRYPT_ACTIVATE_READONLY | CRYPT_ACTIVATE_NO_WRITE_WORKQUEUE,
+        ));
+        expect!(flags.bits()).to(be_equal_to(0x2000009));
+
+        // ignoring the options of the entry is not a cryptsetup flag
+        let ignore = ActivationFlags {
+            ignore_entry_options: true,
+            ..ActivationFlags::default()
+        };
+        expect!(ignore.is_empty()).to(be_true());
+    }
+
+    #[test]
+    fn test_all_disks_uuids_must_return_something() {
+        let maybe_uuids = Disks::all_disk_uuids();
+        expect!(maybe_uuids).to(be_ok());
+    }
+
+    #[test]
+    fn test_check_keyslot_range() {
+        expect!(check_keyslot_range(None, LUKS1_KEYSLOT_COUNT)).to(be_ok());
+        expect!(check_keyslot_range(Some(7), LUKS1_KEYSLOT_COUNT)).to(be_ok());
+        expect!(check_keyslot_range(Some(8), LUKS1_KEYSLOT_COUNT)).to(be_err());
+        expect!(check_keyslot_range(Some(31), LUKS2_KEYSLOT_COUNT)).to(be_ok());
+        expect!(check_keyslot_range(Some(32), LUKS2_KEYSLOT_COUNT)).to(be_err());
+    }
+
+    #[test]
+    fn test_is_wrong_key() {
+        expect!(CryptsetupSnafu {
+            underlying: errno::Errno(1)
+        }
+        .build()
+        .is_wrong_key())
+        .to(be_true());
+        expect!(CryptsetupSnafu {
+            underlying: errno::Errno(2)
+        }
+        .build()
+        .is_wrong_key())
+        .to(be_false());
+    }
+
+    #[test]
+    fn test_udev_escape() {
+        expect!(udev_escape("backup-disk_1")).to(be_equal_to("backup-disk_1"));
+        expect!(udev_escape("my disk")).to(be_equal_to("my\\x20disk"));
+        expect!(udev_escape("a/b")).to(be_equal_to("a\\x2fb"));
+        expect!(udev_escape("dïsk")).to(be_equal_to("dïsk"));
+    }
+
+    #[test]
+    fn test_luks_version_from_header() {
+        expect!(LuksVersion::from_header(b"LUKS\xba\xbe\x00\x01")).to(be_some().value(LuksVersion::Luks1));
+        expect!(LuksVersion::from_header(b"LUKS\xba\xbe\x00\x02")).to(be_some().value(LuksVersion::Luks2));
+        expect!(LuksVersion::from_header(b"LUKS\xba\xbe\x00\x03")).to(be_none());
+        // the secondary LUKS2 header has a different magic
+        expect!(LuksVersion::from_header(b"SKUL\xba\xbe\x00\x02")).to(be_none());
+        expect!(LuksVersion::from_header(b"LUKS")).to(be_none());
+    }
+
+    #[test]
+    fn test_is_luks() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let luks = dir.path().join("luks");
+        let mut header = b"LUKS\xba\xbe\x00\x02".to_vec();
+        header.resize(4096, 0);
+        fs::write(&luks, &header).expect("write header");
+        let other = dir.path().join("other");
+        fs::write(&other, [0u8; 4096]).expect("write zeros");
+
+        expect!(luks.is_luks()).to(be_true());
+        expect!(other.is_luks()).to(be_false());
+        expect!(dir.path().join("missing").is_luks()).to(be_false());
+    }
+
+    /// A LUKS1 header as written by `cryptsetup luksFormat --type luks1` (without any keyslot in use)
+    fn luks1_header(uuid: &Uuid) -> Vec<u8> {
+        let mut header = b"LUKS\xba\xbe\x00\x01".to_vec();
+        header.resize(4096, 0);
+        header[8..11].copy_from_slice(b"aes");
+        header[40..51].copy_from_slice(b"xts-plain64");
+        header[72..78].copy_from_slice(b"sha256");
+        header[104..108].copy_from_slice(&4096u32.to_be_bytes());
+        header[108..112].copy_from_slice(&64u32.to_be_bytes());
+        header[164..168].copy_from_slice(&1000u32.to_be_bytes());
+        header[168..204].copy_from_slice(uuid.hyphenated().to_string().as_bytes());
+        for keyslot in 0..8 {
+            let offset = 208 + keyslot * 48;
+            header[offset..offset + 4].copy_from_slice(&0x0000_DEADu32.to_be_bytes());
+        }
+        header
+    }
+
+    #[test]
+    fn test_luks_uuid_via_blkid_reads_the_header() {
+        let uuid = Uuid::parse_str("0a6f4e4c-8d7b-4f3e-9a51-2c8b1f3d7e60").unwrap();
+        let header = luks1_header(&uuid);
+
+        let dir = tempfile::tempdir().expect("tempdir");
+        let luks = dir.path().join("luks");
+        fs::write(&luks, &header).expect("write header");
+        let other = dir.path().join("other");
+        fs::write(&other, [0u8; 4096]).expect("write zeros");
+
+        expect!(luks_uuid_via_blkid(&luks)).to(be_ok().value(uuid));
+        expect!(luks_uuid_via_blkid(&other)).to(be_err());
+    }
+
+    #[test]
+    fn test_header_backup_uuid_must_match() {
+        let uuid = Uuid::parse_str("0a6f4e4c-8d7b-4f3e-9a51-2c8b1f3d7e60").unwrap();
+        let header = luks1_header(&uuid);
+
+        let dir = tempfile::tempdir().expect("tempdir");
+        let backup = dir.path().join("backup");
+        fs::write(&backup, &header).expect("write header");
+
+        expect!(ensure_header_backup_uuid(&backup, &uuid)).to(be_ok());
+        expect!(ensure_header_backup_uuid(&backup, &Uuid::nil())).to(be_err());
+    }
+
+    #[test]
+    fn test_reencrypt_status_from_info() {
+        expect!(ReencryptStatus::from_info(CRYPT_REENCRYPT_NONE)).to(be_ok().value(ReencryptStatus::None));
+        expect!(ReencryptStatus::from_info(CRYPT_REENCRYPT_CLEAN)).to(be_ok().value(ReencryptStatus::InProgress));
+        expect!(ReencryptStatus::from_info(CRYPT_REENCRYPT_CRASH)).to(be_ok().value(ReencryptStatus::Interrupted));
+        // CRYPT_REENCRYPT_INVALID
+        expect!(ReencryptStatus::from_info(3)).to(be_err());
+    }
+
+    #[test]
+    fn test_reencrypt_status_of_luks1_and_missing_header() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let mut header = b"LUKS\xba\xbe\x00\x01".to_vec();
+        header.resize(4096, 0);
+        let luks1 = dir.path().join("luks1");
+        fs::write(&luks1, &header).expect("write header");
+        let not_luks = dir.path().join("not-luks");
+        fs::write(&not_luks, [0u8; 4096]).expect("write header");
+
+        expect!(luks1.luks_reencrypt_status()).to(be_ok().value(ReencryptStatus::None));
+        expect!(ensure_not_reencrypting(&luks1)).to(be_ok());
+        expect!(not_luks.luks_reencrypt_status()).to(be_err());
+        expect!(ensure_not_reencrypting(&not_luks)).to(be_err());
+    }
+
+    /// Format a new disk image file as LUKS1 with the key (cheaply, as it is only used in tests)
+    pub(crate) fn luks1_image(path: &Path, key: &SecStr) {
+        fs::File::create(path)
+            .and_then(|file| file.set_len(20 * 1024 * 1024))
+            .expect("create disk image");
+        let params = FormatContainerParams::Luks1 {
+            iteration_ms: 1,
+            cipher: "aes".to_string(),
+            cipher_mode: "xts-plain64".to_string(),
+            hash: "sha256".to_string(),
+            mk_bits: 256,
+            uuid: None,
+        };
+        path.luks_format_with_key(key, &params, None, None)
+            .expect("format disk image");
+    }
+
+    /// Fail the test unless devices can be activated with device-mapper (on a loop device for a file), which needs
+    /// root - the tests that do this are ignored by default and run with `cargo test -- --ignored`
+    pub(crate) fn assert_can_activate_devices() {
+        assert!(
+            unsafe { libc::geteuid() } == 0,
+            "this test needs root to activate devices"
+        );
+        assert!(
+            Path::new("/dev/mapper/control").exists() && Path::new("/dev/loop-control").exists(),
+            "this test needs device-mapper and loop devices"
+        );
+    }
+
+    #[test]
+    #[ignore = "needs root and device-mapper"]
+    fn test_activate_read_only_loopback_file() {
+        use std::os::unix::fs::MetadataExt;
+
+        assert_can_activate_devices();
+        let dir = tempfile::tempdir().expect("tempdir");
+        let disk = dir.path().join("disk-image");
+        let key = SecStr::from("test-read-only");
+        luks1_image(&disk, &key);
+
+        let name = format!("peroxs-test-ro-{}", std::process::id());
+        let flags = ActivationFlags {
+            read_only: true,
+            ..ActivationFlags::default()
+        };
+        expect!(disk.luks_activate_with_flags(&name, &key, flags)).to(be_ok());
+        let read_only = fs::metadata(Path::new("/dev/mapper").join(&name)).and_then(|mapping| {
+            let rdev = mapping.rdev();
+            let (major, minor) = (libc::major(rdev), libc::minor(rdev));
+            fs::read_to_string(format!("/sys/dev/block/{}:{}/ro", major, minor))
+        });
+        let deactivated = disk.luks_deactivate(&name);
+
+        expect!(read_only.map(|ro| ro.trim().to_string())).to(be_ok().value("1".to_string()));
+        expect!(deactivated).to(be_ok());
+    }
+
+    #[test]
+    fn test_sysfs_serial() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        expect!(Disks::sysfs_serial(dir.path())).to(be_none());
+        fs::write(dir.path().join("serial"), "BTLJ0123456\n").expect("write serial");
+        expect!(Disks::sysfs_serial(dir.path())).to(be_some().value("BTLJ0123456".to_string()));
+        // the serial of the device takes precedence
+        fs::create_dir(dir.path().join("device")).expect("create device dir");
+        fs::write(dir.path().join("device/serial"), "  S4EWNX0R123456  \n").expect("write serial");
+        expect!(Disks::sysfs_serial(dir.path())).to(be_some().value("S4EWNX0R123456".to_string()));
+    }
+
+    #[test]
+    fn test_udev_serial() {
+        let data = "S:disk/by-id/ata-Samsung_SSD_860_S3Z9NB0K123456\nE:ID_SERIAL=Samsung_SSD_860_S3Z9NB0K123456\nE:ID_SERIAL_SHORT=S3Z9NB0K123456\n";
+        expect!(udev_serial(data)).to(be_some().value("S3Z9NB0K123456".to_string()));
+        expect!(udev_serial("E:ID_SERIAL_SHORT=\n")).to(be_none());
+    }
+
+    #[test]
+    fn test_disk_uuid_path_in_dir() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let uuid = Uuid::new_v4();
+        let path = dir.path().join(uuid.hyphenated().to_string());
+        fs::File::create(&path).expect("create file");
+
+        expect!(Disks::disk_uuid_path_in(dir.path(), &uuid)).to(be_some().value(path));
+        expect!(Disks::disk_uuid_path_in(dir.path(), &Uuid::nil())).to(be_none());
+    }
+
+    #[test]
+    fn test_scan_sysfs_for_active_crypt_devices_must_be_ok() {
+        let maybe_devices = Disks::scan_sysfs_for_active_crypt_devices();
+        expect!(maybe_devices).to(be_ok());
+    }
+
+    #[test]
+    fn test_keyslot_priority_from_str() {
+        expect!(KeyslotPriority::from_str("ignore").ok()).to(be_some().value(KeyslotPriority::Ignore));
+        expect!(KeyslotPriority::from_str("prefer").ok()).to(be_some().value(KeyslotPriority::Prefer));
+        expect!(KeyslotPriority::from_str("sometimes")).to(be_err());
+    }
+
+    #[test]
+    fn test_active_device_info_of_missing_mapping_must_be_none() {
+        let info = Disks::active_device_info("peroxs-test-no-such-mapping");
+        expect!(info.map(|i| i.is_none())).to(be_ok().value(true));
+    }
+
+    #[test]
+    fn test_opened_devices_must_not_cache_missing_device() {
+        let devices = OpenedDevices::new();
+        expect!(devices.device("/dev/peroxs-test-no-such-disk").luks_uuid()).to(be_err());
+        expect!(devices.devices.borrow().is_empty()).to(be_true());
     }
 }
@@ -0,0 +1,83 @@
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use snafu::prelude::*;
+
+use crate::input::{read_new_passphrase, AskPasswordSnafu, InputName, IoSnafu, KeyInput, Result, SecStr};
+
+/// A prompt through `systemd-ask-password` (so that it integrates with password agents like plymouth)
+pub struct AskPasswordPrompt {
+    pub timeout: Option<Duration>,
+}
+
+impl KeyInput for AskPasswordPrompt {
+    fn get_key(&self, name: &InputName, is_new: bool) -> Result<SecStr> {
+        let prompt = name.prompt_override.clone().unwrap_or_else(|| {
+            if is_new {
+                format!("Enter new passphrase for {}:", name.name)
+            } else if let Some(ref uuid) = name.uuid {
+                format!("Enter passphrase to unlock {} (uuid={}):", name.name, uuid)
+            } else {
+                format!("Enter passphrase to unlock {}:", name.name)
+            }
+        });
+
+        let read = |prompt: &str| ask_password(prompt, self.timeout);
+        if is_new {
+            read_new_passphrase(&prompt, read)
+        } else {
+            read(&prompt)
+        }
+    }
+}
+
+/// The `--timeout` of `systemd-ask-password` in whole seconds, rounded up so that a timeout under a second does not
+/// become 0 (which waits indefinitely, while the default of systemd-ask-password is 90 seconds)
+fn timeout_secs(timeout: Option<Duration>) -> u64 {
+    timeout.map_or(0, |t| t.as_secs() + u64::from(t.subsec_nanos() > 0))
+}
+
+/// Ask for a passphrase with `systemd-ask-password`
+fn ask_password(prompt: &str, timeout: Option<Duration>) -> Result<SecStr> {
+    let output = Command::new("systemd-ask-password")
+        .arg(format!("--timeout={}", timeout_secs(timeout)))
+        .arg(prompt)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .context(IoSnafu)?;
+
+    if !output.status.success() {
+        return AskPasswordSnafu {
+            cause: format!("exited with {}", output.status),
+        }
+        .fail();
+    }
+
+    let mut buf = output.stdout;
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+    }
+    if buf.is_empty() {
+        return AskPasswordSnafu {
+            cause: "empty response".to_string(),
+        }
+        .fail();
+    }
+    Ok(SecStr::new(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use expectest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_timeout_is_rounded_up_to_seconds() {
+        expect!(timeout_secs(None)).to(be_equal_to(0));
+        expect!(timeout_secs(Some(Duration::from_millis(500)))).to(be_equal_to(1));
+        expect!(timeout_secs(Some(Duration::from_secs(30)))).to(be_equal_to(30));
+        expect!(timeout_secs(Some(Duration::from_millis(30_001)))).to(be_equal_to(31));
+    }
+}
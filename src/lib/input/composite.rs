@@ -0,0 +1,73 @@
+use sha2::{Digest, Sha256};
+
+use crate::input::{InputName, KeyInput, Result, SecStr};
+
+/// Parameters for a key combined from several inputs (e.g. a keyfile and a passphrase)
+///
+/// The key is the SHA-256 hash of the keys of the parts, in order, each prefixed with its length (as a big-endian
+/// 64-bit integer) so that a different split of the same bytes gives a different key.
+pub struct CompositeKeyInput {
+    /// Inputs of the parts of the key
+    pub parts: Vec<Box<dyn KeyInput>>,
+}
+
+impl KeyInput for CompositeKeyInput {
+    fn get_key(&self, name: &InputName, is_new: bool) -> Result<SecStr> {
+        let keys = self
+            .parts
+            .iter()
+            .map(|part| part.get_key(name, is_new))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(combine(&keys))
+    }
+}
+
+fn combine(keys: &[SecStr]) -> SecStr {
+    let mut hasher = Sha256::new();
+    for key in keys {
+        hasher.update((key.unsecure().len() as u64).to_be_bytes());
+        hasher.update(key.unsecure());
+    }
+    SecStr::new(hasher.finalize().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use expectest::prelude::*;
+
+    use super::*;
+
+    struct FixedKey(&'static str);
+
+    impl KeyInput for FixedKey {
+        fn get_key(&self, _name: &InputName, _is_new: bool) -> Result<SecStr> {
+            Ok(SecStr::from(self.0))
+        }
+    }
+
+    fn composite(parts: &[&'static str]) -> CompositeKeyInput {
+        CompositeKeyInput {
+            parts: parts
+                .iter()
+                .map(|&part| Box::new(FixedKey(part)) as Box<dyn KeyInput>)
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_composite_key_is_sha256_of_length_prefixed_parts() {
+        let key = composite(&["keyfile", "passphrase"]).get_key(&InputName::blank(), false);
+        let expected: [u8; 32] = [
+            0x3a, 0x87, 0x58, 0x1e, 0x25, 0xb1, 0x02, 0x3d, 0x4a, 0x56, 0x93, 0xa7, 0x66, 0xa9, 0x1d, 0x68, 0x19, 0xe5,
+            0x79, 0x25, 0xc7, 0x53, 0xca, 0x8f, 0xde, 0x21, 0x91, 0xca, 0x81, 0xe9, 0xa5, 0xe0,
+        ];
+        expect!(key.map(|k| k.unsecure().to_vec())).to(be_ok().value(expected.to_vec()));
+    }
+
+    #[test]
+    fn test_composite_key_depends_on_the_split_of_the_parts() {
+        let key = composite(&["keyfile", "passphrase"]).get_key(&InputName::blank(), false);
+        let other = composite(&["keyfilepass", "phrase"]).get_key(&InputName::blank(), false);
+        expect!(key.unwrap() == other.unwrap()).to(be_false());
+    }
+}
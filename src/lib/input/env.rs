@@ -0,0 +1,52 @@
+use std::env;
+use std::os::unix::ffi::OsStringExt;
+
+use crate::input::{EnvVarMissingSnafu, InputName, KeyInput, Result, SecStr};
+
+/// A passphrase read from an environment variable before any prompt (for CI systems that inject secrets that way)
+pub struct EnvPassphrase {
+    pub passphrase: SecStr,
+}
+
+impl KeyInput for EnvPassphrase {
+    fn get_key(&self, _name: &InputName, _is_new: bool) -> Result<SecStr> {
+        Ok(self.passphrase.clone())
+    }
+}
+
+/// Read the passphrase from the variable and remove the variable, so that child processes do not inherit it
+pub fn take(var: &str) -> Result<SecStr> {
+    let value = env::var_os(var)
+        .filter(|value| !value.is_empty())
+        .ok_or_else(|| EnvVarMissingSnafu { var }.build())?;
+    env::remove_var(var);
+    // a warning about the weakness of the passphrase source, so it is shown even when quiet
+    eprintln!(
+        "WARNING: the passphrase is read from the environment variable {}, which other processes of the same user may be able to read",
+        var
+    );
+
+    Ok(SecStr::new(value.into_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use expectest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_take_reads_and_removes_variable() {
+        env::set_var("PEROXS_TEST_TAKE_PASSPHRASE", "secret");
+        let passphrase = take("PEROXS_TEST_TAKE_PASSPHRASE");
+        expect!(passphrase.map(|p| p.unsecure().to_vec())).to(be_ok().value(b"secret".to_vec()));
+        expect!(env::var_os("PEROXS_TEST_TAKE_PASSPHRASE")).to(be_none());
+    }
+
+    #[test]
+    fn test_take_fails_if_unset_or_empty() {
+        env::set_var("PEROXS_TEST_TAKE_EMPTY", "");
+        expect!(take("PEROXS_TEST_TAKE_EMPTY")).to(be_err());
+        expect!(take("PEROXS_TEST_TAKE_UNSET")).to(be_err());
+    }
+}
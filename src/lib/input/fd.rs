@@ -0,0 +1,106 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Read;
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::rc::Rc;
+
+use snafu::prelude::*;
+
+use crate::input::{InputName, IoSnafu, KeyFdEmptySnafu, KeyFdReusedSnafu, KeyInput, Result, SecStr};
+
+/// Key material read from a file descriptor inherited from the caller (so that the secret never touches the disk)
+///
+/// The descriptor is read until EOF, and the bytes are used as they are (like a keyfile, so a trailing newline is part
+/// of the key). It is left open for the caller. It can only be read once, so the key is kept (by all the clones) for
+/// the next times it is asked for, e.g. for each disk that is opened. A command that needs a current and a new key
+/// cannot get both from it.
+#[derive(Debug, Clone)]
+pub struct FdPrompt {
+    fd: RawFd,
+    // the key that was read, and whether it was asked for as a new key
+    read: Rc<RefCell<Option<(SecStr, bool)>>>,
+}
+
+impl FdPrompt {
+    pub fn new(fd: RawFd) -> FdPrompt {
+        FdPrompt {
+            fd,
+            read: Rc::new(RefCell::new(None)),
+        }
+    }
+}
+
+impl PartialEq for FdPrompt {
+    fn eq(&self, other: &Self) -> bool {
+        self.fd == other.fd
+    }
+}
+
+impl Eq for FdPrompt {}
+
+impl KeyInput for FdPrompt {
+    fn get_key(&self, _name: &InputName, is_new: bool) -> Result<SecStr> {
+        if let Some((key, read_as_new)) = self.read.borrow().as_ref() {
+            ensure!(*read_as_new == is_new, KeyFdReusedSnafu { fd: self.fd });
+            return Ok(key.clone());
+        }
+
+        // the descriptor belongs to the caller, so the file must never be dropped (which would close it)
+        let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(self.fd) });
+        let mut buf = Vec::new();
+        // `read_to_end` retries partial and interrupted reads until EOF
+        file.read_to_end(&mut buf).context(IoSnafu)?;
+        ensure!(!buf.is_empty(), KeyFdEmptySnafu { fd: self.fd });
+
+        let key = SecStr::new(buf);
+        *self.read.borrow_mut() = Some((key.clone(), is_new));
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom, Write};
+    use std::os::unix::io::AsRawFd;
+
+    use expectest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_fd_prompt_reads_until_eof_and_keeps_fd_open() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"secret\n").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+
+        let key = FdPrompt::new(file.as_raw_fd()).get_key(&InputName::blank(), false);
+        expect!(key.map(|k| k.unsecure().to_vec())).to(be_ok().value(b"secret\n".to_vec()));
+
+        // the descriptor is still usable by the caller
+        expect!(file.seek(SeekFrom::Start(0))).to(be_ok().value(0));
+    }
+
+    #[test]
+    fn test_fd_prompt_keeps_the_key_it_read() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"secret").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+
+        let prompt = FdPrompt::new(file.as_raw_fd());
+        let first = prompt.get_key(&InputName::blank(), false);
+        expect!(first.map(|k| k.unsecure().to_vec())).to(be_ok().value(b"secret".to_vec()));
+        // the descriptor is at EOF now, but the key is not read again
+        let again = prompt.clone().get_key(&InputName::blank(), false);
+        expect!(again.map(|k| k.unsecure().to_vec())).to(be_ok().value(b"secret".to_vec()));
+        // the same key cannot be the new key as well
+        expect!(prompt.get_key(&InputName::blank(), true)).to(be_err());
+    }
+
+    #[test]
+    fn test_fd_prompt_fails_at_eof() {
+        let file = tempfile::tempfile().unwrap();
+        let key = FdPrompt::new(file.as_raw_fd()).get_key(&InputName::blank(), false);
+        expect!(key).to(be_err());
+    }
+}
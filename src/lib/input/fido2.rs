@@ -0,0 +1,76 @@
+use std::convert::TryFrom;
+
+use fido2_rs::{Error as Fido2Error, Fido2Device, HmacSecret, CLIENT_DATA_HASH_LENGTH, HMAC_SECRET_SALT_LENGTH};
+use snafu::prelude::*;
+
+use crate::input::{Fido2Credential, Fido2Snafu, InputName, KeyInput, Result, SecStr, UnknownCryptoSnafu};
+use crate::messages;
+
+/// Relying party id of the credentials created by peroxide-cryptsetup
+const RP_ID: &str = "peroxide-cryptsetup";
+/// Length of the (random) user id of created credentials
+const USER_ID_LENGTH: usize = 16;
+
+/// Parameters for FIDO2 hmac-secret input
+pub struct Fido2Prompt {
+    /// Id of the credential created at enrollment
+    pub credential_id: Vec<u8>,
+    /// Salt for the hmac-secret extension
+    pub salt: Vec<u8>,
+    /// Key input mechanism for the PIN of the device (only used if the device requires one)
+    pub pin_input: Box<dyn KeyInput>,
+}
+
+impl KeyInput for Fido2Prompt {
+    fn get_key(&self, name: &InputName, _is_new: bool) -> Result<SecStr> {
+        let mut dev = Fido2Device::open_first().context(Fido2Snafu)?;
+        let salt =
+            <[u8; HMAC_SECRET_SALT_LENGTH]>::try_from(self.salt.as_slice()).map_err(|_| UnknownCryptoSnafu.build())?;
+        let client_data_hash = random_bytes::<CLIENT_DATA_HASH_LENGTH>()?;
+
+        messages::status("Please touch the FIDO2 device now...");
+        let secret = with_pin(&*self.pin_input, &name.name, |pin| {
+            dev.hmac_secret(RP_ID, &self.credential_id, &salt, &client_data_hash, pin)
+        })?;
+        Ok(SecStr::new(secret))
+    }
+}
+
+/// Create a new credential (and salt) on the first FIDO2 device found
+pub fn new_credential(pin_input: &dyn KeyInput) -> Result<Fido2Credential> {
+    let mut dev = Fido2Device::open_first().context(Fido2Snafu)?;
+    let user_id = random_bytes::<USER_ID_LENGTH>()?;
+    let client_data_hash = random_bytes::<CLIENT_DATA_HASH_LENGTH>()?;
+    let salt = random_bytes::<HMAC_SECRET_SALT_LENGTH>()?;
+
+    messages::status("Please touch the FIDO2 device to create a new credential...");
+    let credential_id = with_pin(pin_input, "new credential", |pin| {
+        dev.make_credential(RP_ID, &user_id, &client_data_hash, pin)
+    })?;
+    Ok(Fido2Credential {
+        credential_id,
+        salt: salt.to_vec(),
+    })
+}
+
+/// Run a device operation, retrying with a PIN if the device asks for one
+fn with_pin<T, F>(pin_input: &dyn KeyInput, name: &str, mut op: F) -> Result<T>
+where
+    F: FnMut(Option<&[u8]>) -> fido2_rs::Result<T>,
+{
+    match op(None) {
+        Err(Fido2Error::PinRequired) => {
+            let pin_name = InputName::with_override("fido2_pin".to_string(), format!("FIDO2 PIN for {}:", name));
+            let pin = pin_input.get_key(&pin_name, false)?;
+            messages::status("Please touch the FIDO2 device again...");
+            op(Some(pin.unsecure())).context(Fido2Snafu)
+        }
+        other => other.context(Fido2Snafu),
+    }
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).map_err(|_| UnknownCryptoSnafu.build())?;
+    Ok(bytes)
+}
@@ -1,16 +1,54 @@
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 
 use snafu::prelude::*;
 
-use crate::input::{FileNotFoundSnafu, InputName, IoSnafu, KeyInput, Result, SecStr};
+use crate::input::{
+    FileNotFoundSnafu, InputName, IoSnafu, KeyInput, KeyfileTooShortSnafu, Result, SecStr, UnknownCryptoSnafu,
+};
 
 /// Parameters for key file input
 pub struct KeyfilePrompt {
     /// Absolute path to the keyfile
     pub key_file: PathBuf,
+    /// Offset of the key in the keyfile (start of the file if not set)
+    pub key_offset: Option<u64>,
+    /// Size of the key (rest of the file if not set)
+    pub key_size: Option<usize>,
+}
+
+impl KeyfilePrompt {
+    /// Read the key from a region of the file, checking that the file is long enough first
+    fn read_region(&self, file: &mut File, offset: u64, size: Option<usize>) -> Result<Vec<u8>> {
+        // seeking to the end (rather than the metadata) gives the size of block devices too
+        let actual = file.seek(SeekFrom::End(0)).context(IoSnafu)?;
+        let required = offset + size.unwrap_or(0) as u64;
+        if actual < required {
+            return Err(KeyfileTooShortSnafu {
+                path: self.key_file.clone(),
+                required,
+                actual,
+            }
+            .build());
+        }
+
+        file.seek(SeekFrom::Start(offset)).context(IoSnafu)?;
+        match size {
+            Some(size) => {
+                let mut key = vec![0u8; size];
+                file.read_exact(&mut key).context(IoSnafu)?;
+                Ok(key)
+            }
+            None => {
+                let mut key = Vec::with_capacity((actual - offset) as usize);
+                file.read_to_end(&mut key).context(IoSnafu)?;
+                Ok(key)
+            }
+        }
+    }
 }
 
 impl KeyInput for KeyfilePrompt {
@@ -23,10 +61,16 @@ impl KeyInput for KeyfilePrompt {
         }
 
         let mut file = File::open(&self.key_file).context(IoSnafu)?;
-        let meta = file.metadata().context(IoSnafu)?;
-        let mut key = Vec::with_capacity(meta.len() as usize);
-        let read = file.read_to_end(&mut key).context(IoSnafu)?;
-        if read == 0 {
+        let key = match (self.key_offset, self.key_size) {
+            (None, None) => {
+                let meta = file.metadata().context(IoSnafu)?;
+                let mut key = Vec::with_capacity(meta.len() as usize);
+                file.read_to_end(&mut key).context(IoSnafu)?;
+                key
+            }
+            (offset, size) => self.read_region(&mut file, offset.unwrap_or(0), size)?,
+        };
+        if key.is_empty() {
             Err(io::Error::new(
                 io::ErrorKind::UnexpectedEof,
                 format!("Zero byte key file at {}", self.key_file.display()),
@@ -38,17 +82,35 @@ impl KeyInput for KeyfilePrompt {
     }
 }
 
+/// Write a new keyfile of random bytes that only its owner can read (an existing file is never overwritten)
+pub fn generate(path: &Path, size: usize) -> Result<()> {
+    // the key is zeroed once written, like every other key
+    let mut key = SecStr::new(vec![0u8; size]);
+    getrandom::getrandom(key.unsecure_mut()).map_err(|_| UnknownCryptoSnafu.build())?;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .context(IoSnafu)?;
+    file.write_all(key.unsecure()).context(IoSnafu)?;
+    file.sync_all().context(IoSnafu)
+}
+
 #[cfg(test)]
 mod tests {
+    use std::fs;
     use std::fs::File;
     use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
     use std::str;
 
     use expectest::prelude::*;
     use snafu::prelude::*;
     use tempfile::{Builder, TempDir};
 
-    use crate::input::IoSnafu;
+    use crate::input::{Error, IoSnafu};
 
     use super::*;
 
@@ -67,7 +129,11 @@ mod tests {
     fn read_key_from_file() -> Result<()> {
         let (_tmp_dir, key_file) = _write_keyfile("correcthorsebatterystaple")?;
 
-        let prompt = KeyfilePrompt { key_file };
+        let prompt = KeyfilePrompt {
+            key_file,
+            key_offset: None,
+            key_size: None,
+        };
         let key = prompt.get_key(&InputName::blank(), false)?;
         let key_str = str::from_utf8(key.unsecure()).expect("unsecure key to utf8");
 
@@ -75,4 +141,75 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn read_key_from_file_region() -> Result<()> {
+        let (_tmp_dir, key_file) = _write_keyfile("correcthorsebatterystaple")?;
+
+        let prompt = KeyfilePrompt {
+            key_file,
+            key_offset: Some(7),
+            key_size: Some(7),
+        };
+        let key = prompt.get_key(&InputName::blank(), false)?;
+        let key_str = str::from_utf8(key.unsecure()).expect("unsecure key to utf8");
+
+        expect!(key_str).to(be_equal_to("horseba"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_key_from_file_offset_only() -> Result<()> {
+        let (_tmp_dir, key_file) = _write_keyfile("correcthorsebatterystaple")?;
+
+        let prompt = KeyfilePrompt {
+            key_file,
+            key_offset: Some(19),
+            key_size: None,
+        };
+        let key = prompt.get_key(&InputName::blank(), false)?;
+        let key_str = str::from_utf8(key.unsecure()).expect("unsecure key to utf8");
+
+        expect!(key_str).to(be_equal_to("staple"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_key_from_too_short_file_must_fail() -> Result<()> {
+        let (_tmp_dir, key_file) = _write_keyfile("correcthorsebatterystaple")?;
+
+        let prompt = KeyfilePrompt {
+            key_file,
+            key_offset: Some(20),
+            key_size: Some(10),
+        };
+        let res = prompt.get_key(&InputName::blank(), false);
+
+        match res {
+            Err(Error::KeyfileTooShortError { required, actual, .. }) => {
+                expect!(required).to(be_equal_to(30));
+                expect!(actual).to(be_equal_to(25));
+            }
+            _ => panic!("Expected a key file too short error"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn generate_private_keyfile_once() -> Result<()> {
+        let tmp_dir = Builder::new().prefix("keyfile_generate").tempdir().context(IoSnafu)?;
+        let key_file = tmp_dir.path().join("new.key");
+
+        generate(&key_file, 4096)?;
+        let meta = fs::metadata(&key_file).context(IoSnafu)?;
+        expect!(meta.len()).to(be_equal_to(4096));
+        expect!(meta.permissions().mode() & 0o777).to(be_equal_to(0o600));
+        expect!(generate(&key_file, 64)).to(be_err());
+        expect!(fs::metadata(&key_file).context(IoSnafu)?.len()).to(be_equal_to(4096));
+
+        Ok(())
+    }
 }
@@ -1,8 +1,11 @@
 use std::io;
+use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 use std::result;
 use std::time::Duration;
 
+#[cfg(feature = "fido2")]
+use fido2_rs::Error as Fido2Error;
 #[cfg(feature = "pinentry")]
 use pinentry_rs::Error as PinEntryError;
 pub use secstr::SecStr;
@@ -13,7 +16,9 @@ use snafu::{prelude::*, Backtrace, IntoError};
 use ykpers_rs::Error as YubikeyError;
 
 use crate::context::{DatabaseOps, DeviceOps};
-use crate::db::{DbEntry, PeroxideDb, YubikeyEntryType, YubikeySlot};
+use crate::db::{DbEntry, Error as DbError, PeroxideDb, YubikeyEntryType, YubikeySlot};
+
+pub use self::fd::FdPrompt;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -21,6 +26,15 @@ pub enum Error {
     FeatureNotAvailableError { backtrace: Backtrace },
     #[snafu(display("The file was not found at {}", path.display()))]
     FileNotFoundError { path: PathBuf, backtrace: Backtrace },
+    #[snafu(display("The key file at {} is {actual} bytes long, but at least {required} bytes are needed", path.display()))]
+    KeyfileTooShortError {
+        path: PathBuf,
+        required: u64,
+        actual: u64,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("The key file at {} is empty", path.display()))]
+    KeyfileEmptyError { path: PathBuf, backtrace: Backtrace },
     #[snafu(display("A generic I/O error occurred"))]
     IoError { source: io::Error, backtrace: Backtrace },
     #[snafu(display("Unexpected crypto error - yikes!"))]
@@ -29,6 +43,26 @@ pub enum Error {
     BackupDbEntryNotFoundError { uuid: Uuid, backtrace: Backtrace },
     #[snafu(display("Backup DB error: {cause}"))]
     BackupDbError { cause: String, backtrace: Backtrace },
+    #[cfg(feature = "systemd")]
+    #[snafu(display("systemd-ask-password failed: {cause}"))]
+    AskPasswordError { cause: String, backtrace: Backtrace },
+    #[snafu(display("Standard input was closed before a passphrase could be read"))]
+    StdinClosedError { backtrace: Backtrace },
+    #[snafu(display("File descriptor {fd} was at EOF before any key could be read"))]
+    KeyFdEmptyError { fd: RawFd, backtrace: Backtrace },
+    #[snafu(display(
+        "The key read from file descriptor {fd} cannot be both the current and the new key (give one of them another way)"
+    ))]
+    KeyFdReusedError { fd: RawFd, backtrace: Backtrace },
+    #[snafu(display("The environment variable {var} is not set or is empty"))]
+    EnvVarMissingError { var: String, backtrace: Backtrace },
+    #[snafu(display("The new passphrase was not repeated correctly ({attempts} attempts)"))]
+    NewPassphraseMismatchError { attempts: usize, backtrace: Backtrace },
+    #[snafu(display("Invalid database entry"))]
+    InvalidDbEntryError { source: DbError, backtrace: Backtrace },
+    #[cfg(feature = "fido2")]
+    #[snafu(display("FIDO2 error"))]
+    Fido2Error { source: Fido2Error, backtrace: Backtrace },
     #[cfg(feature = "yubikey")]
     #[snafu(display("Yubikey error"))]
     YubikeyError { source: YubikeyError, backtrace: Backtrace },
@@ -42,6 +76,9 @@ pub enum Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// Number of times a new passphrase is asked for before giving up when it is not repeated correctly
+const NEW_PASSPHRASE_ATTEMPTS: usize = 3;
+
 pub struct InputName {
     pub name: String,
     pub uuid: Option<Uuid>,
@@ -57,6 +94,18 @@ impl InputName {
         }
     }
 
+    /// The name of the key of an entry (that of its volume unless overridden)
+    pub fn of_entry(db_entry: &DbEntry, name_override: Option<String>, prompt_override: Option<String>) -> InputName {
+        let name = name_override
+            .or(db_entry.volume_id().name.clone())
+            .unwrap_or_else(|| "unknown".to_string());
+        InputName {
+            name,
+            uuid: Some(db_entry.uuid().to_owned()),
+            prompt_override,
+        }
+    }
+
     pub fn with_override(name: String, prompt_override: String) -> InputName {
         InputName {
             name,
@@ -71,10 +120,69 @@ pub trait KeyInput {
     fn get_key(&self, name: &InputName, is_new: bool) -> Result<SecStr>;
 }
 
-#[derive(Debug)]
+/// Read a new passphrase twice (with `read` given the prompt to show), asking again when the two do not match so that
+/// a typo cannot end up in a keyslot
+fn read_new_passphrase<F: FnMut(&str) -> Result<SecStr>>(prompt: &str, mut read: F) -> Result<SecStr> {
+    let repeat_prompt = format!("{} (again):", prompt.trim_end().trim_end_matches(':'));
+    for attempt in 0..NEW_PASSPHRASE_ATTEMPTS {
+        let passphrase = if attempt == 0 {
+            read(prompt)?
+        } else {
+            read(&format!("The passphrases do not match, try again. {}", prompt))?
+        };
+        if read(&repeat_prompt)? == passphrase {
+            return Ok(passphrase);
+        }
+    }
+    NewPassphraseMismatchSnafu {
+        attempts: NEW_PASSPHRASE_ATTEMPTS,
+    }
+    .fail()
+}
+
+/// Source of passphrases (for passphrase entries and the challenge of Yubikey entries)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PassphraseInput {
+    /// Interactive prompt (pinentry or the terminal)
+    Prompt,
+    /// A line read from standard input (for scripting)
+    Stdin,
+    /// All of the bytes read from an inherited file descriptor (for scripting without the secret touching the disk)
+    Fd(fd::FdPrompt),
+    /// A passphrase read from an environment variable when the command started (see `passphrase_from_env`)
+    Env(SecStr),
+    /// A prompt through `systemd-ask-password` (and therefore any running password agent)
+    #[cfg(feature = "systemd")]
+    AskPassword,
+}
+
+#[derive(Debug, Clone)]
 pub struct KeyInputConfig {
     /// Timeout for password input (on terminal or elsewhere)
     pub password_input_timeout: Option<Duration>,
+    /// Where passphrases are read from
+    pub passphrase_input: PassphraseInput,
+    /// Flag to prompt interactively with pinentry rather than on the terminal (only with the `pinentry` feature)
+    pub use_pinentry: bool,
+}
+
+/// An attached Yubikey (for diagnostics)
+#[derive(Debug, Clone)]
+pub struct YubikeyInfo {
+    /// Serial of the Yubikey (if it exposes one)
+    pub serial: Option<u32>,
+    /// Firmware version as (major, minor, build)
+    pub version: (i32, i32, i32),
+    /// Slots that have a configuration
+    pub configured_slots: Vec<YubikeySlot>,
+}
+
+/// A FIDO2 credential (with the hmac-secret extension) created at enrollment
+#[derive(Debug, Clone)]
+pub struct Fido2Credential {
+    pub credential_id: Vec<u8>,
+    /// Salt passed to the hmac-secret extension to derive the key
+    pub salt: Vec<u8>,
 }
 
 /// Get a key for a given db entry
@@ -87,16 +195,33 @@ pub fn get_key_for<P: AsRef<Path>>(
     is_new: bool,
 ) -> Result<SecStr> {
     let method = get_input_method_for(db_entry, key_input_config, working_dir)?;
-    let name = name_override
-        .or(db_entry.volume_id().name.clone())
-        .unwrap_or_else(|| "unknown".to_string());
-    let uuid = db_entry.uuid().to_owned();
-    let input = InputName {
-        name,
-        uuid: Some(uuid),
-        prompt_override,
-    };
-    method.get_key(&input, is_new)
+    method.get_key(&InputName::of_entry(db_entry, name_override, prompt_override), is_new)
+}
+
+/// Read a raw LUKS volume key from a file (a relative path is relative to the current directory)
+pub fn volume_key_file(path: &Path) -> Result<SecStr> {
+    let name = InputName::with_override("volume_key".to_string(), format!("Volume key in {}", path.display()));
+    keyfile(path, None, None, Path::new("."))?.get_key(&name, false)
+}
+
+/// Read a key from a whole file given instead of the input of an entry (a relative path is relative to the current
+/// directory)
+pub fn key_file(path: &Path) -> Result<SecStr> {
+    let name = InputName::with_override("key_file".to_string(), format!("Key in {}", path.display()));
+    let key = keyfile(path, None, None, Path::new("."))?.get_key(&name, false)?;
+    ensure!(!key.unsecure().is_empty(), KeyfileEmptySnafu { path });
+    Ok(key)
+}
+
+/// Read the key of a keyfile entry the way opening its disk does (a relative path is relative to the working directory)
+pub fn entry_key_file(
+    key_path: &Path,
+    key_offset: Option<u64>,
+    key_size: Option<usize>,
+    working_dir: &Path,
+) -> Result<SecStr> {
+    let name = InputName::with_override("key_file".to_string(), format!("Key in {}", key_path.display()));
+    keyfile(key_path, key_offset, key_size, working_dir)?.get_key(&name, false)
 }
 
 /// Special type of input - a prompt that takes a second, backup database - and finds the key there
@@ -108,7 +233,7 @@ pub struct BackupPrompt<Ctx: DeviceOps> {
 
 impl<Ctx: DeviceOps> BackupPrompt<Ctx> {
     pub fn prompt_key(&self, uuid: &Uuid) -> Result<SecStr> {
-        if let Some(entry) = self.db.find_entry(&uuid) {
+        if let Some(entry) = self.db.find_entry(uuid) {
             self.ctx.prompt_key(entry, None, false).map_err(|e| {
                 BackupDbSnafu {
                     cause: format!("Error during backup db operation: {:?}", e),
@@ -116,7 +241,7 @@ impl<Ctx: DeviceOps> BackupPrompt<Ctx> {
                 .build()
             })
         } else {
-            Err(BackupDbEntryNotFoundSnafu { uuid: uuid.clone() }.build())
+            Err(BackupDbEntryNotFoundSnafu { uuid: *uuid }.build())
         }
     }
 }
@@ -127,39 +252,88 @@ fn get_input_method_for<P: AsRef<Path>>(
     key_input_config: &KeyInputConfig,
     working_dir: P,
 ) -> Result<Box<dyn KeyInput>> {
-    match db_entry {
-        &DbEntry::KeyfileEntry { ref key_file, .. } => Ok(Box::new(keyfile(&key_file, working_dir.as_ref())?)),
-        &DbEntry::PassphraseEntry { .. } => Ok(Box::new(passphrase(key_input_config.password_input_timeout))),
-        &DbEntry::YubikeyEntry {
+    // the key given on a file descriptor replaces the key file (which can then be kept off the disk)
+    if let (DbEntry::KeyfileEntry { .. }, PassphraseInput::Fd(fd)) = (db_entry, &key_input_config.passphrase_input) {
+        return Ok(Box::new(fd.clone()));
+    }
+
+    match *db_entry {
+        DbEntry::KeyfileEntry {
+            ref key_file,
+            key_offset,
+            key_size,
+            ..
+        } => Ok(Box::new(keyfile(key_file, key_offset, key_size, working_dir.as_ref())?)),
+        DbEntry::PassphraseEntry { .. } => Ok(passphrase(key_input_config)),
+        DbEntry::YubikeyEntry {
             entry_type,
             slot,
+            serial,
             ref volume_id,
         } => {
-            let passphrase_input = Box::new(passphrase(key_input_config.password_input_timeout));
+            let passphrase_input = passphrase(key_input_config);
             Ok(Box::new(yubikey(
                 entry_type,
                 passphrase_input,
                 slot,
-                volume_id.uuid().clone(),
+                serial,
+                *volume_id.uuid(),
             )))
         }
+        DbEntry::Fido2Entry {
+            ref credential_id,
+            ref salt,
+            ..
+        } => {
+            let pin_input = passphrase(key_input_config);
+            fido2(credential_id.clone(), salt.clone(), pin_input)
+        }
+        DbEntry::CompositeEntry { ref parts, .. } => {
+            // composites are only allowed one level deep, so this recursion always terminates
+            db_entry.validate().context(InvalidDbEntrySnafu)?;
+            let parts = parts
+                .iter()
+                .map(|part| get_input_method_for(part, key_input_config, working_dir.as_ref()))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Box::new(composite::CompositeKeyInput { parts }))
+        }
+    }
+}
+
+/// Create parameters for a passphrase input (depending on the configured passphrase input)
+fn passphrase(key_input_config: &KeyInputConfig) -> Box<dyn KeyInput> {
+    let timeout = key_input_config.password_input_timeout;
+    match &key_input_config.passphrase_input {
+        PassphraseInput::Prompt => prompt(timeout, key_input_config.use_pinentry),
+        PassphraseInput::Stdin => Box::new(stdin::StdinPrompt { timeout }),
+        PassphraseInput::Fd(fd) => Box::new(fd.clone()),
+        PassphraseInput::Env(passphrase) => Box::new(env::EnvPassphrase {
+            passphrase: passphrase.clone(),
+        }),
+        #[cfg(feature = "systemd")]
+        PassphraseInput::AskPassword => Box::new(ask_password::AskPasswordPrompt { timeout }),
     }
 }
 
-/// Create parameters for a passphrase input (a terminal)
+/// Create parameters for an interactive passphrase input (a terminal)
 #[cfg(not(feature = "pinentry"))]
-fn passphrase(timeout: Option<Duration>) -> impl KeyInput {
-    terminal::TerminalPrompt { timeout }
+fn prompt(timeout: Option<Duration>, _use_pinentry: bool) -> Box<dyn KeyInput> {
+    Box::new(terminal::TerminalPrompt { timeout })
 }
 
-/// Create parameters for a passphrase input (using pinentry)
+/// Create parameters for an interactive passphrase input (using pinentry, unless the terminal is preferred)
 #[cfg(feature = "pinentry")]
-fn passphrase(timeout: Option<Duration>) -> impl KeyInput {
-    pinentry::PinentryPrompt { timeout }
+fn prompt(timeout: Option<Duration>, use_pinentry: bool) -> Box<dyn KeyInput> {
+    if use_pinentry {
+        Box::new(pinentry::PinentryPrompt { timeout })
+    } else {
+        Box::new(terminal::TerminalPrompt { timeout })
+    }
 }
 
-/// Create parameters for a keyfile input (a physical file)
-fn keyfile(key_path: &Path, working_dir: &Path) -> Result<impl KeyInput> {
+/// Find the keyfile of an entry the way opening its disk does: a relative path is relative to the working directory
+/// (which is the directory of the database for the entries in it), not to the current directory
+pub fn resolve_key_file(key_path: &Path, working_dir: &Path) -> Result<PathBuf> {
     let not_found_handler = |e: io::Error| {
         if e.kind() == io::ErrorKind::NotFound {
             FileNotFoundSnafu {
@@ -179,8 +353,23 @@ fn keyfile(key_path: &Path, working_dir: &Path) -> Result<impl KeyInput> {
     }
     .map_err(not_found_handler)?;
     debug!("Will read from key path {}", key_file.display());
+    Ok(key_file)
+}
 
-    Ok(keyfile::KeyfilePrompt { key_file })
+/// Create parameters for a keyfile input (a physical file)
+fn keyfile(
+    key_path: &Path,
+    key_offset: Option<u64>,
+    key_size: Option<usize>,
+    working_dir: &Path,
+) -> Result<impl KeyInput> {
+    let key_file = resolve_key_file(key_path, working_dir)?;
+
+    Ok(keyfile::KeyfilePrompt {
+        key_file,
+        key_offset,
+        key_size,
+    })
 }
 
 #[cfg(not(feature = "yubikey"))]
@@ -188,6 +377,7 @@ fn yubikey(
     entry_type: YubikeyEntryType,
     passphrase_input: Box<dyn KeyInput>,
     slot: YubikeySlot,
+    serial: Option<u32>,
     uuid: Uuid,
 ) -> impl KeyInput {
     Err(Error::FeatureNotAvailable)
@@ -199,21 +389,181 @@ fn yubikey(
     entry_type: YubikeyEntryType,
     passphrase_input: Box<dyn KeyInput>,
     slot: YubikeySlot,
+    serial: Option<u32>,
     uuid: Uuid,
 ) -> impl KeyInput {
     yubikey::YubikeyPrompt {
         entry_type,
         passphrase_input,
         slot,
+        serial,
         uuid,
     }
 }
 
+/// Get the serial of the Yubikey that would be used for enrollment (if it exposes one)
+#[cfg(not(feature = "yubikey"))]
+pub fn yubikey_serial() -> Option<u32> {
+    None
+}
+
+/// Get the serial of the Yubikey that would be used for enrollment (if it exposes one)
+#[cfg(feature = "yubikey")]
+pub fn yubikey_serial() -> Option<u32> {
+    yubikey::first_device_serial()
+}
+
+/// List the Yubikeys attached to the system
+#[cfg(not(feature = "yubikey"))]
+pub fn yubikey_devices() -> Result<Vec<YubikeyInfo>> {
+    FeatureNotAvailableSnafu.fail()
+}
+
+/// List the Yubikeys attached to the system
+#[cfg(feature = "yubikey")]
+pub fn yubikey_devices() -> Result<Vec<YubikeyInfo>> {
+    yubikey::list_devices()
+}
+
+#[cfg(not(feature = "fido2"))]
+fn fido2(_credential_id: Vec<u8>, _salt: Vec<u8>, _pin_input: Box<dyn KeyInput>) -> Result<Box<dyn KeyInput>> {
+    FeatureNotAvailableSnafu.fail()
+}
+
+/// Create parameters for a FIDO2 hmac-secret input
+#[cfg(feature = "fido2")]
+fn fido2(credential_id: Vec<u8>, salt: Vec<u8>, pin_input: Box<dyn KeyInput>) -> Result<Box<dyn KeyInput>> {
+    Ok(Box::new(fido2::Fido2Prompt {
+        credential_id,
+        salt,
+        pin_input,
+    }))
+}
+
+/// Create a new FIDO2 credential on the first FIDO2 device found
+#[cfg(not(feature = "fido2"))]
+pub fn new_fido2_credential(_key_input_config: &KeyInputConfig) -> Result<Fido2Credential> {
+    FeatureNotAvailableSnafu.fail()
+}
+
+/// Create a new FIDO2 credential on the first FIDO2 device found
+#[cfg(feature = "fido2")]
+pub fn new_fido2_credential(key_input_config: &KeyInputConfig) -> Result<Fido2Credential> {
+    fido2::new_credential(passphrase(key_input_config).as_ref())
+}
+
+/// Write a new keyfile of `size` random bytes that only its owner can read (failing if the file exists)
+pub fn generate_key_file(path: &Path, size: usize) -> Result<()> {
+    keyfile::generate(path, size)
+}
+
+/// Generate a random passphrase for a recovery keyslot
+pub fn new_recovery_passphrase() -> Result<SecStr> {
+    recovery::generate()
+}
+
+/// Save a recovery passphrase to a new file that only its owner can read
+pub fn save_recovery_passphrase(path: &Path, passphrase: &SecStr) -> Result<()> {
+    recovery::save(path, passphrase)
+}
+
+/// Read a passphrase from an environment variable, which is removed from the environment (warning that this is insecure)
+///
+/// The variable is read only once, so the passphrase is kept for `PassphraseInput::Env`.
+pub fn passphrase_from_env(var: &str) -> Result<SecStr> {
+    env::take(var)
+}
+
+mod composite;
+mod env;
+mod fd;
 mod keyfile;
+mod recovery;
+mod stdin;
 mod terminal;
 
+#[cfg(feature = "fido2")]
+mod fido2;
+
 #[cfg(feature = "yubikey")]
 mod yubikey;
 
 #[cfg(feature = "pinentry")]
 mod pinentry;
+
+#[cfg(feature = "systemd")]
+mod ask_password;
+
+#[cfg(test)]
+mod tests {
+    use expectest::prelude::*;
+
+    use crate::db::VolumeId;
+
+    use super::*;
+
+    /// Read the new passphrase from the given answers, returning it with the prompts that were shown
+    fn read_answers(answers: &[&str]) -> (Result<SecStr>, Vec<String>) {
+        let mut prompts = vec![];
+        let mut answers = answers.iter();
+        let passphrase = read_new_passphrase("Enter new passphrase for awesome:", |prompt| {
+            prompts.push(prompt.to_string());
+            Ok(SecStr::from(*answers.next().expect("no more answers")))
+        });
+        (passphrase, prompts)
+    }
+
+    #[test]
+    fn test_new_passphrase_must_be_repeated() {
+        let (passphrase, prompts) = read_answers(&["hunter2", "hunter2"]);
+        expect!(passphrase).to(be_ok().value(SecStr::from("hunter2")));
+        expect!(prompts).to(be_equal_to(vec![
+            "Enter new passphrase for awesome:".to_string(),
+            "Enter new passphrase for awesome (again):".to_string(),
+        ]));
+    }
+
+    #[test]
+    fn test_new_passphrase_is_asked_again_on_mismatch() {
+        let (passphrase, prompts) = read_answers(&["hunter2", "hunter3", "hunter4", "hunter4"]);
+        expect!(passphrase).to(be_ok().value(SecStr::from("hunter4")));
+        expect!(&prompts[2]).to(be_equal_to(
+            "The passphrases do not match, try again. Enter new passphrase for awesome:",
+        ));
+    }
+
+    #[test]
+    fn test_new_passphrase_attempts_are_bounded() {
+        let (passphrase, prompts) = read_answers(&["a", "b", "c", "d", "e", "f"]);
+        expect!(passphrase).to(be_err());
+        expect!(prompts.len()).to(be_equal_to(2 * NEW_PASSPHRASE_ATTEMPTS));
+    }
+
+    #[test]
+    fn test_nested_composite_entry_is_invalid() {
+        let passphrase = DbEntry::PassphraseEntry {
+            volume_id: VolumeId::of(None, Uuid::nil()),
+        };
+        let nested = DbEntry::CompositeEntry {
+            parts: vec![DbEntry::CompositeEntry {
+                parts: vec![passphrase],
+                volume_id: VolumeId::of(None, Uuid::nil()),
+            }],
+            volume_id: VolumeId::of(None, Uuid::nil()),
+        };
+        let config = KeyInputConfig {
+            password_input_timeout: None,
+            passphrase_input: PassphraseInput::Prompt,
+            use_pinentry: false,
+        };
+        let method = get_input_method_for(&nested, &config, ".");
+        expect!(matches!(
+            method,
+            Err(Error::InvalidDbEntryError {
+                source: DbError::NestedCompositeEntryError { .. },
+                ..
+            })
+        ))
+        .to(be_true());
+    }
+}
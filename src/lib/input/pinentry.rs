@@ -3,7 +3,7 @@ use std::time::Duration;
 use pinentry_rs::pinentry;
 use snafu::prelude::*;
 
-use crate::input::{InputName, KeyInput, PinentrySnafu, Result, SecStr};
+use crate::input::{read_new_passphrase, InputName, KeyInput, PinentrySnafu, Result, SecStr};
 
 /// A prompt using pinentry for password entry
 pub struct PinentryPrompt {
@@ -22,21 +22,25 @@ impl KeyInput for PinentryPrompt {
             }
         });
         let title = if is_new {
-            format!("New passphrase")
+            "New passphrase".to_string()
         } else if let Some(ref uuid) = name.uuid {
             format!("Unlock disk (uuid={})", uuid)
         } else {
-            format!("Unlock disk")
+            "Unlock disk".to_string()
         };
 
-        let mut entry = pinentry().window_title(title);
-
-        if let Some(duration) = self.timeout {
-            entry = entry.timeout(duration.as_secs() as u32)
+        // pinentry-rs does not expose the repeat field of pinentry, so new passphrases are confirmed with a second dialog
+        let read = |prompt: &str| -> Result<SecStr> {
+            let mut entry = pinentry().window_title(title.clone());
+            if let Some(duration) = self.timeout {
+                entry = entry.timeout(duration.as_secs() as u32)
+            }
+            entry.pin(prompt.to_string()).context(PinentrySnafu {})
+        };
+        if is_new {
+            read_new_passphrase(&prompt, read)
+        } else {
+            read(&prompt)
         }
-
-        let pin = entry.pin(prompt).context(PinentrySnafu {})?;
-
-        Ok(pin)
     }
 }
@@ -0,0 +1,80 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use snafu::prelude::*;
+
+use crate::input::{IoSnafu, Result, SecStr, UnknownCryptoSnafu};
+
+/// Number of random bytes in a recovery passphrase (160 bits)
+const RECOVERY_PASSPHRASE_BYTES: usize = 20;
+/// Number of hex digits in each dash-separated group of a recovery passphrase
+const RECOVERY_GROUP_LENGTH: usize = 5;
+
+/// Generate a random recovery passphrase (lowercase hex digits in groups of 5, so that it can be typed in)
+pub fn generate() -> Result<SecStr> {
+    let mut bytes = SecStr::new(vec![0u8; RECOVERY_PASSPHRASE_BYTES]);
+    getrandom::getrandom(bytes.unsecure_mut()).map_err(|_| UnknownCryptoSnafu.build())?;
+    Ok(format_passphrase(&bytes))
+}
+
+fn format_passphrase(bytes: &SecStr) -> SecStr {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    let digits = bytes.unsecure().len() * 2;
+    // the capacity is reserved up front, so that no copy of the passphrase is left behind by a reallocation
+    let mut passphrase = Vec::with_capacity(digits + digits / RECOVERY_GROUP_LENGTH);
+    for (i, digit) in bytes
+        .unsecure()
+        .iter()
+        .flat_map(|b| [HEX[(b >> 4) as usize], HEX[(b & 0xf) as usize]])
+        .enumerate()
+    {
+        if i > 0 && i % RECOVERY_GROUP_LENGTH == 0 {
+            passphrase.push(b'-');
+        }
+        passphrase.push(digit);
+    }
+    SecStr::new(passphrase)
+}
+
+/// Write a recovery passphrase to a new file that only its owner can read
+pub fn save(path: &Path, passphrase: &SecStr) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .context(IoSnafu)?;
+    file.write_all(passphrase.unsecure()).context(IoSnafu)?;
+    file.write_all(b"\n").context(IoSnafu)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use expectest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_format_passphrase_groups_hex_digits() {
+        let bytes = SecStr::new(vec![0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        expect!(format_passphrase(&bytes).unsecure()).to(be_equal_to(&b"01234-56789-abcde-f"[..]));
+    }
+
+    #[test]
+    fn test_save_creates_private_file_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recovery.txt");
+        let passphrase = SecStr::from("01234-56789");
+
+        expect!(save(&path, &passphrase)).to(be_ok());
+        expect!(fs::read(&path).unwrap()).to(be_equal_to(b"01234-56789\n".to_vec()));
+        expect!(fs::metadata(&path).unwrap().permissions().mode() & 0o777).to(be_equal_to(0o600));
+        // an existing file is never overwritten
+        expect!(save(&path, &passphrase)).to(be_err());
+    }
+}
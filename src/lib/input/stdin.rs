@@ -0,0 +1,41 @@
+use std::io;
+use std::io::BufRead;
+use std::time::Duration;
+
+use snafu::prelude::*;
+
+use ttypass;
+
+use crate::input::terminal::TerminalPrompt;
+use crate::input::{InputName, IoSnafu, KeyInput, Result, SecStr, StdinClosedSnafu};
+
+/// A passphrase read from standard input, one line per prompt (for scripting)
+///
+/// Note that when several disks are opened or enrolled at once, the key is prompted for only once and is reused
+/// for all of them, so a single line is enough.
+pub struct StdinPrompt {
+    /// Timeout for the terminal prompt (if stdin turns out to be a tty)
+    pub timeout: Option<Duration>,
+}
+
+impl KeyInput for StdinPrompt {
+    fn get_key(&self, name: &InputName, is_new: bool) -> Result<SecStr> {
+        if ttypass::stdin_is_tty() {
+            // nothing is being piped in, so prompt without echo instead
+            return TerminalPrompt { timeout: self.timeout }.get_key(name, is_new);
+        }
+
+        let mut buf = Vec::new();
+        let read = io::stdin().lock().read_until(b'\n', &mut buf).context(IoSnafu)?;
+        if read == 0 {
+            return Err(StdinClosedSnafu.build());
+        }
+
+        // strip the trailing newline (the last line may not have one)
+        if let Some(b'\n') = buf.last() {
+            buf.pop();
+        }
+
+        Ok(SecStr::new(buf))
+    }
+}
@@ -4,7 +4,7 @@ use snafu::prelude::*;
 
 use ttypass;
 
-use crate::input::{InputName, IoSnafu, KeyInput, Result, SecStr};
+use crate::input::{read_new_passphrase, InputName, IoSnafu, KeyInput, Result, SecStr};
 
 /// A terminal prompt for a key (password)
 pub struct TerminalPrompt {
@@ -23,7 +23,14 @@ impl KeyInput for TerminalPrompt {
             }
         });
 
-        let buf = ttypass::read_password(&prompt, self.timeout.clone()).context(IoSnafu)?;
-        Ok(SecStr::new(buf))
+        let read = |prompt: &str| -> Result<SecStr> {
+            let buf = ttypass::read_password(prompt, self.timeout).context(IoSnafu)?;
+            Ok(SecStr::new(buf))
+        };
+        if is_new {
+            read_new_passphrase(&prompt, read)
+        } else {
+            read(&prompt)
+        }
     }
 }
@@ -1,16 +1,22 @@
+use std::time::Duration;
+
 use snafu::prelude::*;
 use uuid::Uuid;
 
 use ykpers_rs::{
-    ChallengeResponse, ChallengeResponseParams, Yubikey, YubikeyDevice, SHA1_BLOCK_LENGTH, SHA1_RESPONSE_LENGTH,
+    ChallengeResponse, ChallengeResponseParams, Error as YubikeyError, Yubikey, YubikeyDevice, YubikeyManager, YK_ERR,
 };
 
 use crate::db::{YubikeyEntryType, YubikeySlot};
-use crate::input::{InputName, KeyInput, Result, SecStr, YubikeySnafu};
+use crate::input::{InputName, KeyInput, Result, SecStr, YubikeyInfo, YubikeySnafu};
+use crate::messages;
 
 #[cfg(feature = "yubikey_hybrid")]
 use self::hybrid::read_hybrid_challenge_response;
 
+/// How long to wait for the Yubikey to be touched (if its slot requires it)
+const TOUCH_TIMEOUT: Duration = Duration::from_secs(15);
+
 /// Parameters for Yubikey input
 pub struct YubikeyPrompt {
     /// Entry type (vanilla challenge-response or hybrid)
@@ -19,13 +25,17 @@ pub struct YubikeyPrompt {
     pub passphrase_input: Box<dyn KeyInput>,
     /// Slot of Yubikey
     pub slot: YubikeySlot,
+    /// Serial of the Yubikey used at enrollment (if recorded)
+    pub serial: Option<u32>,
     /// UUID of the key entry (used as a salt for hybrid)
     pub uuid: Uuid,
 }
 
 impl KeyInput for YubikeyPrompt {
     fn get_key(&self, name: &InputName, is_new: bool) -> Result<SecStr> {
-        let mut dev = get_yubikey_device()?;
+        // the Yubikey is kept to this thread until the key is read (libykpers cannot be used by several at once)
+        let manager = YubikeyManager::lock().context(YubikeySnafu {})?;
+        let mut dev = get_yubikey_device(&manager, self.serial)?;
         let suffix = if is_new {
             format!("new disk {}:", name.name)
         } else {
@@ -46,26 +56,65 @@ impl KeyInput for YubikeyPrompt {
     }
 }
 
-fn get_yubikey_device() -> Result<YubikeyDevice> {
-    let dev = YubikeyDevice::new().context(YubikeySnafu {})?;
+fn get_yubikey_device(manager: &YubikeyManager, serial: Option<u32>) -> Result<YubikeyDevice<'_>> {
+    let dev = match serial {
+        Some(serial) => manager.open_by_serial(serial),
+        None => manager.open_first(),
+    }
+    .context(YubikeySnafu {})?;
     Ok(dev)
 }
 
+/// Get the serial of the first Yubikey found (not all Yubikeys expose their serial)
+pub fn first_device_serial() -> Option<u32> {
+    match YubikeyManager::lock().and_then(|manager| manager.open_first()?.get_serial()) {
+        Ok(serial) => Some(serial),
+        Err(e) => {
+            debug!("Could not read the serial of the Yubikey: {}", e);
+            None
+        }
+    }
+}
+
+/// Read the serial, firmware version and configured slots of all attached Yubikeys
+pub fn list_devices() -> Result<Vec<YubikeyInfo>> {
+    let devices = YubikeyManager::lock()
+        .and_then(|manager| manager.enumerate())
+        .context(YubikeySnafu {})?;
+    Ok(devices
+        .into_iter()
+        .map(|device| YubikeyInfo {
+            serial: device.serial,
+            version: device.version,
+            configured_slots: device.configured_slots,
+        })
+        .collect())
+}
+
 fn read_challenge_response<Dev: ChallengeResponse>(
     dev: &mut Dev,
     slot: YubikeySlot,
     challenge: &SecStr,
 ) -> Result<SecStr> {
-    let params = ChallengeResponseParams { slot, is_hmac: true };
-    println!("Please interact with the Yubikey now...");
-    let mut response = [0u8; SHA1_BLOCK_LENGTH];
-    dev.challenge_response(params, challenge.unsecure(), &mut response)
-        .context(YubikeySnafu {})?;
-    let key = SecStr::new(response[0..SHA1_RESPONSE_LENGTH].to_vec());
-    for b in response.iter_mut() {
-        *b = 0u8;
+    // try without blocking first, so that the user is only asked to touch the key if it is needed
+    let params = ChallengeResponseParams {
+        may_block: false,
+        ..ChallengeResponseParams::new(true, slot)
+    };
+    let response = match dev.hmac_challenge_response(params, challenge.unsecure()) {
+        Err(YubikeyError::YkError(YK_ERR::EWOULDBLOCK)) => {
+            messages::status("Please touch the Yubikey now...");
+            // the key already has the challenge and only waits for the touch to answer it
+            let params = ChallengeResponseParams {
+                timeout: Some(TOUCH_TIMEOUT),
+                ..ChallengeResponseParams::new(true, slot)
+            };
+            dev.hmac_read_pending_response(params)
+        }
+        other => other,
     }
-    Ok(key)
+    .context(YubikeySnafu {})?;
+    Ok(SecStr::new(response.as_bytes().to_vec()))
 }
 
 #[cfg(not(feature = "yubikey_hybrid"))]
@@ -82,12 +131,18 @@ fn read_hybrid_challenge_response<Dev>(
 #[cfg(test)]
 pub mod tests {
     use std::collections::HashMap;
+    use std::time::Duration;
 
     use expectest::prelude::*;
 
-    use ykpers_rs::{ChallengeResponse, ChallengeResponseParams, Result, SHA1_BLOCK_LENGTH};
+    use ykpers_rs::{
+        ChallengeResponse, ChallengeResponseParams, Error, Result, SHA1_BLOCK_LENGTH, SHA1_RESPONSE_LENGTH, YK_ERR,
+    };
 
     use crate::db::YubikeySlot;
+    use crate::input::SecStr;
+
+    use super::{read_challenge_response, TOUCH_TIMEOUT};
 
     pub struct MockChallengeResponse<'a> {
         responses: HashMap<(YubikeySlot, &'a [u8]), Result<&'a [u8; SHA1_BLOCK_LENGTH]>>,
@@ -106,6 +161,14 @@ pub mod tests {
                 .unwrap_or_else(|| panic!("Nothing found for slot: {:?}, challenge {:?}", params.slot, challenge))
                 .map(|got_bytes| response.clone_from(got_bytes))
         }
+
+        fn read_pending_response(
+            &mut self,
+            _params: ChallengeResponseParams,
+            _response: &mut [u8; SHA1_BLOCK_LENGTH],
+        ) -> Result<()> {
+            panic!("No challenge is pending, the mock never blocks")
+        }
     }
 
     impl<'a> MockChallengeResponse<'a> {
@@ -120,9 +183,70 @@ pub mod tests {
         }
     }
 
+    /// Mock of a Yubikey that needs to be touched (non-blocking challenges would block)
+    struct TouchChallengeResponse {
+        /// Whether each challenge that was sent may block
+        challenges: Vec<bool>,
+        /// Timeout of each wait for the response to a pending challenge
+        pending_reads: Vec<Option<Duration>>,
+    }
+
+    impl ChallengeResponse for TouchChallengeResponse {
+        fn challenge_response(
+            &mut self,
+            params: ChallengeResponseParams,
+            _challenge: &[u8],
+            response: &mut [u8; SHA1_BLOCK_LENGTH],
+        ) -> Result<()> {
+            self.challenges.push(params.may_block);
+            if params.may_block {
+                response.copy_from_slice(&[42u8; SHA1_BLOCK_LENGTH]);
+                Ok(())
+            } else {
+                Err(Error::YkError(YK_ERR::EWOULDBLOCK))
+            }
+        }
+
+        fn read_pending_response(
+            &mut self,
+            params: ChallengeResponseParams,
+            response: &mut [u8; SHA1_BLOCK_LENGTH],
+        ) -> Result<()> {
+            assert!(!self.challenges.is_empty(), "No challenge was sent");
+            self.pending_reads.push(params.timeout);
+            response.copy_from_slice(&[42u8; SHA1_BLOCK_LENGTH]);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_read_challenge_response_blocks_when_touch_needed() {
+        let mut dev = TouchChallengeResponse {
+            challenges: vec![],
+            pending_reads: vec![],
+        };
+        let key = read_challenge_response(&mut dev, 1, &SecStr::new(b"hello world".to_vec()));
+
+        expect!(key.as_ref().map(|k| k.unsecure())).to(be_ok().value(&[42u8; SHA1_RESPONSE_LENGTH][..]));
+        // the challenge is only sent once, its response is then waited for
+        expect!(dev.challenges).to(be_equal_to(vec![false]));
+        expect!(dev.pending_reads).to(be_equal_to(vec![Some(TOUCH_TIMEOUT)]));
+    }
+
+    #[test]
+    fn test_hmac_challenge_response_keeps_only_the_digest() {
+        let challenge = b"hello world";
+        let mut response = [7u8; SHA1_BLOCK_LENGTH];
+        response[..SHA1_RESPONSE_LENGTH].copy_from_slice(&[42u8; SHA1_RESPONSE_LENGTH]);
+        let mut mock = MockChallengeResponse::new(1, &challenge[..], &response);
+
+        let got = mock.hmac_challenge_response(ChallengeResponseParams::new(true, 1), &challenge[..]);
+        expect!(got.as_ref().map(|r| *r.as_bytes())).to(be_ok().value([42u8; SHA1_RESPONSE_LENGTH]));
+    }
+
     #[test]
     fn test_sanity() {
-        let params = ChallengeResponseParams { is_hmac: true, slot: 1 };
+        let params = ChallengeResponseParams::new(true, 1);
         let challenge = b"hello world";
         let response = [42u8; SHA1_BLOCK_LENGTH];
         let mut got_response = [0u8; SHA1_BLOCK_LENGTH];
@@ -140,6 +264,7 @@ mod hybrid {
     use sodiumoxide::crypto::hash::sha256;
     use sodiumoxide::crypto::pwhash::scryptsalsa208sha256;
     use uuid::Uuid;
+    use zeroize::Zeroize;
 
     use ykpers_rs::{ChallengeResponse, SHA1_BLOCK_LENGTH};
 
@@ -159,17 +284,18 @@ mod hybrid {
     }
 
     fn derive_challenge_key(challenge: &SecStr, uuid: &Uuid) -> Result<SecStr> {
-        let mut derived_key = vec![0u8; SHA1_BLOCK_LENGTH];
+        // derive into a `SecStr` directly, so that the key is cleared on drop (also if the derivation fails)
+        let mut derived_key = SecStr::new(vec![0u8; SHA1_BLOCK_LENGTH]);
         let salt = salt_from_uuid(uuid);
         let _ = scryptsalsa208sha256::derive_key(
-            &mut derived_key,
+            derived_key.unsecure_mut(),
             challenge.unsecure(),
             &salt,
             scryptsalsa208sha256::OpsLimit(PWHASH_OPSLIMIT),
             scryptsalsa208sha256::MemLimit(PWHASH_MEMLIMIT),
         )
         .map_err(|_| UnknownCryptoSnafu.build())?;
-        Ok(SecStr::new(derived_key))
+        Ok(derived_key)
     }
 
     fn hash_challenge_and_then_response<Dev: ChallengeResponse>(
@@ -194,10 +320,14 @@ mod hybrid {
         sodiumoxide::init().expect("libsodium to be initialised");
 
         let response = hash_challenge_and_then_response(dev, slot, chal, uuid)?;
-        let sha256::Digest(response_hash) = sha256::hash(&response.unsecure());
-        let auth_key = hmacsha512::Key(response_hash);
-        let hmacsha512::Tag(final_key) = hmacsha512::authenticate(other_passphrase.unsecure(), &auth_key);
-        Ok(SecStr::new(final_key.to_vec()))
+        let mut response_hash = sha256::hash(response.unsecure());
+        // the key is cleared on drop by sodiumoxide, the hash and the tag are plain byte arrays and need clearing here
+        let auth_key = hmacsha512::Key(response_hash.0);
+        response_hash.0.zeroize();
+        let mut final_key = hmacsha512::authenticate(other_passphrase.unsecure(), &auth_key);
+        let key = SecStr::new(final_key.0.to_vec());
+        final_key.0.zeroize();
+        Ok(key)
     }
 
     #[cfg(test)]
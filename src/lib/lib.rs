@@ -2,8 +2,10 @@
 #![deny(bare_trait_objects)]
 #![warn(unused_must_use)]
 
+extern crate base64;
 extern crate cryptsetup_rs;
 extern crate errno;
+extern crate libcryptsetup_sys;
 extern crate secstr;
 extern crate serde;
 extern crate serde_json;
@@ -13,13 +15,17 @@ extern crate uuid;
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "fido2")]
+extern crate fido2_rs;
+
+extern crate getrandom;
+
 #[cfg(feature = "pinentry")]
 extern crate pinentry_rs;
 
 #[macro_use]
 extern crate serde_derive;
 
-#[macro_use]
 extern crate vec1;
 
 #[cfg(feature = "yubikey_hybrid")]
@@ -28,6 +34,9 @@ extern crate sodiumoxide;
 #[cfg(feature = "yubikey")]
 extern crate ykpers_rs;
 
+#[cfg(feature = "yubikey")]
+extern crate zeroize;
+
 #[cfg(test)]
 extern crate env_logger;
 
@@ -37,7 +46,11 @@ extern crate expectest;
 #[cfg(test)]
 extern crate tempfile;
 
+#[macro_use]
+mod logging;
+
 pub mod context;
 pub mod db;
 pub mod device;
 pub mod input;
+pub mod messages;
@@ -0,0 +1,10 @@
+//! Log lines about a disk, prefixed with the operation and the LUKS UUID of the disk (e.g. `[open 2f4c...] ...`), so
+//! that the lines of an operation on many disks can be told apart
+
+/// Log a line about a disk at the given level, like `log!` does (`disk_log!(Level::Debug, "open", uuid, "...", ...)`)
+#[macro_export]
+macro_rules! disk_log {
+    ($level:expr, $operation:expr, $uuid:expr, $($arg:tt)+) => {
+        ::log::log!($level, "[{} {}] {}", $operation, $uuid, format_args!($($arg)+))
+    };
+}
@@ -0,0 +1,104 @@
+//! Status messages for the user (e.g. asking them to touch a token), which respect the verbosity set by the caller
+//!
+//! Prompts for keys and the results of commands are not status messages and are always shown. Status messages go to
+//! standard error, so that standard output only has the results of commands (e.g. for `--json`).
+
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How much is printed besides prompts and results
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// No status messages
+    Quiet = 0,
+    /// Status messages that ask the user to do something or explain a wait (the default)
+    Normal = 1,
+    /// Details of what is being done as well
+    Verbose = 2,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+const SPINNER_FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+const SPINNER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Set the verbosity for the rest of the process
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+/// The current verbosity
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        _ => Verbosity::Verbose,
+    }
+}
+
+/// Print a status message on standard error (unless quiet)
+pub fn status<M: Display>(message: M) {
+    if verbosity() >= Verbosity::Normal {
+        eprintln!("{}", message);
+    }
+}
+
+/// Print a detail of what is being done on standard error (only if verbose)
+pub fn detail<M: Display>(message: M) {
+    if verbosity() >= Verbosity::Verbose {
+        eprintln!("{}", message);
+    }
+}
+
+/// Spinner shown on standard error while a slow step runs (e.g. deriving the key of a new argon2 keyslot), until the
+/// guard is dropped
+///
+/// Nothing is shown when quiet or when standard error is not a terminal (the spinner would only clutter a log).
+pub struct ProgressGuard {
+    spinner: Option<(Arc<AtomicBool>, JoinHandle<()>)>,
+}
+
+impl ProgressGuard {
+    /// Start showing the spinner with the message next to it
+    pub fn start<M: Display>(message: M) -> ProgressGuard {
+        if verbosity() < Verbosity::Normal || unsafe { libc::isatty(libc::STDERR_FILENO) } != 1 {
+            return ProgressGuard { spinner: None };
+        }
+
+        let message = message.to_string();
+        let done = Arc::new(AtomicBool::new(false));
+        let spinner_done = done.clone();
+        let handle = thread::spawn(move || {
+            let mut stderr = io::stderr();
+            for frame in SPINNER_FRAMES.iter().cycle() {
+                if spinner_done.load(Ordering::Acquire) {
+                    break;
+                }
+                let _ = write!(stderr, "\r{} {}", frame, message);
+                let _ = stderr.flush();
+                // woken up early when the guard is dropped
+                thread::park_timeout(SPINNER_INTERVAL);
+            }
+            // the line is cleared, so that whatever is printed next starts on an empty one
+            let _ = write!(stderr, "\r{}\r", " ".repeat(message.chars().count() + 2));
+            let _ = stderr.flush();
+        });
+        ProgressGuard {
+            spinner: Some((done, handle)),
+        }
+    }
+}
+
+impl Drop for ProgressGuard {
+    fn drop(&mut self) {
+        if let Some((done, handle)) = self.spinner.take() {
+            done.store(true, Ordering::Release);
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
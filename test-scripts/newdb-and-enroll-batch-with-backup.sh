@@ -0,0 +1,68 @@
+#!/bin/sh -xe
+
+cleanup() {
+    if [ -d "$OPDIR" ]; then
+        cd $OPDIR
+        $peroxs close --name test-batch disk-image-0 disk-image-1 || true
+        echo "Removing OPDIR $OPDIR"
+        rm -rf $OPDIR
+    fi
+}
+trap cleanup EXIT
+
+echo "Starting the newdb-and-enroll-batch-with-backup scenario"
+
+export RUST_LOG=peroxide_cryptsetup=debug,cryptsetup_rs=debug
+export OPDIR=`mktemp -d`
+
+peroxs="`pwd`/target/debug/peroxs"
+[ ! -f $peroxs ] && echo "ERROR: peroxs not found in $peroxs" && exit 1
+
+echo "Using $OPDIR"
+
+cd $OPDIR
+
+dd if=/dev/urandom of=disk-image-0 bs=1M count=20
+dd if=/dev/urandom of=disk-image-1 bs=1M count=20
+echo "Made disk images"
+
+$peroxs init backup --db backup.json
+echo "Made db of type backup"
+
+dd if=/dev/urandom of=keyfile.key count=256
+echo "Made keyfile"
+
+$peroxs enroll keyfile \
+  -2 \
+  --db backup.json \
+  --format \
+  --cipher aes-xts-plain \
+  --hash sha256 \
+  --key-bits 256 \
+  --iteration-ms 200 \
+  --argon2-iterations 1000 \
+  --argon2-memory-kb 1024 \
+  --argon2-parallel-threads 1 \
+  keyfile.key disk-image-0 disk-image-1
+echo "Enrolled keyfile in backup db"
+
+$peroxs init operation
+echo "Made db of type operation"
+
+# only a single line is piped in: a second prompt for either disk would fail with stdin being closed
+echo "correct horse battery staple" | $peroxs enroll passphrase \
+  --passphrase-stdin \
+  --backup-db backup.json \
+  --iteration-ms 200 \
+  --argon2-iterations 1000 \
+  --argon2-memory-kb 1024 \
+  --argon2-parallel-threads 1 \
+  disk-image-0 disk-image-1
+echo "Enrolled passphrase for both disks with a single prompt"
+
+echo "correct horse battery staple" | $peroxs open --passphrase-stdin --name test-batch disk-image-0 disk-image-1
+echo "Opened both disks with a single prompt"
+
+for mapping in test-batch_0 test-batch_1; do
+    [ -b /dev/mapper/$mapping ] || (echo "ERROR: $mapping is not open" && exit 1)
+done
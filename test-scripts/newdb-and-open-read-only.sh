@@ -0,0 +1,55 @@
+#!/bin/sh -xe
+
+cleanup() {
+    if [ -d "$OPDIR" ]; then
+        cd $OPDIR
+        $peroxs close --name test-ro disk-image-0 disk-image-1 || true
+        echo "Removing OPDIR $OPDIR"
+        rm -rf $OPDIR
+    fi
+}
+trap cleanup EXIT
+
+echo "Starting the newdb-and-open-read-only scenario"
+
+export RUST_LOG=peroxide_cryptsetup=debug,cryptsetup_rs=debug
+export OPDIR=`mktemp -d`
+
+peroxs="`pwd`/target/debug/peroxs"
+[ ! -f $peroxs ] && echo "ERROR: peroxs not found in $peroxs" && exit 1
+
+echo "Using $OPDIR"
+
+cd $OPDIR
+
+dd if=/dev/urandom of=disk-image-0 bs=1M count=20
+dd if=/dev/urandom of=disk-image-1 bs=1M count=20
+echo "Made disk images"
+
+$peroxs init backup
+echo "Made db of type backup"
+
+dd if=/dev/urandom of=keyfile.key count=256
+echo "Made keyfile"
+
+$peroxs enroll keyfile \
+  -2 \
+  --format \
+  --cipher aes-xts-plain \
+  --hash sha256 \
+  --key-bits 256 \
+  --iteration-ms 200 \
+  --argon2-iterations 1000 \
+  --argon2-memory-kb 1024 \
+  --argon2-parallel-threads 1 \
+  keyfile.key disk-image-0 disk-image-1
+echo "Enrolled db"
+
+# opening several disks at once goes through the multi-disk path of open_disks
+$peroxs open --read-only --name test-ro disk-image-0 disk-image-1
+echo "Opened disks read-only"
+
+for mapping in test-ro_0 test-ro_1; do
+    [ "`blockdev --getro /dev/mapper/$mapping`" = "1" ] || (echo "ERROR: $mapping is not read-only" && exit 1)
+done
+echo "Mappings are read-only"